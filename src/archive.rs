@@ -0,0 +1,378 @@
+use crate::objects::Hash;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Write};
+
+/// The kind of an archive entry, mapped to a unix mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Directory,
+    File,
+    Executable,
+    Symlink,
+}
+
+impl Kind {
+    /// Permissions like git writes them with the default umask (002)
+    fn mode(self) -> u32 {
+        match self {
+            Kind::Directory => 0o40775,
+            Kind::File => 0o100664,
+            Kind::Executable => 0o100775,
+            Kind::Symlink => 0o120777,
+        }
+    }
+}
+
+/// A writer of archive entries
+pub trait Archive {
+    /// Add an entry given the hash of its object. Directory paths end with a
+    /// slash and the data of a symlink is its target.
+    fn add(&mut self, path: &str, kind: Kind, hash: Hash, data: &[u8]) -> io::Result<()>;
+
+    /// Write the end of the archive
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+const BLOCK: usize = 512;
+const RECORD: usize = 20 * BLOCK;
+
+/// A tar archive in the ustar format, using pax headers for long names
+pub struct Tar<W: Write> {
+    out: W,
+    mtime: i64,
+    written: usize,
+}
+
+/// Write `value` in octal on `width - 1` digits followed by a NUL byte
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let value = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&value.as_bytes()[value.len() - width..]);
+    field[width] = 0;
+}
+
+/// A pax record: `<length> <key>=<value>\n`, the length including itself
+fn pax_record(key: &str, value: &str) -> String {
+    let len = key.len() + value.len() + 3;
+    let mut total = len + len.to_string().len();
+    if total.to_string().len() != len.to_string().len() {
+        total += 1;
+    }
+    format!("{} {}={}\n", total, key, value)
+}
+
+/// Split a long path between the prefix and name fields of a ustar header
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let bytes = path.as_bytes();
+    (1..bytes.len().min(156))
+        .rev()
+        .filter(|i| bytes[*i] == b'/')
+        .map(|i| (&path[..i], &path[i + 1..]))
+        .find(|(_, name)| !name.is_empty() && name.len() <= 100)
+}
+
+impl<W: Write> Tar<W> {
+    /// Create a tar archive whose entries are dated `mtime`. The `comment`
+    /// (usually the commit id) is stored in a pax global header.
+    pub fn new(out: W, mtime: i64, comment: Option<String>) -> io::Result<Self> {
+        let mut tar = Tar {
+            out,
+            mtime,
+            written: 0,
+        };
+        if let Some(comment) = comment {
+            let record = pax_record("comment", &comment);
+            tar.entry("pax_global_header", "", b'g', 0o666, record.as_bytes(), "")?;
+        }
+        Ok(tar)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    /// Write a header and the data of an entry padded to a block
+    fn entry(
+        &mut self,
+        name: &str,
+        prefix: &str,
+        typeflag: u8,
+        mode: u32,
+        data: &[u8],
+        link: &str,
+    ) -> io::Result<()> {
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut header[100..108], u64::from(mode & 0o7777));
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], data.len() as u64);
+        octal(&mut header[136..148], self.mtime.max(0) as u64);
+        header[156] = typeflag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[265..269].copy_from_slice(b"root");
+        header[297..301].copy_from_slice(b"root");
+        octal(&mut header[329..337], 0);
+        octal(&mut header[337..345], 0);
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // The checksum is computed with its own field filled with spaces
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+        octal(&mut header[148..156], u64::from(sum));
+
+        self.write(&header)?;
+        self.write(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.write(&vec![0; padding])
+    }
+}
+
+impl<W: Write> Archive for Tar<W> {
+    fn add(&mut self, path: &str, kind: Kind, hash: Hash, data: &[u8]) -> io::Result<()> {
+        let (typeflag, data, link) = match kind {
+            Kind::Directory => (b'5', &b""[..], ""),
+            Kind::Symlink => (b'2', &b""[..], std::str::from_utf8(data).unwrap_or("")),
+            _ => (b'0', data, ""),
+        };
+        let mut extended = String::new();
+        let data_name = format!("{}.data", hash);
+        let (prefix, name) = match split_path(path) {
+            _ if path.len() <= 100 => ("", path),
+            Some((prefix, name)) => (prefix, name),
+            None => {
+                extended.push_str(&pax_record("path", path));
+                ("", &data_name[..])
+            }
+        };
+        let link = if link.len() > 100 {
+            extended.push_str(&pax_record("linkpath", link));
+            &link[..100]
+        } else {
+            link
+        };
+        if !extended.is_empty() {
+            let name = format!("{}.paxheader", hash);
+            self.entry(&name, "", b'x', 0o666, extended.as_bytes(), "")?;
+        }
+        self.entry(name, prefix, typeflag, kind.mode(), data, link)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write(&[0; 2 * BLOCK])?;
+        let padding = (RECORD - self.written % RECORD) % RECORD;
+        self.write(&vec![0; padding])?;
+        self.out.flush()
+    }
+}
+
+/// A zip archive, files are deflated unless it doesn't make them smaller
+pub struct Zip<W: Write> {
+    out: W,
+    time: u16,
+    date: u16,
+    offset: u32,
+    central: Vec<u8>,
+    count: u16,
+    comment: String,
+}
+
+/// Convert a timestamp to the MS-DOS time and date fields
+fn dos_datetime(timestamp: i64) -> (u16, u16) {
+    let date = NaiveDateTime::from_timestamp(timestamp.max(315_532_800), 0);
+    let time = (date.hour() << 11) | (date.minute() << 5) | (date.second() / 2);
+    let day = ((date.year() as u32 - 1980) << 9) | (date.month() << 5) | date.day();
+    (time as u16, day as u16)
+}
+
+impl<W: Write> Zip<W> {
+    pub fn new(out: W, mtime: i64, comment: Option<String>) -> Self {
+        let (time, date) = dos_datetime(mtime);
+        Zip {
+            out,
+            time,
+            date,
+            offset: 0,
+            central: vec![],
+            count: 0,
+            comment: comment.unwrap_or_default(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Archive for Zip<W> {
+    fn add(&mut self, path: &str, kind: Kind, _hash: Hash, data: &[u8]) -> io::Result<()> {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(data)?;
+        let deflated = encoder.finish()?;
+        let (method, version, content) = if kind != Kind::Directory && deflated.len() < data.len() {
+            (8u16, 20u16, &deflated[..])
+        } else {
+            (0, 10, data)
+        };
+        // Names are UTF-8 (bit 11)
+        let flags: u16 = if path.is_ascii() { 0 } else { 1 << 11 };
+        let mut attributes = kind.mode() << 16;
+        if kind == Kind::Directory {
+            attributes |= 0x10;
+        }
+
+        let mut common = vec![];
+        common.extend(&version.to_le_bytes());
+        common.extend(&flags.to_le_bytes());
+        common.extend(&method.to_le_bytes());
+        common.extend(&self.time.to_le_bytes());
+        common.extend(&self.date.to_le_bytes());
+        common.extend(&crc.sum().to_le_bytes());
+        common.extend(&(content.len() as u32).to_le_bytes());
+        common.extend(&(data.len() as u32).to_le_bytes());
+        common.extend(&(path.len() as u16).to_le_bytes());
+        common.extend(&0u16.to_le_bytes());
+
+        let mut local = vec![];
+        local.extend(&0x0403_4b50u32.to_le_bytes());
+        local.extend(&common);
+        local.extend(path.as_bytes());
+        self.out.write_all(&local)?;
+        self.out.write_all(content)?;
+
+        // Made by unix (3), version 3.0
+        self.central.extend(&0x0201_4b50u32.to_le_bytes());
+        self.central.extend(&0x031eu16.to_le_bytes());
+        self.central.extend(&common);
+        self.central.extend(&0u16.to_le_bytes());
+        self.central.extend(&0u16.to_le_bytes());
+        self.central.extend(&0u16.to_le_bytes());
+        self.central.extend(&attributes.to_le_bytes());
+        self.central.extend(&self.offset.to_le_bytes());
+        self.central.extend(path.as_bytes());
+
+        self.offset += (local.len() + content.len()) as u32;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut end = vec![];
+        end.extend(&0x0605_4b50u32.to_le_bytes());
+        end.extend(&0u16.to_le_bytes());
+        end.extend(&0u16.to_le_bytes());
+        end.extend(&self.count.to_le_bytes());
+        end.extend(&self.count.to_le_bytes());
+        end.extend(&(self.central.len() as u32).to_le_bytes());
+        end.extend(&self.offset.to_le_bytes());
+        end.extend(&(self.comment.len() as u16).to_le_bytes());
+        end.extend(self.comment.as_bytes());
+        self.out.write_all(&self.central)?;
+        self.out.write_all(&end)?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn pax_record_length() {
+        assert_eq!(
+            pax_record("comment", &"a".repeat(40)),
+            format!("52 comment={}\n", "a".repeat(40))
+        );
+        // The length gains a digit when the record reaches 100 bytes
+        let record = pax_record("path", &"a".repeat(91));
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with("101 path="));
+    }
+
+    #[test]
+    fn tar_header() {
+        let mut tar = Tar::new(vec![], 1_000_000_000, None).unwrap();
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        tar.add("dir/file", Kind::Executable, hash, b"hello\n")
+            .unwrap();
+        tar.finish().unwrap();
+        let out = tar.into_inner();
+        assert_eq!(out.len(), RECORD);
+        assert_eq!(&out[..8], b"dir/file");
+        assert_eq!(&out[100..108], b"0000775\0");
+        assert_eq!(&out[124..136], b"00000000006\0");
+        assert_eq!(&out[136..148], b"07346545000\0");
+        assert_eq!(&out[BLOCK..BLOCK + 6], b"hello\n");
+    }
+
+    #[test]
+    fn zip_headers() {
+        let mut zip = Zip::new(vec![], 1_000_000_000, Some(String::from("abc")));
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        zip.add("dir/", Kind::Directory, hash, b"").unwrap();
+        zip.add("dir/file", Kind::Executable, hash, b"hello\n")
+            .unwrap();
+        let long = "a".repeat(1000);
+        zip.add("long", Kind::File, hash, long.as_bytes()).unwrap();
+        zip.finish().unwrap();
+        let out = zip.into_inner();
+        let u16_at = |pos: usize| u16::from_le_bytes([out[pos], out[pos + 1]]);
+        let u32_at =
+            |pos: usize| u32::from_le_bytes([out[pos], out[pos + 1], out[pos + 2], out[pos + 3]]);
+
+        // Local file headers followed by the contents
+        let mut offsets = vec![];
+        let mut pos = 0;
+        for (name, method, size) in &[("dir/", 0, 0), ("dir/file", 0, 6), ("long", 8, 1000)] {
+            offsets.push(pos as u32);
+            assert_eq!(u32_at(pos), 0x0403_4b50);
+            assert_eq!(u16_at(pos + 8), *method);
+            // 9 Sep 2001 01:46:40 UTC
+            assert_eq!(u16_at(pos + 10), (1 << 11) | (46 << 5) | 20);
+            assert_eq!(u16_at(pos + 12), (21 << 9) | (9 << 5) | 9);
+            assert_eq!(u32_at(pos + 22), *size);
+            assert_eq!(u16_at(pos + 26) as usize, name.len());
+            assert_eq!(&out[pos + 30..pos + 30 + name.len()], name.as_bytes());
+            let compressed = u32_at(pos + 18) as usize;
+            pos += 30 + name.len() + compressed;
+        }
+        let stored = offsets[1] as usize + 30 + 8;
+        assert_eq!(&out[stored..stored + 6], b"hello\n");
+        let central = pos;
+
+        // The central directory points to each local header
+        for ((name, mode), offset) in [
+            ("dir/", 0o40775),
+            ("dir/file", 0o100775),
+            ("long", 0o100664),
+        ]
+        .iter()
+        .zip(offsets)
+        {
+            assert_eq!(u32_at(pos), 0x0201_4b50);
+            assert_eq!(u32_at(pos + 38) >> 16, *mode);
+            assert_eq!(u32_at(pos + 42), offset);
+            assert_eq!(&out[pos + 46..pos + 46 + name.len()], name.as_bytes());
+            pos += 46 + name.len();
+        }
+
+        // And the end record to the central directory
+        assert_eq!(u32_at(pos), 0x0605_4b50);
+        assert_eq!(u16_at(pos + 10), 3);
+        assert_eq!(u32_at(pos + 12) as usize, pos - central);
+        assert_eq!(u32_at(pos + 16) as usize, central);
+        assert_eq!(&out[pos + 20..], b"\x03\x00abc");
+    }
+}
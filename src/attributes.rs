@@ -0,0 +1,319 @@
+use crate::diff;
+use crate::filter::{Direction, Filters};
+use crate::ignore::Rule;
+use crate::objects::{Blob, Object, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The state of an attribute assigned to a path
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    /// `attr`
+    Set,
+    /// `-attr`
+    Unset,
+    /// `attr=value`
+    Value(String),
+}
+
+/// A line of an attributes file: a pattern and the attributes it assigns.
+/// A None state (`!attr`) makes the attribute unspecified again.
+struct Line {
+    rule: Rule,
+    attributes: Vec<(String, Option<State>)>,
+}
+
+impl Line {
+    /// Parse a line of an attributes file located in the `base` directory.
+    /// Return None for blank lines, comments and negative patterns which
+    /// are forbidden.
+    fn parse(line: &str, base: &Path, source: &str, number: usize) -> Option<Line> {
+        let mut words = line.split_whitespace();
+        let rule = Rule::parse(words.next()?, base, source, number)?;
+        if rule.negated {
+            return None;
+        }
+        let mut attributes = vec![];
+        for word in words {
+            if let Some(name) = word.strip_prefix('-') {
+                attributes.push((name.to_string(), Some(State::Unset)));
+            } else if let Some(name) = word.strip_prefix('!') {
+                attributes.push((name.to_string(), None));
+            } else if let Some((name, value)) = word.split_once('=') {
+                attributes.push((name.to_string(), Some(State::Value(value.to_string()))));
+            } else if word == "binary" {
+                // The only built-in macro
+                attributes.push((String::from("binary"), Some(State::Set)));
+                for name in ["diff", "merge", "text"].iter() {
+                    attributes.push((name.to_string(), Some(State::Unset)));
+                }
+            } else {
+                attributes.push((word.to_string(), Some(State::Set)));
+            }
+        }
+        Some(Line { rule, attributes })
+    }
+}
+
+fn parse_lines(content: &str, base: &Path, source: &str) -> Vec<Line> {
+    content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Line::parse(line, base, source, i + 1))
+        .collect()
+}
+
+/// The attributes of the paths of a repository. By order of precedence they
+/// come from `.my_git/info/attributes` and the `.my_gitattributes` files of
+/// the directory of a path and of its parents.
+pub struct Attributes {
+    root: PathBuf,
+    /// Content of the `.my_gitattributes` files of a tree by directory, the
+    /// files of the working tree are read otherwise
+    files: Option<HashMap<PathBuf, Vec<u8>>>,
+    /// Lines of the `.my_gitattributes` file of each directory, read when
+    /// needed
+    dirs: Mutex<HashMap<PathBuf, Arc<Vec<Line>>>>,
+    info: Vec<Line>,
+    filters: Filters,
+}
+
+impl Attributes {
+    pub(crate) fn new(
+        root: &Path,
+        files: Option<HashMap<PathBuf, Vec<u8>>>,
+        filters: Filters,
+    ) -> Attributes {
+        let info = fs::read_to_string(root.join(".my_git/info/attributes")).unwrap_or_default();
+        Attributes {
+            root: root.to_path_buf(),
+            files,
+            dirs: Mutex::new(HashMap::new()),
+            info: parse_lines(&info, Path::new(""), ".my_git/info/attributes"),
+            filters,
+        }
+    }
+
+    /// Attributes read from the working tree
+    pub fn load(root: &Path) -> Attributes {
+        Attributes::new(root, None, Filters::load())
+    }
+
+    /// Attributes read from the `.my_gitattributes` files of a tree, used
+    /// when its files are written to the working tree or archived
+    pub fn from_tree(repo_path: &Path, root: &Path, tree: &Tree) -> Attributes {
+        let files = tree
+            .files()
+            .into_iter()
+            .filter(|(path, _)| Path::new(path).ends_with(".my_gitattributes"))
+            .map(|(path, (_, hash))| {
+                let dir = Path::new(&path).parent().unwrap().to_path_buf();
+                (dir, Blob::load(repo_path, hash).data)
+            })
+            .collect();
+        Attributes::new(root, Some(files), Filters::load())
+    }
+
+    fn dir_lines(&self, dir: &Path) -> Arc<Vec<Line>> {
+        if let Some(lines) = self.dirs.lock().unwrap().get(dir) {
+            return lines.clone();
+        }
+        let file = dir.join(".my_gitattributes");
+        let content = match &self.files {
+            Some(files) => files.get(dir).cloned().unwrap_or_default(),
+            None => fs::read(self.root.join(&file)).unwrap_or_default(),
+        };
+        let content = String::from_utf8_lossy(&content);
+        let lines = Arc::new(parse_lines(&content, dir, file.to_str().unwrap_or("")));
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), lines.clone());
+        lines
+    }
+
+    /// Return the attributes of a file given its path, relative to the root
+    /// or absolute
+    pub fn get(&self, path: &Path) -> BTreeMap<String, State> {
+        self.lookup(path, false)
+    }
+
+    /// Whether an attribute is set for a file or a directory
+    pub fn is_set(&self, path: &Path, is_dir: bool, name: &str) -> bool {
+        self.lookup(path, is_dir).get(name) == Some(&State::Set)
+    }
+
+    /// Lines of higher precedence override the previous ones
+    fn lookup(&self, path: &Path, is_dir: bool) -> BTreeMap<String, State> {
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+        dirs.reverse();
+        let mut res = BTreeMap::new();
+        let mut assign = |line: &Line| {
+            if !line.rule.matches(path, is_dir) {
+                return;
+            }
+            for (name, state) in line.attributes.iter() {
+                match state {
+                    Some(state) => res.insert(name.clone(), state.clone()),
+                    None => res.remove(name),
+                };
+            }
+        };
+        for dir in dirs {
+            self.dir_lines(dir).iter().for_each(&mut assign);
+        }
+        self.info.iter().for_each(assign);
+        res
+    }
+
+    /// Pass a content through the filter named by the `filter` attribute
+    fn filter(
+        &self,
+        path: &Path,
+        attributes: &BTreeMap<String, State>,
+        direction: Direction,
+        data: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        match attributes.get("filter") {
+            Some(State::Value(name)) => {
+                let path = path.strip_prefix(&self.root).unwrap_or(path);
+                let path = path.to_str().unwrap_or("");
+                self.filters.apply(name, direction, path, data)
+            }
+            _ => Ok(data),
+        }
+    }
+
+    /// Convert the content of a file of the working tree to the content
+    /// stored in the repository: the clean filter is applied then text files
+    /// get LF line endings
+    pub fn to_git(&self, path: &Path, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let attributes = self.get(path);
+        let data = self.filter(path, &attributes, Direction::Clean, data)?;
+        if !is_text(&attributes, &data) {
+            return Ok(data);
+        }
+        Ok(crlf_to_lf(&data))
+    }
+
+    /// Convert the content of a file stored in the repository to the content
+    /// written in the working tree: text files with `eol=crlf` get CRLF line
+    /// endings then the smudge filter is applied
+    pub fn to_worktree(&self, path: &Path, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let attributes = self.get(path);
+        let crlf = attributes.get("eol") == Some(&State::Value(String::from("crlf")));
+        let data = if crlf && is_text(&attributes, &data) {
+            lf_to_crlf(&data)
+        } else {
+            data
+        };
+        self.filter(path, &attributes, Direction::Smudge, data)
+    }
+}
+
+/// Whether the line endings of a file must be converted given its
+/// attributes. `text=auto` only converts files which don't look binary and
+/// `eol` alone implies `text`.
+fn is_text(attributes: &BTreeMap<String, State>, data: &[u8]) -> bool {
+    match attributes.get("text") {
+        Some(State::Set) => true,
+        Some(State::Unset) => false,
+        Some(State::Value(value)) if value == "auto" => !diff::is_binary(data),
+        _ => matches!(attributes.get("eol"), Some(State::Value(_))),
+    }
+}
+
+fn crlf_to_lf(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for (i, c) in data.iter().enumerate() {
+        if *c != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            res.push(*c);
+        }
+    }
+    res
+}
+
+/// Convert the LF not already preceded by a CR
+fn lf_to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for (i, c) in data.iter().enumerate() {
+        if *c == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            res.push(b'\r');
+        }
+        res.push(*c);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::config::Drivers;
+
+    fn attributes(lines: &str) -> Attributes {
+        let mut files = HashMap::new();
+        files.insert(PathBuf::new(), lines.as_bytes().to_vec());
+        Attributes::new(
+            &PathBuf::from("/nonexistent"),
+            Some(files),
+            Filters::new(Drivers::new()),
+        )
+    }
+
+    #[test]
+    fn parsing() {
+        let attributes = attributes(
+            "# comment\n*.txt text eol=crlf diff=words\n\
+             *.png binary\n!*.md text\nlocal.txt -text !eol\n",
+        );
+        let txt = attributes.get(Path::new("a/b.txt"));
+        assert_eq!(txt.get("text"), Some(&State::Set));
+        assert_eq!(txt.get("eol"), Some(&State::Value(String::from("crlf"))));
+        assert_eq!(txt.get("diff"), Some(&State::Value(String::from("words"))));
+        let png = attributes.get(Path::new("x.png"));
+        assert_eq!(png.get("binary"), Some(&State::Set));
+        assert_eq!(png.get("merge"), Some(&State::Unset));
+        assert!(attributes.get(Path::new("x.md")).is_empty());
+        let local = attributes.get(Path::new("local.txt"));
+        assert_eq!(local.get("text"), Some(&State::Unset));
+        assert_eq!(local.get("eol"), None);
+    }
+
+    #[test]
+    fn conversion() {
+        let attributes = attributes("*.txt text\n*.bat eol=crlf\n*.auto text=auto\n");
+        let path = Path::new("a.txt");
+        assert_eq!(
+            attributes.to_git(path, b"a\r\nb\n".to_vec()).unwrap(),
+            b"a\nb\n"
+        );
+        assert_eq!(
+            attributes.to_worktree(path, b"a\nb\n".to_vec()).unwrap(),
+            b"a\nb\n"
+        );
+        let path = Path::new("a.bat");
+        assert_eq!(
+            attributes.to_git(path, b"a\r\nb\r\n".to_vec()).unwrap(),
+            b"a\nb\n"
+        );
+        assert_eq!(
+            attributes.to_worktree(path, b"a\nb\r\n".to_vec()).unwrap(),
+            b"a\r\nb\r\n"
+        );
+        let path = Path::new("a.auto");
+        assert_eq!(
+            attributes.to_git(path, b"a\r\n\0".to_vec()).unwrap(),
+            b"a\r\n\0"
+        );
+        assert_eq!(attributes.to_git(path, b"a\r\n".to_vec()).unwrap(), b"a\n");
+        let path = Path::new("other");
+        assert_eq!(
+            attributes.to_git(path, b"a\r\n".to_vec()).unwrap(),
+            b"a\r\n"
+        );
+    }
+}
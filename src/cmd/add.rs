@@ -1,40 +1,123 @@
+use crate::attributes::Attributes;
+use crate::cmd::status::{self, Worktree};
 use crate::index::Index;
+use crate::objects::{Blob, Object};
+use crate::patch::{self, Mode, Outcome};
+use crate::pathspec::Pathspec;
 use crate::utils;
 use clap::ArgMatches;
-use glob::glob;
 use std::error::Error;
 use std::fmt;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Interactively stage the hunks of the changes between the index and the
+/// working tree
+fn add_patch(
+    index: &mut Index,
+    repo_path: &Path,
+    root: &Path,
+    pathspec: &Pathspec,
+    attributes: &Attributes,
+) -> Result<(), Box<dyn Error>> {
+    let mut changed = vec![];
+    for (name, entry) in index.entries.iter() {
+        if !pathspec.matches(name, false) {
+            continue;
+        }
+        let full_path = root.join(name);
+        match status::check_worktree(index, attributes, name, entry, &full_path)? {
+            Worktree::Modified => changed.push((name.clone(), entry.clone(), Some(full_path))),
+            Worktree::Deleted => changed.push((name.clone(), entry.clone(), None)),
+            _ => (),
+        }
+    }
+    if changed.is_empty() {
+        println!("No changes.");
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    for (name, (entry_type, hash), full_path) in changed {
+        let old = Blob::load(repo_path, hash).data;
+        let new = match full_path {
+            Some(path) => Some(Blob::from_worktree(&path, attributes)?.data),
+            None => None,
+        };
+        let files = (Some(old.as_slice()), new.as_deref());
+        let (outcome, quit) = patch::select(&mut input, repo_path, &name, files, Mode::Stage)?;
+        match outcome {
+            Outcome::Content(data) => {
+                let blob = Blob::new(data);
+                blob.save(repo_path);
+                index
+                    .entries
+                    .insert(name.clone(), (entry_type, blob.hash()));
+                index.clear_intent_to_add(&name);
+                // The file doesn't match the entry anymore
+                index.stats.remove(&name);
+            }
+            Outcome::Deleted => {
+                index.entries.remove(&name);
+                index.flags.remove(&name);
+            }
+            Outcome::Unchanged => (),
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
 
 pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let root = utils::find_root()?;
     let repo_path = utils::find_repo()?;
 
-    let mut index = Index::load(&repo_path);
+    let mut index = Index::load(&repo_path)?;
     let ignored = utils::ignored(&root)?;
-    let mut fails = vec![];
-    let force = args.is_present("force");
+    let attributes = Attributes::load(&root);
+    let rules = match args.is_present("force") {
+        true => None,
+        false => Some(&ignored),
+    };
+    let sparse = args.is_present("sparse");
+    let pathspec = Pathspec::new(args.values_of("PATHSPEC").into_iter().flatten())?;
 
-    for spec in args.values_of("PATHSPEC").unwrap() {
-        if glob(spec)?.count() == 0 {
-            index.remove(&PathBuf::from(spec), &root)?;
-        } else {
-            for entry in glob(spec)? {
-                if let Ok(file) = entry {
-                    fails.append(&mut index.add(
-                        &PathBuf::from(&file),
-                        &repo_path,
-                        &root,
-                        force,
-                        &ignored,
-                    )?);
-                    index.remove(&PathBuf::from(&file), &root)?;
-                }
-            }
-        }
+    if args.is_present("patch") {
+        add_patch(&mut index, &repo_path, &root, &pathspec, &attributes)?;
+        index.save(&repo_path);
+        return Ok(());
+    }
+
+    // Only new files are added with intent to add, tracked ones are kept as
+    // they are
+    let (fails, outside) = match args.is_present("intent-to-add") {
+        true => index.add_intent_to_add(&pathspec, &repo_path, &root, rules, sparse)?,
+        false => index.add(&pathspec, &repo_path, &root, rules, &attributes, sparse)?,
+    };
+    // Deleted files match the entries of the index
+    let mut matched = vec![];
+    for fail in fails.iter() {
+        matched.push(utils::path_in_repo(fail.to_str().unwrap())?);
     }
+    for file in outside.iter() {
+        matched.push(file.strip_prefix(&root)?.to_path_buf());
+    }
+    let entries = index.entries.keys().map(String::as_str);
+    pathspec.check_matched(entries.chain(matched.iter().map(|p| p.to_str().unwrap())))?;
+    index.remove(&pathspec, &root);
     index.save(&repo_path);
 
+    if !outside.is_empty() {
+        let outside = outside.iter().map(utils::find_relative_path).collect();
+        let error = FailAddSparse::new(outside);
+        if fails.is_empty() {
+            return Err(Box::new(error));
+        }
+        eprintln!("{}", error);
+    }
     if !fails.is_empty() {
         return Err(Box::new(FailAddIgnored::new(fails)));
     }
@@ -69,3 +152,35 @@ impl fmt::Display for FailAddIgnored {
     }
 }
 impl Error for FailAddIgnored {}
+
+#[derive(Debug)]
+struct FailAddSparse {
+    outside: Vec<PathBuf>,
+}
+
+impl FailAddSparse {
+    pub fn new(outside: Vec<PathBuf>) -> Self {
+        FailAddSparse { outside }
+    }
+}
+
+impl fmt::Display for FailAddSparse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut outside = String::new();
+        for path in self.outside.iter() {
+            outside.push_str(path.to_str().unwrap());
+            outside.push('\n');
+        }
+        write!(
+            f,
+            "The following paths and/or pathspecs matched paths that exist\n\
+             outside of your sparse-checkout definition, so will not be\n\
+             updated in the index:\n{}\
+             hint: If you intend to update such entries, try one of the following:\n\
+             hint: * Use the --sparse option.\n\
+             hint: * Disable or modify the sparsity rules.",
+            outside
+        )
+    }
+}
+impl Error for FailAddSparse {}
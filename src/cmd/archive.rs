@@ -0,0 +1,161 @@
+use crate::archive::{Archive, Kind, Tar, Zip};
+use crate::attributes::Attributes;
+use crate::objects::{self, Blob, Commit, Object, Tag, Tree, TreeEntry};
+use crate::pathspec::Pathspec;
+use crate::{refs, utils};
+use chrono::Local;
+use clap::ArgMatches;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Whether a path must be archived given the pathspec. Directories leading
+/// to a requested path are archived too.
+fn selected(path: &Path, pathspec: &Pathspec, is_dir: bool) -> bool {
+    let path = path.to_str().unwrap();
+    pathspec.matches(path, is_dir) || (is_dir && pathspec.may_contain(path))
+}
+
+/// Write the entries of a tree in the order git stores them: directories
+/// are sorted as if their name ended with a slash. Entries with the
+/// `export-ignore` attribute are left out.
+fn write_tree(
+    repo_path: &PathBuf,
+    archive: &mut dyn Archive,
+    tree: &Tree,
+    path: &Path,
+    prefix: &str,
+    (paths, attributes): (&Pathspec, &Attributes),
+) -> io::Result<()> {
+    let mut entries: Vec<_> = tree.entries.iter().collect();
+    entries.sort_by_key(|(name, entry)| match entry {
+        TreeEntry::Directory(_) => format!("{}/", name),
+        _ => name.to_string(),
+    });
+    for (name, entry) in entries {
+        let path = path.join(name);
+        let is_dir = matches!(entry, TreeEntry::Directory(_));
+        if !selected(&path, paths, is_dir) || attributes.is_set(&path, is_dir, "export-ignore") {
+            continue;
+        }
+        let name = format!("{}{}", prefix, path.display());
+        match entry {
+            TreeEntry::Directory(tree) => {
+                archive.add(&format!("{}/", name), Kind::Directory, tree.hash(), b"")?;
+                write_tree(repo_path, archive, tree, &path, prefix, (paths, attributes))?;
+            }
+            TreeEntry::File(hash) => {
+                archive.add(&name, Kind::File, *hash, &Blob::load(repo_path, *hash).data)?
+            }
+            TreeEntry::Executable(hash) => archive.add(
+                &name,
+                Kind::Executable,
+                *hash,
+                &Blob::load(repo_path, *hash).data,
+            )?,
+            TreeEntry::Symlink(hash) => archive.add(
+                &name,
+                Kind::Symlink,
+                *hash,
+                &Blob::load(repo_path, *hash).data,
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// Guess the format from the name of the output file, tar by default
+fn guess_format(output: Option<&str>) -> &'static str {
+    match output {
+        Some(file) if file.ends_with(".zip") => "zip",
+        Some(file) if file.ends_with(".tar.gz") || file.ends_with(".tgz") => "tar.gz",
+        _ => "tar",
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+    let rev = args.value_of("TREEISH").unwrap();
+    let output = args.value_of("output");
+    let format = match args.value_of("format") {
+        Some("tgz") => "tar.gz",
+        Some(format @ ("tar" | "tar.gz" | "zip")) => format,
+        Some(format) => return Err(Box::new(ErrorArchive::UnknownFormat(format.to_string()))),
+        None => guess_format(output),
+    };
+    let prefix = args.value_of("prefix").unwrap_or("");
+    let paths = Pathspec::new(args.values_of("PATHS").into_iter().flatten())?;
+
+    // Find the tree and the date of the entries, a tree alone is dated now
+    let mut hash = refs::resolve_object(&repo_path, rev)?;
+    while objects::object_type(&repo_path, hash).as_deref() == Some("tag") {
+        hash = Tag::load(&repo_path, hash).object;
+    }
+    let (tree, mtime, comment) = match objects::object_type(&repo_path, hash).as_deref() {
+        Some("commit") => {
+            let commit = Commit::load(&repo_path, hash);
+            let mtime = commit.committer.1.timestamp();
+            (commit.tree, mtime, Some(hash.to_string()))
+        }
+        Some("tree") => (hash, Local::now().timestamp(), None),
+        _ => return Err(Box::new(ErrorArchive::NotATree(rev.to_string()))),
+    };
+    let tree = Tree::load(&repo_path, tree);
+    paths.check_matched(tree.files().keys().map(String::as_str))?;
+
+    let out: Box<dyn Write> = match output {
+        Some(file) => Box::new(File::create(file)?),
+        None => Box::new(io::stdout()),
+    };
+    let out = BufWriter::new(out);
+    let root = PathBuf::new();
+    let attributes = Attributes::from_tree(&repo_path, &utils::find_root()?, &tree);
+    let write = |archive: &mut dyn Archive| -> io::Result<()> {
+        // The prefix directory itself is an entry of the archive
+        if prefix.ends_with('/') {
+            archive.add(prefix, Kind::Directory, tree.hash(), b"")?;
+        }
+        write_tree(
+            &repo_path,
+            archive,
+            &tree,
+            &root,
+            prefix,
+            (&paths, &attributes),
+        )?;
+        archive.finish()
+    };
+    match format {
+        "zip" => write(&mut Zip::new(out, mtime, comment))?,
+        "tar.gz" => {
+            let mut tar = Tar::new(GzEncoder::new(out, Compression::default()), mtime, comment)?;
+            write(&mut tar)?;
+            tar.into_inner().finish()?.flush()?;
+        }
+        _ => write(&mut Tar::new(out, mtime, comment)?)?,
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorArchive {
+    NotATree(String),
+    UnknownFormat(String),
+}
+
+impl fmt::Display for ErrorArchive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorArchive::NotATree(rev) => write!(f, "fatal: not a tree object: {}", rev),
+            ErrorArchive::UnknownFormat(format) => {
+                write!(f, "fatal: Unknown archive format '{}'", format)
+            }
+        }
+    }
+}
+
+impl Error for ErrorArchive {}
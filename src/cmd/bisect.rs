@@ -0,0 +1,572 @@
+use crate::attributes::Attributes;
+use crate::cmd::switch::{self, Mode};
+use crate::objects::{Commit, Hash, Object, Tree};
+use crate::{diff, refs, utils};
+use clap::ArgMatches;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// Commits left to bisect and their parents among them
+type Graph = BTreeMap<Hash, Vec<Hash>>;
+
+/// Outcome of a bisection step
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// The good or the bad revision is still unknown
+    Waiting,
+    /// The first bad commit has been found
+    Found(Hash),
+    /// Only skipped commits are left, the first bad commit is among them
+    OnlySkipped(Vec<Hash>),
+    /// The commit to test next and how many revisions may be left after it
+    Test(Hash, usize),
+}
+
+/// The state of a bisection as stored in the repository
+struct State {
+    bad: Option<Hash>,
+    goods: Vec<Hash>,
+    skips: HashSet<Hash>,
+}
+
+fn is_bisecting(repo_path: &Path) -> bool {
+    repo_path.join("BISECT_START").exists()
+}
+
+fn load_state(repo_path: &PathBuf) -> State {
+    let mut state = State {
+        bad: refs::resolve(repo_path, &String::from("refs/bisect/bad")).ok(),
+        goods: vec![],
+        skips: HashSet::new(),
+    };
+    if let Ok(entries) = fs::read_dir(repo_path.join("refs/bisect")) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().into_string().unwrap_or_default();
+            if let Some(hash) = name.strip_prefix("good-") {
+                state.goods.extend(Hash::from_str(hash).ok());
+            } else if let Some(hash) = name.strip_prefix("skip-") {
+                state.skips.extend(Hash::from_str(hash).ok());
+            }
+        }
+    }
+    state.goods.sort();
+    state
+}
+
+/// Remove every trace of the bisection except the checked out commit
+fn clean_state(repo_path: &Path) -> Result<(), Box<dyn Error>> {
+    let dir = repo_path.join("refs/bisect");
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    for file in ["BISECT_START", "BISECT_LOG"].iter() {
+        let path = repo_path.join(file);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn append_log(repo_path: &Path, line: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(repo_path.join("BISECT_LOG"))?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+fn describe(repo_path: &Path, hash: Hash) -> String {
+    format!("[{}] {}", hash, Commit::load(repo_path, hash).subject())
+}
+
+/// Record the verdict `term` (good, bad or skip) about a commit
+fn mark(repo_path: &PathBuf, term: &str, hash: Hash) -> Result<(), Box<dyn Error>> {
+    let ref_ = match term {
+        "bad" => String::from("refs/bisect/bad"),
+        _ => format!("refs/bisect/{}-{}", term, hash),
+    };
+    refs::update(repo_path, &ref_, &hash.to_string(), false)?;
+    append_log(
+        repo_path,
+        &format!("# {}: {}", term, describe(repo_path, hash)),
+    )?;
+    append_log(repo_path, &format!("my_git bisect {} {}", term, hash))
+}
+
+/// Record the revision to go back to and start logging
+fn start_state(repo_path: &PathBuf, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let head = match refs::current_branch(repo_path) {
+        Some((branch, _)) => branch,
+        None => match refs::get_head(repo_path) {
+            Some(commit) => commit.hash().to_string(),
+            None => return Err(Box::new(ErrorBisect::BadHead)),
+        },
+    };
+    clean_state(repo_path)?;
+    fs::write(repo_path.join("BISECT_START"), format!("{}\n", head))?;
+    let args: String = args.iter().map(|arg| format!(" '{}'", arg)).collect();
+    append_log(repo_path, &format!("my_git bisect start{}", args))
+}
+
+/// Build the graph of the commits that may be the first bad one: the
+/// ancestors of the bad commit which are not ancestors of a good one
+fn graph(repo_path: &Path, bad: Hash, goods: &[Hash]) -> Graph {
+    let excluded = Commit::ancestors(repo_path, goods);
+    let mut graph = Graph::new();
+    let mut stack = vec![bad];
+    while let Some(hash) = stack.pop() {
+        if excluded.contains(&hash) || graph.contains_key(&hash) {
+            continue;
+        }
+        let parents = Commit::load(repo_path, hash).parents;
+        stack.extend(parents.iter().copied());
+        let parents = parents.into_iter().filter(|p| !excluded.contains(p));
+        graph.insert(hash, parents.collect());
+    }
+    graph
+}
+
+/// Count the commits of the graph reachable from `start` (itself included)
+fn weight(graph: &Graph, start: Hash) -> usize {
+    let mut seen = HashSet::new();
+    let mut stack = vec![start];
+    while let Some(hash) = stack.pop() {
+        if seen.insert(hash) {
+            stack.extend(graph[&hash].iter().copied());
+        }
+    }
+    seen.len()
+}
+
+/// Pick the commit splitting the graph in the two most balanced halves
+fn choose(graph: &Graph, bad: Hash, skips: &HashSet<Hash>) -> Step {
+    if graph.len() == 1 {
+        return Step::Found(bad);
+    }
+    let total = graph.len();
+    let best = graph
+        .keys()
+        .filter(|hash| **hash != bad && !skips.contains(hash))
+        .map(|hash| (*hash, weight(graph, *hash)))
+        .max_by_key(|(hash, weight)| (*weight.min(&(total - weight)), std::cmp::Reverse(*hash)));
+    match best {
+        Some((hash, weight)) => Step::Test(hash, (weight - 1).max(total - weight - 1)),
+        None => {
+            let mut left: Vec<Hash> = graph.keys().copied().collect();
+            left.sort_by_key(|hash| *hash != bad);
+            Step::OnlySkipped(left)
+        }
+    }
+}
+
+/// Estimate the number of steps left like git does
+fn estimate_steps(total: usize) -> usize {
+    if total < 3 {
+        return 0;
+    }
+    let n = (usize::BITS - 1 - total.leading_zeros()) as usize;
+    let e = 1 << n;
+    if e < 3 * (total - e) {
+        n
+    } else {
+        n - 1
+    }
+}
+
+fn plural(count: usize, word: &str) -> String {
+    match count {
+        1 => format!("{} {}", count, word),
+        _ => format!("{} {}s", count, word),
+    }
+}
+
+/// Print the first bad commit with a summary of its changes
+fn print_found(repo_path: &Path, hash: Hash) -> Result<(), Box<dyn Error>> {
+    let commit = Commit::load(repo_path, hash);
+    println!("{} is the first bad commit", hash);
+    print!("{}", commit.medium(&[]));
+    let tree = Tree::load(repo_path, commit.tree).files();
+    let parent = match commit.parents.first() {
+        Some(parent) => Tree::load(repo_path, Commit::load(repo_path, *parent).tree).files(),
+        None => Default::default(),
+    };
+    let changes = diff::changes(&parent, &tree);
+    if !changes.is_empty() {
+        let attributes = Attributes::load(&utils::find_root()?);
+        print!("\n{}", diff::stat(repo_path, &attributes, &changes));
+    }
+    Ok(())
+}
+
+/// Compute the next step of the bisection, report it and check out the next
+/// commit to test if any
+fn next(repo_path: &PathBuf) -> Result<Step, Box<dyn Error>> {
+    let state = load_state(repo_path);
+    let bad = match state.bad {
+        Some(bad) if !state.goods.is_empty() => bad,
+        bad => {
+            let status = match (bad, state.goods.len()) {
+                (None, 0) => String::from("waiting for both good and bad commits"),
+                (None, goods) => format!(
+                    "waiting for bad commit, {} known",
+                    plural(goods, "good commit")
+                ),
+                _ => String::from("waiting for good commit(s), bad commit known"),
+            };
+            println!("status: {}", status);
+            append_log(repo_path, &format!("# status: {}", status))?;
+            return Ok(Step::Waiting);
+        }
+    };
+
+    let graph = graph(repo_path, bad, &state.goods);
+    let step = choose(&graph, bad, &state.skips);
+    match &step {
+        Step::Found(hash) => {
+            print_found(repo_path, *hash)?;
+            let line = format!("# first bad commit: {}", describe(repo_path, *hash));
+            append_log(repo_path, &line)?;
+        }
+        Step::OnlySkipped(left) => {
+            let mut line = String::from("# only skipped commits left to test");
+            for hash in left {
+                line.push_str(&format!(
+                    "\n# possible first bad commit: {}",
+                    describe(repo_path, *hash)
+                ));
+            }
+            append_log(repo_path, &line)?;
+            return Err(Box::new(ErrorBisect::OnlySkipped(left.clone())));
+        }
+        Step::Test(hash, left) => {
+            let commit = Commit::load(repo_path, *hash);
+            switch::detach(repo_path, &commit, Mode::Safe)?;
+            println!(
+                "Bisecting: {} left to test after this (roughly {})",
+                plural(*left, "revision"),
+                plural(estimate_steps(graph.len()), "step")
+            );
+            println!("[{}] {}", hash, commit.subject());
+        }
+        Step::Waiting => (),
+    }
+    Ok(step)
+}
+
+fn start(repo_path: &PathBuf, revs: &[&str]) -> Result<Step, Box<dyn Error>> {
+    let hashes = revs
+        .iter()
+        .map(|rev| refs::rev_parse(repo_path, rev))
+        .collect::<Result<Vec<_>, _>>()?;
+    start_state(repo_path, revs)?;
+    for (i, hash) in hashes.into_iter().enumerate() {
+        mark(repo_path, if i == 0 { "bad" } else { "good" }, hash)?;
+    }
+    next(repo_path)
+}
+
+/// Mark the given revisions (HEAD by default) with a term
+fn mark_revs(repo_path: &PathBuf, term: &str, revs: &[&str]) -> Result<Step, Box<dyn Error>> {
+    if !is_bisecting(repo_path) {
+        return Err(Box::new(ErrorBisect::NotStarted));
+    }
+    let revs = if revs.is_empty() { &["HEAD"][..] } else { revs };
+    if term == "bad" && revs.len() > 1 {
+        return Err(Box::new(ErrorBisect::OnlyOneBad));
+    }
+    let hashes = revs
+        .iter()
+        .map(|rev| refs::rev_parse(repo_path, rev))
+        .collect::<Result<Vec<_>, _>>()?;
+    for hash in hashes {
+        mark(repo_path, term, hash)?;
+    }
+    next(repo_path)
+}
+
+fn reset(repo_path: &PathBuf, rev: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !is_bisecting(repo_path) {
+        println!("We are not bisecting.");
+        return Ok(());
+    }
+    let start = fs::read_to_string(repo_path.join("BISECT_START"))?;
+    let target = rev.unwrap_or_else(|| start.trim_end()).to_string();
+    let branches = refs::branches(repo_path);
+    if branches.contains_key(&target) {
+        switch::switch_branch(repo_path, &target, &branches, Mode::Safe)?;
+    } else {
+        let hash = refs::rev_parse(repo_path, &target)?;
+        switch::detach(repo_path, &Commit::load(repo_path, hash), Mode::Safe)?;
+    }
+    clean_state(repo_path)
+}
+
+/// Replay a bisection log, only the last step is checked out
+fn replay(repo_path: &PathBuf, file: &str) -> Result<(), Box<dyn Error>> {
+    let log = fs::read_to_string(file)?;
+    let mut started = false;
+    for line in log.lines() {
+        let words: Vec<&str> = line
+            .split_whitespace()
+            .map(|w| w.trim_matches('\''))
+            .collect();
+        let (command, args) = match words.as_slice() {
+            ["git", "bisect", command, args @ ..] | ["my_git", "bisect", command, args @ ..] => {
+                (*command, args)
+            }
+            _ => continue,
+        };
+        match command {
+            // The verdicts given to start are logged on their own lines
+            "start" => start_state(repo_path, &[])?,
+            "good" | "bad" | "skip" if started => {
+                for arg in args {
+                    mark(repo_path, command, refs::rev_parse(repo_path, arg)?)?;
+                }
+            }
+            _ => return Err(Box::new(ErrorBisect::InvalidLog(line.to_string()))),
+        }
+        started = true;
+    }
+    if !started {
+        return Err(Box::new(ErrorBisect::InvalidLog(file.to_string())));
+    }
+    next(repo_path)?;
+    Ok(())
+}
+
+/// Run a command on each commit to test until the first bad one is found
+fn run_command(repo_path: &PathBuf, command: &[&str]) -> Result<(), Box<dyn Error>> {
+    if !is_bisecting(repo_path) {
+        return Err(Box::new(ErrorBisect::NotStarted));
+    }
+    let state = load_state(repo_path);
+    if state.bad.is_none() || state.goods.is_empty() {
+        return Err(Box::new(ErrorBisect::RunWaiting));
+    }
+    let display: Vec<String> = command.iter().map(|arg| format!("'{}'", arg)).collect();
+    let display = display.join(" ");
+    loop {
+        println!("running  {}", display);
+        let status = Command::new(command[0])
+            .args(&command[1..])
+            .status()
+            .map_err(|err| ErrorBisect::RunFailed(format!("{}: {}", command[0], err)))?;
+        let term = match status.code() {
+            Some(0) => "good",
+            Some(125) => "skip",
+            Some(code) if (1..128).contains(&code) => "bad",
+            code => {
+                let code = code.map_or(String::from("signal"), |c| c.to_string());
+                let msg = format!("exit code {} from {} is < 0 or >= 128", code, display);
+                return Err(Box::new(ErrorBisect::RunFailed(msg)));
+            }
+        };
+        let head = refs::rev_parse(repo_path, "HEAD")?;
+        mark(repo_path, term, head)?;
+        if let Step::Found(_) = next(repo_path)? {
+            println!("bisect found first bad commit");
+            return Ok(());
+        }
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+    let values = |args: &ArgMatches, name| -> Vec<String> {
+        match args.values_of(name) {
+            Some(values) => values.map(String::from).collect(),
+            None => vec![],
+        }
+    };
+    match args.subcommand() {
+        ("start", Some(args)) => {
+            let mut revs = values(args, "BAD");
+            revs.extend(values(args, "GOOD"));
+            let revs: Vec<&str> = revs.iter().map(String::as_str).collect();
+            start(&repo_path, &revs)?;
+        }
+        (term @ "good", Some(args)) | (term @ "bad", Some(args)) | (term @ "skip", Some(args)) => {
+            let revs = values(args, "REV");
+            let revs: Vec<&str> = revs.iter().map(String::as_str).collect();
+            mark_revs(&repo_path, term, &revs)?;
+        }
+        ("reset", Some(args)) => reset(&repo_path, args.value_of("COMMIT"))?,
+        ("log", Some(_)) => match fs::read_to_string(repo_path.join("BISECT_LOG")) {
+            Ok(log) if is_bisecting(&repo_path) => print!("{}", log),
+            _ => return Err(Box::new(ErrorBisect::NotBisecting)),
+        },
+        ("replay", Some(args)) => replay(&repo_path, args.value_of("FILE").unwrap())?,
+        ("run", Some(args)) => {
+            let command = values(args, "COMMAND");
+            let command: Vec<&str> = command.iter().map(String::as_str).collect();
+            run_command(&repo_path, &command)?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorBisect {
+    BadHead,
+    NotStarted,
+    NotBisecting,
+    OnlyOneBad,
+    OnlySkipped(Vec<Hash>),
+    InvalidLog(String),
+    RunWaiting,
+    RunFailed(String),
+}
+
+impl fmt::Display for ErrorBisect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorBisect::BadHead => write!(f, "fatal: bad HEAD - I need a HEAD"),
+            ErrorBisect::NotStarted => {
+                write!(f, "error: you need to start by \"my_git bisect start\"")
+            }
+            ErrorBisect::NotBisecting => write!(f, "error: we are not bisecting"),
+            ErrorBisect::OnlyOneBad => {
+                write!(f, "error: 'my_git bisect bad' can take only one argument")
+            }
+            ErrorBisect::OnlySkipped(left) => {
+                writeln!(f, "There are only 'skip'ped commits left to test.")?;
+                writeln!(f, "The first bad commit could be any of:")?;
+                for hash in left {
+                    writeln!(f, "{}", hash)?;
+                }
+                write!(f, "We cannot bisect more!")
+            }
+            ErrorBisect::InvalidLog(line) => {
+                write!(f, "error: '{}' is not a valid bisect log", line)
+            }
+            ErrorBisect::RunWaiting => {
+                write!(
+                    f,
+                    "error: bisect run cannot continue, good and bad commits are needed"
+                )
+            }
+            ErrorBisect::RunFailed(msg) => write!(f, "error: bisect run failed: {}", msg),
+        }
+    }
+}
+
+impl Error for ErrorBisect {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{add, commit};
+    use crate::utils::tests::{in_repo, subcommand};
+
+    fn hash(i: usize) -> Hash {
+        Hash::from_str(&format!("{:040x}", i)).unwrap()
+    }
+
+    /// A linear history where each commit `i` has `i - 1` as parent
+    fn linear(len: usize) -> Graph {
+        (1..=len)
+            .map(|i| (hash(i), if i > 1 { vec![hash(i - 1)] } else { vec![] }))
+            .collect()
+    }
+
+    #[test]
+    fn choose_midpoint() {
+        let graph = linear(9);
+        assert_eq!(
+            choose(&graph, hash(9), &HashSet::new()),
+            Step::Test(hash(4), 4)
+        );
+        assert_eq!(estimate_steps(graph.len()), 2);
+
+        let skips: HashSet<Hash> = [hash(4)].iter().copied().collect();
+        assert_eq!(choose(&graph, hash(9), &skips), Step::Test(hash(5), 4));
+        assert_eq!(choose(&linear(1), hash(1), &skips), Step::Found(hash(1)));
+
+        let skips: HashSet<Hash> = (1..5).map(hash).collect();
+        let left = [5, 1, 2, 3, 4].iter().map(|i| hash(*i)).collect();
+        assert_eq!(choose(&linear(5), hash(5), &skips), Step::OnlySkipped(left));
+    }
+
+    #[test]
+    fn session() {
+        in_repo("bisect", |root| {
+            let repo_path = utils::find_repo().unwrap();
+            let config = "user:\n  name: John Doe\n  email: john@doe.com\n";
+            fs::write(root.parent().unwrap().join(".my_gitconfig"), config).unwrap();
+            for i in 1..=8 {
+                fs::write(root.join("f"), i.to_string()).unwrap();
+                add::run(&subcommand(&["add", "f"])).unwrap();
+                let message = format!("c{}", i);
+                commit::run(&subcommand(&["commit", "-m", &message])).unwrap();
+            }
+            let commits: Vec<Hash> = (0..8)
+                .rev()
+                .map(|n| refs::rev_parse(&repo_path, &format!("HEAD~{}", n)).unwrap())
+                .collect();
+            let checked_out = || fs::read_to_string(root.join("f")).unwrap();
+
+            assert_eq!(start(&repo_path, &[]).unwrap(), Step::Waiting);
+            assert_eq!(mark_revs(&repo_path, "bad", &[]).unwrap(), Step::Waiting);
+            let step = mark_revs(&repo_path, "good", &["HEAD~7"]).unwrap();
+
+            // c7 is the first bad commit and the first commit proposed, one
+            // of the two midpoints, is skipped
+            let skipped: usize = checked_out().parse().unwrap();
+            assert!(skipped == 4 || skipped == 5);
+            assert_eq!(step, Step::Test(commits[skipped - 1], 3));
+            let mut tested = vec![];
+            let mut step = mark_revs(&repo_path, "skip", &[]).unwrap();
+            while let Step::Test(hash, _) = step {
+                let i: usize = checked_out().parse().unwrap();
+                assert_eq!(hash, commits[i - 1]);
+                tested.push(i);
+                let term = if i >= 7 { "bad" } else { "good" };
+                step = mark_revs(&repo_path, term, &[]).unwrap();
+            }
+            assert_eq!(step, Step::Found(commits[6]));
+            assert!(!tested.contains(&skipped));
+            let log = fs::read_to_string(repo_path.join("BISECT_LOG")).unwrap();
+            assert!(log.starts_with(
+                "my_git bisect start\n# status: waiting for both good and bad commits\n"
+            ));
+            assert!(log.ends_with(&format!("# first bad commit: [{}] c7\n", commits[6])));
+            let state = load_state(&repo_path);
+            assert_eq!(state.bad, Some(commits[6]));
+            assert_eq!(
+                state.goods.len(),
+                1 + tested.iter().filter(|i| **i < 7).count()
+            );
+            let skips: HashSet<Hash> = [commits[skipped - 1]].iter().copied().collect();
+            assert_eq!(state.skips, skips);
+
+            // Replaying the log after a reset gives the same state back
+            let log_path = root.parent().unwrap().join("log");
+            fs::write(&log_path, &log).unwrap();
+            reset(&repo_path, None).unwrap();
+            assert!(!is_bisecting(&repo_path));
+            let head = refs::current_branch(&repo_path).unwrap();
+            assert_eq!(head, (String::from("master"), commits[7]));
+            assert_eq!(checked_out(), "8");
+            replay(&repo_path, log_path.to_str().unwrap()).unwrap();
+            let replayed = load_state(&repo_path);
+            assert_eq!(replayed.bad, state.bad);
+            assert_eq!(replayed.goods, state.goods);
+            assert_eq!(replayed.skips, state.skips);
+
+            reset(&repo_path, None).unwrap();
+            assert!(!is_bisecting(&repo_path));
+            assert!(!repo_path.join("refs/bisect").exists());
+            assert_eq!(checked_out(), "8");
+        });
+    }
+}
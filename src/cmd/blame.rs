@@ -0,0 +1,563 @@
+use crate::diff::{self, Edit};
+use crate::index::EntryType;
+use crate::objects::{Blob, Commit, Hash, Object, Tree};
+use crate::{pager, refs, utils};
+use clap::ArgMatches;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Origin of a line: the commit which introduced it, the path of the file in
+/// this commit and the line number (from 0) in this version of the file
+#[derive(Clone)]
+struct Origin {
+    commit: Hash,
+    path: String,
+    line: usize,
+}
+
+/// Lines still to attribute: their final line number and their line number
+/// in the version of the file being inspected
+type Lines = Vec<(usize, usize)>;
+
+struct Blame<'a> {
+    repo_path: &'a PathBuf,
+    ignore_whitespace: bool,
+    /// Follow lines moved within a file
+    moves: bool,
+    /// Follow lines copied from other files modified by the same commit
+    copies: bool,
+    /// Commits whose changes are passed through to their parent
+    ignored: HashSet<Hash>,
+    commits: HashMap<Hash, Commit>,
+    files: HashMap<Hash, BTreeMap<String, (EntryType, Hash)>>,
+    /// Lines waiting to be inspected for each commit and path
+    pending: HashMap<Hash, BTreeMap<String, Lines>>,
+    queue: BinaryHeap<(i64, Hash)>,
+}
+
+impl<'a> Blame<'a> {
+    fn commit(&mut self, hash: Hash) -> &Commit {
+        let repo_path = self.repo_path;
+        self.commits
+            .entry(hash)
+            .or_insert_with(|| *Commit::load(repo_path, hash))
+    }
+
+    /// Return the files of a commit
+    fn files(&mut self, hash: Hash) -> &BTreeMap<String, (EntryType, Hash)> {
+        let tree = self.commit(hash).tree;
+        let repo_path = self.repo_path;
+        self.files
+            .entry(hash)
+            .or_insert_with(|| Tree::load(repo_path, tree).files())
+    }
+
+    /// Return the lines of a file in a commit
+    fn lines(&mut self, hash: Hash, path: &str) -> Option<Vec<Vec<u8>>> {
+        let (_, blob) = self.files(hash).get(path)?.clone();
+        let blob = Blob::load(self.repo_path, blob);
+        Some(
+            diff::lines(&blob.data)
+                .into_iter()
+                .map(|l| l.to_vec())
+                .collect(),
+        )
+    }
+
+    /// Return the value compared to match lines
+    fn key(&self, line: &[u8]) -> Vec<u8> {
+        if self.ignore_whitespace {
+            line.iter()
+                .filter(|c| !c.is_ascii_whitespace())
+                .cloned()
+                .collect()
+        } else {
+            line.to_vec()
+        }
+    }
+
+    fn keys(&self, lines: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        lines.iter().map(|line| self.key(line)).collect()
+    }
+
+    /// Give lines to inspect to a commit
+    fn pass(&mut self, hash: Hash, path: &str, lines: Lines) {
+        if lines.is_empty() {
+            return;
+        }
+        let date = self.commit(hash).committer.1.timestamp();
+        self.pending
+            .entry(hash)
+            .or_default()
+            .entry(path.to_string())
+            .or_default()
+            .extend(lines);
+        self.queue.push((date, hash));
+    }
+
+    /// Pass the lines of a file unchanged by a commit to its parents and
+    /// return the lines introduced by the commit
+    fn inspect(&mut self, hash: Hash, path: &str, mut lines: Lines) -> Lines {
+        let content = self.lines(hash, path).unwrap();
+        let keys = self.keys(&content);
+        let parents = self.commit(hash).parents.clone();
+
+        for parent in parents.iter() {
+            if lines.is_empty() {
+                break;
+            }
+            let parent_content = match self.lines(*parent, path) {
+                Some(content) => content,
+                None => continue,
+            };
+            let parent_keys = self.keys(&parent_content);
+            let edits = diff::diff(&parent_keys, &keys);
+            let mut origin = vec![None; keys.len()];
+            let mut deleted = vec![];
+            for edit in edits.iter() {
+                match edit {
+                    Edit::Equal(i, j) => origin[*j] = Some(*i),
+                    Edit::Delete(i) => deleted.push(*i),
+                    Edit::Insert(_) => (),
+                }
+            }
+
+            // Moved lines were deleted somewhere else in the parent
+            if self.moves {
+                for (_, line) in lines.iter() {
+                    if origin[*line].is_some() || is_blank(&keys[*line]) {
+                        continue;
+                    }
+                    if let Some(pos) = deleted.iter().position(|i| parent_keys[*i] == keys[*line]) {
+                        origin[*line] = Some(deleted.remove(pos));
+                    }
+                }
+            }
+
+            let (found, remaining): (Lines, Lines) = lines
+                .into_iter()
+                .partition(|(_, line)| origin[*line].is_some());
+            let found = found
+                .into_iter()
+                .map(|(last, line)| (last, origin[line].unwrap()))
+                .collect();
+            self.pass(*parent, path, found);
+            lines = remaining;
+        }
+
+        if let (true, Some(parent)) = (self.copies, parents.first()) {
+            lines = self.find_copies(hash, *parent, path, &keys, lines);
+        }
+        if let (true, Some(parent)) = (self.ignored.contains(&hash), parents.first()) {
+            lines = self.skip_ignored(*parent, path, &keys, lines);
+        }
+        lines
+    }
+
+    /// Pass the lines copied from other files modified by the commit to the
+    /// parent and return the other ones
+    fn find_copies(
+        &mut self,
+        hash: Hash,
+        parent: Hash,
+        path: &str,
+        keys: &[Vec<u8>],
+        mut lines: Lines,
+    ) -> Lines {
+        let files = self.files(hash).clone();
+        let parent_files = self.files(parent).clone();
+        for (other, entry) in files.iter() {
+            if lines.is_empty() {
+                break;
+            }
+            if other == path || parent_files.get(other) == Some(entry) {
+                continue;
+            }
+            let other_keys = match self.lines(parent, other) {
+                Some(content) => self.keys(&content),
+                None => continue,
+            };
+            let (found, remaining): (Lines, Lines) = lines.into_iter().partition(|(_, line)| {
+                !is_blank(&keys[*line]) && other_keys.contains(&keys[*line])
+            });
+            let found = found
+                .into_iter()
+                .map(|(last, line)| {
+                    let pos = other_keys.iter().position(|k| *k == keys[line]).unwrap();
+                    (last, pos)
+                })
+                .collect();
+            self.pass(parent, other, found);
+            lines = remaining;
+        }
+        lines
+    }
+
+    /// Pass the lines changed by an ignored commit to the lines they replaced
+    /// in the parent when there are some
+    fn skip_ignored(&mut self, parent: Hash, path: &str, keys: &[Vec<u8>], lines: Lines) -> Lines {
+        let parent_keys = match self.lines(parent, path) {
+            Some(content) => self.keys(&content),
+            None => return lines,
+        };
+        // Pair the lines inserted and deleted by each block of changes
+        let mut replaced = vec![None; keys.len()];
+        let (mut deleted, mut inserted) = (vec![], vec![]);
+        let edits = diff::diff(&parent_keys, keys);
+        for edit in edits.iter().chain([Edit::Equal(0, 0)].iter()) {
+            match edit {
+                Edit::Delete(i) => deleted.push(*i),
+                Edit::Insert(j) => inserted.push(*j),
+                Edit::Equal(_, _) => {
+                    for (i, j) in deleted.drain(..).zip(inserted.drain(..)) {
+                        replaced[j] = Some(i);
+                    }
+                    inserted.clear();
+                }
+            }
+        }
+        let (found, remaining): (Lines, Lines) = lines
+            .into_iter()
+            .partition(|(_, line)| replaced[*line].is_some());
+        let found = found
+            .into_iter()
+            .map(|(last, line)| (last, replaced[line].unwrap()))
+            .collect();
+        self.pass(parent, path, found);
+        remaining
+    }
+
+    /// Find the origin of the given lines of a file
+    fn run(&mut self, start: Hash, path: &str, lines: Lines) -> HashMap<usize, Origin> {
+        let mut res = HashMap::new();
+        self.pass(start, path, lines);
+        while let Some((_, hash)) = self.queue.pop() {
+            let pending = match self.pending.remove(&hash) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            for (path, lines) in pending {
+                for (last, line) in self.inspect(hash, &path, lines) {
+                    let path = path.clone();
+                    res.insert(
+                        last,
+                        Origin {
+                            commit: hash,
+                            path,
+                            line,
+                        },
+                    );
+                }
+            }
+        }
+        res
+    }
+}
+
+fn is_blank(key: &[u8]) -> bool {
+    key.iter().all(|c| c.is_ascii_whitespace())
+}
+
+/// Parse the `-L` option: `start,end`, `start,+count` or `start,-count`
+/// (1-based and inclusive). Return the range of lines from 0.
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let start = match parts.next()? {
+        "" => 1,
+        start => start.parse::<usize>().ok()?,
+    };
+    let (start, end) = match parts.next() {
+        None | Some("") => (start, len),
+        Some(end) if end.starts_with('+') => {
+            let count = end[1..].parse::<usize>().ok()?;
+            (start, start + count.max(1) - 1)
+        }
+        Some(end) if end.starts_with('-') => {
+            let count = end[1..].parse::<usize>().ok()?;
+            (start.saturating_sub(count.max(1) - 1).max(1), start)
+        }
+        Some(end) => {
+            let end = end.parse::<usize>().ok()?;
+            (start.min(end), start.max(end))
+        }
+    };
+    if start == 0 || start > len {
+        return None;
+    }
+    Some((start - 1, end.min(len)))
+}
+
+/// Read the commits listed in an ignore-revs file (one per line, `#` starts
+/// a comment)
+fn ignored_revs(repo_path: &PathBuf, path: &str) -> Result<HashSet<Hash>, Box<dyn Error>> {
+    let mut res = HashSet::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let rev = line.split('#').next().unwrap().trim();
+        if !rev.is_empty() {
+            res.insert(refs::rev_parse(repo_path, rev)?);
+        }
+    }
+    Ok(res)
+}
+
+/// Print the blame in the default human readable format
+fn print_default(repo_path: &Path, origins: &[(usize, Origin)], content: &[&[u8]], path: &str) {
+    let commits: HashMap<Hash, Commit> = origins
+        .iter()
+        .map(|(_, o)| (o.commit, *Commit::load(repo_path, o.commit)))
+        .collect();
+    let name_width = commits
+        .values()
+        .map(|c| c.author.0.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let last = origins.last().map_or(0, |(last, _)| last + 1);
+    let line_width = last.to_string().len();
+    // File names are shown when lines come from other files
+    let show_path = origins.iter().any(|(_, o)| o.path != path);
+    let path_width = origins
+        .iter()
+        .map(|(_, o)| o.path.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (last, origin) in origins {
+        let commit = &commits[&origin.commit];
+        let hash = origin.commit.to_string();
+        let hash = if commit.parents.is_empty() {
+            format!("^{}", &hash[..7])
+        } else {
+            hash[..8].to_string()
+        };
+        let mut prefix = hash;
+        if show_path {
+            prefix.push_str(&format!(" {:<w$}", origin.path, w = path_width));
+        }
+        let (author, date) = &commit.author;
+        let line = String::from_utf8_lossy(content[*last]);
+        let line = line.trim_end_matches('\n');
+        println!(
+            "{} ({:<nw$} {} {:>lw$}) {}",
+            prefix,
+            author.name,
+            date.format("%Y-%m-%d %H:%M:%S %z"),
+            last + 1,
+            line,
+            nw = name_width,
+            lw = line_width
+        );
+    }
+}
+
+/// Print the blame in the format designed for machine consumption
+fn print_porcelain(repo_path: &Path, origins: &[(usize, Origin)], content: &[&[u8]]) {
+    let mut described = HashSet::new();
+    let mut i = 0;
+    while i < origins.len() {
+        // Group of consecutive lines coming from consecutive lines of a commit
+        let (first, origin) = &origins[i];
+        let mut size = 1;
+        while i + size < origins.len() {
+            let (last, next) = &origins[i + size];
+            if next.commit != origin.commit
+                || next.path != origin.path
+                || *last != first + size
+                || next.line != origin.line + size
+            {
+                break;
+            }
+            size += 1;
+        }
+
+        for (j, (last, origin)) in origins[i..i + size].iter().enumerate() {
+            if j == 0 {
+                println!(
+                    "{} {} {} {}",
+                    origin.commit,
+                    origin.line + 1,
+                    last + 1,
+                    size
+                );
+            } else {
+                println!("{} {} {}", origin.commit, origin.line + 1, last + 1);
+            }
+            if described.insert(origin.commit) {
+                let commit = Commit::load(repo_path, origin.commit);
+                for (role, (user, date)) in
+                    [("author", &commit.author), ("committer", &commit.committer)].iter()
+                {
+                    println!("{} {}", role, user.name);
+                    println!("{}-mail <{}>", role, user.email);
+                    println!("{}-time {}", role, date.timestamp());
+                    println!("{}-tz {}", role, date.format("%z"));
+                }
+                println!("summary {}", commit.subject());
+                match commit.parents.first() {
+                    Some(parent) => println!("previous {} {}", parent, origin.path),
+                    None => println!("boundary"),
+                }
+                println!("filename {}", origin.path);
+            }
+            let line = String::from_utf8_lossy(content[*last]);
+            println!("\t{}", line.trim_end_matches('\n'));
+        }
+        i += size;
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+    let file = args.value_of("FILE").unwrap();
+    let path = utils::path_in_repo(file)?;
+    let path = path.to_str().unwrap();
+    let rev = args.value_of("REV").unwrap_or("HEAD");
+    let start = refs::rev_parse(&repo_path, rev)?;
+
+    let commit = Commit::load(&repo_path, start);
+    let blob = match Tree::load(&repo_path, commit.tree).files().get(path) {
+        Some((_, hash)) => Blob::load(&repo_path, *hash),
+        None => {
+            return Err(Box::new(ErrorBlame::NoSuchPath(
+                path.to_string(),
+                rev.to_string(),
+            )))
+        }
+    };
+    let content = diff::lines(&blob.data);
+    let (first, last) = match args.value_of("range") {
+        Some(range) => parse_range(range, content.len())
+            .ok_or_else(|| ErrorBlame::InvalidRange(range.to_string(), content.len()))?,
+        None => (0, content.len()),
+    };
+
+    let ignored = match args.value_of("ignore-revs-file") {
+        Some(file) => ignored_revs(&repo_path, file)?,
+        None => HashSet::new(),
+    };
+    let mut blame = Blame {
+        repo_path: &repo_path,
+        ignore_whitespace: args.is_present("ignore-whitespace"),
+        moves: args.is_present("moves") || args.is_present("copies"),
+        copies: args.is_present("copies"),
+        ignored,
+        commits: HashMap::new(),
+        files: HashMap::new(),
+        pending: HashMap::new(),
+        queue: BinaryHeap::new(),
+    };
+    let lines = (first..last).map(|line| (line, line)).collect();
+    let mut origins: Vec<(usize, Origin)> = blame.run(start, path, lines).into_iter().collect();
+    origins.sort_by_key(|(last, _)| *last);
+
+    let _pager = pager::start("blame");
+    if args.is_present("porcelain") {
+        print_porcelain(&repo_path, &origins, &content);
+    } else {
+        print_default(&repo_path, &origins, &content, path);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorBlame {
+    NoSuchPath(String, String),
+    InvalidRange(String, usize),
+}
+
+impl fmt::Display for ErrorBlame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorBlame::NoSuchPath(path, rev) => {
+                write!(f, "fatal: no such path '{}' in {}", path, rev)
+            }
+            ErrorBlame::InvalidRange(range, len) => write!(
+                f,
+                "fatal: invalid -L range '{}': file has only {} lines",
+                range, len
+            ),
+        }
+    }
+}
+
+impl Error for ErrorBlame {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::User;
+    use chrono::{FixedOffset, TimeZone};
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("2,4", 10), Some((1, 4)));
+        assert_eq!(parse_range("3,+2", 10), Some((2, 4)));
+        assert_eq!(parse_range("5,-2", 10), Some((3, 5)));
+        assert_eq!(parse_range("8,", 10), Some((7, 10)));
+        assert_eq!(parse_range("9,20", 10), Some((8, 10)));
+        assert_eq!(parse_range("11,12", 10), None);
+    }
+
+    #[test]
+    fn attribution() {
+        let repo_path = env::temp_dir().join(format!("my_git_blame_{}", process::id()));
+        fs::create_dir_all(repo_path.join("objects")).unwrap();
+        let mut time = 1_000_000_000;
+        let mut commit = |parents: Vec<Hash>, content: &str| {
+            let blob = Blob::new(content.into());
+            blob.save(&repo_path);
+            let mut tree = Tree::new();
+            tree.add_file(String::from("file"), blob.hash());
+            tree.save(&repo_path);
+            time += 60;
+            let commit = Commit::new(
+                tree.hash(),
+                parents,
+                User::new(String::from("John Doe"), String::from("john@doe.com")),
+                FixedOffset::east(0).timestamp(time, 0),
+                String::from("commit\n"),
+            );
+            commit.save(&repo_path);
+            commit.hash()
+        };
+        let first = commit(vec![], "a\nb\nc\n");
+        let second = commit(vec![first], "a\nB\nc\nd\n");
+        let third = commit(vec![second], "x\na\nB\nc\nd\n");
+
+        let mut blame = Blame {
+            repo_path: &repo_path,
+            ignore_whitespace: false,
+            moves: false,
+            copies: false,
+            ignored: HashSet::new(),
+            commits: HashMap::new(),
+            files: HashMap::new(),
+            pending: HashMap::new(),
+            queue: BinaryHeap::new(),
+        };
+        let lines = (0..5).map(|line| (line, line)).collect();
+        let origins = blame.run(third, "file", lines);
+        let mut origins: Vec<(usize, Hash, usize)> = origins
+            .into_iter()
+            .map(|(last, origin)| (last, origin.commit, origin.line))
+            .collect();
+        origins.sort();
+        // Each line comes from the commit which wrote it, at its line number
+        // in this commit
+        assert_eq!(
+            origins,
+            vec![
+                (0, third, 0),
+                (1, first, 0),
+                (2, second, 1),
+                (3, first, 2),
+                (4, second, 3),
+            ]
+        );
+        fs::remove_dir_all(&repo_path).unwrap();
+    }
+}
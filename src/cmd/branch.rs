@@ -1,5 +1,5 @@
 use crate::objects::{Commit, Hash, Object};
-use crate::{refs, utils};
+use crate::{pager, refs, utils};
 use clap::ArgMatches;
 use colored::Colorize;
 use regex::Regex;
@@ -9,12 +9,13 @@ use std::fmt;
 use std::path::PathBuf;
 
 fn display(repo_path: &PathBuf, branches: &HashMap<String, Hash>) {
-    let current_branch = match refs::current_branch(&repo_path) {
+    let current_branch = match refs::current_branch(repo_path) {
         Some((branch, _)) => branch,
         _ => String::new(),
     };
+    let _pager = pager::start("branch");
 
-    for (branch, _) in branches {
+    for branch in branches.keys() {
         if current_branch == *branch {
             println!("* {}", branch.green());
         } else {
@@ -42,11 +43,11 @@ pub fn create_branch(
     if !force && branches.iter().any(|(b, _)| b == branch) {
         return Err(Box::new(ErrorBranch::AlreadyExists(branch.to_string())));
     }
-    let head = refs::get_head(&repo_path);
+    let head = refs::get_head(repo_path);
     match head {
         Some(head) => {
             refs::update(
-                &repo_path,
+                repo_path,
                 &format!("refs/heads/{}", branch).to_string(),
                 &head.hash().to_string(),
                 false,
@@ -69,14 +70,14 @@ pub fn delete_branch(
         _ => return Err(Box::new(ErrorBranch::NoBranchFound(branch.clone()))),
     };
 
-    if let Some((current_branch, _)) = refs::current_branch(&repo_path) {
+    if let Some((current_branch, _)) = refs::current_branch(repo_path) {
         if current_branch == *branch {
             return Err(Box::new(ErrorBranch::DeleteCurrentBranch(current_branch)));
         }
     }
 
     // Check if the branch is merged
-    let head = refs::get_head(&repo_path).unwrap();
+    let head = refs::get_head(repo_path).unwrap();
     if !force && !commit.is_ancestor(repo_path, &head) {
         return Err(Box::new(ErrorBranch::NotMerged(branch.clone())));
     }
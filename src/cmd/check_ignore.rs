@@ -0,0 +1,53 @@
+use crate::index::Index;
+use crate::{utils, Exit};
+use clap::ArgMatches;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let ignored = utils::ignored(&root)?;
+    let index = Index::load(&repo_path)?;
+    let verbose = args.is_present("verbose");
+    let non_matching = args.is_present("non-matching");
+    let quiet = args.is_present("quiet");
+
+    let mut found = false;
+    for name in args.values_of("PATHNAME").unwrap() {
+        let path = utils::path_in_repo(name)?;
+        let is_dir = name.ends_with('/') || fs::symlink_metadata(name).is_ok_and(|m| m.is_dir());
+
+        // Tracked files are not subject to the ignore rules
+        let tracked =
+            !args.is_present("no-index") && index.entries.contains_key(path.to_str().unwrap());
+        let rule = if tracked || path.iter().any(|c| c == ".my_git") {
+            None
+        } else {
+            ignored.matching(Path::new(&path), is_dir)
+        };
+        match rule {
+            Some(rule) if verbose => {
+                found |= !rule.negated;
+                if !quiet {
+                    println!("{}:{}:{}\t{}", rule.source, rule.line, rule.pattern, name);
+                }
+            }
+            Some(rule) if !rule.negated => {
+                found = true;
+                if !quiet {
+                    println!("{}", name);
+                }
+            }
+            _ if verbose && non_matching && !quiet => println!("::\t{}", name),
+            _ => (),
+        }
+    }
+
+    // Like git, exit with 1 when no path is ignored
+    if !found {
+        return Err(Box::new(Exit(1)));
+    }
+    Ok(())
+}
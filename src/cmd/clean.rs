@@ -0,0 +1,340 @@
+use crate::cmd::status::{self, Status};
+use crate::ignore::Ignore;
+use crate::index::Index;
+use crate::objects::{Object, Tree};
+use crate::pathspec::Pathspec;
+use crate::{refs, utils};
+use clap::ArgMatches;
+use std::collections::BTreeSet;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+/// Which untracked files are candidates for removal
+#[derive(PartialEq)]
+enum Ignored {
+    /// Ignored files are kept
+    Keep,
+    /// Ignored files are removed too (-x)
+    Include,
+    /// Only ignored files are removed (-X)
+    Only,
+}
+
+/// Whether a path is a directory, without following symlinks
+fn is_dir(path: &PathBuf) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir())
+}
+
+fn display(path: &PathBuf) -> String {
+    if is_dir(path) {
+        format!("{}/", path.display())
+    } else {
+        path.display().to_string()
+    }
+}
+
+/// Collect the ignored paths under an untracked directory
+fn ignored_in(
+    path: &PathBuf,
+    ignored: &Ignore,
+    res: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    for path in entries(path)? {
+        if utils::is_ignored(&path, ignored)? {
+            res.push(path);
+        } else if is_dir(&path) {
+            ignored_in(&path, ignored, res)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collect the paths under an untracked directory which aren't ignored, the
+/// directories without ignored files as a whole. Return false, collecting
+/// nothing, when nothing is ignored in the directory.
+fn unignored_in(
+    path: &PathBuf,
+    ignored: &Ignore,
+    res: &mut Vec<PathBuf>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut found = vec![];
+    let mut any_ignored = false;
+    for path in entries(path)? {
+        // Directories with ignored files are collected by the recursive call
+        if utils::is_ignored(&path, ignored)?
+            || (is_dir(&path) && unignored_in(&path, ignored, &mut found)?)
+        {
+            any_ignored = true;
+        } else {
+            found.push(path);
+        }
+    }
+    if any_ignored {
+        res.extend(found);
+    }
+    Ok(any_ignored)
+}
+
+/// The paths in a directory, sorted
+fn entries(path: &PathBuf) -> io::Result<Vec<PathBuf>> {
+    let mut res = vec![];
+    for entry in fs::read_dir(path)? {
+        res.push(path.join(entry?.file_name()));
+    }
+    res.sort();
+    Ok(res)
+}
+
+/// List the untracked paths to remove, relative to the current directory.
+/// Untracked directories are listed as a whole.
+fn candidates(args: &ArgMatches, mode: &Ignored) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let index = Index::load(&repo_path)?;
+    let last_commit = match refs::get_head(&repo_path) {
+        Some(commit) => *Tree::load(&repo_path, commit.tree),
+        None => Tree::new(),
+    };
+    let ignored = utils::ignored(&root)?;
+    let patterns = match mode {
+        Ignored::Keep => utils::ignored(&root)?,
+        _ => Ignore::empty(&root),
+    };
+
+    // Without pathspec, only the current directory is cleaned
+    let cwd = env::current_dir()?;
+    let specs = args.values_of("PATHSPEC");
+    let pathspec = Pathspec::new(specs.map_or(vec!["."], Iterator::collect))?;
+    let mut starts = vec![root.clone()];
+    let mut untracked = BTreeSet::new();
+    while let Some(path) = starts.pop() {
+        let mut found = BTreeSet::new();
+        status::compute_untracked(
+            &mut found,
+            &path,
+            &pathspec,
+            &last_commit,
+            &index,
+            &patterns,
+            None,
+        )?;
+        for status in found {
+            if let Status::Untracked(path) = &status {
+                // Never remove the current directory, look inside instead
+                if cwd.starts_with(fs::canonicalize(path)?) {
+                    for entry in fs::read_dir(path)? {
+                        starts.push(cwd.join(path).join(entry?.file_name()));
+                    }
+                    continue;
+                }
+            }
+            untracked.insert(status);
+        }
+    }
+
+    let mut res = vec![];
+    for status in untracked {
+        let path = match status {
+            Status::Untracked(path) => PathBuf::from(path.trim_end_matches('/')),
+            _ => continue,
+        };
+        if *mode == Ignored::Only {
+            if utils::is_ignored(&path, &ignored)? {
+                res.push(path);
+            } else if is_dir(&path) {
+                ignored_in(&path, &ignored, &mut res)?;
+            }
+        } else if *mode == Ignored::Keep
+            && args.is_present("directories")
+            && is_dir(&path)
+            && !path.join(".my_git").exists()
+        {
+            // The ignored files of untracked directories are kept
+            if !unignored_in(&path, &ignored, &mut res)? {
+                res.push(path);
+            }
+        } else {
+            res.push(path);
+        }
+    }
+    Ok(res)
+}
+
+/// Ask whether to remove each path, return the accepted ones
+fn ask_each(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut res = vec![];
+    for path in paths {
+        print!("Remove {} [y/N]? ", display(&path));
+        io::stdout().flush()?;
+        match lines.next() {
+            Some(answer) => {
+                if answer?.trim().to_lowercase().starts_with('y') {
+                    res.push(path);
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(res)
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    clean(args, &mut io::stdout())
+}
+
+/// Remove the untracked paths, telling which ones on `out`
+fn clean(args: &ArgMatches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let dry_run = args.is_present("dry-run");
+    let interactive = args.is_present("interactive");
+    if !dry_run && !interactive && !args.is_present("force") {
+        return Err(Box::new(ErrorClean::RequireForce));
+    }
+    let mode = if args.is_present("ignored") {
+        Ignored::Include
+    } else if args.is_present("only-ignored") {
+        Ignored::Only
+    } else {
+        Ignored::Keep
+    };
+    // Nested repositories are only removed when -f is given twice
+    let nested = args.occurrences_of("force") > 1;
+
+    let mut paths = vec![];
+    for path in candidates(args, &mode)? {
+        if is_dir(&path)
+            && (!args.is_present("directories") || (!nested && path.join(".my_git").exists()))
+        {
+            continue;
+        }
+        paths.push(path);
+    }
+    if interactive && !dry_run {
+        paths = ask_each(paths)?;
+    }
+
+    for path in paths {
+        if dry_run {
+            writeln!(out, "Would remove {}", display(&path))?;
+            continue;
+        }
+        writeln!(out, "Removing {}", display(&path))?;
+        if is_dir(&path) {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorClean {
+    RequireForce,
+}
+
+impl fmt::Display for ErrorClean {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorClean::RequireForce => {
+                write!(f, "fatal: refusing to clean without -f, -i or -n")
+            }
+        }
+    }
+}
+
+impl Error for ErrorClean {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::EntryType;
+    use crate::objects::{Blob, Hash};
+    use crate::utils::tests::{in_repo, subcommand};
+
+    /// The output of `clean` with the given options
+    fn clean_with(options: &[&str]) -> String {
+        let mut out = vec![];
+        clean(&subcommand(&[&["clean"], options].concat()), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn removed_paths() {
+        in_repo("clean", |root| {
+            let repo_path = root.join(".my_git");
+            let mut index = Index::load_text(b"");
+            for (path, content) in [
+                ("tracked", "tracked\n"),
+                (".my_gitignore", "*.log\nbuild/\n"),
+                ("dir/tracked", "tracked\n"),
+            ]
+            .iter()
+            {
+                fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+                fs::write(root.join(path), content).unwrap();
+                let hash: Hash = Blob::new(content.as_bytes().to_vec()).hash();
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, hash));
+            }
+            index.save(&repo_path);
+            for path in [
+                "untracked",
+                "new/file",
+                "dir/new",
+                "x.log",
+                "build/out",
+                "new/y.log",
+            ]
+            .iter()
+            {
+                fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+                fs::write(root.join(path), "").unwrap();
+            }
+
+            // Untracked directories are only removed with -d
+            assert_eq!(
+                clean_with(&["-n"]),
+                "Would remove dir/new\nWould remove untracked\n"
+            );
+            // Without -x the ignored files of untracked directories are kept
+            assert_eq!(
+                clean_with(&["-n", "-d"]),
+                "Would remove dir/new\nWould remove new/file\nWould remove untracked\n"
+            );
+            // -x removes the ignored paths too, -X only them
+            assert_eq!(
+                clean_with(&["-n", "-d", "-x"]),
+                "Would remove build/\nWould remove dir/new\nWould remove new/\n\
+                 Would remove untracked\nWould remove x.log\n"
+            );
+            assert_eq!(
+                clean_with(&["-n", "-d", "-X"]),
+                "Would remove build/\nWould remove new/y.log\nWould remove x.log\n"
+            );
+            assert_eq!(
+                clean_with(&["-n", "-X"]),
+                "Would remove new/y.log\nWould remove x.log\n"
+            );
+            assert!(root.join("x.log").exists());
+
+            assert_eq!(
+                clean_with(&["-f", "-X"]),
+                "Removing new/y.log\nRemoving x.log\n"
+            );
+            assert!(!root.join("x.log").exists() && root.join("new/file").exists());
+            assert_eq!(
+                clean_with(&["-f", "-d"]),
+                "Removing dir/new\nRemoving new/\nRemoving untracked\n"
+            );
+            assert!(!root.join("new").exists() && root.join("build/out").exists());
+            assert!(root.join("tracked").exists() && root.join("dir/tracked").exists());
+        });
+    }
+}
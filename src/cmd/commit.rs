@@ -1,20 +1,185 @@
+use crate::attributes::Attributes;
 use crate::cmd::config::{Config, ConfigError};
 use crate::index::Index;
-use crate::objects::{Commit, Object, Tree};
-use crate::refs;
-use crate::utils;
+use crate::objects::{Blob, Commit, Hash, Object, Tree, User};
+use crate::{hooks, refs, utils};
 use clap::ArgMatches;
+use regex::RegexBuilder;
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Stage the modifications and deletions of the files already tracked
+fn stage_tracked(repo_path: &Path, index: &mut Index) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let attributes = Attributes::load(&root);
+    for (path, (entry_type, hash)) in index.entries.clone() {
+        if index.flags(&path).ignores_worktree() {
+            continue;
+        }
+        let full_path = root.join(&path);
+        match fs::symlink_metadata(&full_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                if Index::get_file_type(&full_path) == entry_type
+                    && index.stat_matches(&path, &metadata)
+                {
+                    continue;
+                }
+                let blob = Blob::from_worktree(&full_path, &attributes)?;
+                if blob.hash() != hash || Index::get_file_type(&full_path) != entry_type {
+                    blob.save(repo_path);
+                    index.update_entry(&full_path, &blob)?;
+                }
+            }
+            _ => {
+                index.entries.remove(&path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The temporary index of `commit -a`, removed when dropped
+struct NextIndex(PathBuf);
+
+impl NextIndex {
+    fn new(repo_path: &PathBuf) -> io::Result<NextIndex> {
+        let path = fs::canonicalize(repo_path)?.join("next-index");
+        env::set_var("GIT_INDEX_FILE", &path);
+        Ok(NextIndex(path))
+    }
+}
+
+impl Drop for NextIndex {
+    fn drop(&mut self) {
+        env::remove_var("GIT_INDEX_FILE");
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Build the commented template shown in the editor
+fn template(repo_path: &PathBuf, message: &str, tree: &Tree, parent: Option<&Commit>) -> String {
+    let mut res = String::from(message);
+    res.push_str(
+        "\n# Please enter the commit message for your changes. Lines starting\n\
+         # with '#' will be ignored, and an empty message aborts the commit.\n#\n",
+    );
+    match refs::current_branch(repo_path) {
+        Some((branch, _)) => res.push_str(&format!("# On branch {}\n", branch)),
+        None if parent.is_some() => res.push_str("# HEAD detached\n"),
+        None => res.push_str("# Initial commit\n"),
+    }
+
+    // List the changes to be committed
+    let old = match parent {
+        Some(parent) => Tree::load(repo_path, parent.tree).files(),
+        None => Default::default(),
+    };
+    let new = tree.files();
+    let mut changes = BTreeMap::new();
+    for (path, entry) in new.iter() {
+        match old.get(path) {
+            None => {
+                changes.insert(path, "new file:");
+            }
+            Some(old_entry) if old_entry != entry => {
+                changes.insert(path, "modified:");
+            }
+            _ => (),
+        }
+    }
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        changes.insert(path, "deleted:");
+    }
+    if !changes.is_empty() {
+        res.push_str("#\n# Changes to be committed:\n");
+        for (path, change) in changes {
+            res.push_str(&format!("#\t{:<12}{}\n", change, path));
+        }
+    }
+    res.push_str("#\n");
+    res
+}
+
+/// Open the user's editor on the message file and return the edited content
+pub(crate) fn edit(path: &PathBuf) -> Result<String, Box<dyn Error>> {
+    let editor = env::var("EDITOR")
+        .or_else(|_| env::var("VISUAL"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(Box::new(ErrorCommit::Editor(editor)));
+    }
+    Ok(fs::read_to_string(path)?)
+}
+
+/// Clean up a message: strip trailing whitespaces and leading/trailing empty
+/// lines. Comments are removed when `strip_comments` is set.
+fn cleanup(message: &str, strip_comments: bool) -> String {
+    let lines: Vec<&str> = message
+        .lines()
+        .filter(|line| !strip_comments || !line.starts_with('#'))
+        .map(|line| line.trim_end())
+        .collect();
+    let mut res = String::new();
+    let mut blank = false;
+    for line in lines {
+        if line.is_empty() {
+            blank = !res.is_empty();
+            continue;
+        }
+        if blank {
+            res.push('\n');
+            blank = false;
+        }
+        res.push_str(line);
+        res.push('\n');
+    }
+    res
+}
+
+/// Return the author of the most recent commit of all the refs whose
+/// `Name <email>` matches the pattern, ignoring case, like git does when
+/// `--author` is not an identity
+fn find_author(repo_path: &PathBuf, pattern: &str) -> Option<User> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .ok()?;
+    let mut heads: Vec<Hash> = refs::branches(repo_path).into_values().collect();
+    heads.extend(
+        refs::tags(repo_path)
+            .keys()
+            .filter_map(|tag| refs::rev_parse(repo_path, tag).ok()),
+    );
+    heads.extend(refs::get_head(repo_path).map(|head| head.hash()));
+    Commit::ancestors(repo_path, &heads)
+        .into_iter()
+        .map(|hash| Commit::load(repo_path, hash))
+        .filter(|commit| {
+            let (author, _) = &commit.author;
+            regex.is_match(&format!("{} <{}>", author.name, author.email))
+        })
+        .max_by_key(|commit| commit.committer.1)
+        .map(|commit| commit.author.0.clone())
+}
 
 pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
     // Load config
     let config = Config::load()?;
     let user_name = config.user.name;
-    let user_email = match config.user.email {
-        Some(email) => email,
-        None => String::new(),
-    };
+    let user_email = config.user.email.unwrap_or_default();
 
     // Return an error in case of an empty configuration
     match &user_name {
@@ -24,31 +189,129 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
 
     // Create tree object
     let repo_path = utils::find_repo()?;
-    let index = Index::load(&repo_path);
+    let mut index = Index::load(&repo_path)?;
+    // With -a the files are staged in a temporary index, which the hooks see
+    // through GIT_INDEX_FILE. The index is only updated once the commit is
+    // created.
+    let next_index = match args.is_present("all") {
+        true => {
+            stage_tracked(&repo_path, &mut index)?;
+            let next_index = NextIndex::new(&repo_path)?;
+            index.save(&repo_path);
+            Some(next_index)
+        }
+        false => None,
+    };
+
+    // The pre-commit hook may update the index
+    let verify = !args.is_present("no-verify");
+    if verify {
+        hooks::run(&repo_path, "pre-commit", &[], None)?;
+        index = Index::load(&repo_path)?;
+    }
     let tree = Tree::from(&index);
 
-    // Save tree
-    tree.save(&repo_path);
+    // Get head commit and the parents of the new commit
+    let head = refs::get_head(&repo_path);
+    let amend = args.is_present("amend");
+    let parents = match (&head, amend) {
+        (Some(head), true) => head
+            .parents
+            .iter()
+            .map(|hash| *Commit::load(&repo_path, *hash))
+            .collect(),
+        (None, true) => return Err(Box::new(ErrorCommit::NothingToAmend)),
+        (Some(head), false) => vec![head.clone()],
+        (None, false) => vec![],
+    };
 
-    // Get head commit (parent)
-    let mut parent = vec![];
-    if let Some(commit) = refs::get_head(&repo_path) {
-        parent.push(commit);
+    // Nothing to commit
+    let empty = match parents.first() {
+        Some(parent) => tree.hash() == parent.tree,
+        None => tree.entries.is_empty(),
+    };
+    if empty && !args.is_present("allow-empty") {
+        if amend {
+            return Err(Box::new(ErrorCommit::EmptyAmend));
+        }
+        return Err(Box::new(ErrorCommit::NothingToCommit));
     }
 
-    // Nothing to commit
-    if (parent.is_empty() && tree.entries.is_empty())
-        || (!parent.is_empty() && tree.hash() == parent[0].hash())
-    {
-        return Err(Box::new(NothingToCommit {}));
+    // Compute the message
+    let (message, source) = if let Some(messages) = args.values_of("msg") {
+        (messages.collect::<Vec<&str>>().join("\n\n"), "message")
+    } else if let Some(file) = args.value_of("file") {
+        let mut message = String::new();
+        if file == "-" {
+            io::stdin().read_to_string(&mut message)?;
+        } else {
+            message = fs::read_to_string(file)?;
+        }
+        (message, "message")
+    } else {
+        match (&head, amend) {
+            (Some(head), true) => (head.message.clone(), "commit"),
+            _ => (String::new(), ""),
+        }
+    };
+    let use_editor = !args.is_present("msg") && !args.is_present("file");
+    let path = repo_path.join("COMMIT_EDITMSG");
+    if use_editor {
+        fs::write(
+            &path,
+            template(&repo_path, &message, &tree, parents.first()),
+        )?;
+    } else {
+        fs::write(&path, &message)?;
+    }
+
+    // Let the hooks prepare and check the message
+    let path_str = path.to_str().unwrap();
+    let head_hash = head.as_ref().map(|head| head.hash().to_string());
+    let mut hook_args = vec![path_str];
+    if !source.is_empty() {
+        hook_args.push(source);
+    }
+    if let (Some(hash), true) = (&head_hash, amend) {
+        hook_args.push(hash);
+    }
+    hooks::run(&repo_path, "prepare-commit-msg", &hook_args, None)?;
+    let message = if use_editor {
+        edit(&path)?
+    } else {
+        fs::read_to_string(&path)?
+    };
+    let mut message = cleanup(&message, use_editor);
+    if verify && !message.is_empty() {
+        fs::write(&path, &message)?;
+        hooks::run(&repo_path, "commit-msg", &[path_str], None)?;
+        message = cleanup(&fs::read_to_string(&path)?, use_editor);
+    }
+    if message.is_empty() {
+        return Err(Box::new(ErrorCommit::EmptyMessage));
     }
 
     // Create commit object
-    let message = String::from(args.value_of("msg").unwrap());
-    let commit = Commit::create(&tree, parent, user_name.unwrap(), user_email, message);
+    let mut commit = Commit::create(&tree, parents, user_name.unwrap(), user_email, message);
+    if let (Some(head), true) = (&head, amend) {
+        commit.author = head.author.clone();
+    }
+    if let Some(author) = args.value_of("author") {
+        let author = User::parse(author)
+            .or_else(|| find_author(&repo_path, author))
+            .ok_or_else(|| ErrorCommit::InvalidAuthor(author.to_string()))?;
+        commit.author.0 = author;
+    }
+    if let Some(date) = args.value_of("date") {
+        commit.author.1 =
+            utils::parse_date(date).ok_or_else(|| ErrorCommit::InvalidDate(date.to_string()))?;
+    }
 
-    // Save commit object
+    // Save objects
+    tree.save(&repo_path);
     commit.save(&repo_path);
+    drop(next_index);
+    index.save(&repo_path);
 
     // Update HEAD
     refs::update(
@@ -59,14 +322,128 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
     )
     .expect("fatal: error while updating HEAD ref");
 
+    hooks::run_post(&repo_path, "post-commit", &[], None);
+    if let (Some(hash), true) = (head_hash, amend) {
+        let rewritten = format!("{} {}\n", hash, commit.hash());
+        hooks::run_post(&repo_path, "post-rewrite", &["amend"], Some(&rewritten));
+    }
     Ok(())
 }
 
 #[derive(Debug)]
-struct NothingToCommit {}
-impl fmt::Display for NothingToCommit {
+pub enum ErrorCommit {
+    NothingToCommit,
+    NothingToAmend,
+    EmptyAmend,
+    EmptyMessage,
+    InvalidAuthor(String),
+    InvalidDate(String),
+    Editor(String),
+}
+
+impl fmt::Display for ErrorCommit {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "nothing to commit")
+        match self {
+            ErrorCommit::NothingToCommit => write!(f, "nothing to commit"),
+            ErrorCommit::NothingToAmend => write!(f, "fatal: You have nothing to amend."),
+            ErrorCommit::EmptyAmend => write!(
+                f,
+                "You asked to amend the most recent commit, but doing so would make\n\
+                 it empty. Use --allow-empty to create an empty commit anyway."
+            ),
+            ErrorCommit::EmptyMessage => {
+                write!(f, "Aborting commit due to empty commit message.")
+            }
+            ErrorCommit::InvalidAuthor(author) => write!(
+                f,
+                "fatal: --author '{}' is not 'Name <email>' and matches no existing author",
+                author
+            ),
+            ErrorCommit::InvalidDate(date) => write!(f, "fatal: invalid date format: {}", date),
+            ErrorCommit::Editor(editor) => write!(
+                f,
+                "error: There was a problem with the editor '{}'.\n\
+                 Please supply the message using either -m or -F option.",
+                editor
+            ),
+        }
+    }
+}
+
+impl Error for ErrorCommit {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::add;
+    use crate::utils::tests::{in_repo, subcommand};
+
+    #[test]
+    fn cleanup_message() {
+        let message = "\n  \nSubject  \n\n\n\nBody\n# Comment\n\n";
+        assert_eq!(cleanup(message, true), "Subject\n\nBody\n");
+        assert_eq!(cleanup(message, false), "Subject\n\nBody\n# Comment\n");
+        assert_eq!(cleanup("# Only comments\n\n", true), "");
+    }
+
+    #[test]
+    fn amend_and_authors() {
+        in_repo("commit", |root| {
+            let repo_path = utils::find_repo().unwrap();
+            let home = root.parent().unwrap();
+            let config = "user:\n  name: John Doe\n  email: john@doe.com\n";
+            fs::write(home.join(".my_gitconfig"), config).unwrap();
+            fs::write(root.join("f"), "v1\n").unwrap();
+            add::run(&subcommand(&["add", "f"])).unwrap();
+            let jane = "Jane Roe <jane@roe.com>";
+            let args = ["commit", "-m", "Subject", "-m", "Body", "--author", jane];
+            run(&subcommand(&args)).unwrap();
+            let first = refs::get_head(&repo_path).unwrap();
+            assert_eq!(first.message, "Subject\n\nBody\n");
+
+            // The template lists the staged changes
+            fs::write(root.join("f"), "v2\n").unwrap();
+            add::run(&subcommand(&["add", "f"])).unwrap();
+            let tree = Tree::from(&Index::load(&repo_path).unwrap());
+            let template = template(&repo_path, "Subject\n", &tree, Some(&first));
+            assert!(template.starts_with("Subject\n\n# Please enter the commit message"));
+            assert!(template.ends_with(
+                "# On branch master\n#\n# Changes to be committed:\n#\tmodified:   f\n#\n"
+            ));
+
+            // Amending reuses the message and the author, the editor leaves
+            // the message as is
+            env::set_var("EDITOR", "true");
+            let res = run(&subcommand(&["commit", "--amend"]));
+            env::remove_var("EDITOR");
+            res.unwrap();
+            let amended = refs::get_head(&repo_path).unwrap();
+            assert_eq!(amended.message, first.message);
+            assert_eq!(amended.author.0.name, "Jane Roe");
+            assert!(amended.parents.is_empty());
+            assert_ne!(amended.tree, first.tree);
+
+            // An author which isn't an identity is searched for
+            let args = ["commit", "--allow-empty", "-m", "Next", "--author", "JANE"];
+            run(&subcommand(&args)).unwrap();
+            let author = &refs::get_head(&repo_path).unwrap().author.0;
+            assert_eq!(
+                (&*author.name, &*author.email),
+                ("Jane Roe", "jane@roe.com")
+            );
+            let args = [
+                "commit",
+                "--allow-empty",
+                "-m",
+                "Next",
+                "--author",
+                "nobody",
+            ];
+            let err = run(&subcommand(&args)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "fatal: --author 'nobody' is not 'Name <email>' and matches no existing author"
+            );
+        });
     }
 }
-impl Error for NothingToCommit {}
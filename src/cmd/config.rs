@@ -2,6 +2,7 @@ extern crate yaml_rust;
 use crate::utils;
 use clap::ArgMatches;
 use dirs::home_dir;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
@@ -16,11 +17,11 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
         let key = args.value_of("NAME").unwrap();
         if args.is_present("global") {
             let mut config = Config::load_global()?;
-            config.set(&String::from(key), &String::from(value))?;
+            config.set(key, value)?;
             config.dump_global()?;
         } else {
             let mut config = Config::load_local()?;
-            config.set(&String::from(key), &String::from(value))?;
+            config.set(key, value)?;
             config.dump_local()?;
         }
     } else {
@@ -29,20 +30,44 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
         } else {
             Config::load()?
         };
-        if let Some(val) = config.get(&String::from(args.value_of("NAME").unwrap()))? {
+        if let Some(val) = config.get(args.value_of("NAME").unwrap())? {
             println!("{}", val);
         }
     }
     Ok(())
 }
 
+/// Settings of named drivers or branches, eg: `diff.<driver>.textconv`
+pub type Drivers = BTreeMap<String, BTreeMap<String, String>>;
+
 pub struct Config {
     pub user: User,
+    pub core: Core,
+    pub index: IndexConfig,
+    /// Whether to use a pager (or which one) for each command
+    pub pager: BTreeMap<String, String>,
+    /// Diff drivers selected by the `diff` attribute
+    pub diff: Drivers,
+    /// Merge drivers selected by the `merge` attribute
+    pub merge: Drivers,
+    /// Content filters selected by the `filter` attribute
+    pub filter: Drivers,
+    /// Settings of each branch, eg: its upstream with `remote` and `merge`
+    pub branch: Drivers,
 }
 
 impl Config {
     fn new() -> Config {
-        Config { user: User::new() }
+        Config {
+            user: User::new(),
+            core: Core::new(),
+            index: IndexConfig::new(),
+            pager: BTreeMap::new(),
+            diff: Drivers::new(),
+            merge: Drivers::new(),
+            filter: Drivers::new(),
+            branch: Drivers::new(),
+        }
     }
 
     pub fn load() -> Result<Config, Box<dyn Error>> {
@@ -105,6 +130,21 @@ impl Config {
                     if let Yaml::String(key) = key {
                         match key.as_str() {
                             "user" => self.user.apply_config(val),
+                            "core" => self.core.apply_config(val),
+                            "index" => self.index.apply_config(val),
+                            "pager" => {
+                                if let Yaml::Hash(val) = val {
+                                    for (cmd, val) in val.iter() {
+                                        if let (Some(cmd), Some(val)) = (scalar(cmd), scalar(val)) {
+                                            self.pager.insert(cmd, val);
+                                        }
+                                    }
+                                }
+                            }
+                            "diff" => apply_drivers(&mut self.diff, val),
+                            "merge" => apply_drivers(&mut self.merge, val),
+                            "filter" => apply_drivers(&mut self.filter, val),
+                            "branch" => apply_drivers(&mut self.branch, val),
                             _ => (),
                         }
                     }
@@ -118,12 +158,12 @@ impl Config {
             _ => (),
         }
     }
-    fn set(&mut self, key: &String, value: &String) -> Result<(), ConfigError> {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
         let mut key = key.split(".");
         match key.next() {
             Some("user") => match key.next() {
-                Some("name") => self.user.name = Some(value.clone()),
-                Some("email") => self.user.email = Some(value.clone()),
+                Some("name") => self.user.name = Some(value.to_string()),
+                Some("email") => self.user.email = Some(value.to_string()),
                 Some(key) => {
                     return Err(ConfigError::InvalidKey(
                         String::from("user"),
@@ -132,6 +172,55 @@ impl Config {
                 }
                 None => return Err(ConfigError::EmptyKey(String::from("user"))),
             },
+            Some("core") => match key.next() {
+                Some("hooksPath") => self.core.hooks_path = Some(value.to_string()),
+                Some("pager") => self.core.pager = Some(value.to_string()),
+                Some("excludesFile") => self.core.excludes_file = Some(value.to_string()),
+                Some("threads") => self.core.threads = Some(value.to_string()),
+                Some("untrackedCache") => self.core.untracked_cache = Some(value.to_string()),
+                Some("fsmonitor") => self.core.fsmonitor = Some(value.to_string()),
+                Some("sparseCheckout") => self.core.sparse_checkout = Some(value.to_string()),
+                Some(key) => {
+                    return Err(ConfigError::InvalidKey(
+                        String::from("core"),
+                        String::from(key),
+                    ))
+                }
+                None => return Err(ConfigError::EmptyKey(String::from("core"))),
+            },
+            Some("index") => match key.next() {
+                Some("version") => self.index.version = Some(value.to_string()),
+                Some(key) => {
+                    return Err(ConfigError::InvalidKey(
+                        String::from("index"),
+                        String::from(key),
+                    ))
+                }
+                None => return Err(ConfigError::EmptyKey(String::from("index"))),
+            },
+            Some("pager") => match key.next() {
+                Some(cmd) => {
+                    self.pager.insert(String::from(cmd), value.to_string());
+                }
+                None => return Err(ConfigError::EmptyKey(String::from("pager"))),
+            },
+            Some(section @ ("diff" | "merge" | "filter" | "branch")) => {
+                let drivers = match section {
+                    "diff" => &mut self.diff,
+                    "merge" => &mut self.merge,
+                    "filter" => &mut self.filter,
+                    _ => &mut self.branch,
+                };
+                match (key.next(), key.next()) {
+                    (Some(driver), Some(name)) => {
+                        drivers
+                            .entry(String::from(driver))
+                            .or_default()
+                            .insert(String::from(name), value.to_string());
+                    }
+                    _ => return Err(ConfigError::EmptyKey(String::from(section))),
+                }
+            }
             key => {
                 return Err(ConfigError::InvalidKey(
                     String::from("config"),
@@ -142,7 +231,7 @@ impl Config {
         Ok(())
     }
 
-    fn get(&self, key: &String) -> Result<Option<String>, ConfigError> {
+    fn get(&self, key: &str) -> Result<Option<String>, ConfigError> {
         let mut key = key.split(".");
         match key.next() {
             Some("user") => match key.next() {
@@ -154,6 +243,47 @@ impl Config {
                 )),
                 None => Err(ConfigError::EmptyKey(String::from("user"))),
             },
+            Some("core") => match key.next() {
+                Some("hooksPath") => Ok(self.core.hooks_path.clone()),
+                Some("pager") => Ok(self.core.pager.clone()),
+                Some("excludesFile") => Ok(self.core.excludes_file.clone()),
+                Some("threads") => Ok(self.core.threads.clone()),
+                Some("untrackedCache") => Ok(self.core.untracked_cache.clone()),
+                Some("fsmonitor") => Ok(self.core.fsmonitor.clone()),
+                Some("sparseCheckout") => Ok(self.core.sparse_checkout.clone()),
+                Some(key) => Err(ConfigError::InvalidKey(
+                    String::from("core"),
+                    String::from(key),
+                )),
+                None => Err(ConfigError::EmptyKey(String::from("core"))),
+            },
+            Some("index") => match key.next() {
+                Some("version") => Ok(self.index.version.clone()),
+                Some(key) => Err(ConfigError::InvalidKey(
+                    String::from("index"),
+                    String::from(key),
+                )),
+                None => Err(ConfigError::EmptyKey(String::from("index"))),
+            },
+            Some("pager") => match key.next() {
+                Some(cmd) => Ok(self.pager.get(cmd).cloned()),
+                None => Err(ConfigError::EmptyKey(String::from("pager"))),
+            },
+            Some(section @ ("diff" | "merge" | "filter" | "branch")) => {
+                let drivers = match section {
+                    "diff" => &self.diff,
+                    "merge" => &self.merge,
+                    "filter" => &self.filter,
+                    _ => &self.branch,
+                };
+                match (key.next(), key.next()) {
+                    (Some(driver), Some(name)) => Ok(drivers
+                        .get(driver)
+                        .and_then(|driver| driver.get(name))
+                        .cloned()),
+                    _ => Err(ConfigError::EmptyKey(String::from(section))),
+                }
+            }
             key => Err(ConfigError::InvalidKey(
                 String::from("config"),
                 String::from(key.unwrap()),
@@ -188,6 +318,75 @@ impl Config {
         }
         dump.insert(Yaml::from_str("user"), Yaml::Hash(user));
 
+        // Core
+        let mut core = Hash::new();
+        if let Some(hooks_path) = &self.core.hooks_path {
+            core.insert(
+                Yaml::from_str("hooksPath"),
+                Yaml::String(hooks_path.clone()),
+            );
+        }
+        if let Some(pager) = &self.core.pager {
+            core.insert(Yaml::from_str("pager"), Yaml::String(pager.clone()));
+        }
+        if let Some(excludes_file) = &self.core.excludes_file {
+            core.insert(
+                Yaml::from_str("excludesFile"),
+                Yaml::String(excludes_file.clone()),
+            );
+        }
+        if let Some(threads) = &self.core.threads {
+            core.insert(Yaml::from_str("threads"), Yaml::String(threads.clone()));
+        }
+        if let Some(untracked_cache) = &self.core.untracked_cache {
+            core.insert(
+                Yaml::from_str("untrackedCache"),
+                Yaml::String(untracked_cache.clone()),
+            );
+        }
+        if let Some(fsmonitor) = &self.core.fsmonitor {
+            core.insert(Yaml::from_str("fsmonitor"), Yaml::String(fsmonitor.clone()));
+        }
+        if let Some(sparse_checkout) = &self.core.sparse_checkout {
+            core.insert(
+                Yaml::from_str("sparseCheckout"),
+                Yaml::String(sparse_checkout.clone()),
+            );
+        }
+        if !core.is_empty() {
+            dump.insert(Yaml::from_str("core"), Yaml::Hash(core));
+        }
+
+        // Index
+        if let Some(version) = &self.index.version {
+            let mut index = Hash::new();
+            index.insert(Yaml::from_str("version"), Yaml::String(version.clone()));
+            dump.insert(Yaml::from_str("index"), Yaml::Hash(index));
+        }
+
+        // Pager
+        let mut pager = Hash::new();
+        for (cmd, val) in self.pager.iter() {
+            pager.insert(Yaml::String(cmd.clone()), Yaml::String(val.clone()));
+        }
+        if !pager.is_empty() {
+            dump.insert(Yaml::from_str("pager"), Yaml::Hash(pager));
+        }
+
+        // Drivers
+        for (section, drivers) in [
+            ("diff", &self.diff),
+            ("merge", &self.merge),
+            ("filter", &self.filter),
+            ("branch", &self.branch),
+        ]
+        .iter()
+        {
+            if !drivers.is_empty() {
+                dump.insert(Yaml::from_str(section), dump_drivers(drivers));
+            }
+        }
+
         // Dump
         let mut dump_str = String::new();
         let mut emitter = YamlEmitter::new(&mut dump_str);
@@ -202,6 +401,12 @@ pub struct User {
     pub email: Option<String>,
 }
 
+impl Default for User {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl User {
     pub fn new() -> User {
         User {
@@ -225,6 +430,127 @@ impl User {
     }
 }
 
+pub struct Core {
+    pub hooks_path: Option<String>,
+    pub pager: Option<String>,
+    /// Ignore file applying to every repository
+    pub excludes_file: Option<String>,
+    /// Number of threads hashing and walking the working tree
+    pub threads: Option<String>,
+    /// Whether the untracked files are cached in the index
+    pub untracked_cache: Option<String>,
+    /// Hook telling which files changed since its last call
+    pub fsmonitor: Option<String>,
+    /// Whether only the directories of `info/sparse-checkout` are checked out
+    pub sparse_checkout: Option<String>,
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Core {
+    pub fn new() -> Core {
+        Core {
+            hooks_path: None,
+            pager: None,
+            excludes_file: None,
+            threads: None,
+            untracked_cache: None,
+            fsmonitor: None,
+            sparse_checkout: None,
+        }
+    }
+
+    pub fn apply_config(&mut self, config: &Yaml) {
+        if let Yaml::Hash(config) = config {
+            for (key, val) in config.iter() {
+                if let (Yaml::String(key), Some(val)) = (key, scalar(val)) {
+                    match key.as_str() {
+                        "hooksPath" => self.hooks_path = Some(val),
+                        "pager" => self.pager = Some(val),
+                        "excludesFile" => self.excludes_file = Some(val),
+                        "threads" => self.threads = Some(val),
+                        "untrackedCache" => self.untracked_cache = Some(val),
+                        "fsmonitor" => self.fsmonitor = Some(val),
+                        "sparseCheckout" => self.sparse_checkout = Some(val),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct IndexConfig {
+    /// Version of the format of new index files
+    pub version: Option<String>,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IndexConfig {
+    pub fn new() -> IndexConfig {
+        IndexConfig { version: None }
+    }
+
+    pub fn apply_config(&mut self, config: &Yaml) {
+        if let Yaml::Hash(config) = config {
+            for (key, val) in config.iter() {
+                if let (Yaml::String(key), Some(val)) = (key, scalar(val)) {
+                    if key == "version" {
+                        self.version = Some(val);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read the settings of the drivers of a section
+fn apply_drivers(drivers: &mut Drivers, config: &Yaml) {
+    if let Yaml::Hash(config) = config {
+        for (driver, settings) in config.iter() {
+            if let (Some(driver), Yaml::Hash(settings)) = (scalar(driver), settings) {
+                let driver = drivers.entry(driver).or_default();
+                for (key, val) in settings.iter() {
+                    if let (Some(key), Some(val)) = (scalar(key), scalar(val)) {
+                        driver.insert(key, val);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn dump_drivers(drivers: &Drivers) -> Yaml {
+    let mut res = Hash::new();
+    for (driver, settings) in drivers.iter() {
+        let mut hash = Hash::new();
+        for (key, val) in settings.iter() {
+            hash.insert(Yaml::String(key.clone()), Yaml::String(val.clone()));
+        }
+        res.insert(Yaml::String(driver.clone()), Yaml::Hash(hash));
+    }
+    Yaml::Hash(res)
+}
+
+/// Return the string representation of a scalar value
+fn scalar(val: &Yaml) -> Option<String> {
+    match val {
+        Yaml::String(val) | Yaml::Real(val) => Some(val.clone()),
+        Yaml::Integer(val) => Some(val.to_string()),
+        Yaml::Boolean(val) => Some(val.to_string()),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     MissingAuthor(String),
@@ -0,0 +1,334 @@
+use crate::index::{EntryType, Index};
+use crate::objects::{Blob, Commit, Hash, Object, Tree};
+use crate::pathspec::Pathspec;
+use crate::{pager, parallel, refs, utils, Exit};
+use clap::ArgMatches;
+use colored::Colorize;
+use regex::bytes::{Regex, RegexBuilder};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to print for each file
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Lines { line_number: bool },
+    FileNames,
+    Count,
+}
+
+/// Where the content of a file comes from
+enum Source {
+    WorkTree(PathBuf),
+    Blob(Hash),
+}
+
+struct File {
+    /// Path relative to the root of the repository
+    path: PathBuf,
+    /// Path as printed, prefixed with the revision if any
+    name: String,
+    source: Source,
+}
+
+/// Translate a POSIX basic regular expression to the extended syntax used by
+/// the regex crate: `\+ \? \| \( \) \{ \}` are operators while the unescaped
+/// characters are literals.
+fn basic_to_extended(pattern: &str) -> String {
+    let mut res = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if "+?|(){}".contains(c) => res.push(c),
+                Some(c) => {
+                    res.push('\\');
+                    res.push(c);
+                }
+                None => res.push_str("\\\\"),
+            },
+            '[' => {
+                // Bracket expressions are copied as is, a leading ] is literal
+                res.push(c);
+                if chars.peek() == Some(&'^') {
+                    res.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    res.push_str("\\]");
+                    chars.next();
+                }
+                for c in chars.by_ref() {
+                    res.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            '+' | '?' | '|' | '(' | ')' | '{' | '}' => {
+                res.push('\\');
+                res.push(c);
+            }
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn build_regex(pattern: &str, args: &ArgMatches) -> Result<Regex, Box<dyn Error>> {
+    let mut pattern = if args.is_present("extended-regexp") {
+        pattern.to_string()
+    } else {
+        basic_to_extended(pattern)
+    };
+    if args.is_present("word-regexp") {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+    RegexBuilder::new(&pattern)
+        .case_insensitive(args.is_present("ignore-case"))
+        .build()
+        .map_err(|err| Box::new(ErrorGrep::InvalidPattern(err.to_string())) as Box<dyn Error>)
+}
+
+/// Express a path relative to the repository root from the current directory
+fn display_path(prefix: &Path, path: &Path) -> String {
+    let common = prefix
+        .iter()
+        .zip(path.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut res = PathBuf::new();
+    for _ in common..prefix.iter().count() {
+        res.push("..");
+    }
+    res.extend(path.iter().skip(common));
+    res.to_str().unwrap().to_string()
+}
+
+/// List the files to search: the tracked files of the working tree, the
+/// files of the index with `cached` or the files of a commit
+fn list_files(
+    repo_path: &PathBuf,
+    rev: Option<&str>,
+    cached: bool,
+    pathspec: &Pathspec,
+) -> Result<Vec<File>, Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let prefix = env::current_dir()?.strip_prefix(&root)?.to_path_buf();
+    let ignored = utils::ignored(&root)?;
+    let entries = match rev {
+        Some(rev) => {
+            let commit = Commit::load(repo_path, refs::rev_parse(repo_path, rev)?);
+            Tree::load(repo_path, commit.tree).files()
+        }
+        None => {
+            // Entries only recording the intent to add a file have no content
+            let index = Index::load(repo_path)?;
+            let flags = index.flags;
+            let intent_to_add = |path: &String| flags.get(path).is_some_and(|f| f.intent_to_add);
+            index
+                .entries
+                .into_iter()
+                .filter(|(path, _)| !(cached && intent_to_add(path)))
+                .collect()
+        }
+    };
+    let mut files = vec![];
+    for (path, (kind, hash)) in entries {
+        let path = PathBuf::from(path);
+        if kind == EntryType::Symlink
+            || !pathspec.matches(path.to_str().unwrap(), false)
+            || ignored.is_ignored(&path, false)
+        {
+            continue;
+        }
+        let name = display_path(&prefix, &path);
+        let file = match rev {
+            Some(rev) => File {
+                name: format!("{}:{}", rev, name),
+                source: Source::Blob(hash),
+                path,
+            },
+            None if cached => File {
+                name,
+                source: Source::Blob(hash),
+                path,
+            },
+            None if root.join(&path).is_file() => File {
+                name,
+                source: Source::WorkTree(root.join(&path)),
+                path,
+            },
+            None => continue,
+        };
+        files.push(file);
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Highlight the matches of a line
+fn highlight(line: &[u8], regex: &Regex) -> String {
+    let mut res = String::new();
+    let mut last = 0;
+    for m in regex.find_iter(line) {
+        res.push_str(&String::from_utf8_lossy(&line[last..m.start()]));
+        let found = String::from_utf8_lossy(m.as_bytes());
+        res.push_str(&found.red().bold().to_string());
+        last = m.end();
+    }
+    res.push_str(&String::from_utf8_lossy(&line[last..]));
+    res
+}
+
+/// Search a file and return what to print about it, if it matches
+fn search(repo_path: &Path, file: &File, regex: &Regex, output: Output) -> Option<String> {
+    let data = match &file.source {
+        Source::WorkTree(path) => fs::read(path).ok()?,
+        Source::Blob(hash) => Blob::load(repo_path, *hash).data,
+    };
+    let name = file.name.magenta();
+    let sep = ":".cyan();
+    let binary = data.iter().take(8000).any(|b| *b == 0);
+    let data = data.strip_suffix(b"\n").unwrap_or(&data);
+    let mut res = String::new();
+    let mut count = 0;
+    for (i, line) in data.split(|b| *b == b'\n').enumerate() {
+        if !regex.is_match(line) {
+            continue;
+        }
+        count += 1;
+        match output {
+            Output::FileNames => return Some(format!("{}\n", name)),
+            Output::Lines { .. } if binary => {
+                return Some(format!("Binary file {} matches\n", file.name))
+            }
+            Output::Lines { line_number } => {
+                res.push_str(&format!("{}{}", name, sep));
+                if line_number {
+                    res.push_str(&format!("{}{}", (i + 1).to_string().green(), sep));
+                }
+                res.push_str(&highlight(line, regex));
+                res.push('\n');
+            }
+            Output::Count => (),
+        }
+    }
+    match output {
+        _ if count == 0 => None,
+        Output::Count => Some(format!("{}{}{}\n", name, sep, count)),
+        _ => Some(res),
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+    let regex = build_regex(args.value_of("PATTERN").unwrap(), args)?;
+    let output = if args.is_present("files-with-matches") {
+        Output::FileNames
+    } else if args.is_present("count") {
+        Output::Count
+    } else {
+        Output::Lines {
+            line_number: args.is_present("line-number"),
+        }
+    };
+    // Without pathspec, only the files under the current directory are searched
+    let specs = args.values_of("PATHS");
+    let pathspec = Pathspec::new(specs.map_or(vec!["."], Iterator::collect))?;
+
+    let cached = args.is_present("cached");
+    let files = list_files(&repo_path, args.value_of("REV"), cached, &pathspec)?;
+    let results = parallel::map(parallel::threads(), &files, |file| {
+        search(&repo_path, file, &regex, output)
+    });
+    let _pager = pager::start("grep");
+    let mut found = false;
+    for res in results.into_iter().flatten() {
+        print!("{}", res);
+        found = true;
+    }
+
+    // Like grep, exit with 1 when nothing matched
+    if !found {
+        return Err(Box::new(Exit(1)));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorGrep {
+    InvalidPattern(String),
+}
+
+impl fmt::Display for ErrorGrep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorGrep::InvalidPattern(err) => write!(f, "fatal: invalid pattern: {}", err),
+        }
+    }
+}
+
+impl Error for ErrorGrep {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Flags;
+    use crate::utils::tests::in_repo;
+
+    #[test]
+    fn basic_regex() {
+        assert_eq!(basic_to_extended(r"a+b?(c|d)"), r"a\+b\?\(c\|d\)");
+        assert_eq!(basic_to_extended(r"\(ab\)\{2\}x\+"), r"(ab){2}x+");
+        assert_eq!(basic_to_extended(r"[]+(]\.*"), r"[\]+(]\.*");
+    }
+
+    #[test]
+    fn relative_display() {
+        let path = Path::new("src/cmd/grep.rs");
+        assert_eq!(display_path(Path::new(""), path), "src/cmd/grep.rs");
+        assert_eq!(display_path(Path::new("src"), path), "cmd/grep.rs");
+        assert_eq!(
+            display_path(Path::new("doc/api"), path),
+            "../../src/cmd/grep.rs"
+        );
+    }
+
+    #[test]
+    fn cached() {
+        in_repo("grep", |root| {
+            let repo_path = root.join(".my_git");
+            let mut index = Index::load_text(b"");
+            for (path, content) in [("file", "staged\n"), ("ita", "")].iter() {
+                let blob = Blob::new(content.as_bytes().to_vec());
+                blob.save(&repo_path);
+                let entry = (EntryType::File, blob.hash());
+                index.entries.insert(path.to_string(), entry);
+            }
+            let intent = Flags {
+                intent_to_add: true,
+                ..Default::default()
+            };
+            index.flags.insert(String::from("ita"), intent);
+            index.save(&repo_path);
+            fs::write(root.join("file"), "modified\n").unwrap();
+            fs::write(root.join("ita"), "modified\n").unwrap();
+
+            colored::control::set_override(false);
+            let regex = Regex::new("ed$").unwrap();
+            let output = Output::Lines { line_number: false };
+            let pathspec = Pathspec::new(vec!["."]).unwrap();
+            let found = |cached: bool| -> Vec<_> {
+                let files = list_files(&repo_path, None, cached, &pathspec).unwrap();
+                files
+                    .iter()
+                    .filter_map(|file| search(&repo_path, file, &regex, output))
+                    .collect()
+            };
+            assert_eq!(found(false), vec!["file:modified\n", "ita:modified\n"]);
+            assert_eq!(found(true), vec!["file:staged\n"]);
+        });
+    }
+}
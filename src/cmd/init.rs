@@ -1,8 +1,10 @@
+use crate::hooks;
+use crate::index::Index;
 use clap::ArgMatches;
 use std::env;
 use std::error::Error;
 use std::fs;
-use std::fs::{DirBuilder, File};
+use std::fs::DirBuilder;
 
 /// This funciton initialize the git repository. It returns an error if
 /// something went wrong like a lake of rights
@@ -35,8 +37,9 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
         fs::write(git_path.join("HEAD"), "ref: refs/heads/master\n")?;
     }
     if !git_path.join("index").is_file() {
-        File::create(git_path.join("index"))?;
+        Index::new().save(&git_path);
     }
+    hooks::install_samples(&git_path)?;
 
     if !args.is_present("quiet") {
         match reinitialized {
@@ -1,38 +1,524 @@
-use crate::objects::{Commit, Object};
-use crate::{refs, utils};
+use crate::objects::{decoration, Commit, Hash, Object, Tree};
+use crate::pathspec::Pathspec;
+use crate::{pager, refs, utils};
+use chrono::{DateTime, FixedOffset};
 use clap::ArgMatches;
-use std::collections::{HashSet, VecDeque};
+use colored::Colorize;
+use regex::{Regex, RegexBuilder};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io;
+use std::path::{Path, PathBuf};
 
-pub fn run(_: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let repo_path = utils::find_repo()?;
-    // TODO: Handle piping to the $PAGER (eg: less)
-
-    if let Some(head) = refs::get_head(&repo_path) {
-        // This is used for the commits queue
-        let mut commits = VecDeque::new();
-        // This set allow duplicate handling
-        let mut used = HashSet::new();
-
-        used.insert(head.hash());
-        commits.push_front(head);
-
-        while let Some(commit) = commits.pop_front() {
-            println!("{}", commit);
-            for parent in commit.parents.iter() {
-                if !used.contains(parent) {
-                    commits.push_back(*Commit::load(&repo_path, *parent));
-                    used.insert(*parent);
+/// Criteria limiting the commits shown
+struct Filters {
+    authors: Vec<Regex>,
+    greps: Vec<Regex>,
+    since: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+    paths: Option<Pathspec>,
+}
+
+impl Filters {
+    fn new(args: &ArgMatches) -> Result<Self, Box<dyn Error>> {
+        let regexes = |name: &str| -> Result<Vec<Regex>, Box<dyn Error>> {
+            let mut res = vec![];
+            for pattern in args.values_of(name).into_iter().flatten() {
+                res.push(
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(args.is_present("regexp-ignore-case"))
+                        .build()?,
+                );
+            }
+            Ok(res)
+        };
+        let date = |name: &str| -> Result<Option<DateTime<FixedOffset>>, Box<dyn Error>> {
+            match args.value_of(name) {
+                Some(date) => match utils::parse_date(date) {
+                    Some(date) => Ok(Some(date)),
+                    None => Err(Box::new(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("fatal: invalid date format: {}", date),
+                    ))),
+                },
+                None => Ok(None),
+            }
+        };
+
+        // Paths are given relatively to the current directory
+        let paths = match args.values_of("PATHS") {
+            Some(specs) => Some(Pathspec::new(specs)?),
+            None => None,
+        };
+
+        Ok(Filters {
+            authors: regexes("author")?,
+            greps: regexes("grep")?,
+            since: date("since")?,
+            until: date("until")?,
+            paths,
+        })
+    }
+
+    /// Return whether a commit must be shown
+    fn matches(&self, repo_path: &Path, commit: &Commit) -> bool {
+        let (author, _) = &commit.author;
+        let author = format!("{} <{}>", author.name, author.email);
+        let (_, date) = &commit.committer;
+        (self.authors.is_empty() || self.authors.iter().any(|re| re.is_match(&author)))
+            && (self.greps.is_empty() || self.greps.iter().any(|re| re.is_match(&commit.message)))
+            && self.since.is_none_or(|since| *date >= since)
+            && self.until.is_none_or(|until| *date <= until)
+            && self
+                .paths
+                .as_ref()
+                .is_none_or(|paths| touches(paths, repo_path, commit))
+    }
+}
+
+/// Return whether a commit changes the files matching the pathspec compared
+/// to its parents. Merge commits must differ from all their parents.
+fn touches(pathspec: &Pathspec, repo_path: &Path, commit: &Commit) -> bool {
+    let files = |tree: &Tree| -> Vec<_> {
+        let files = tree.files().into_iter();
+        files
+            .filter(|(path, _)| pathspec.matches(path, false))
+            .collect()
+    };
+    let tree = files(&Tree::load(repo_path, commit.tree));
+    if commit.parents.is_empty() {
+        return !tree.is_empty();
+    }
+    commit.parents.iter().all(|parent| {
+        let parent = Commit::load(repo_path, *parent);
+        tree != files(&Tree::load(repo_path, parent.tree))
+    })
+}
+
+/// Compute the commits to start from and the commits to exclude given
+/// revision arguments (`rev`, `^rev`, `a..b`, `a...b`)
+fn parse_revisions(
+    repo_path: &PathBuf,
+    revisions: Vec<&str>,
+) -> Result<(Vec<Hash>, HashSet<Hash>), Box<dyn Error>> {
+    let parse = |rev: &str| {
+        let rev = if rev.is_empty() { "HEAD" } else { rev };
+        refs::rev_parse(repo_path, rev)
+    };
+    let mut include = vec![];
+    let mut exclude = vec![];
+    let mut symmetric = vec![];
+    for rev in revisions {
+        if let Some(pos) = rev.find("...") {
+            let (a, b) = (parse(&rev[..pos])?, parse(&rev[pos + 3..])?);
+            include.extend(&[a, b]);
+            symmetric.push((a, b));
+        } else if let Some(pos) = rev.find("..") {
+            exclude.push(parse(&rev[..pos])?);
+            include.push(parse(&rev[pos + 2..])?);
+        } else if let Some(rev) = rev.strip_prefix('^') {
+            exclude.push(parse(rev)?);
+        } else {
+            include.push(parse(rev)?);
+        }
+    }
+    if include.is_empty() {
+        include.push(refs::rev_parse(repo_path, "HEAD").map_err(|_| no_commit())?);
+    }
+
+    let mut excluded = Commit::ancestors(repo_path, &exclude);
+    for (a, b) in symmetric {
+        let a = Commit::ancestors(repo_path, &[a]);
+        let b = Commit::ancestors(repo_path, &[b]);
+        excluded.extend(a.intersection(&b));
+    }
+    Ok((include, excluded))
+}
+
+/// Order in which the commits are shown
+#[derive(Clone, Copy, PartialEq)]
+enum Order {
+    /// Most recent commits first
+    Date,
+    /// Most recent commits first but no parent before all of its children
+    DateTopo,
+    /// No parent before all of its children and lines of history are not
+    /// intermixed
+    Topo,
+}
+
+/// Walk the history from the given commits skipping the excluded ones
+fn walk(repo_path: &Path, include: &[Hash], excluded: &HashSet<Hash>, order: Order) -> Vec<Commit> {
+    // Load every commit to show
+    let mut commits = HashMap::new();
+    let mut stack = include.to_vec();
+    while let Some(hash) = stack.pop() {
+        if excluded.contains(&hash) || commits.contains_key(&hash) {
+            continue;
+        }
+        let commit = Commit::load(repo_path, hash);
+        stack.extend(commit.parents.iter());
+        commits.insert(hash, *commit);
+    }
+    let dates: HashMap<Hash, i64> = commits
+        .iter()
+        .map(|(hash, commit)| (*hash, commit.committer.1.timestamp()))
+        .collect();
+
+    // Topological orders wait for all the children of a commit to be shown
+    let mut children: HashMap<Hash, usize> = HashMap::new();
+    if order != Order::Date {
+        for commit in commits.values() {
+            for parent in commit.parents.iter().filter(|p| dates.contains_key(p)) {
+                *children.entry(*parent).or_default() += 1;
+            }
+        }
+    }
+
+    // Commits that can be shown next
+    let mut ready = vec![];
+    for hash in include {
+        if dates.contains_key(hash) && !ready.contains(hash) && !children.contains_key(hash) {
+            ready.push(*hash);
+        }
+    }
+    if order == Order::Topo {
+        ready.reverse();
+    }
+
+    let mut shown = HashSet::new();
+    let mut res = vec![];
+    while !ready.is_empty() {
+        let index = match order {
+            Order::Topo => ready.len() - 1,
+            _ => {
+                // The first most recent one
+                let mut best = 0;
+                for (i, hash) in ready.iter().enumerate() {
+                    if dates[hash] > dates[&ready[best]] {
+                        best = i;
+                    }
+                }
+                best
+            }
+        };
+        let hash = ready.remove(index);
+        if !shown.insert(hash) {
+            continue;
+        }
+        let commit = commits.remove(&hash).unwrap();
+        for parent in commit.parents.iter() {
+            if !dates.contains_key(parent) || shown.contains(parent) {
+                continue;
+            }
+            match children.get_mut(parent) {
+                Some(count) => {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(*parent);
+                    }
                 }
+                None => ready.push(*parent),
             }
         }
-        Ok(())
+        res.push(commit);
+    }
+    res
+}
+
+/// Return the nearest ancestors of a commit that are shown, going through
+/// the parents of the hidden ones
+fn visible_parents(
+    hash: &Hash,
+    parents: &HashMap<Hash, Vec<Hash>>,
+    shown: &HashSet<Hash>,
+) -> Vec<Hash> {
+    let mut res = vec![];
+    let mut seen = HashSet::new();
+    let mut stack: Vec<Hash> = parents[hash].iter().rev().cloned().collect();
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        if shown.contains(&hash) {
+            res.push(hash);
+        } else if let Some(parents) = parents.get(&hash) {
+            stack.extend(parents.iter().rev());
+        }
+    }
+    res
+}
+
+/// ASCII drawing of the history shown on the left of the commits
+#[derive(Default)]
+struct Graph {
+    /// The commit expected in each column
+    columns: Vec<Hash>,
+}
+
+impl Graph {
+    /// Draw the row of a commit followed by the rows linking its column to
+    /// the columns of its parents. Also return the row to draw next to the
+    /// remaining lines of the commit and the width (in columns) of the graph.
+    fn next(&mut self, hash: Hash, parents: &[Hash]) -> (Vec<String>, String, usize) {
+        let index = match self.columns.iter().position(|h| *h == hash) {
+            Some(index) => index,
+            None => {
+                self.columns.push(hash);
+                self.columns.len() - 1
+            }
+        };
+
+        // Replace the commit by its parents, each column then moves to the
+        // first column expecting the same commit
+        let mut expanded = vec![];
+        for (i, column) in self.columns.iter().enumerate() {
+            if i == index {
+                expanded.extend(parents.iter().map(|parent| (index, *parent)));
+            } else {
+                expanded.push((i, *column));
+            }
+        }
+        let mut columns: Vec<Hash> = vec![];
+        for (_, hash) in expanded.iter() {
+            if !columns.contains(hash) {
+                columns.push(*hash);
+            }
+        }
+        let mut edges: Vec<(usize, usize)> = expanded
+            .iter()
+            .map(|(pos, hash)| (*pos, columns.iter().position(|h| h == hash).unwrap()))
+            .collect();
+
+        let width = self.columns.len().max(columns.len());
+        let mut commit_row = vec![' '; 2 * width];
+        for i in 0..self.columns.len() {
+            commit_row[2 * i] = if i == index { '*' } else { '|' };
+        }
+        let mut rows = vec![commit_row.into_iter().collect::<String>()];
+        while edges.iter().any(|(pos, target)| pos != target) {
+            let mut row = vec![' '; 2 * width];
+            for (pos, target) in edges.iter_mut() {
+                if *pos < *target {
+                    row[2 * *pos + 1] = '\\';
+                    *pos += 1;
+                } else if *pos > *target {
+                    row[2 * *pos - 1] = '/';
+                    *pos -= 1;
+                } else {
+                    row[2 * *pos] = '|';
+                }
+            }
+            rows.push(row.into_iter().collect());
+        }
+        let filler = vec!["|"; columns.len()].join(" ");
+        self.columns = columns;
+        (rows, filler, width)
+    }
+}
+
+/// Indent a message like git does in its default formats
+fn indent(message: &str) -> String {
+    message
+        .trim_end()
+        .lines()
+        .map(|line| format!("    {}\n", line))
+        .collect()
+}
+
+/// The preset pretty formats
+const PRESETS: [&str; 5] = ["oneline", "short", "medium", "full", "fuller"];
+
+/// Check a pretty format given by the user like git: the name of a preset
+/// (or a prefix, the shortest matching preset wins), `format:<string>`,
+/// `tformat:<string>` or a string with placeholders.
+pub fn parse_format(format: &str) -> io::Result<&str> {
+    if format.is_empty()
+        || format.contains('%')
+        || format.starts_with("format:")
+        || format.starts_with("tformat:")
+    {
+        return Ok(format);
+    }
+    PRESETS
+        .iter()
+        .filter(|preset| preset.starts_with(format))
+        .min_by_key(|preset| preset.len())
+        .copied()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fatal: invalid --pretty format: {}", format),
+            )
+        })
+}
+
+/// Format a commit given a pretty format: a preset (`oneline`, `short`,
+/// `medium`, `full`, `fuller`), `format:<string>`, `tformat:<string>` or a
+/// string with placeholders.
+pub fn pretty(commit: &Commit, format: &str, refs: &[String]) -> String {
+    let header = || {
+        let hash = format!("commit {}", commit.hash()).yellow();
+        format!("{}{}", hash, decoration(refs))
+    };
+    match format {
+        "oneline" => commit.format_decorated("%C(yellow)%H%Creset%d %s\n", refs),
+        "short" => format!(
+            "{}\n{}\n\n{}",
+            header(),
+            commit.format("Author: %an <%ae>"),
+            indent(&commit.subject())
+        ),
+        "medium" => commit.medium(refs),
+        "full" => format!(
+            "{}\n{}\n\n{}",
+            header(),
+            commit.format("Author: %an <%ae>\nCommit: %cn <%ce>"),
+            indent(&commit.message)
+        ),
+        "fuller" => format!(
+            "{}\n{}\n\n{}",
+            header(),
+            commit.format(
+                "Author:     %an <%ae>\nAuthorDate: %ad\nCommit:     %cn <%ce>\nCommitDate: %cd"
+            ),
+            indent(&commit.message)
+        ),
+        format => {
+            if let Some(format) = format.strip_prefix("format:") {
+                commit.format_decorated(format, refs)
+            } else {
+                let format = format.strip_prefix("tformat:").unwrap_or(format);
+                format!("{}\n", commit.format_decorated(format, refs))
+            }
+        }
+    }
+}
+
+fn no_commit() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "fatal: your current branch does not have any commits yet",
+    )
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+
+    let revisions = args.values_of("REVISION").into_iter().flatten().collect();
+    let (include, excluded) = parse_revisions(&repo_path, revisions)?;
+    let filters = Filters::new(args)?;
+    let max_count = match args.value_of("max-count") {
+        Some(count) => Some(count.parse::<usize>()?),
+        None => None,
+    };
+    let format = match (args.value_of("format"), args.is_present("oneline")) {
+        (Some(format), _) => parse_format(format)?,
+        (None, true) => "%C(yellow)%h%Creset%d %s",
+        (None, false) => "medium",
+    };
+    let order = if args.is_present("topo-order") {
+        Order::Topo
+    } else if args.is_present("date-order") {
+        Order::DateTopo
+    } else if args.is_present("graph") {
+        Order::Topo
+    } else {
+        Order::Date
+    };
+    let decorations = if args.is_present("decorate") {
+        refs::decorations(&repo_path)
     } else {
-        // No commit found
-        Err(Box::new(io::Error::new(
-            io::ErrorKind::NotFound,
-            "fatal: your current branch does not have any commits yet",
-        )))
+        HashMap::new()
+    };
+
+    let commits = walk(&repo_path, &include, &excluded, order);
+    let parents: HashMap<Hash, Vec<Hash>> = commits
+        .iter()
+        .map(|commit| (commit.hash(), commit.parents.clone()))
+        .collect();
+    let mut commits: Vec<Commit> = commits
+        .into_iter()
+        .filter(|commit| filters.matches(&repo_path, commit))
+        .take(max_count.unwrap_or(usize::MAX))
+        .collect();
+    if args.is_present("reverse") {
+        commits.reverse();
+    }
+    let _pager = pager::start("log");
+    let shown: HashSet<Hash> = commits.iter().map(|commit| commit.hash()).collect();
+
+    // Commits are separated by a blank line in multi-line formats
+    let separator = match format {
+        "short" | "medium" | "full" | "fuller" => "\n",
+        // `format:` has separator semantics, unlike `tformat:`
+        format if format.starts_with("format:") => "\n",
+        _ => "",
+    };
+    let mut graph = Graph::default();
+    for (i, commit) in commits.iter().enumerate() {
+        let hash = commit.hash();
+        let refs = decorations.get(&hash).map(Vec::as_slice).unwrap_or(&[]);
+        let mut text = pretty(commit, format, refs);
+        if i + 1 < commits.len() {
+            text.push_str(separator);
+        }
+        if !args.is_present("graph") {
+            print!("{}", text);
+            continue;
+        }
+
+        let parents = visible_parents(&hash, &parents, &shown);
+        let (rows, filler, width) = graph.next(hash, &parents);
+        let lines: Vec<&str> = text.lines().collect();
+        for i in 0..rows.len().max(lines.len()) {
+            let row = rows.get(i).unwrap_or(&filler);
+            match lines.get(i) {
+                Some(line) if !line.is_empty() => {
+                    println!("{:<width$}{}", row, line, width = 2 * width)
+                }
+                _ => println!("{}", row.trim_end()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn hash(n: u8) -> Hash {
+        Hash::from_str(&format!("{:040x}", n)).unwrap()
+    }
+
+    #[test]
+    fn graph_merge() {
+        let (merge, a, b, base) = (hash(1), hash(2), hash(3), hash(4));
+        let mut graph = Graph::default();
+        let mut lines = vec![];
+        for (commit, parents) in [(merge, vec![a, b]), (b, vec![base]), (a, vec![base])].iter() {
+            let (rows, _, width) = graph.next(*commit, parents);
+            lines.extend(rows.iter().map(|row| format!("{:<w$}", row, w = 2 * width)));
+        }
+        assert_eq!(lines, vec!["*   ", "|\\  ", "| * ", "* | ", "|/  "]);
+        let (rows, filler, _) = graph.next(base, &[]);
+        assert_eq!(rows, vec!["* "]);
+        assert_eq!(filler, "");
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(parse_format("oneline").unwrap(), "oneline");
+        assert_eq!(parse_format("s").unwrap(), "short");
+        assert_eq!(parse_format("full").unwrap(), "full");
+        assert_eq!(parse_format("fulle").unwrap(), "fuller");
+        assert_eq!(parse_format("%h %s").unwrap(), "%h %s");
+        assert_eq!(parse_format("format:x").unwrap(), "format:x");
+        assert_eq!(parse_format("tformat:x").unwrap(), "tformat:x");
+        let err = parse_format("foo").unwrap_err();
+        assert_eq!(err.to_string(), "fatal: invalid --pretty format: foo");
+        assert!(parse_format("shorter").is_err());
     }
 }
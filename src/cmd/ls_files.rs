@@ -0,0 +1,276 @@
+use crate::attributes::Attributes;
+use crate::cmd::status::{self, Status, Worktree};
+use crate::ignore::Ignore;
+use crate::index::{self, EntryType, Index};
+use crate::objects::{Hash, Tree};
+use crate::pathspec::Pathspec;
+use crate::{parallel, utils};
+use clap::ArgMatches;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// List the untracked files matching the pathspec, relative to the current
+/// directory. Files ignored by `ignored` are left out, the others are all
+/// listed.
+fn others(
+    root: &Path,
+    pathspec: &Pathspec,
+    index: &Index,
+    ignored: &Ignore,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut found = Default::default();
+    status::compute_untracked(
+        &mut found,
+        root,
+        pathspec,
+        &Tree::new(),
+        index,
+        ignored,
+        None,
+    )?;
+
+    // Untracked directories are listed as a whole, look inside them
+    let paths = found.into_iter().filter_map(|status| match status {
+        Status::Untracked(path) => Some(path),
+        _ => None,
+    });
+    status::untracked_files(paths, pathspec, ignored)
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    ls_files(args, &mut io::stdout().lock())
+}
+
+/// List the files selected by the arguments on `out`
+fn ls_files(args: &ArgMatches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let index = Index::load(&repo_path)?;
+    let stage = args.is_present("stage");
+    let modified = args.is_present("modified");
+    let deleted = args.is_present("deleted");
+    let show_others = args.is_present("others");
+    let only_ignored = args.is_present("ignored");
+    let cached = args.is_present("cached") || stage;
+    let show_index = cached || !(modified || deleted || show_others);
+    let term = if args.is_present("z") { '\0' } else { '\n' };
+    let lowercase = args.is_present("tags-unchanged");
+    let tags = args.is_present("tags") || lowercase;
+
+    if only_ignored && !cached && !show_others {
+        return Err(Box::new(ErrorLsFiles::IgnoredWithoutListing));
+    }
+    let ignored = match args.is_present("exclude-standard") {
+        true => utils::ignored(&root)?,
+        false if only_ignored => return Err(Box::new(ErrorLsFiles::IgnoredWithoutExclude)),
+        false => Ignore::empty(&root),
+    };
+
+    // Without pathspec, only the files under the current directory are listed
+    let specs = args.values_of("PATHSPEC");
+    let pathspec = Pathspec::new(specs.map_or(vec!["."], Iterator::collect))?;
+
+    if show_others {
+        // Ignored files are searched for among all the untracked files
+        let empty = Ignore::empty(&root);
+        let rules = if only_ignored { &empty } else { &ignored };
+        for path in others(&root, &pathspec, &index, rules)? {
+            let in_repo = utils::path_in_repo(&path)?;
+            if only_ignored && !ignored.is_ignored(&in_repo, false) {
+                continue;
+            }
+            let tag = if tags { "? " } else { "" };
+            write!(out, "{}{}{}", tag, path, term)?;
+        }
+    }
+
+    let entries: Vec<(&String, &(EntryType, Hash))> = index
+        .entries
+        .iter()
+        .filter(|(name, _)| pathspec.matches(name, false))
+        .filter(|(name, _)| !only_ignored || ignored.is_ignored(Path::new(name), false))
+        .collect();
+    let worktree = match modified || deleted {
+        true => {
+            let attributes = Attributes::load(&root);
+            parallel::map(parallel::threads(), &entries, |(name, entry)| {
+                status::check_worktree(&index, &attributes, name, entry, &root.join(name))
+            })
+        }
+        false => vec![],
+    };
+
+    // With -t, lines start with the status of the file: cached, skipping
+    // the working tree, removed or changed. With -v, the tag is lowercase
+    // for files assumed unchanged.
+    let show = |out: &mut dyn Write, name: &str, entry: &(EntryType, Hash), tag: char| {
+        let path = utils::find_relative_path(&root.join(name));
+        let flags = index.flags(name);
+        let tag = match (tags, flags.skip_worktree) {
+            (false, _) => String::new(),
+            (true, true) => String::from("S "),
+            (true, false) if lowercase && flags.assume_unchanged => {
+                format!("{} ", tag.to_ascii_lowercase())
+            }
+            (true, false) => format!("{} ", tag),
+        };
+        match stage {
+            true => {
+                let mode = index::mode(&entry.0);
+                write!(
+                    out,
+                    "{}{:06o} {} 0\t{}{}",
+                    tag,
+                    mode,
+                    entry.1,
+                    path.display(),
+                    term
+                )
+            }
+            false => write!(out, "{}{}{}", tag, path.display(), term),
+        }
+    };
+    let mut worktree = worktree.into_iter();
+    for (name, entry) in entries {
+        if show_index {
+            show(out, name, entry, 'H')?;
+        }
+        // Like git, deleted files are modified too
+        match worktree.next().transpose()? {
+            Some(Worktree::Deleted) => {
+                if deleted {
+                    show(out, name, entry, 'R')?;
+                }
+                if modified {
+                    show(out, name, entry, 'C')?;
+                }
+            }
+            Some(Worktree::Modified) if modified => show(out, name, entry, 'C')?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorLsFiles {
+    IgnoredWithoutExclude,
+    IgnoredWithoutListing,
+}
+
+impl fmt::Display for ErrorLsFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorLsFiles::IgnoredWithoutExclude => {
+                write!(f, "fatal: ls-files --ignored needs some exclude pattern")
+            }
+            ErrorLsFiles::IgnoredWithoutListing => {
+                write!(f, "fatal: ls-files -i must be used with either -o or -c")
+            }
+        }
+    }
+}
+
+impl Error for ErrorLsFiles {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Flags;
+    use crate::objects::{Blob, Object};
+    use crate::utils::tests::{in_repo, subcommand};
+    use std::fs;
+
+    /// The output of `ls-files` with the given options
+    fn ls_files_with(options: &[&str]) -> Result<String, Box<dyn Error>> {
+        let mut out = vec![];
+        ls_files(&subcommand(&[&["ls-files"], options].concat()), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn filters() {
+        in_repo("ls_files", |root| {
+            let repo_path = root.join(".my_git");
+            let blob = |content: &str| {
+                let blob = Blob::new(content.into());
+                blob.save(&repo_path);
+                blob.hash()
+            };
+            let (v1, ignore) = (blob("v1\n"), blob("*.log\n"));
+
+            let mut index = Index::load_text(b"");
+            let entry = (EntryType::File, ignore);
+            index.entries.insert(String::from(".my_gitignore"), entry);
+            for path in ["del", "kept", "mod", "skip", "t.log", "unch"].iter() {
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, v1));
+            }
+            let skip = Flags {
+                skip_worktree: true,
+                ..Default::default()
+            };
+            index.flags.insert(String::from("skip"), skip);
+            let unchanged = Flags {
+                assume_unchanged: true,
+                ..Default::default()
+            };
+            index.flags.insert(String::from("unch"), unchanged);
+            index.save(&repo_path);
+            for (path, content) in [
+                (".my_gitignore", "*.log\n"),
+                ("kept", "v1\n"),
+                ("mod", "v2\n"),
+                ("t.log", "v1\n"),
+                ("unch", "v1\n"),
+                ("u", ""),
+                ("u.log", ""),
+            ]
+            .iter()
+            {
+                fs::write(root.join(path), content).unwrap();
+            }
+
+            let cached = ".my_gitignore\ndel\nkept\nmod\nskip\nt.log\nunch\n";
+            assert_eq!(ls_files_with(&[]).unwrap(), cached);
+            assert_eq!(ls_files_with(&["-c"]).unwrap(), cached);
+            // Deleted files are modified too
+            assert_eq!(ls_files_with(&["-m"]).unwrap(), "del\nmod\n");
+            assert_eq!(ls_files_with(&["-d"]).unwrap(), "del\n");
+            assert_eq!(ls_files_with(&["-o"]).unwrap(), "u\nu.log\n");
+            assert_eq!(ls_files_with(&["-o", "--exclude-standard"]).unwrap(), "u\n");
+            assert_eq!(
+                ls_files_with(&["-o", "-i", "--exclude-standard"]).unwrap(),
+                "u.log\n"
+            );
+            assert_eq!(
+                ls_files_with(&["-c", "-i", "--exclude-standard"]).unwrap(),
+                "t.log\n"
+            );
+            assert_eq!(
+                ls_files_with(&["-s", "--", "mod"]).unwrap(),
+                format!("100644 {} 0\tmod\n", v1)
+            );
+            assert_eq!(
+                ls_files_with(&["-t", "-c", "-d", "-m"]).unwrap(),
+                "H .my_gitignore\nH del\nR del\nC del\nH kept\nH mod\nC mod\nS skip\n\
+                 H t.log\nH unch\n"
+            );
+            assert_eq!(ls_files_with(&["-v", "--", "unch"]).unwrap(), "h unch\n");
+
+            let err = ls_files_with(&["-i"]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "fatal: ls-files -i must be used with either -o or -c"
+            );
+            let err = ls_files_with(&["-o", "-i"]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "fatal: ls-files --ignored needs some exclude pattern"
+            );
+        });
+    }
+}
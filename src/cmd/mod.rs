@@ -1,8 +1,20 @@
 pub mod add;
+pub mod archive;
+pub mod bisect;
+pub mod blame;
 pub mod branch;
+pub mod check_ignore;
+pub mod clean;
 pub mod commit;
 pub mod config;
+pub mod grep;
 pub mod init;
 pub mod log;
+pub mod ls_files;
+pub mod reset;
+pub mod restore;
+pub mod show;
+pub mod sparse_checkout;
 pub mod status;
 pub mod switch;
+pub mod update_index;
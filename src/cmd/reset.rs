@@ -0,0 +1,159 @@
+use crate::attributes::Attributes;
+use crate::cmd::status::{self, Worktree};
+use crate::index::{EntryType, Index};
+use crate::objects::{Blob, Commit, Hash, Object, Tree};
+use crate::patch::{self, Mode, Outcome};
+use crate::pathspec::Pathspec;
+use crate::{refs, utils};
+use clap::ArgMatches;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Return the files of the tree of a commit. HEAD is an empty tree until the
+/// first commit.
+pub(crate) fn commit_files(
+    repo_path: &PathBuf,
+    rev: &str,
+) -> Result<BTreeMap<String, (EntryType, Hash)>, Box<dyn Error>> {
+    if rev == "HEAD" && refs::get_head(repo_path).is_none() {
+        return Ok(BTreeMap::new());
+    }
+    let commit = Commit::load(repo_path, refs::rev_parse(repo_path, rev)?);
+    Ok(Tree::load(repo_path, commit.tree).files())
+}
+
+/// Set the entries of the index matching the pathspec to the given files
+pub(crate) fn reset_entries(
+    index: &mut Index,
+    files: &BTreeMap<String, (EntryType, Hash)>,
+    pathspec: &Pathspec,
+) {
+    let paths: BTreeSet<String> = files.keys().chain(index.entries.keys()).cloned().collect();
+    for path in paths.iter().filter(|path| pathspec.matches(path, false)) {
+        match files.get(path) {
+            Some(entry) if index.entries.get(path) != Some(entry) => {
+                index.entries.insert(path.clone(), entry.clone());
+                index.clear_intent_to_add(path);
+                // The stat data were those of the file of the previous entry
+                index.stats.remove(path);
+            }
+            Some(_) => (),
+            None => {
+                index.entries.remove(path);
+                index.flags.remove(path);
+            }
+        }
+    }
+}
+
+/// Interactively unstage the hunks of the changes between the given files
+/// and the index
+pub(crate) fn unstage_patch(
+    index: &mut Index,
+    repo_path: &Path,
+    files: &BTreeMap<String, (EntryType, Hash)>,
+    pathspec: &Pathspec,
+) -> Result<(), Box<dyn Error>> {
+    let paths: BTreeSet<String> = files.keys().chain(index.entries.keys()).cloned().collect();
+    let changed: Vec<String> = paths
+        .into_iter()
+        .filter(|path| pathspec.matches(path, false))
+        .filter(|path| files.get(path) != index.entries.get(path))
+        .collect();
+    if changed.is_empty() {
+        println!("No changes.");
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    for path in changed {
+        let content = |entry: Option<&(EntryType, Hash)>| {
+            entry.map(|(_, hash)| Blob::load(repo_path, *hash).data)
+        };
+        let old = content(files.get(&path));
+        let new = content(index.entries.get(&path));
+        let sides = (old.as_deref(), new.as_deref());
+        let (outcome, quit) = patch::select(&mut input, repo_path, &path, sides, Mode::Unstage)?;
+        match outcome {
+            Outcome::Content(data) => {
+                let entry_type = match index.entries.get(&path).or(files.get(&path)) {
+                    Some((entry_type, _)) => entry_type.clone(),
+                    None => EntryType::File,
+                };
+                let blob = Blob::new(data);
+                blob.save(repo_path);
+                index
+                    .entries
+                    .insert(path.clone(), (entry_type, blob.hash()));
+                index.clear_intent_to_add(&path);
+                index.stats.remove(&path);
+            }
+            Outcome::Deleted => {
+                index.entries.remove(&path);
+                index.flags.remove(&path);
+            }
+            Outcome::Unchanged => (),
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Print the files of the index which differ from the working tree
+fn unstaged(index: &Index, root: &Path, attributes: &Attributes) -> io::Result<()> {
+    let mut header = false;
+    for (name, entry) in index.entries.iter() {
+        let status = match status::check_worktree(index, attributes, name, entry, &root.join(name))?
+        {
+            Worktree::Modified => 'M',
+            Worktree::Deleted => 'D',
+            _ => continue,
+        };
+        if !header {
+            println!("Unstaged changes after reset:");
+            header = true;
+        }
+        println!("{}\t{}", status, name);
+    }
+    Ok(())
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let mut index = Index::load(&repo_path)?;
+    let mut rev = args.value_of("COMMIT").unwrap_or("HEAD");
+    let mut specs: Option<Vec<&str>> = args.values_of("PATHSPEC").map(Iterator::collect);
+
+    // Like git, an argument which isn't a revision is a path
+    let unknown =
+        |rev| matches!(refs::rev_parse(&repo_path, rev), Err(e) if e.kind() == ErrorKind::NotFound);
+    if specs.is_none() && rev != "HEAD" && unknown(rev) {
+        specs = Some(vec![rev]);
+        rev = "HEAD";
+    }
+    let files = commit_files(&repo_path, rev)?;
+    let pathspec = Pathspec::new(specs.clone().into_iter().flatten())?;
+    if args.is_present("patch") {
+        unstage_patch(&mut index, &repo_path, &files, &pathspec)?;
+        index.save(&repo_path);
+        return Ok(());
+    }
+    reset_entries(&mut index, &files, &pathspec);
+    index.save(&repo_path);
+
+    // Without pathspec the current branch is moved to the commit
+    if specs.is_none() && rev != "HEAD" {
+        let hash = refs::rev_parse(&repo_path, rev)?;
+        refs::update(&repo_path, &"HEAD".into(), &hash.to_string(), true)?;
+    }
+    if !args.is_present("quiet") {
+        unstaged(&index, &root, &Attributes::load(&root))?;
+    }
+    Ok(())
+}
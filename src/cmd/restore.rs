@@ -0,0 +1,206 @@
+use crate::attributes::Attributes;
+use crate::cmd::reset;
+use crate::cmd::status::{self, Worktree};
+use crate::index::{EntryType, Index};
+use crate::objects::{Blob, Hash, Object};
+use crate::patch::{self, Mode, Outcome};
+use crate::pathspec::Pathspec;
+use crate::utils;
+use clap::ArgMatches;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Replace a file of the working tree by a blob, or remove it
+fn write_file(
+    full_path: &PathBuf,
+    content: Option<(&Blob, &EntryType)>,
+    attributes: &Attributes,
+) -> io::Result<()> {
+    if fs::symlink_metadata(full_path).is_ok_and(|metadata| !metadata.is_dir()) {
+        fs::remove_file(full_path)?;
+    }
+    match content {
+        Some((blob, entry_type)) => {
+            fs::create_dir_all(full_path.parent().unwrap())?;
+            blob.to_worktree(full_path, entry_type, attributes)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Restore the files of the working tree matching the pathspec from the
+/// given files. Tracked files missing from them are removed.
+fn restore_worktree(
+    index: &mut Index,
+    repo_path: &Path,
+    root: &Path,
+    files: &BTreeMap<String, (EntryType, Hash)>,
+    pathspec: &Pathspec,
+    attributes: &Attributes,
+) -> Result<(), Box<dyn Error>> {
+    let paths: BTreeSet<String> = files.keys().chain(index.entries.keys()).cloned().collect();
+    for path in paths.iter().filter(|path| pathspec.matches(path, false)) {
+        let full_path = root.join(path);
+        let entry = match files.get(path) {
+            Some(entry) => entry,
+            None => {
+                write_file(&full_path, None, attributes)?;
+                continue;
+            }
+        };
+        let from_index = index.entries.get(path) == Some(entry);
+        if from_index {
+            match status::check_worktree(index, attributes, path, entry, &full_path)? {
+                Worktree::Unchanged => continue,
+                Worktree::Refresh(metadata) => {
+                    index.refresh(path, &metadata);
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        let blob = Blob::load(repo_path, entry.1);
+        write_file(&full_path, Some((&blob, &entry.0)), attributes)?;
+        if from_index {
+            index.update_entry(&full_path, &blob)?;
+        }
+    }
+    Ok(())
+}
+
+/// Interactively discard the hunks of the changes between the given files
+/// and the working tree
+fn discard_patch(
+    index: &Index,
+    repo_path: &Path,
+    root: &Path,
+    files: &BTreeMap<String, (EntryType, Hash)>,
+    pathspec: &Pathspec,
+    attributes: &Attributes,
+) -> Result<(), Box<dyn Error>> {
+    let paths: BTreeSet<&String> = files.keys().chain(index.entries.keys()).collect();
+    let mut changed = vec![];
+    for path in paths
+        .into_iter()
+        .filter(|path| pathspec.matches(path, false))
+    {
+        let full_path = root.join(path);
+        let old = files
+            .get(path)
+            .map(|(_, hash)| Blob::load(repo_path, *hash).data);
+        let new = match fs::symlink_metadata(&full_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                Some(Blob::from_worktree(&full_path, attributes)?.data)
+            }
+            _ => None,
+        };
+        if old != new {
+            changed.push((path, full_path, old, new));
+        }
+    }
+    if changed.is_empty() {
+        println!("No changes.");
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    for (path, full_path, old, new) in changed {
+        let sides = (old.as_deref(), new.as_deref());
+        let (outcome, quit) = patch::select(&mut input, repo_path, path, sides, Mode::Discard)?;
+        match outcome {
+            Outcome::Content(data) => {
+                let entry_type = match files.get(path) {
+                    Some((entry_type, _)) => entry_type.clone(),
+                    None => Index::get_file_type(&full_path),
+                };
+                write_file(
+                    &full_path,
+                    Some((&Blob::new(data), &entry_type)),
+                    attributes,
+                )?;
+            }
+            Outcome::Deleted => write_file(&full_path, None, attributes)?,
+            Outcome::Unchanged => (),
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let mut index = Index::load(&repo_path)?;
+    let attributes = Attributes::load(&root);
+    let staged = args.is_present("staged");
+    let worktree = args.is_present("worktree") || !staged;
+    let patch = args.is_present("patch");
+
+    let specs = args.values_of("PATHSPEC");
+    if specs.is_none() && !patch {
+        return Err(Box::new(ErrorRestore::NoPaths));
+    }
+    if patch && staged && worktree {
+        return Err(Box::new(ErrorRestore::PatchBoth));
+    }
+    let pathspec = Pathspec::new(specs.into_iter().flatten())?;
+
+    // The index is restored from HEAD and the working tree from the index
+    // unless another source is given
+    let files = match args.value_of("source") {
+        Some(rev) => reset::commit_files(&repo_path, rev)?,
+        None if staged => reset::commit_files(&repo_path, "HEAD")?,
+        None => index.entries.clone(),
+    };
+    let known = files.keys().chain(index.entries.keys());
+    pathspec.check_matched(known.map(String::as_str))?;
+
+    if staged {
+        match patch {
+            true => reset::unstage_patch(&mut index, &repo_path, &files, &pathspec)?,
+            false => reset::reset_entries(&mut index, &files, &pathspec),
+        }
+    }
+    if worktree {
+        match patch {
+            true => discard_patch(&index, &repo_path, &root, &files, &pathspec, &attributes)?,
+            false => restore_worktree(
+                &mut index,
+                &repo_path,
+                &root,
+                &files,
+                &pathspec,
+                &attributes,
+            )?,
+        }
+    }
+    index.save(&repo_path);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorRestore {
+    NoPaths,
+    PatchBoth,
+}
+
+impl fmt::Display for ErrorRestore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorRestore::NoPaths => write!(f, "fatal: you must specify path(s) to restore"),
+            ErrorRestore::PatchBoth => write!(
+                f,
+                "fatal: --patch can't restore both the index and the working tree"
+            ),
+        }
+    }
+}
+
+impl Error for ErrorRestore {}
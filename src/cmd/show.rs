@@ -0,0 +1,190 @@
+use crate::attributes::Attributes;
+use crate::cmd::log;
+use crate::diff;
+use crate::objects::{self, Blob, Commit, Hash, Object, Tag, Tree, TreeEntry};
+use crate::{pager, refs, utils};
+use clap::ArgMatches;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What is printed after the header of a commit
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Patch,
+    Stat,
+    NameOnly,
+    Nothing,
+}
+
+/// How commits are printed
+struct Options<'a> {
+    format: &'a str,
+    output: Output,
+    /// Whether a blank line separates the header from the changes
+    separate: bool,
+}
+
+/// Print a commit followed by the changes it introduces compared to its
+/// first parent (or a combined diff against all its parents for a merge)
+fn show_commit(repo_path: &Path, commit: &Commit, options: &Options) -> Result<(), Box<dyn Error>> {
+    print!("{}", log::pretty(commit, options.format, &[]));
+    let output = options.output;
+
+    let tree = Tree::load(repo_path, commit.tree).files();
+    let attributes = Attributes::load(&utils::find_root()?);
+    let parents: Vec<_> = commit
+        .parents
+        .iter()
+        .map(|parent| {
+            let parent = Commit::load(repo_path, *parent);
+            Tree::load(repo_path, parent.tree).files()
+        })
+        .collect();
+    let mut res = String::new();
+    if parents.len() > 1 && output == Output::Patch {
+        for (path, entry) in tree.iter() {
+            let entries: Vec<_> = parents.iter().map(|p| p.get(path).cloned()).collect();
+            if entries.iter().all(|e| e.as_ref() != Some(entry)) {
+                res.push_str(&diff::combined(
+                    repo_path,
+                    &attributes,
+                    path,
+                    &entries,
+                    &Some(entry.clone()),
+                ));
+            }
+        }
+    } else {
+        let empty = Default::default();
+        let changes = diff::changes(parents.first().unwrap_or(&empty), &tree);
+        res = match output {
+            Output::Patch => changes
+                .iter()
+                .map(|change| diff::patch(repo_path, &attributes, change))
+                .collect(),
+            Output::Stat if !changes.is_empty() => diff::stat(repo_path, &attributes, &changes),
+            Output::NameOnly => changes
+                .iter()
+                .map(|change| format!("{}\n", change.path))
+                .collect(),
+            _ => String::new(),
+        };
+    }
+    if output == Output::Nothing || res.is_empty() {
+        return Ok(());
+    }
+
+    if options.separate {
+        println!();
+    }
+    print!("{}", res);
+    Ok(())
+}
+
+/// Print the entries of a tree, directories end with a slash
+fn show_tree(rev: &str, tree: &Tree) {
+    println!("tree {}\n", rev);
+    for (name, entry) in tree.entries.iter() {
+        match entry {
+            TreeEntry::Directory(_) => println!("{}/", name),
+            _ => println!("{}", name),
+        }
+    }
+}
+
+/// Print the header of an annotated tag
+fn show_tag(tag: &Tag) {
+    println!("tag {}", tag.name);
+    if let Some((user, date)) = &tag.tagger {
+        println!("Tagger: {} <{}>", user.name, user.email);
+        println!("Date:   {}", date.format("%a %b %e %T %Y %z"));
+    }
+    println!("\n{}", tag.message.trim_end());
+    println!();
+}
+
+fn show(
+    repo_path: &PathBuf,
+    rev: &str,
+    hash: Hash,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    match objects::object_type(repo_path, hash).as_deref() {
+        Some("commit") => show_commit(repo_path, &Commit::load(repo_path, hash), options),
+        Some("tree") => {
+            show_tree(rev, &Tree::load(repo_path, hash));
+            Ok(())
+        }
+        Some("blob") => {
+            io::stdout().write_all(&Blob::load(repo_path, hash).data)?;
+            Ok(())
+        }
+        Some("tag") => {
+            let tag = Tag::load(repo_path, hash);
+            show_tag(&tag);
+            show(repo_path, rev, tag.object, options)
+        }
+        _ => Err(Box::new(ErrorShow::BadObject(rev.to_string()))),
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let repo_path = utils::find_repo()?;
+    let revs: Vec<&str> = match args.values_of("OBJECT") {
+        Some(revs) => revs.collect(),
+        None => vec!["HEAD"],
+    };
+    let format = match (args.value_of("format"), args.is_present("oneline")) {
+        (Some(format), _) => log::parse_format(format)?,
+        (None, true) => "%C(yellow)%h%Creset %s",
+        (None, false) => "medium",
+    };
+    let output = if args.is_present("no-patch") {
+        Output::Nothing
+    } else if args.is_present("name-only") {
+        Output::NameOnly
+    } else if args.is_present("stat") {
+        Output::Stat
+    } else {
+        Output::Patch
+    };
+
+    // The changes are separated from the header unless it is on one line
+    let separate = !args.is_present("oneline") && format != "oneline";
+    let options = Options {
+        format,
+        output,
+        separate,
+    };
+
+    // Resolve every object before printing anything
+    let mut objects = vec![];
+    for rev in revs {
+        objects.push((rev, refs::resolve_object(&repo_path, rev)?));
+    }
+    let _pager = pager::start("show");
+    for (i, (rev, hash)) in objects.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        show(&repo_path, rev, hash, &options)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorShow {
+    BadObject(String),
+}
+
+impl fmt::Display for ErrorShow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorShow::BadObject(rev) => write!(f, "fatal: bad object {}", rev),
+        }
+    }
+}
+
+impl Error for ErrorShow {}
@@ -0,0 +1,201 @@
+use crate::attributes::Attributes;
+use crate::cmd::config::Config;
+use crate::cmd::status::{self, Worktree};
+use crate::index::{Flags, Index};
+use crate::objects::{Blob, Object};
+use crate::sparse::Sparse;
+use crate::utils;
+use clap::ArgMatches;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Set `core.sparseCheckout` in the local config
+fn enable(enabled: bool) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load_local()?;
+    config.core.sparse_checkout = Some(enabled.to_string());
+    config.dump_local()
+}
+
+/// Update the working tree to the sparse checkout of the index: the files
+/// which are now included are written, the excluded ones are removed unless
+/// they have local changes
+fn reapply(index: &mut Index, repo_path: &Path, root: &PathBuf) -> Result<(), Box<dyn Error>> {
+    let attributes = Attributes::load(root);
+    let mut kept = vec![];
+    for (path, entry) in index.entries.clone() {
+        let mut flags = index.flags(&path);
+        if flags.intent_to_add {
+            continue;
+        }
+        let included = index
+            .sparse
+            .as_ref()
+            .is_none_or(|sparse| sparse.includes(&path));
+        let full_path = root.join(&path);
+        if included && flags.skip_worktree {
+            // Files which were created meanwhile are kept
+            if fs::symlink_metadata(&full_path).is_err() {
+                fs::create_dir_all(full_path.parent().unwrap())?;
+                let blob = Blob::load(repo_path, entry.1);
+                blob.to_worktree(&full_path, &entry.0, &attributes)?;
+                index.update_entry(&full_path, &blob)?;
+            }
+            flags.skip_worktree = false;
+        } else if !included && !flags.skip_worktree {
+            match status::check_worktree(index, &attributes, &path, &entry, &full_path)? {
+                Worktree::Modified => {
+                    kept.push(path);
+                    continue;
+                }
+                Worktree::Deleted => (),
+                _ => {
+                    fs::remove_file(&full_path)?;
+                    // Remove the directories left empty
+                    let mut dir = full_path.parent();
+                    while let Some(parent) = dir.filter(|dir| *dir != root) {
+                        if fs::remove_dir(parent).is_err() {
+                            break;
+                        }
+                        dir = parent.parent();
+                    }
+                }
+            }
+            index.stats.remove(&path);
+            flags.skip_worktree = true;
+        } else {
+            continue;
+        }
+        match flags == Flags::default() {
+            true => index.flags.remove(&path),
+            false => index.flags.insert(path, flags),
+        };
+    }
+    if !kept.is_empty() {
+        eprintln!(
+            "warning: The following paths are not up to date and were left despite sparse patterns:"
+        );
+        for path in kept {
+            eprintln!("\t{}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Paths relative to the root of the directories given on the command line
+fn dirs(args: &ArgMatches) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut res = vec![];
+    for dir in args.values_of("DIR").into_iter().flatten() {
+        res.push(utils::path_in_repo(dir)?.to_str().unwrap().to_string());
+    }
+    Ok(res)
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let mut index = Index::load(&repo_path)?;
+
+    let sparse = match args.subcommand() {
+        ("init", Some(_)) => Some(Sparse::read(&repo_path)?),
+        ("set", Some(args)) => Some(Sparse::new(dirs(args)?.iter().map(String::as_str))),
+        ("add", Some(args)) => {
+            let sparse = index
+                .sparse
+                .as_ref()
+                .ok_or(ErrorSparseCheckout::NothingToAdd)?;
+            let mut all: Vec<String> = sparse.dirs().cloned().collect();
+            all.extend(dirs(args)?);
+            Some(Sparse::new(all.iter().map(String::as_str)))
+        }
+        ("list", Some(_)) => {
+            let sparse = index
+                .sparse
+                .as_ref()
+                .ok_or(ErrorSparseCheckout::NotSparse)?;
+            for dir in sparse.dirs() {
+                println!("{}", dir);
+            }
+            return Ok(());
+        }
+        ("disable", Some(_)) => None,
+        _ => unreachable!(),
+    };
+
+    if let Some(sparse) = &sparse {
+        sparse.save(&repo_path)?;
+    }
+    enable(sparse.is_some())?;
+    index.sparse = sparse;
+    reapply(&mut index, &repo_path, &root)?;
+    index.save(&repo_path);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorSparseCheckout {
+    NothingToAdd,
+    NotSparse,
+}
+
+impl fmt::Display for ErrorSparseCheckout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorSparseCheckout::NothingToAdd => write!(f, "fatal: no sparse-checkout to add to"),
+            ErrorSparseCheckout::NotSparse => write!(f, "fatal: this worktree is not sparse"),
+        }
+    }
+}
+
+impl Error for ErrorSparseCheckout {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::EntryType;
+    use crate::utils::tests::in_repo;
+
+    #[test]
+    fn reapplied() {
+        in_repo("sparse_checkout", |root| {
+            let repo_path = root.join(".my_git");
+            let mut index = Index::load_text(b"");
+            fs::create_dir(root.join("in")).unwrap();
+            fs::create_dir(root.join("out")).unwrap();
+            for path in ["root", "in/a", "out/b", "out/c"].iter() {
+                let blob = Blob::new(path.as_bytes().to_vec());
+                blob.save(&repo_path);
+                fs::write(root.join(path), &blob.data).unwrap();
+                let entry = (EntryType::File, blob.hash());
+                index.entries.insert(path.to_string(), entry);
+            }
+            fs::write(root.join("out/c"), "local").unwrap();
+
+            // Excluded files are removed unless they have local changes
+            index.sparse = Some(Sparse::new(vec!["in"]));
+            reapply(&mut index, &repo_path, &root.to_path_buf()).unwrap();
+            assert!(root.join("root").exists() && root.join("in/a").exists());
+            assert!(!root.join("out/b").exists() && root.join("out/c").exists());
+            assert!(index.flags("out/b").skip_worktree);
+            assert_eq!(index.flags("out/c"), Flags::default());
+            assert_eq!(index.flags.len(), 1);
+
+            // Files included again are checked out
+            fs::remove_file(root.join("out/c")).unwrap();
+            index.sparse = Some(Sparse::new(vec!["in", "out"]));
+            reapply(&mut index, &repo_path, &root.to_path_buf()).unwrap();
+            assert_eq!(fs::read(root.join("out/b")).unwrap(), b"out/b");
+            assert!(index.flags.is_empty());
+            assert!(index.stats.contains_key("out/b"));
+
+            // Without sparse checkout every file is checked out
+            index.sparse = Some(Sparse::new(vec!["in"]));
+            reapply(&mut index, &repo_path, &root.to_path_buf()).unwrap();
+            assert!(!root.join("out").exists());
+            index.sparse = None;
+            reapply(&mut index, &repo_path, &root.to_path_buf()).unwrap();
+            assert!(root.join("out/b").exists() && index.flags.is_empty());
+        });
+    }
+}
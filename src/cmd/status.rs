@@ -1,30 +1,40 @@
-use crate::index::{EntryType, Index};
-use crate::objects::{Blob, Object, Tree, TreeEntry};
+use crate::attributes::Attributes;
+use crate::fsmonitor;
+use crate::ignore::Ignore;
+use crate::index::{self, EntryType, Index};
+use crate::objects::{Blob, Commit, Hash, Object, Tree, TreeEntry};
+use crate::parallel;
+use crate::pathspec::Pathspec;
 use crate::refs;
+use crate::untracked::UntrackedCache;
 use crate::utils;
 use clap::ArgMatches;
 use colored::Colorize;
-use glob::glob;
-use glob::Pattern;
-use std::collections::BTreeSet;
+use path_abs::PathAbs;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Eq, PartialEq, PartialOrd, Ord)]
-enum Status {
+pub(crate) enum Status {
     New(String),
     ModifiedStaged(String),
     DeletedStaged(String),
     ModifiedNotStaged(String),
     DeletedNotStaged(String),
+    /// Added with `add -N`, the content isn't staged yet
+    IntentToAdd(String),
     Untracked(String),
+    /// Shown with `--ignored`
+    Ignored(String),
 }
 
 impl Status {
     /// Create a new Status given a type and a path
     pub fn new(type_: &str, path: &PathBuf) -> Self {
-        let mut path_str = utils::find_relative_path(&path)
+        let mut path_str = utils::find_relative_path(path)
             .to_str()
             .unwrap()
             .to_string();
@@ -37,58 +47,305 @@ impl Status {
             "modifiednotstaged" => Status::ModifiedNotStaged(path_str),
             "deletenotstaged" => Status::DeletedNotStaged(path_str),
             "deletestaged" => Status::DeletedStaged(path_str),
+            "intenttoadd" => Status::IntentToAdd(path_str),
             "untracked" => Status::Untracked(path_str),
-            _ => panic!(format!("fatal: type '{}' unknown", type_)),
+            "ignored" => Status::Ignored(path_str),
+            _ => panic!("fatal: type '{}' unknown", type_),
+        }
+    }
+
+    /// The path and the letters of the short format: the status in the
+    /// index and in the working tree, a space when unchanged
+    fn short(&self) -> (&str, char, char) {
+        match self {
+            Status::New(path) => (path, 'A', ' '),
+            Status::ModifiedStaged(path) => (path, 'M', ' '),
+            Status::DeletedStaged(path) => (path, 'D', ' '),
+            Status::ModifiedNotStaged(path) => (path, ' ', 'M'),
+            Status::DeletedNotStaged(path) => (path, ' ', 'D'),
+            Status::IntentToAdd(path) => (path, ' ', 'A'),
+            Status::Untracked(path) => (path, '?', '?'),
+            Status::Ignored(path) => (path, '!', '!'),
         }
     }
 }
 
-fn compute_untracked(
+/// Whether a directory is empty or contains a file which isn't ignored
+fn has_unignored(path: &PathBuf, ignored: &Ignore) -> io::Result<bool> {
+    let mut empty = true;
+    for entry in fs::read_dir(path)? {
+        let path = path.join(entry?.file_name());
+        empty = false;
+        if utils::is_ignored(&path, ignored)? {
+            continue;
+        }
+        if !fs::symlink_metadata(&path)?.is_dir() || has_unignored(&path, ignored)? {
+            return Ok(true);
+        }
+    }
+    Ok(empty)
+}
+
+/// Compute the untracked files and directories of a path matching the
+/// pathspec. The directories of each level are read on several threads. With
+/// the untracked cache, the directories which didn't change aren't read again.
+pub(crate) fn compute_untracked(
     status: &mut BTreeSet<Status>,
-    path: &PathBuf,
+    path: &Path,
+    pathspec: &Pathspec,
     last_commit: &Tree,
     index: &Index,
-    ignored: &Vec<Pattern>,
-) -> Result<(), Box<Error>> {
-    // TODO: Not that simple should check that it could be track (need gitignore feature)
-    if utils::is_ignored(path, ignored)? {
-        return Ok(());
+    ignored: &Ignore,
+    mut cache: Option<&mut UntrackedCache>,
+) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let threads = parallel::threads();
+    // Paths to look at, whether they are directories when they are known
+    // not to be ignored
+    let mut paths = vec![(path.to_path_buf(), None)];
+    while !paths.is_empty() {
+        let cached = cache.as_deref();
+        let found = parallel::map(threads, &paths, |(path, is_dir)| -> io::Result<_> {
+            let mut res = (None, vec![], None);
+            let is_dir = match is_dir {
+                Some(is_dir) => *is_dir,
+                None if utils::is_ignored(path, ignored)? => return Ok(res),
+                None => fs::symlink_metadata(path)?.is_dir(),
+            };
+            let full_path: Option<PathBuf> = PathAbs::new(path).ok().map(Into::into);
+            let entry_path = full_path
+                .as_ref()
+                .and_then(|p| p.strip_prefix(&root).ok())
+                .and_then(Path::to_str);
+            let tracked = match entry_path {
+                Some("") => true,
+                Some(entry_path) => index.contains_entry(entry_path),
+                None => false,
+            };
+            let name = entry_path.unwrap_or("");
+            // The areas excluded by a sparse checkout aren't looked at
+            if let Some(sparse) = &index.sparse {
+                if (is_dir && !sparse.may_contain(name)) || (!is_dir && !sparse.includes(name)) {
+                    return Ok(res);
+                }
+            }
+            let selected = pathspec.matches(name, is_dir);
+            if is_dir && !selected && !pathspec.may_contain(name) {
+                return Ok(res);
+            }
+            if !tracked && !last_commit.contains(path).unwrap_or(false) && selected {
+                // Directories whose content is all ignored are not shown
+                if !is_dir || has_unignored(path, ignored)? {
+                    res.0 = Some(Status::new("untracked", path));
+                }
+                return Ok(res);
+            }
+            if !is_dir {
+                return Ok(res);
+            }
+
+            // Look inside directories
+            let (cached, dir) = match (cached, entry_path) {
+                (Some(cached), Some(dir)) => (cached, dir),
+                _ => {
+                    for entry in fs::read_dir(path)? {
+                        res.1.push((path.join(entry?.file_name()), None));
+                    }
+                    return Ok(res);
+                }
+            };
+            // The directories which the file system monitor didn't report
+            // are unchanged
+            let rules = ignored.hash(Path::new(dir));
+            let metadata = match index.fsmonitor {
+                Some(_) => None,
+                None => Some(fs::symlink_metadata(path)?),
+            };
+            if let Some(entries) = cached.get(dir, &rules, metadata.as_ref()) {
+                for (name, is_dir) in entries {
+                    res.1.push((path.join(name), Some(*is_dir)));
+                }
+                return Ok(res);
+            }
+            let mut entries = vec![];
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let child = path.join(entry.file_name());
+                if !utils::is_ignored(&child, ignored)? {
+                    let is_dir = entry.file_type()?.is_dir();
+                    entries.push((entry.file_name().to_string_lossy().to_string(), is_dir));
+                    res.1.push((child, Some(is_dir)));
+                }
+            }
+            let metadata = match metadata {
+                Some(metadata) => metadata,
+                None => fs::symlink_metadata(path)?,
+            };
+            res.2 = Some((dir.to_string(), metadata, rules, entries));
+            Ok(res)
+        });
+        paths = vec![];
+        for res in found {
+            let (found, children, read) = res?;
+            status.extend(found);
+            paths.extend(children);
+            if let (Some(cache), Some((dir, metadata, rules, entries))) = (cache.as_mut(), read) {
+                cache.insert(dir, &metadata, rules, entries);
+            }
+        }
     }
-    if !index.contains(path)? && !last_commit.contains(path)? {
-        status.insert(Status::new("untracked", &path));
-        return Ok(());
+    Ok(())
+}
+
+/// List the files of untracked paths found by `compute_untracked`, relative
+/// to the current directory, looking inside the directories. Files ignored by
+/// `ignored` or not matching the pathspec are left out.
+pub(crate) fn untracked_files(
+    paths: impl IntoIterator<Item = String>,
+    pathspec: &Pathspec,
+    ignored: &Ignore,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut stack: Vec<String> = paths.into_iter().collect();
+    let mut res = vec![];
+    while let Some(path) = stack.pop() {
+        if !path.ends_with('/') {
+            res.push(path);
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let name = entry?.file_name();
+            let child = format!("{}{}", path, name.to_str().unwrap());
+            if utils::is_ignored(&PathBuf::from(&child), ignored)? {
+                continue;
+            }
+            let in_repo = utils::path_in_repo(&child)?;
+            match fs::symlink_metadata(&child)?.is_dir() {
+                true => stack.push(format!("{}/", child)),
+                false if pathspec.matches(in_repo.to_str().unwrap(), false) => res.push(child),
+                false => (),
+            }
+        }
     }
+    res.sort();
+    Ok(res)
+}
 
-    // If directory call recursively
-    if path.is_dir() {
-        for entry in fs::read_dir(path).unwrap() {
-            let file_name = entry.unwrap().file_name();
-            let path = path.join(&file_name);
-            compute_untracked(status, &path, last_commit, index, &ignored)?;
+/// Compute the ignored files and directories matching the pathspec which
+/// aren't tracked. Directories, including the untracked ones which only
+/// contain ignored files, are shown as a whole unless `all` is set, then the
+/// files inside them are listed.
+fn compute_ignored(
+    status: &mut BTreeSet<Status>,
+    root: &Path,
+    pathspec: &Pathspec,
+    index: &Index,
+    ignored: &Ignore,
+    all: bool,
+) -> io::Result<()> {
+    // Directories to read, whether they are ignored
+    let mut stack = vec![(String::new(), false)];
+    while let Some((dir, in_ignored)) = stack.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let name = format!("{}{}", dir, entry.file_name().to_string_lossy());
+            let is_dir = entry.file_type()?.is_dir();
+            if name == ".my_git" {
+                continue;
+            }
+            if let Some(sparse) = &index.sparse {
+                if (is_dir && !sparse.may_contain(&name)) || (!is_dir && !sparse.includes(&name)) {
+                    continue;
+                }
+            }
+            let selected = pathspec.matches(&name, is_dir);
+            let tracked = index.contains_entry(&name);
+            let mut is_ignored = in_ignored || ignored.is_ignored(Path::new(&name), is_dir);
+            // Untracked directories whose content is all ignored are ignored
+            if is_dir && !is_ignored && !tracked && !all && selected {
+                is_ignored = !has_unignored(&root.join(&name), ignored)?;
+            }
+            if is_dir && (tracked || !is_ignored || all || !selected) {
+                if selected || pathspec.may_contain(&name) {
+                    stack.push((format!("{}/", name), is_ignored));
+                }
+            } else if is_ignored && selected && !index.entries.contains_key(&name) {
+                status.insert(Status::new("ignored", &root.join(&name)));
+            }
         }
     }
     Ok(())
 }
 
+/// How the file of an index entry compares with it
+pub(crate) enum Worktree {
+    Unchanged,
+    /// Same content with different stat data, the entry can be refreshed
+    Refresh(fs::Metadata),
+    Modified,
+    Deleted,
+}
+
+/// Compare an entry of the index with its file, which is only hashed when
+/// its stat data changed
+pub(crate) fn check_worktree(
+    index: &Index,
+    attributes: &Attributes,
+    name: &str,
+    entry: &(EntryType, Hash),
+    full_path: &PathBuf,
+) -> io::Result<Worktree> {
+    // Files assumed unchanged or left out of the working tree aren't looked
+    // at
+    if index.flags(name).ignores_worktree() {
+        return Ok(Worktree::Unchanged);
+    }
+    // Files which the file system monitor didn't report are unchanged
+    let fsmonitor = index.fsmonitor.as_ref();
+    if fsmonitor.is_some_and(|fsmonitor| fsmonitor.is_valid(name, entry)) {
+        return Ok(Worktree::Unchanged);
+    }
+    let metadata = match fs::symlink_metadata(full_path) {
+        Ok(metadata) if !metadata.is_dir() => metadata,
+        _ => return Ok(Worktree::Deleted),
+    };
+    if Index::get_file_type(full_path) != entry.0 {
+        return Ok(Worktree::Modified);
+    }
+    if index.stat_matches(name, &metadata) {
+        return Ok(Worktree::Unchanged);
+    }
+    if Blob::from_worktree(full_path, attributes)?.hash() != entry.1 {
+        Ok(Worktree::Modified)
+    } else {
+        Ok(Worktree::Refresh(metadata))
+    }
+}
+
+/// Compute the status of the entries of the index. Files are only hashed
+/// when their stat data changed, the entries of unchanged files are
+/// refreshed. Return whether an entry was refreshed.
 fn compute_tracked(
     status: &mut BTreeSet<Status>,
-    path: &PathBuf,
+    pathspec: &Pathspec,
     last_commit: &Tree,
-    index: &Index,
-) -> Result<(), Box<Error>> {
+    index: &mut Index,
+) -> Result<bool, Box<dyn Error>> {
     let root = utils::find_root()?;
-    let path = fs::canonicalize(&path)?;
-    for (entry_path, (entry_type, hash)) in index.entries.iter() {
-        let entry_path = PathBuf::from(&entry_path);
+    let attributes = Attributes::load(&root);
+    let mut tracked = vec![];
+    for (name, entry) in index.entries.iter() {
+        let (entry_type, hash) = entry;
+        let entry_path = PathBuf::from(&name);
         let full_path = root.join(&entry_path);
 
-        // Check if the file is part of path
-        if !full_path.starts_with(&path) {
+        if !pathspec.matches(name, false) {
             continue;
         }
 
         // Staged files (new/modified)
-        if !last_commit.contains(&entry_path)? {
+        if index.flags(name).intent_to_add {
+            // Nothing is staged for the files intended to be added
+        } else if !last_commit.contains(&entry_path)? {
             status.insert(Status::new("new", &full_path));
         } else {
             let entry = last_commit.get_entry(&entry_path).unwrap();
@@ -104,30 +361,35 @@ fn compute_tracked(
                 }
             }
         }
+        tracked.push((name, entry, full_path));
+    }
 
-        // Unstaged files (modified/deleted)
-        let metadata = fs::symlink_metadata(&full_path);
-        if !full_path.exists() || metadata.unwrap().is_dir() {
-            status.insert(Status::new("deletenotstaged", &full_path));
-        } else {
-            // Compute blob
-            let metadata = fs::symlink_metadata(&full_path).unwrap();
-            let content = if metadata.file_type().is_symlink() {
-                fs::read_link(&full_path)
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .as_bytes()
-                    .to_vec()
-            } else {
-                fs::read(&full_path)?
-            };
-            let blob = Blob::new(content);
-            let file_type = Index::get_file_type(&full_path);
-            if blob.hash() != *hash || file_type != *entry_type {
-                status.insert(Status::new("modifiednotstaged", &full_path));
+    // Unstaged files (modified/deleted), hashed on several threads
+    let threads = parallel::threads();
+    let unstaged = parallel::map(threads, &tracked, |(name, entry, full_path)| {
+        check_worktree(index, &attributes, name, entry, full_path)
+    });
+    let mut refreshed = vec![];
+    for ((name, entry, full_path), res) in tracked.iter().zip(unstaged) {
+        let (found, metadata) = match res? {
+            Worktree::Deleted => (Some(Status::new("deletenotstaged", full_path)), None),
+            _ if index.flags(name).intent_to_add => {
+                (Some(Status::new("intenttoadd", full_path)), None)
+            }
+            Worktree::Unchanged => (None, None),
+            Worktree::Refresh(metadata) => (None, Some(metadata)),
+            Worktree::Modified => (Some(Status::new("modifiednotstaged", full_path)), None),
+        };
+        if let Some(fsmonitor) = &mut index.fsmonitor {
+            match found {
+                Some(_) => fsmonitor.invalidate(name),
+                None => fsmonitor.validate(name, entry),
             }
         }
+        status.extend(found);
+        if let Some(metadata) = metadata {
+            refreshed.push((name.to_string(), metadata));
+        }
     }
 
     // Staged files (deleted)
@@ -136,85 +398,360 @@ fn compute_tracked(
         for (file_name, entry) in tree.entries.iter() {
             let cur_path = cur_path.join(file_name);
             let full_path = root.join(&cur_path);
-            // Check if the file is part of path
-            if !full_path.starts_with(&path) {
-                continue;
-            }
-
+            let name = cur_path.to_str().unwrap();
             if let TreeEntry::Directory(tree) = entry {
-                stack.push((cur_path, tree));
-            } else if !index.contains(&cur_path)? {
+                if pathspec.may_contain(name) || pathspec.matches(name, true) {
+                    stack.push((cur_path, tree));
+                }
+            } else if pathspec.matches(name, false) && !index.entries.contains_key(name) {
                 status.insert(Status::new("deletestaged", &full_path));
             }
         }
     }
+
+    let mut changed = false;
+    for (name, metadata) in refreshed {
+        changed |= index.refresh(&name, &metadata);
+    }
+    Ok(changed)
+}
+
+/// The output formats
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Long,
+    Short,
+    /// Like the short format with paths relative to the root and no colors
+    Porcelain,
+    PorcelainV2,
+}
+
+/// The current branch compared with its upstream, shown with `--branch`
+struct Branch {
+    /// None when HEAD is detached
+    name: Option<String>,
+    /// None when there is no commit yet
+    head: Option<Hash>,
+    /// The upstream with the number of commits ahead of it and behind it,
+    /// which are unknown when its ref doesn't exist
+    upstream: Option<(String, Option<(usize, usize)>)>,
+}
+
+impl Branch {
+    fn load(repo_path: &PathBuf) -> Branch {
+        let name = refs::head_branch(repo_path);
+        let head = refs::get_head(repo_path).map(|commit| commit.hash());
+        let upstream = name
+            .as_ref()
+            .and_then(|name| refs::upstream(repo_path, name))
+            .map(|(upstream, hash)| {
+                let counts = hash.zip(head).map(|(theirs, ours)| {
+                    let ours = Commit::ancestors(repo_path, &[ours]);
+                    let theirs = Commit::ancestors(repo_path, &[theirs]);
+                    let ahead = ours.difference(&theirs).count();
+                    (ahead, theirs.difference(&ours).count())
+                });
+                (upstream, counts)
+            });
+        Branch {
+            name,
+            head,
+            upstream,
+        }
+    }
+
+    /// The header of the short format, after `## `
+    fn short(&self, color: bool) -> String {
+        let local = |text: &str| match color {
+            true => text.green().to_string(),
+            false => text.to_string(),
+        };
+        let remote = |text: &str| match color {
+            true => text.red().to_string(),
+            false => text.to_string(),
+        };
+        let name = match (&self.name, self.head) {
+            (None, _) => return String::from("HEAD (no branch)"),
+            (Some(name), None) => return format!("No commits yet on {}", local(name)),
+            (Some(name), Some(_)) => local(name),
+        };
+        let (upstream, counts) = match &self.upstream {
+            Some(upstream) => upstream,
+            None => return name,
+        };
+        let counts = match counts {
+            None => String::from(" [gone]"),
+            Some((0, 0)) => String::new(),
+            Some((ahead, 0)) => format!(" [ahead {}]", local(&ahead.to_string())),
+            Some((0, behind)) => format!(" [behind {}]", remote(&behind.to_string())),
+            Some((ahead, behind)) => format!(
+                " [ahead {}, behind {}]",
+                local(&ahead.to_string()),
+                remote(&behind.to_string())
+            ),
+        };
+        format!("{}...{}{}", name, remote(upstream), counts)
+    }
+
+    /// The headers of the porcelain v2 format, after `# `
+    fn headers(&self) -> Vec<String> {
+        let oid = self.head.map(|hash| hash.to_string());
+        let mut res = vec![
+            format!("branch.oid {}", oid.as_deref().unwrap_or("(initial)")),
+            format!(
+                "branch.head {}",
+                self.name.as_deref().unwrap_or("(detached)")
+            ),
+        ];
+        if let Some((upstream, counts)) = &self.upstream {
+            res.push(format!("branch.upstream {}", upstream));
+            if let Some((ahead, behind)) = counts {
+                res.push(format!("branch.ab +{} -{}", ahead, behind));
+            }
+        }
+        res
+    }
+}
+
+/// An entry of the short formats: the status in the index and in the
+/// working tree, the path relative to the root and to the current directory
+type ShortEntry = ([char; 2], String, String);
+
+/// The entries of the short formats: the tracked paths, then the untracked
+/// and the ignored ones
+fn short_entries(status: &BTreeSet<Status>) -> io::Result<Vec<ShortEntry>> {
+    let mut tracked: BTreeMap<String, ([char; 2], String)> = BTreeMap::new();
+    let mut others = vec![];
+    for status in status.iter() {
+        let (path, x, y) = status.short();
+        let mut in_repo = utils::path_in_repo(path)?.to_str().unwrap().to_string();
+        if path.ends_with('/') {
+            in_repo.push('/');
+        }
+        if x == '?' || x == '!' {
+            others.push(([x, y], in_repo, path.to_string()));
+            continue;
+        }
+        let entry = tracked
+            .entry(in_repo)
+            .or_insert(([' ', ' '], path.to_string()));
+        if x != ' ' {
+            entry.0[0] = x;
+        }
+        if y != ' ' {
+            entry.0[1] = y;
+        }
+    }
+    others.sort_by(|a, b| (a.0[0] == '!', &a.1).cmp(&(b.0[0] == '!', &b.1)));
+    let tracked = tracked
+        .into_iter()
+        .map(|(in_repo, (code, path))| (code, in_repo, path));
+    Ok(tracked.chain(others).collect())
+}
+
+/// Print `--short` or `--porcelain` (v1): a line `XY <path>` for each path
+fn display_short(
+    entries: &[ShortEntry],
+    branch: Option<&Branch>,
+    format: Format,
+    term: char,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let color = format == Format::Short && term == '\n';
+    if let Some(branch) = branch {
+        write!(out, "## {}{}", branch.short(color), term)?;
+    }
+    for ([x, y], in_repo, path) in entries {
+        let path = if format == Format::Short {
+            path
+        } else {
+            in_repo
+        };
+        match color {
+            true if *x == '?' || *x == '!' => {
+                let code = format!("{}{}", x, y);
+                write!(out, "{} {}{}", code.red(), path, term)?
+            }
+            true => {
+                let (x, y) = (x.to_string(), y.to_string());
+                write!(out, "{}{} {}{}", x.green(), y.red(), path, term)?
+            }
+            false => write!(out, "{}{} {}{}", x, y, path, term)?,
+        }
+    }
     Ok(())
 }
-fn display(status: &BTreeSet<Status>) {
-    // Clean working tree
-    if status.is_empty() {
-        return println!("nothing to commit, working tree clean");
+
+/// Print `--porcelain=v2`: the modes and hashes of the changed files in HEAD,
+/// the index and the working tree (`1 XY N... <mH> <mI> <mW> <hH> <hI>
+/// <path>`), `? <path>` for untracked paths and `! <path>` for ignored ones
+fn display_v2(
+    entries: &[ShortEntry],
+    branch: Option<&Branch>,
+    term: char,
+    index: &Index,
+    last_commit: &Tree,
+    root: &Path,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    for header in branch.map(Branch::headers).into_iter().flatten() {
+        write!(out, "# {}{}", header, term)?;
     }
+    let head = last_commit.files();
+    let zero = "0".repeat(40);
+    for ([x, y], path, _) in entries {
+        if *x == '?' || *x == '!' {
+            write!(out, "{} {}{}", x, path, term)?;
+            continue;
+        }
+        let head = head.get(path);
+        // Nothing is staged for the files intended to be added
+        let entry = index
+            .entries
+            .get(path)
+            .filter(|_| !index.flags(path).intent_to_add);
+        let worktree = match fs::symlink_metadata(root.join(path)) {
+            Ok(_) if index.entries.contains_key(path) && *y != 'D' => {
+                index::mode(&Index::get_file_type(&root.join(path)))
+            }
+            _ => 0,
+        };
+        let code = |c: char| if c == ' ' { '.' } else { c };
+        write!(
+            out,
+            "1 {}{} N... {:06o} {:06o} {:06o} {} {} {}{}",
+            code(*x),
+            code(*y),
+            head.map_or(0, |(entry_type, _)| index::mode(entry_type)),
+            entry.map_or(0, |(entry_type, _)| index::mode(entry_type)),
+            worktree,
+            head.map_or(zero.clone(), |(_, hash)| hash.to_string()),
+            entry.map_or(zero.clone(), |(_, hash)| hash.to_string()),
+            path,
+            term
+        )?;
+    }
+    Ok(())
+}
 
+/// Print the long format
+fn display(status: &BTreeSet<Status>, show_untracked: bool, out: &mut dyn Write) -> io::Result<()> {
     // Staged files
-    if status.iter().any(|s| match s {
-        Status::New(_) | Status::ModifiedStaged(_) | Status::DeletedStaged(_) => true,
-        _ => false,
+    if status.iter().any(|s| {
+        matches!(
+            s,
+            Status::New(_) | Status::ModifiedStaged(_) | Status::DeletedStaged(_)
+        )
     }) {
-        println!("Changes to be committed:\n");
+        writeln!(out, "Changes to be committed:\n")?;
         for status in status.iter() {
             match status {
-                Status::New(path) => println!("\tnew file:   {}", path.green()),
-                Status::ModifiedStaged(path) => println!("\tmodified:   {}", path.green()),
-                Status::DeletedStaged(path) => println!("\tdeleted:   {}", path.green()),
+                Status::New(path) => writeln!(out, "\tnew file:   {}", path.green())?,
+                Status::ModifiedStaged(path) => writeln!(out, "\tmodified:   {}", path.green())?,
+                Status::DeletedStaged(path) => writeln!(out, "\tdeleted:   {}", path.green())?,
                 _ => (),
             }
         }
-        println!();
+        writeln!(out)?;
     }
 
     // Unstaged files
-    if status.iter().any(|s| match s {
-        Status::ModifiedNotStaged(_) | Status::DeletedNotStaged(_) => true,
-        _ => false,
+    if status.iter().any(|s| {
+        matches!(
+            s,
+            Status::ModifiedNotStaged(_) | Status::DeletedNotStaged(_) | Status::IntentToAdd(_)
+        )
     }) {
-        println!(
+        writeln!(
+            out,
             "Changes not staged for commit:\n  \
              (use \"git add <file>...\" to update what will be committed)\n"
-        );
+        )?;
         for status in status.iter() {
             match status {
-                Status::ModifiedNotStaged(path) => println!("\tmodified:   {}", path.red()),
-                Status::DeletedNotStaged(path) => println!("\tdeleted:   {}", path.red()),
+                Status::ModifiedNotStaged(path) => writeln!(out, "\tmodified:   {}", path.red())?,
+                Status::DeletedNotStaged(path) => writeln!(out, "\tdeleted:   {}", path.red())?,
+                Status::IntentToAdd(path) => writeln!(out, "\tnew file:   {}", path.red())?,
                 _ => (),
             }
         }
-        println!();
+        writeln!(out)?;
     }
 
     // Untracked files or directories
-    if status.iter().any(|s| match s {
-        Status::Untracked(_) => true,
-        _ => false,
-    }) {
-        println!(
+    if status.iter().any(|s| matches!(s, Status::Untracked(_))) {
+        writeln!(
+            out,
             "Untracked files:\n  \
              (use \"git add <file>...\" to include in what will be comitted)\n"
-        );
+        )?;
         for status in status.iter() {
             if let Status::Untracked(path) = status {
-                println!("\t{}", path.red());
+                writeln!(out, "\t{}", path.red())?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    // Ignored files or directories
+    if status.iter().any(|s| matches!(s, Status::Ignored(_))) {
+        writeln!(
+            out,
+            "Ignored files:\n  \
+             (use \"git add -f <file>...\" to include in what will be committed)\n"
+        )?;
+        for status in status.iter() {
+            if let Status::Ignored(path) = status {
+                writeln!(out, "\t{}", path.red())?;
             }
         }
-        println!();
+        writeln!(out)?;
+    }
+
+    // Clean working tree
+    if status.iter().all(|s| matches!(s, Status::Ignored(_))) {
+        match show_untracked {
+            true => writeln!(out, "nothing to commit, working tree clean")?,
+            false => writeln!(out, "nothing to commit (use -u to show untracked files)")?,
+        }
     }
+    Ok(())
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    status(args, &mut io::stdout().lock())
+}
+
+/// Show the status in the format given by the arguments on `out`
+fn status(args: &ArgMatches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
     let root = utils::find_root()?;
     let repo_path = utils::find_repo()?;
 
-    let index = Index::load(&repo_path);
+    // -z alone implies --porcelain
+    let format = match args.value_of("porcelain") {
+        Some("v2") => Format::PorcelainV2,
+        _ if args.is_present("porcelain") => Format::Porcelain,
+        _ if args.is_present("short") => Format::Short,
+        _ if args.is_present("z") => Format::Porcelain,
+        _ => Format::Long,
+    };
+    let term = if args.is_present("z") { '\0' } else { '\n' };
+    // -u alone shows every untracked file
+    let untracked = match args.value_of("untracked-files") {
+        Some(mode) => mode,
+        None if args.is_present("untracked-files") => "all",
+        None => "normal",
+    };
+
+    let mut index = Index::load(&repo_path)?;
+    let mut changed = fsmonitor::refresh(&root, &mut index);
+    // The untracked cache is created or dropped according to the config
+    let mut cache = index.untracked.take();
+    if UntrackedCache::enabled() {
+        cache.get_or_insert_with(UntrackedCache::new);
+    } else {
+        changed |= cache.take().is_some();
+    }
 
     let last_commit = match refs::get_head(&repo_path) {
         Some(commit) => *Tree::load(&repo_path, commit.tree),
@@ -224,19 +761,238 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let mut status = BTreeSet::new();
     let ignored = utils::ignored(&root)?;
 
-    if !args.is_present("PATHSPEC") {
-        compute_untracked(&mut status, &root, &last_commit, &index, &ignored)?;
-        compute_tracked(&mut status, &root, &last_commit, &index)?;
-    } else {
-        for spec in args.values_of("PATHSPEC").unwrap() {
-            for entry in glob(spec)? {
-                if let Ok(path) = entry {
-                    compute_untracked(&mut status, &path, &last_commit, &index, &ignored)?;
-                    compute_tracked(&mut status, &path, &last_commit, &index)?;
-                }
-            }
+    let pathspec = Pathspec::new(args.values_of("PATHSPEC").into_iter().flatten())?;
+    if untracked != "no" {
+        compute_untracked(
+            &mut status,
+            &root,
+            &pathspec,
+            &last_commit,
+            &index,
+            &ignored,
+            cache.as_mut(),
+        )?;
+    }
+    if untracked == "all" {
+        let paths: Vec<String> = status
+            .iter()
+            .filter_map(|status| match status {
+                Status::Untracked(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        status.retain(|status| !matches!(status, Status::Untracked(_)));
+        let files = untracked_files(paths, &pathspec, &ignored)?;
+        status.extend(files.into_iter().map(Status::Untracked));
+    }
+    // Ignored files are only shown with the untracked ones
+    if args.is_present("ignored") && untracked != "no" {
+        let all = untracked == "all";
+        compute_ignored(&mut status, &root, &pathspec, &index, &ignored, all)?;
+    }
+    changed |= compute_tracked(&mut status, &pathspec, &last_commit, &mut index)?;
+    // Save the refreshed stat data and caches so that the files aren't
+    // read next time
+    changed |= cache.as_ref().is_some_and(UntrackedCache::is_modified);
+    index.untracked = cache;
+    if changed {
+        index.save(&repo_path);
+    }
+
+    let branch = match args.is_present("branch") {
+        true => Some(Branch::load(&repo_path)),
+        false => None,
+    };
+    match format {
+        Format::Long => display(&status, untracked != "no", out)?,
+        Format::PorcelainV2 => {
+            let entries = short_entries(&status)?;
+            let branch = branch.as_ref();
+            display_v2(&entries, branch, term, &index, &last_commit, &root, out)?
         }
+        _ => display_short(&short_entries(&status)?, branch.as_ref(), format, term, out)?,
     }
-    display(&status);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Flags;
+    use crate::objects::User;
+    use crate::utils::tests::{in_repo, subcommand};
+    use chrono::{FixedOffset, TimeZone};
+    use std::str::FromStr;
+
+    fn branch(upstream: Option<(&str, Option<(usize, usize)>)>) -> Branch {
+        Branch {
+            name: Some(String::from("main")),
+            head: Some(Hash::from_str("96426655341fe444d5a6231f34b89b7c21ec992c").unwrap()),
+            upstream: upstream.map(|(name, counts)| (name.to_string(), counts)),
+        }
+    }
+
+    #[test]
+    fn short_header() {
+        assert_eq!(branch(None).short(false), "main");
+        let header = branch(Some(("origin/main", Some((2, 1))))).short(false);
+        assert_eq!(header, "main...origin/main [ahead 2, behind 1]");
+        let header = branch(Some(("origin/main", Some((0, 3))))).short(false);
+        assert_eq!(header, "main...origin/main [behind 3]");
+        let header = branch(Some(("main", Some((0, 0))))).short(false);
+        assert_eq!(header, "main...main");
+        let header = branch(Some(("origin/main", None))).short(false);
+        assert_eq!(header, "main...origin/main [gone]");
+        let unborn = Branch {
+            head: None,
+            ..branch(None)
+        };
+        assert_eq!(unborn.short(false), "No commits yet on main");
+        let detached = Branch {
+            name: None,
+            ..branch(None)
+        };
+        assert_eq!(detached.short(false), "HEAD (no branch)");
+    }
+
+    #[test]
+    fn v2_headers() {
+        let headers = branch(Some(("origin/main", Some((2, 1))))).headers();
+        assert_eq!(
+            headers,
+            vec![
+                "branch.oid 96426655341fe444d5a6231f34b89b7c21ec992c",
+                "branch.head main",
+                "branch.upstream origin/main",
+                "branch.ab +2 -1",
+            ]
+        );
+        let unborn = Branch {
+            name: Some(String::from("main")),
+            head: None,
+            upstream: Some((String::from("origin/main"), None)),
+        };
+        let headers = unborn.headers();
+        assert_eq!(
+            headers,
+            vec![
+                "branch.oid (initial)",
+                "branch.head main",
+                "branch.upstream origin/main",
+            ]
+        );
+    }
+
+    /// The output of `status` with the given options
+    fn status_with(options: &[&str]) -> String {
+        let mut out = vec![];
+        status(&subcommand(&[&["status"], options].concat()), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn short_formats() {
+        in_repo("status", |root| {
+            let repo_path = root.join(".my_git");
+            let blob = |content: &str| {
+                let blob = Blob::new(content.into());
+                blob.save(&repo_path);
+                blob.hash()
+            };
+            let (v1, v2, new) = (blob("v1\n"), blob("v2\n"), blob("new\n"));
+
+            // Changes staged, not staged and both, in HEAD and in the index
+            let mut tree = Tree::new();
+            let mut index = Index::load_text(b"");
+            fs::write(root.join(".my_gitignore"), "*.log\nbuild/\n").unwrap();
+            let ignore = blob("*.log\nbuild/\n");
+            tree.add_file(String::from(".my_gitignore"), ignore);
+            let entry = (EntryType::File, ignore);
+            index.entries.insert(String::from(".my_gitignore"), entry);
+            for path in ["staged", "modified", "deleted", "both"].iter() {
+                tree.add_file(path.to_string(), v1);
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, v1));
+            }
+            tree.save(&repo_path);
+            let commit = Commit::new(
+                tree.hash(),
+                vec![],
+                User::new(String::from("John Doe"), String::from("john@doe.com")),
+                FixedOffset::east(0).timestamp(1_000_000_000, 0),
+                String::from("first\n"),
+            );
+            commit.save(&repo_path);
+            let head = format!("{}\n", commit.hash());
+            fs::write(repo_path.join("refs/heads/master"), head).unwrap();
+            for (path, hash) in [("staged", v2), ("both", v2), ("new", new)].iter() {
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, *hash));
+            }
+            let empty = blob("");
+            index
+                .entries
+                .insert(String::from("ita"), (EntryType::File, empty));
+            let intent = Flags {
+                intent_to_add: true,
+                ..Default::default()
+            };
+            index.flags.insert(String::from("ita"), intent);
+            index.save(&repo_path);
+            for (path, content) in [
+                ("staged", "v2\n"),
+                ("modified", "v2\n"),
+                ("both", "v3\n"),
+                ("new", "new\n"),
+                ("ita", "ita\n"),
+            ]
+            .iter()
+            {
+                fs::write(root.join(path), content).unwrap();
+            }
+            fs::create_dir(root.join("ud")).unwrap();
+            fs::create_dir(root.join("build")).unwrap();
+            for path in ["u", "ud/a", "ud/b", "x.log", "build/o"].iter() {
+                fs::write(root.join(path), "").unwrap();
+            }
+
+            let tracked = "MM both\n D deleted\n A ita\n M modified\nA  new\nM  staged\n";
+            assert_eq!(
+                status_with(&["--porcelain"]),
+                format!("{}?? u\n?? ud/\n", tracked)
+            );
+            assert_eq!(status_with(&["--porcelain", "-uno"]), tracked);
+            // -u lists the untracked files inside directories, and the
+            // ignored ones
+            assert_eq!(
+                status_with(&["--porcelain", "-u", "--ignored"]),
+                format!("{}?? u\n?? ud/a\n?? ud/b\n!! build/o\n!! x.log\n", tracked)
+            );
+            // -z terminates the entries with NUL and implies --porcelain
+            assert_eq!(
+                status_with(&["-z", "--ignored"]),
+                format!("{}?? u\n?? ud/\n!! build/\n!! x.log\n", tracked).replace('\n', "\0")
+            );
+
+            let zero = "0".repeat(40);
+            let expected = [
+                format!("1 MM N... 100644 100644 100644 {} {} both", v1, v2),
+                format!("1 .D N... 100644 100644 000000 {} {} deleted", v1, v1),
+                format!("1 .A N... 000000 000000 100644 {} {} ita", zero, zero),
+                format!("1 .M N... 100644 100644 100644 {} {} modified", v1, v1),
+                format!("1 A. N... 000000 100644 100644 {} {} new", zero, new),
+                format!("1 M. N... 100644 100644 100644 {} {} staged", v1, v2),
+                String::from("? u"),
+                String::from("? ud/"),
+                String::from("! build/"),
+                String::from("! x.log"),
+            ];
+            assert_eq!(
+                status_with(&["--porcelain=v2", "--ignored"]),
+                format!("{}\n", expected.join("\n"))
+            );
+        });
+    }
+}
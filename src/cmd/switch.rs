@@ -1,16 +1,320 @@
+use crate::attributes::Attributes;
 use crate::cmd::branch;
-use crate::index::Index;
-use crate::objects::{Commit, Hash, Object, Tree};
-use crate::{refs, utils};
+use crate::ignore::Ignore;
+use crate::index::{EntryType, Index};
+use crate::merge;
+use crate::objects::{Blob, Commit, Hash, Object, Tree};
+use crate::{hooks, refs, utils};
 use clap::ArgMatches;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How local changes are handled when checking out another commit
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Mode {
+    /// Refuse to overwrite local changes or untracked files
+    Safe,
+    /// Carry local changes over with a three-way merge
+    Merge,
+    /// Throw local changes away
+    Discard,
+}
+
+/// Local changes that would be lost by a checkout
+#[derive(Default)]
+struct Conflicts {
+    /// Tracked files with staged or unstaged changes
+    local: Vec<String>,
+    /// Untracked files in the way of the new tree
+    untracked: Vec<String>,
+    /// Every tracked file with local changes (even unaffected ones)
+    dirty: Vec<String>,
+}
+
+/// Return the type and hash of a file in the working tree, if any. Files
+/// whose stat data match their entry aren't read, nor files assumed
+/// unchanged or left out of the working tree.
+fn worktree_entry(
+    root: &Path,
+    path: &str,
+    index: &Index,
+    attributes: &Attributes,
+) -> Result<Option<(EntryType, Hash)>, Box<dyn Error>> {
+    if index.flags(path).ignores_worktree() {
+        return Ok(index.entries.get(path).cloned());
+    }
+    let full_path = root.join(path);
+    let metadata = match fs::symlink_metadata(&full_path) {
+        Ok(metadata) if !metadata.is_dir() => metadata,
+        _ => return Ok(None),
+    };
+    let file_type = Index::get_file_type(&full_path);
+    if let Some((entry_type, hash)) = index.entries.get(path) {
+        if *entry_type == file_type && index.stat_matches(path, &metadata) {
+            return Ok(Some((file_type, *hash)));
+        }
+    }
+    let hash = Blob::from_worktree(&full_path, attributes)?.hash();
+    Ok(Some((file_type, hash)))
+}
+
+/// Collect the untracked files (not ignored) inside a directory
+fn untracked_files(
+    root: &PathBuf,
+    path: &PathBuf,
+    index: &Index,
+    ignored: &Ignore,
+    res: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    if utils::is_ignored(path, ignored)? {
+        return Ok(());
+    }
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            untracked_files(root, &entry?.path(), index, ignored, res)?;
+        }
+    } else {
+        let relative: PathBuf = path.iter().skip(root.iter().count()).collect();
+        let relative = relative.to_str().unwrap().to_string();
+        if !index.entries.contains_key(&relative) {
+            res.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Compute the paths whose local state would be lost going from `head` to
+/// `target`
+fn check(
+    root: &PathBuf,
+    index: &Index,
+    head: &BTreeMap<String, (EntryType, Hash)>,
+    target: &BTreeMap<String, (EntryType, Hash)>,
+    ignored: &Ignore,
+    attributes: &Attributes,
+) -> Result<Conflicts, Box<dyn Error>> {
+    let mut conflicts = Conflicts::default();
+    let paths: BTreeSet<&String> = head
+        .keys()
+        .chain(target.keys())
+        .chain(index.entries.keys())
+        .collect();
+
+    for path in paths {
+        let (h, t, i) = (head.get(path), target.get(path), index.entries.get(path));
+        let w = worktree_entry(root, path, index, attributes)?;
+        let w = w.as_ref();
+        if i != h || (i.is_some() && w != i) {
+            conflicts.dirty.push(path.clone());
+        }
+
+        // Unchanged between both commits: local changes are kept
+        if h == t {
+            continue;
+        }
+
+        if i.is_none() && h.is_none() {
+            if w.is_some() && w != t && !ignored.is_ignored(Path::new(path), false) {
+                conflicts.untracked.push(path.clone());
+            }
+        } else if (i != h && i != t) || (i.is_some() && w != i && w != t) {
+            conflicts.local.push(path.clone());
+        }
+    }
+
+    // Untracked files where the new tree needs a directory or a file
+    let mut obstacles = BTreeSet::new();
+    for path in target.keys() {
+        let mut parent = PathBuf::from(path);
+        while parent.pop() && parent != PathBuf::new() {
+            let full_path = root.join(&parent);
+            let parent = parent.to_str().unwrap().to_string();
+            if let Ok(metadata) = fs::symlink_metadata(&full_path) {
+                if !metadata.is_dir()
+                    && !index.entries.contains_key(&parent)
+                    && !ignored.is_ignored(Path::new(&parent), false)
+                {
+                    obstacles.insert(parent);
+                }
+            }
+        }
+
+        let full_path = root.join(path);
+        if let Ok(metadata) = fs::symlink_metadata(&full_path) {
+            if metadata.is_dir() {
+                let mut files = vec![];
+                untracked_files(root, &full_path, index, ignored, &mut files)?;
+                obstacles.extend(files);
+            }
+        }
+    }
+    conflicts.untracked.extend(obstacles);
+    Ok(conflicts)
+}
+
+/// Return an error if the conflicts would lose local work in this mode
+fn refuse(conflicts: &Conflicts, mode: Mode) -> Result<(), ErrorSwitch> {
+    if mode == Mode::Safe && !conflicts.local.is_empty() {
+        return Err(ErrorSwitch::LocalChanges(conflicts.local.clone()));
+    }
+    if mode != Mode::Discard && !conflicts.untracked.is_empty() {
+        return Err(ErrorSwitch::UntrackedFiles(conflicts.untracked.clone()));
+    }
+    Ok(())
+}
+
+/// Restore the index and the working tree of the given paths to `head`
+fn discard(
+    repo_path: &Path,
+    root: &Path,
+    index: &mut Index,
+    head: &BTreeMap<String, (EntryType, Hash)>,
+    paths: &[String],
+    attributes: &Attributes,
+) -> Result<(), Box<dyn Error>> {
+    for path in paths {
+        let full_path = root.join(path);
+        match head.get(path) {
+            Some((entry_type, hash)) => {
+                if let Ok(metadata) = fs::symlink_metadata(&full_path) {
+                    if metadata.is_dir() {
+                        fs::remove_dir_all(&full_path)?;
+                    } else {
+                        fs::remove_file(&full_path)?;
+                    }
+                }
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let blob = Blob::load(repo_path, *hash);
+                blob.to_worktree(&full_path, entry_type, attributes)?;
+                index.update_entry(&full_path, &blob)?;
+            }
+            None => {
+                index.entries.remove(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// This function updates the working tree and the index from HEAD to the
+/// `target` commit. Files that differ between both commits and have local
+/// changes are handled according to `mode`. The `label` names the target in
+/// conflict markers.
+pub fn checkout(
+    repo_path: &PathBuf,
+    target: &Commit,
+    label: &str,
+    mode: Mode,
+) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let head_tree = match refs::get_head(repo_path) {
+        Some(commit) => *Tree::load(repo_path, commit.tree),
+        None => Tree::new(),
+    };
+    let target_tree = Tree::load(repo_path, target.tree);
+    let mut index = Index::load(repo_path)?;
+    let head = head_tree.files();
+    let target_files = target_tree.files();
+
+    // Check that no work is lost
+    let ignored = utils::ignored(&root)?;
+    let attributes = Attributes::load(&root);
+    let conflicts = check(&root, &index, &head, &target_files, &ignored, &attributes)?;
+    refuse(&conflicts, mode)?;
+
+    // Save local versions to merge them once the target is applied
+    let mut local = vec![];
+    if mode == Mode::Merge {
+        for path in conflicts.local.iter() {
+            let content = match fs::symlink_metadata(root.join(path)) {
+                Ok(metadata) if metadata.is_file() => {
+                    Some(Blob::from_worktree(&root.join(path), &attributes)?.data)
+                }
+                _ => None,
+            };
+            local.push((path, content));
+        }
+    }
+    match mode {
+        Mode::Merge => discard(
+            repo_path,
+            &root,
+            &mut index,
+            &head,
+            &conflicts.local,
+            &attributes,
+        )?,
+        Mode::Discard => discard(
+            repo_path,
+            &root,
+            &mut index,
+            &head,
+            &conflicts.dirty,
+            &attributes,
+        )?,
+        Mode::Safe => (),
+    }
+
+    // Apply the commit to the fs with the attributes of the target
+    let attributes = Attributes::from_tree(repo_path, &root, &target_tree);
+    head_tree.apply(repo_path, &mut index, &root, &target_tree, &attributes)?;
+
+    for (path, ours) in local {
+        let full_path = root.join(path);
+        match (ours, target_files.get(path)) {
+            (Some(ours), Some((EntryType::File, hash)))
+            | (Some(ours), Some((EntryType::Executable, hash))) => {
+                let base = match head.get(path) {
+                    Some((_, hash)) => Blob::load(repo_path, *hash).data,
+                    None => vec![],
+                };
+                let theirs = Blob::load(repo_path, *hash).data;
+                let res = merge::merge_file(
+                    repo_path,
+                    path,
+                    attributes.get(Path::new(path)).get("merge"),
+                    &base,
+                    &theirs,
+                    &ours,
+                    (label, "local"),
+                )?;
+                fs::write(&full_path, attributes.to_worktree(&full_path, res.data)?)?;
+                if res.conflicts > 0 {
+                    println!("CONFLICT (content): Merge conflict in {}", path);
+                }
+            }
+            (Some(ours), None) => {
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&full_path, attributes.to_worktree(&full_path, ours)?)?;
+                println!(
+                    "CONFLICT (modify/delete): {} deleted in {} and modified locally.",
+                    path, label
+                );
+            }
+            _ => println!(
+                "CONFLICT (modify/delete): {} deleted locally and modified in {}.",
+                path, label
+            ),
+        }
+    }
+
+    // Save the new index
+    index.save(repo_path);
+    Ok(())
+}
 
 pub fn switch_branch(
     repo_path: &PathBuf,
     branch: &String,
     branches: &HashMap<String, Hash>,
+    mode: Mode,
 ) -> Result<(), Box<dyn Error>> {
     // Find branch commit
     let commit = match branches.iter().find(|(b, _)| *b == branch) {
@@ -19,7 +323,7 @@ pub fn switch_branch(
     };
 
     // Check if nothing has to be done
-    if let Some((current_branch, _)) = refs::current_branch(&repo_path) {
+    if let Some((current_branch, _)) = refs::current_branch(repo_path) {
         if current_branch == *branch {
             println!("Already on '{}'", branch);
             return Ok(());
@@ -27,14 +331,8 @@ pub fn switch_branch(
     }
 
     // Apply the commit to the fs
-    let commit_tree = Tree::load(repo_path, commit.tree);
-    let root = utils::find_root()?;
-    let head_tree = Tree::load(repo_path, refs::get_head(repo_path).unwrap().tree);
-    let mut index = Index::load(repo_path);
-    head_tree.apply(repo_path, &mut index, &root, &commit_tree)?;
-
-    // Save the new index
-    index.save(repo_path);
+    let previous = refs::get_head(repo_path).map(|head| head.hash());
+    checkout(repo_path, &commit, branch, mode)?;
 
     // Update HEAD
     refs::update(
@@ -43,6 +341,32 @@ pub fn switch_branch(
         &format!("ref: refs/heads/{}", branch),
         false,
     )?;
+
+    let previous = match previous {
+        Some(hash) => hash.to_string(),
+        None => "0".repeat(40),
+    };
+    hooks::run_post(
+        repo_path,
+        "post-checkout",
+        &[&previous, &commit.hash().to_string(), "1"],
+        None,
+    );
+    Ok(())
+}
+
+/// This function checks out a commit and detaches HEAD at it
+pub fn detach(repo_path: &PathBuf, commit: &Commit, mode: Mode) -> Result<(), Box<dyn Error>> {
+    let previous = refs::get_head(repo_path).map(|head| head.hash());
+    let hash = commit.hash().to_string();
+    checkout(repo_path, commit, &hash[..7], mode)?;
+    refs::update(repo_path, &String::from("HEAD"), &hash, false)?;
+
+    let previous = match previous {
+        Some(hash) => hash.to_string(),
+        None => "0".repeat(40),
+    };
+    hooks::run_post(repo_path, "post-checkout", &[&previous, &hash, "1"], None);
     Ok(())
 }
 
@@ -50,6 +374,13 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let repo_path = utils::find_repo()?;
     let branch = args.value_of("BRANCH").unwrap().to_string();
     let mut branches = refs::branches(&repo_path);
+    let mode = if args.is_present("merge") {
+        Mode::Merge
+    } else if args.is_present("discard-changes") {
+        Mode::Discard
+    } else {
+        Mode::Safe
+    };
 
     // Create branch
     if args.is_present("create") || args.is_present("force-create") {
@@ -61,7 +392,85 @@ pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
         )?;
     }
 
-    switch_branch(&repo_path, &branch, &branches)?;
+    switch_branch(&repo_path, &branch, &branches, mode)?;
 
     Ok(())
 }
+
+#[derive(Debug)]
+pub enum ErrorSwitch {
+    LocalChanges(Vec<String>),
+    UntrackedFiles(Vec<String>),
+}
+
+impl fmt::Display for ErrorSwitch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorSwitch::LocalChanges(paths) => write!(
+                f,
+                "error: Your local changes to the following files would be overwritten by checkout:\n\
+                 {}Please commit your changes or stash them before you switch branches.\n\
+                 Aborting",
+                paths.iter().map(|p| format!("\t{}\n", p)).collect::<String>()
+            ),
+            ErrorSwitch::UntrackedFiles(paths) => write!(
+                f,
+                "error: The following untracked working tree files would be overwritten by checkout:\n\
+                 {}Please move or remove them before you switch branches.\n\
+                 Aborting",
+                paths.iter().map(|p| format!("\t{}\n", p)).collect::<String>()
+            ),
+        }
+    }
+}
+
+impl Error for ErrorSwitch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::config::Drivers;
+    use crate::filter::Filters;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn local_changes() {
+        let root = env::temp_dir().join(format!("my_git_switch_{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let attributes = Attributes::new(&root, Some(HashMap::new()), Filters::new(Drivers::new()));
+        let ignored = Ignore::empty(&root);
+        let entry = |content: &str| (EntryType::File, Blob::new(content.into()).hash());
+
+        // `changed` differs between both commits, `same` doesn't
+        let mut index = Index::load_text(b"");
+        let mut head = BTreeMap::new();
+        for path in ["changed", "same"].iter() {
+            fs::write(root.join(path), "old\n").unwrap();
+            head.insert(path.to_string(), entry("old\n"));
+            index.entries.insert(path.to_string(), entry("old\n"));
+        }
+        let mut target = head.clone();
+        target.insert(String::from("changed"), entry("new\n"));
+        target.insert(String::from("untracked"), entry("new\n"));
+        let conflicts = check(&root, &index, &head, &target, &ignored, &attributes).unwrap();
+        assert!(conflicts.local.is_empty() && conflicts.untracked.is_empty());
+        assert!(refuse(&conflicts, Mode::Safe).is_ok());
+
+        // Modified files which the switch would overwrite refuse it, the
+        // others are carried over
+        fs::write(root.join("changed"), "local\n").unwrap();
+        fs::write(root.join("same"), "local\n").unwrap();
+        fs::write(root.join("untracked"), "local\n").unwrap();
+        let conflicts = check(&root, &index, &head, &target, &ignored, &attributes).unwrap();
+        assert_eq!(conflicts.local, vec!["changed"]);
+        assert_eq!(conflicts.untracked, vec!["untracked"]);
+        assert_eq!(conflicts.dirty, vec!["changed", "same"]);
+        let err = refuse(&conflicts, Mode::Safe).unwrap_err();
+        assert!(matches!(err, ErrorSwitch::LocalChanges(paths) if paths == vec!["changed"]));
+        let err = refuse(&conflicts, Mode::Merge).unwrap_err();
+        assert!(matches!(err, ErrorSwitch::UntrackedFiles(_)));
+        assert!(refuse(&conflicts, Mode::Discard).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
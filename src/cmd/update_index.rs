@@ -0,0 +1,101 @@
+use crate::attributes::Attributes;
+use crate::index::{Flags, Index};
+use crate::objects::{Blob, Object};
+use crate::utils;
+use clap::ArgMatches;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+/// Set or clear a flag of an entry
+fn mark(index: &mut Index, path: &str, set: impl Fn(&mut Flags)) -> Result<(), Box<dyn Error>> {
+    if !index.entries.contains_key(path) {
+        return Err(Box::new(ErrorUpdateIndex::UnableToMark(path.to_string())));
+    }
+    let mut flags = index.flags(path);
+    set(&mut flags);
+    match flags == Flags::default() {
+        true => index.flags.remove(path),
+        false => index.flags.insert(path.to_string(), flags),
+    };
+    Ok(())
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = utils::find_root()?;
+    let repo_path = utils::find_repo()?;
+    let mut index = Index::load(&repo_path)?;
+    let attributes = Attributes::load(&root);
+
+    let options = [
+        "assume-unchanged",
+        "no-assume-unchanged",
+        "skip-worktree",
+        "no-skip-worktree",
+    ];
+    let marking = options.iter().any(|option| args.is_present(option));
+    for file in args.values_of("FILE").into_iter().flatten() {
+        let path = utils::path_in_repo(file)?;
+        let path = path.to_str().unwrap();
+        if marking {
+            if args.is_present("assume-unchanged") {
+                mark(&mut index, path, |flags| flags.assume_unchanged = true)?;
+            }
+            if args.is_present("no-assume-unchanged") {
+                mark(&mut index, path, |flags| flags.assume_unchanged = false)?;
+            }
+            if args.is_present("skip-worktree") {
+                mark(&mut index, path, |flags| flags.skip_worktree = true)?;
+            }
+            if args.is_present("no-skip-worktree") {
+                mark(&mut index, path, |flags| flags.skip_worktree = false)?;
+            }
+            continue;
+        }
+
+        // Update the entry of a tracked file from the working tree
+        if !index.entries.contains_key(path) {
+            return Err(Box::new(ErrorUpdateIndex::MissingAdd(path.to_string())));
+        }
+        let full_path = root.join(path);
+        if !fs::symlink_metadata(&full_path).is_ok_and(|metadata| !metadata.is_dir()) {
+            return Err(Box::new(ErrorUpdateIndex::MissingRemove(path.to_string())));
+        }
+        let blob = Blob::from_worktree(&full_path, &attributes)?;
+        blob.save(&repo_path);
+        index.update_entry(&full_path, &blob)?;
+    }
+    index.save(&repo_path);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ErrorUpdateIndex {
+    UnableToMark(String),
+    MissingAdd(String),
+    MissingRemove(String),
+}
+
+impl fmt::Display for ErrorUpdateIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorUpdateIndex::UnableToMark(path) => {
+                write!(f, "fatal: Unable to mark file {}", path)
+            }
+            ErrorUpdateIndex::MissingAdd(path) => write!(
+                f,
+                "error: {}: cannot add to the index - missing --add option?\n\
+                 fatal: Unable to process path {}",
+                path, path
+            ),
+            ErrorUpdateIndex::MissingRemove(path) => write!(
+                f,
+                "error: {}: does not exist and --remove not passed\n\
+                 fatal: Unable to process path {}",
+                path, path
+            ),
+        }
+    }
+}
+
+impl Error for ErrorUpdateIndex {}
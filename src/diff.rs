@@ -0,0 +1,982 @@
+use crate::attributes::{Attributes, State};
+use crate::cmd::config::Config;
+use crate::index::EntryType;
+use crate::objects::{Blob, Hash, Object};
+use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+
+/// This enum represents one operation of an edit script between an old and a
+/// new sequence. Indexes refer to the position in their own sequence.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// A group of edits surrounded by some context lines
+#[derive(Debug, Eq, PartialEq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub edits: Vec<Edit>,
+}
+
+/// Split a buffer into lines keeping the trailing `\n` of each line
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut res = vec![];
+    let mut start = 0;
+    for (i, c) in data.iter().enumerate() {
+        if *c == b'\n' {
+            res.push(&data[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        res.push(&data[start..]);
+    }
+    res
+}
+
+/// Return whether a buffer looks like binary content (contains a NUL byte in
+/// its first 8000 bytes like git does).
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|c| *c == 0)
+}
+
+/// Compute the shortest edit script between `old` and `new` using Myers'
+/// algorithm.
+pub fn diff<T: Eq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    // Common prefix and suffix don't need the full algorithm
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut res: Vec<Edit> = (0..prefix).map(|i| Edit::Equal(i, i)).collect();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+    for edit in myers(old_mid, new_mid) {
+        res.push(match edit {
+            Edit::Equal(i, j) => Edit::Equal(i + prefix, j + prefix),
+            Edit::Delete(i) => Edit::Delete(i + prefix),
+            Edit::Insert(j) => Edit::Insert(j + prefix),
+        });
+    }
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    res.extend((0..suffix).map(|i| Edit::Equal(old_end + i, new_end + i)));
+
+    // Deletions are displayed before insertions in each block of changes
+    let mut start = 0;
+    while start < res.len() {
+        let end = start
+            + res[start..]
+                .iter()
+                .take_while(|e| !matches!(e, Edit::Equal(_, _)))
+                .count();
+        res[start..end].sort_by_key(|e| match e {
+            Edit::Delete(i) => (0, *i),
+            _ => (1, 0),
+        });
+        start = end + 1;
+    }
+    res
+}
+
+fn myers<T: Eq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    if n == 0 {
+        return (0..new.len()).map(Edit::Insert).collect();
+    }
+    if m == 0 {
+        return (0..old.len()).map(Edit::Delete).collect();
+    }
+
+    // Forward pass keeping a copy of the frontier for each edit distance
+    let max = n + m;
+    let offset = max as usize + 1;
+    let mut v = vec![0isize; 2 * offset + 1];
+    let mut trace = vec![];
+    'outer: for d in 0..=max {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    // Backtrack to build the edit script
+    let mut res = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let idx = (k + offset as isize) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset as isize) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            res.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                res.push(Edit::Insert(prev_y as usize));
+            } else {
+                res.push(Edit::Delete(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    res.reverse();
+    res
+}
+
+/// Group an edit script into hunks with `context` lines around changes
+pub fn hunks(edits: &[Edit], context: usize) -> Vec<Hunk> {
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal(_, _)))
+        .map(|(i, _)| i)
+        .collect();
+    groups(&changes, edits.len(), context)
+        .into_iter()
+        .map(|(start, end)| make_hunk(edits, start, end))
+        .collect()
+}
+
+/// Group the positions of changes in a sequence of `len` elements into
+/// ranges with `context` elements around them. Close changes share a range.
+fn groups(changes: &[usize], len: usize, context: usize) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(context);
+        let mut end = changes[i];
+        // Merge changes whose contexts overlap
+        while i + 1 < changes.len() && changes[i + 1] - end <= 2 * context + 1 {
+            i += 1;
+            end = changes[i];
+        }
+        res.push((start, (end + context + 1).min(len)));
+        i += 1;
+    }
+    res
+}
+
+fn make_hunk(edits: &[Edit], start: usize, end: usize) -> Hunk {
+    // Find where the hunk starts in both sequences
+    let (mut old_start, mut new_start) = (0, 0);
+    for edit in edits[..start].iter() {
+        match edit {
+            Edit::Equal(_, _) => {
+                old_start += 1;
+                new_start += 1;
+            }
+            Edit::Delete(_) => old_start += 1,
+            Edit::Insert(_) => new_start += 1,
+        }
+    }
+    let edits = edits[start..end].to_vec();
+    let old_len = edits
+        .iter()
+        .filter(|e| !matches!(e, Edit::Insert(_)))
+        .count();
+    let new_len = edits
+        .iter()
+        .filter(|e| !matches!(e, Edit::Delete(_)))
+        .count();
+    Hunk {
+        old_start,
+        old_len,
+        new_start,
+        new_len,
+        edits,
+    }
+}
+
+impl Hunk {
+    /// Format the hunk header (`@@ -a,b +c,d @@`)
+    pub fn header(&self) -> String {
+        format!(
+            "@@ -{} +{} @@",
+            range(self.old_start, self.old_len),
+            range(self.new_start, self.new_len)
+        )
+    }
+
+    /// Format the body of the hunk, one line per edit
+    pub fn body(&self, old: &[&[u8]], new: &[&[u8]]) -> Vec<String> {
+        let mut res = vec![];
+        for edit in self.edits.iter() {
+            let (prefix, line) = match edit {
+                Edit::Equal(i, _) => (' ', old[*i]),
+                Edit::Delete(i) => ('-', old[*i]),
+                Edit::Insert(j) => ('+', new[*j]),
+            };
+            let mut line = format!("{}{}", prefix, String::from_utf8_lossy(line));
+            if line.ends_with('\n') {
+                line.pop();
+                res.push(line);
+            } else {
+                res.push(line);
+                res.push(String::from("\\ No newline at end of file"));
+            }
+        }
+        res
+    }
+}
+
+/// Format a range of lines like hunk headers do
+pub(crate) fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Build the unified diff of two buffers (without file headers)
+pub fn unified(old: &[u8], new: &[u8], context: usize) -> String {
+    let (old, new) = (lines(old), lines(new));
+    let mut res = String::new();
+    for hunk in hunks(&diff(&old, &new), context) {
+        writeln!(res, "{}", hunk.header()).unwrap();
+        for line in hunk.body(&old, &new) {
+            writeln!(res, "{}", line).unwrap();
+        }
+    }
+    res
+}
+
+/// A file which differs between two trees
+pub struct FileChange {
+    pub path: String,
+    pub old: Option<(EntryType, Hash)>,
+    pub new: Option<(EntryType, Hash)>,
+}
+
+/// List the files which differ between two trees flattened by `Tree::files`
+pub fn changes(
+    old: &BTreeMap<String, (EntryType, Hash)>,
+    new: &BTreeMap<String, (EntryType, Hash)>,
+) -> Vec<FileChange> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(|path| FileChange {
+            path: path.clone(),
+            old: old.get(path).cloned(),
+            new: new.get(path).cloned(),
+        })
+        .collect()
+}
+
+/// Return the mode of an entry as written in trees
+fn mode(entry_type: &EntryType) -> &'static str {
+    match entry_type {
+        EntryType::File => "100644",
+        EntryType::Executable => "100755",
+        EntryType::Symlink => "120000",
+    }
+}
+
+/// Return the abbreviated hash of an entry (zeros if it doesn't exist)
+fn abbrev(entry: &Option<(EntryType, Hash)>) -> String {
+    match entry {
+        Some((_, hash)) => hash.to_string()[..7].to_string(),
+        None => String::from("0000000"),
+    }
+}
+
+/// Return the content of an entry (empty if it doesn't exist)
+fn content(repo_path: &Path, entry: &Option<(EntryType, Hash)>) -> Vec<u8> {
+    match entry {
+        Some((_, hash)) => Blob::load(repo_path, *hash).data,
+        None => vec![],
+    }
+}
+
+/// Convert a content to text with the `textconv` command of a diff driver,
+/// which is given a temporary file
+fn textconv(command: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+    let file = env::temp_dir().join(format!("my_git_textconv_{}", process::id()));
+    fs::write(&file, data)?;
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg(&file)
+        .output();
+    fs::remove_file(&file).ok();
+    Ok(output?.stdout)
+}
+
+/// Return the contents of some entries of a path as compared by the diff or
+/// None if the file is binary. The `diff` attribute forces text when set
+/// and binary when unset. It can also name a driver of the configuration
+/// making the file binary with `binary` or converting the contents with a
+/// `textconv` command.
+fn text_contents(
+    repo_path: &Path,
+    attributes: &Attributes,
+    path: &str,
+    entries: &[&Option<(EntryType, Hash)>],
+) -> Option<Vec<Vec<u8>>> {
+    let contents: Vec<Vec<u8>> = entries.iter().map(|e| content(repo_path, e)).collect();
+    let driver = match attributes.get(Path::new(path)).remove("diff") {
+        Some(State::Set) => return Some(contents),
+        Some(State::Unset) => return None,
+        Some(State::Value(driver)) => Config::load()
+            .ok()
+            .and_then(|config| config.diff.get(&driver).cloned()),
+        None => None,
+    }
+    .unwrap_or_default();
+    if driver.get("binary").map(String::as_str) == Some("true") {
+        return None;
+    }
+    if let Some(command) = driver.get("textconv") {
+        return contents
+            .into_iter()
+            .zip(entries.iter())
+            .map(|(data, entry)| match entry {
+                Some(_) => textconv(command, &data).ok(),
+                None => Some(data),
+            })
+            .collect();
+    }
+    if contents.iter().any(|data| is_binary(data)) {
+        return None;
+    }
+    Some(contents)
+}
+
+/// Color a line of a patch given its prefixes
+fn color_line(line: &str, prefix_len: usize) -> String {
+    let prefix: String = line.chars().take(prefix_len).collect();
+    if prefix.contains('+') {
+        line.green().to_string()
+    } else if prefix.contains('-') {
+        line.red().to_string()
+    } else {
+        line.to_string()
+    }
+}
+
+/// Format the patch of a changed file like `git diff` does
+pub fn patch(repo_path: &Path, attributes: &Attributes, change: &FileChange) -> String {
+    let path = &change.path;
+    let mut meta = vec![format!("diff --git a/{} b/{}", path, path)];
+    match (&change.old, &change.new) {
+        (None, Some((new_type, _))) => meta.push(format!("new file mode {}", mode(new_type))),
+        (Some((old_type, _)), None) => meta.push(format!("deleted file mode {}", mode(old_type))),
+        (Some((old_type, _)), Some((new_type, _))) if old_type != new_type => {
+            meta.push(format!("old mode {}", mode(old_type)));
+            meta.push(format!("new mode {}", mode(new_type)));
+        }
+        _ => (),
+    }
+    let same_content = match (&change.old, &change.new) {
+        (Some((_, old)), Some((_, new))) => old == new,
+        _ => false,
+    };
+    if !same_content {
+        let mut index = format!("index {}..{}", abbrev(&change.old), abbrev(&change.new));
+        if let (Some((old_type, _)), Some((new_type, _))) = (&change.old, &change.new) {
+            if old_type == new_type {
+                index.push_str(&format!(" {}", mode(new_type)));
+            }
+        }
+        meta.push(index);
+    }
+    let mut res: String = meta
+        .iter()
+        .map(|line| format!("{}\n", line.bold()))
+        .collect();
+    if same_content {
+        return res;
+    }
+
+    let old_name = match change.old {
+        Some(_) => format!("a/{}", path),
+        None => String::from("/dev/null"),
+    };
+    let new_name = match change.new {
+        Some(_) => format!("b/{}", path),
+        None => String::from("/dev/null"),
+    };
+    let contents = text_contents(repo_path, attributes, path, &[&change.old, &change.new]);
+    let (old, new) = match contents.as_deref() {
+        Some([old, new]) => (old, new),
+        _ => {
+            writeln!(res, "Binary files {} and {} differ", old_name, new_name).unwrap();
+            return res;
+        }
+    };
+    writeln!(res, "{}", format!("--- {}", old_name).bold()).unwrap();
+    writeln!(res, "{}", format!("+++ {}", new_name).bold()).unwrap();
+    let (old, new) = (lines(old), lines(new));
+    for hunk in hunks(&diff(&old, &new), 3) {
+        writeln!(res, "{}", hunk.header().cyan()).unwrap();
+        for line in hunk.body(&old, &new) {
+            writeln!(res, "{}", color_line(&line, 1)).unwrap();
+        }
+    }
+    res
+}
+
+/// Count the lines added and removed by a change or return the sizes of
+/// the old and new contents for binary files
+fn count(
+    repo_path: &Path,
+    attributes: &Attributes,
+    change: &FileChange,
+) -> Result<(usize, usize), (usize, usize)> {
+    let contents = text_contents(
+        repo_path,
+        attributes,
+        &change.path,
+        &[&change.old, &change.new],
+    );
+    let (old, new) = match contents.as_deref() {
+        Some([old, new]) => (old, new),
+        _ => {
+            let (old, new) = (
+                content(repo_path, &change.old),
+                content(repo_path, &change.new),
+            );
+            return Err((old.len(), new.len()));
+        }
+    };
+    let edits = diff(&lines(old), &lines(new));
+    let added = edits
+        .iter()
+        .filter(|e| matches!(e, Edit::Insert(_)))
+        .count();
+    let removed = edits
+        .iter()
+        .filter(|e| matches!(e, Edit::Delete(_)))
+        .count();
+    Ok((added, removed))
+}
+
+/// Format the summary of the changes like `--stat` does
+pub fn stat(repo_path: &Path, attributes: &Attributes, changes: &[FileChange]) -> String {
+    // The graph is scaled down when a file has too many changes
+    const MAX_WIDTH: usize = 50;
+    let counts: Vec<Result<(usize, usize), (usize, usize)>> = changes
+        .iter()
+        .map(|change| count(repo_path, attributes, change))
+        .collect();
+    let max = counts
+        .iter()
+        .map(|count| count.map_or(0, |(added, removed)| added + removed))
+        .max()
+        .unwrap_or(0);
+    let name_width = changes.iter().map(|c| c.path.len()).max().unwrap_or(0);
+    let count_width = max.to_string().len();
+    let scale = |n: usize| {
+        if max <= MAX_WIDTH || n == 0 {
+            n
+        } else {
+            (n * MAX_WIDTH / max).max(1)
+        }
+    };
+
+    let mut res = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for (change, count) in changes.iter().zip(counts.iter()) {
+        match count {
+            Ok((added, removed)) => {
+                insertions += added;
+                deletions += removed;
+                writeln!(
+                    res,
+                    " {:<nw$} | {:>cw$} {}{}",
+                    change.path,
+                    added + removed,
+                    "+".repeat(scale(*added)).green(),
+                    "-".repeat(scale(*removed)).red(),
+                    nw = name_width,
+                    cw = count_width
+                )
+                .unwrap();
+            }
+            Err((old, new)) => writeln!(
+                res,
+                " {:<nw$} | Bin {} -> {} bytes",
+                change.path,
+                old,
+                new,
+                nw = name_width
+            )
+            .unwrap(),
+        }
+    }
+    let plural = |n: usize, word: &str| format!("{} {}{}", n, word, if n == 1 { "" } else { "s" });
+    write!(res, " {} changed", plural(changes.len(), "file")).unwrap();
+    if insertions > 0 {
+        write!(res, ", {}(+)", plural(insertions, "insertion")).unwrap();
+    }
+    if deletions > 0 {
+        write!(res, ", {}(-)", plural(deletions, "deletion")).unwrap();
+    }
+    res.push('\n');
+    res
+}
+
+/// A line of the result in a combined diff with the lines removed from the
+/// parents just before it. The last row has no line, only the lines removed
+/// at the end.
+struct Row<'a> {
+    line: &'a [u8],
+    /// The parents which don't have the line
+    added: Vec<bool>,
+    /// The removed lines with the parents which had them
+    lost: Vec<(&'a [u8], Vec<bool>)>,
+    /// Shown in a hunk
+    mark: bool,
+    /// Leading context of a hunk, the lines removed before it are not shown
+    no_pre_delete: bool,
+}
+
+/// Interleave the lines of the result with the lines removed from each parent
+fn combined_rows<'a>(parents_lines: &[Vec<&'a [u8]>], result_lines: &[&'a [u8]]) -> Vec<Row<'a>> {
+    let n = parents_lines.len();
+    let mut rows: Vec<Row> = result_lines
+        .iter()
+        .chain(Some(&&b""[..]))
+        .map(|line| Row {
+            line,
+            added: vec![false; n],
+            lost: vec![],
+            mark: false,
+            no_pre_delete: false,
+        })
+        .collect();
+    for (i, parent_lines) in parents_lines.iter().enumerate() {
+        let mut next = 0;
+        // Lines removed from several parents are shown once
+        let mut cursor = 0;
+        for edit in diff(parent_lines, result_lines) {
+            match edit {
+                Edit::Equal(_, k) => {
+                    next = k + 1;
+                    cursor = 0;
+                }
+                Edit::Insert(k) => {
+                    rows[k].added[i] = true;
+                    next = k + 1;
+                    cursor = 0;
+                }
+                Edit::Delete(j) => {
+                    let line = parent_lines[j];
+                    let lost = &mut rows[next].lost;
+                    match (cursor..lost.len()).find(|l| lost[*l].0 == line) {
+                        Some(l) => {
+                            lost[l].1[i] = true;
+                            cursor = l + 1;
+                        }
+                        None => {
+                            let mut from = vec![false; n];
+                            from[i] = true;
+                            lost.push((line, from));
+                            cursor = lost.len();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    rows
+}
+
+/// Return the first row from `i` which is marked, or not marked with
+/// `unmarked`, or the number of rows if there is none
+fn find_next(rows: &[Row], i: usize, unmarked: bool) -> usize {
+    (i..rows.len())
+        .find(|r| rows[*r].mark != unmarked)
+        .unwrap_or(rows.len())
+}
+
+/// Move back the end of a hunk when its last row only has removed lines,
+/// the line of the row itself is already some context
+fn adjust_hunk_tail(rows: &[Row], begin: usize, end: usize) -> usize {
+    if begin < end && !rows[end - 1].added.contains(&true) {
+        end - 1
+    } else {
+        end
+    }
+}
+
+/// Mark the rows of the hunks of a dense combined diff like git does: the
+/// changes close to each other are grouped in hunks, the hunks where the
+/// result only takes lines from one parent or the other are dropped and the
+/// others are given some context. Return whether there is any hunk left.
+fn mark_hunks(rows: &mut [Row], context: usize) -> bool {
+    let len = rows.len();
+    for row in rows.iter_mut() {
+        row.mark = row.added.contains(&true) || !row.lost.is_empty();
+    }
+    let mut i = 0;
+    loop {
+        i = find_next(rows, i, false);
+        if i == len {
+            break;
+        }
+        let begin = i;
+        let mut end = i + 1;
+        while end < len {
+            if !rows[end].mark {
+                // Continue the hunk if a change is close enough
+                let ahead = (adjust_hunk_tail(rows, begin, end) + context).min(len);
+                match (end..ahead).rev().find(|r| rows[*r].mark) {
+                    Some(r) => end = r,
+                    None => break,
+                }
+            }
+            end += 1;
+        }
+
+        // Only keep the hunks with more than two versions of the lines, or
+        // with a result different from all the parents
+        let mut diffs = rows[begin..end].iter().flat_map(|row| {
+            let added = Some(&row.added).filter(|added| added.contains(&true));
+            added
+                .into_iter()
+                .chain(row.lost.iter().map(|(_, from)| from))
+        });
+        let first = diffs.next();
+        let same = diffs.all(|diff| Some(diff) == first);
+        if same && first.is_none_or(|first| first.contains(&false)) {
+            for row in rows[begin..end].iter_mut() {
+                row.mark = false;
+            }
+        }
+        i = end;
+    }
+
+    let mut i = find_next(rows, 0, false);
+    if i == len {
+        return false;
+    }
+    while i < len {
+        for row in rows[i.saturating_sub(context)..i].iter_mut() {
+            row.no_pre_delete |= !row.mark;
+            row.mark = true;
+        }
+        loop {
+            let end = find_next(rows, i, true);
+            if end == len {
+                return true;
+            }
+            let next = find_next(rows, end, false);
+            let end = adjust_hunk_tail(rows, i, end);
+            i = next;
+            if next < end + context {
+                // Join the hunks close to each other
+                for row in rows[end..next].iter_mut() {
+                    row.mark = true;
+                }
+            } else {
+                for row in rows[end..(end + context).min(len)].iter_mut() {
+                    row.mark = true;
+                }
+                break;
+            }
+        }
+    }
+    true
+}
+
+/// Format the dense combined diff of a merge result against its parents like
+/// `git show` does for merge commits. The hunks where the result takes the
+/// lines of one parent or the other are not shown, an empty string is
+/// returned if there is none left.
+pub fn combined(
+    repo_path: &Path,
+    attributes: &Attributes,
+    path: &str,
+    parents: &[Option<(EntryType, Hash)>],
+    result: &Option<(EntryType, Hash)>,
+) -> String {
+    let mut res = format!("{}\n", format!("diff --cc {}", path).bold());
+    let parents_abbrev: Vec<String> = parents.iter().map(abbrev).collect();
+    writeln!(
+        res,
+        "{}",
+        format!("index {}..{}", parents_abbrev.join(","), abbrev(result)).bold()
+    )
+    .unwrap();
+    let entries: Vec<_> = parents.iter().chain(Some(result)).collect();
+    let mut parents_data = match text_contents(repo_path, attributes, path, &entries) {
+        Some(contents) => contents,
+        None => {
+            writeln!(res, "Binary files differ").unwrap();
+            return res;
+        }
+    };
+    let result_data = parents_data.pop().unwrap();
+    writeln!(res, "{}", format!("--- a/{}", path).bold()).unwrap();
+    writeln!(res, "{}", format!("+++ b/{}", path).bold()).unwrap();
+
+    let result_lines = lines(&result_data);
+    let parents_lines: Vec<Vec<&[u8]>> = parents_data.iter().map(|data| lines(data)).collect();
+    let n = parents.len();
+    let mut rows = combined_rows(&parents_lines, &result_lines);
+    if !mark_hunks(&mut rows, 3) {
+        return String::new();
+    }
+
+    // Line numbers in each parent at each row, before its removed lines
+    let last = rows.len() - 1;
+    let mut parents_lno = vec![vec![1; n]];
+    for (r, row) in rows.iter().enumerate() {
+        let mut lno = parents_lno.last().unwrap().clone();
+        for (i, lno) in lno.iter_mut().enumerate() {
+            *lno += row.lost.iter().filter(|(_, from)| from[i]).count();
+            *lno += (r < last && !row.added[i]) as usize;
+        }
+        parents_lno.push(lno);
+    }
+
+    let mut start = find_next(&rows, 0, false);
+    while start < rows.len() {
+        let end = find_next(&rows, start, true);
+        let mut ranges: Vec<String> = (0..n)
+            .map(|i| {
+                let lno = parents_lno[start][i];
+                format!("-{},{}", lno, parents_lno[end][i] - lno)
+            })
+            .collect();
+        ranges.push(format!("+{},{}", start + 1, end.min(last) - start));
+        let marker = "@".repeat(n + 1);
+        writeln!(
+            res,
+            "{}",
+            format!("{} {} {}", marker, ranges.join(" "), marker).cyan()
+        )
+        .unwrap();
+        for (r, row) in rows.iter().enumerate().take(end).skip(start) {
+            let lost = match row.no_pre_delete {
+                true => &[][..],
+                false => &row.lost,
+            };
+            for (line, from) in lost {
+                let prefix: String = from.iter().map(|f| if *f { '-' } else { ' ' }).collect();
+                let line = String::from_utf8_lossy(line);
+                let line = format!("{}{}", prefix, line.trim_end_matches('\n'));
+                writeln!(res, "{}", color_line(&line, n)).unwrap();
+            }
+            if r == last {
+                break;
+            }
+            let prefix: String = row
+                .added
+                .iter()
+                .map(|a| if *a { '+' } else { ' ' })
+                .collect();
+            let line = String::from_utf8_lossy(row.line);
+            let line = format!("{}{}", prefix, line.trim_end_matches('\n'));
+            writeln!(res, "{}", color_line(&line, n)).unwrap();
+        }
+        start = find_next(&rows, end, false);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::in_repo;
+
+    fn apply(old: &[&str], new: &[&str], edits: &[Edit]) -> Vec<String> {
+        let mut res = vec![];
+        for edit in edits {
+            match edit {
+                Edit::Equal(i, j) => {
+                    assert_eq!(old[*i], new[*j]);
+                    res.push(old[*i].to_string());
+                }
+                Edit::Insert(j) => res.push(new[*j].to_string()),
+                Edit::Delete(_) => (),
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn diff_minimal() {
+        let old = ["a", "b", "c", "a", "b", "b", "a"];
+        let new = ["c", "b", "a", "b", "a", "c"];
+        let edits = diff(&old, &new);
+        let changes = edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Equal(_, _)))
+            .count();
+        assert_eq!(changes, 5);
+        assert_eq!(apply(&old, &new, &edits), new.to_vec());
+    }
+
+    #[test]
+    fn diff_empty() {
+        let old: [&str; 0] = [];
+        assert_eq!(diff(&old, &["a"]), vec![Edit::Insert(0)]);
+        assert_eq!(diff(&["a"], &old), vec![Edit::Delete(0)]);
+    }
+
+    #[test]
+    fn unified_output() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = b"1\n2\n3\n4\nfive\n6\n7\n8\n9";
+        assert_eq!(
+            unified(old, new, 3),
+            "@@ -2,8 +2,8 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n-9\n+9\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn groups_merge_close_changes() {
+        assert_eq!(groups(&[2, 8, 30], 40, 3), vec![(0, 12), (27, 34)]);
+    }
+
+    #[test]
+    fn hunks_split() {
+        let old: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[1] = String::from("x");
+        new[18] = String::from("y");
+        let hunks = hunks(&diff(&old, &new), 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header(), "@@ -1,5 +1,5 @@");
+        assert_eq!(hunks[1].header(), "@@ -16,5 +16,5 @@");
+    }
+
+    /// Save the contents as blobs and return them as file entries
+    fn save_blobs(repo_path: &Path, contents: &[&str]) -> Vec<Option<(EntryType, Hash)>> {
+        contents
+            .iter()
+            .map(|data| {
+                let blob = Blob::new(data.as_bytes().to_vec());
+                blob.save(repo_path);
+                Some((EntryType::File, blob.hash()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn combined_two_parents() {
+        colored::control::set_override(false);
+        in_repo("combined", |root| {
+            let repo_path = root.join(".my_git");
+            let mut entries = save_blobs(
+                &repo_path,
+                &[
+                    "1\n2\n3\n4\nmain\n",
+                    "1\nside\n3\n4\n5\n",
+                    "1\nside\n3\nboth\n4\nmain\n",
+                ],
+            );
+            let result = entries.pop().unwrap();
+            let attributes = Attributes::load(root);
+            assert_eq!(
+                combined(&repo_path, &attributes, "f", &entries, &result),
+                "diff --cc f\n\
+                 index 8c0d3be,cd85c7d..a1d5fea\n\
+                 --- a/f\n\
+                 +++ b/f\n\
+                 @@@ -1,5 -1,5 +1,6 @@@\n  1\n- 2\n+ side\n  3\n++both\n  4\n \
+                 -5\n +main\n"
+            );
+
+            // The hunks taking the lines of one of the parents are not
+            // worth showing
+            let mut entries = save_blobs(
+                &repo_path,
+                &[
+                    "1\n2\n3\n4\n5\n6\n7\n8\n9\nmain\n",
+                    "1\nside\n3\n4\n5\n6\n7\n8\n9\n10\n",
+                    "1\nside\n3\n4\n5\n6\n7\n8\n9\nmain\n",
+                ],
+            );
+            let result = entries.pop().unwrap();
+            assert_eq!(
+                combined(&repo_path, &attributes, "f", &entries, &result),
+                ""
+            );
+        });
+    }
+
+    #[test]
+    fn stat_output() {
+        colored::control::set_override(false);
+        in_repo("stat", |root| {
+            let repo_path = root.join(".my_git");
+            let contents = ["1\n2\n3\n", "1\ntwo\nthree\n", "new\n", "\0"];
+            let entries = save_blobs(&repo_path, &contents);
+            let changes = [
+                FileChange {
+                    path: String::from("bin"),
+                    old: None,
+                    new: entries[3].clone(),
+                },
+                FileChange {
+                    path: String::from("file"),
+                    old: entries[0].clone(),
+                    new: entries[1].clone(),
+                },
+                FileChange {
+                    path: String::from("new"),
+                    old: None,
+                    new: entries[2].clone(),
+                },
+            ];
+            assert_eq!(
+                stat(&repo_path, &Attributes::load(root), &changes),
+                " bin  | Bin 0 -> 1 bytes\n \
+                 file | 4 ++--\n \
+                 new  | 1 +\n \
+                 3 files changed, 3 insertions(+), 2 deletions(-)\n"
+            );
+        });
+    }
+}
@@ -0,0 +1,331 @@
+use crate::cmd::config::{Config, Drivers};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+/// The way content goes through a filter
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the working tree to the repository
+    Clean,
+    /// From the repository to the working tree
+    Smudge,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Clean => write!(f, "clean"),
+            Direction::Smudge => write!(f, "smudge"),
+        }
+    }
+}
+
+/// Largest content of a packet, its length on 4 hex digits included
+const MAX_PACKET: usize = 65520 - 4;
+
+fn write_packet(out: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+fn write_flush(out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(b"0000")
+}
+
+/// Read a packet, None for a flush packet
+fn read_packet(input: &mut dyn Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad packet length"))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut data = vec![0; len.saturating_sub(4)];
+    input.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Read text packets up to a flush packet
+fn read_lines(input: &mut dyn Read) -> io::Result<Vec<String>> {
+    let mut res = vec![];
+    while let Some(packet) = read_packet(input)? {
+        let line = String::from_utf8_lossy(&packet);
+        res.push(line.trim_end_matches('\n').to_string());
+    }
+    Ok(res)
+}
+
+/// Read the packets of a content up to a flush packet
+fn read_content(input: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut res = vec![];
+    while let Some(mut packet) = read_packet(input)? {
+        res.append(&mut packet);
+    }
+    Ok(res)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A long-running filter process speaking the version 2 of git's filter
+/// protocol, it filters every file of a command
+struct Process {
+    child: Child,
+    output: BufReader<ChildStdout>,
+    capabilities: Vec<String>,
+}
+
+impl Process {
+    fn start(command: &str) -> io::Result<Process> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut process = Process {
+            output: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            capabilities: vec![],
+        };
+        process.handshake()?;
+        Ok(process)
+    }
+
+    fn input(&mut self) -> &mut ChildStdin {
+        self.child.stdin.as_mut().unwrap()
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        write_packet(self.input(), b"git-filter-client\n")?;
+        write_packet(self.input(), b"version=2\n")?;
+        write_flush(self.input())?;
+        self.input().flush()?;
+        let welcome = read_lines(&mut self.output)?;
+        if welcome.first().map(String::as_str) != Some("git-filter-server")
+            || !welcome.iter().any(|line| line == "version=2")
+        {
+            return Err(protocol_error("unexpected filter process handshake"));
+        }
+        write_packet(self.input(), b"capability=clean\n")?;
+        write_packet(self.input(), b"capability=smudge\n")?;
+        write_flush(self.input())?;
+        self.input().flush()?;
+        self.capabilities = read_lines(&mut self.output)?
+            .iter()
+            .filter_map(|line| line.strip_prefix("capability="))
+            .map(String::from)
+            .collect();
+        Ok(())
+    }
+
+    /// Filter a file, the content is left as is if the process doesn't
+    /// support the direction. The status of a failure is returned as an
+    /// error.
+    fn filter(
+        &mut self,
+        direction: Direction,
+        path: &str,
+        data: &[u8],
+    ) -> io::Result<Result<Vec<u8>, String>> {
+        if !self.capabilities.contains(&direction.to_string()) {
+            return Ok(Ok(data.to_vec()));
+        }
+        write_packet(self.input(), format!("command={}\n", direction).as_bytes())?;
+        write_packet(self.input(), format!("pathname={}\n", path).as_bytes())?;
+        write_flush(self.input())?;
+        for chunk in data.chunks(MAX_PACKET) {
+            write_packet(self.input(), chunk)?;
+        }
+        write_flush(self.input())?;
+        self.input().flush()?;
+
+        let status = |lines: Vec<String>, previous: &str| {
+            lines
+                .iter()
+                .rev()
+                .find_map(|line| line.strip_prefix("status="))
+                .unwrap_or(previous)
+                .to_string()
+        };
+        let first = status(read_lines(&mut self.output)?, "");
+        if first != "success" {
+            return Ok(Err(first));
+        }
+        let content = read_content(&mut self.output)?;
+        // The status may change after the content
+        match status(read_lines(&mut self.output)?, &first).as_str() {
+            "success" => Ok(Ok(content)),
+            status => Ok(Err(status.to_string())),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Closing the input tells the process to exit
+        self.child.stdin.take();
+        let _ = self.child.wait();
+    }
+}
+
+/// Run a one-shot filter command with the content on its standard input.
+/// `%f` in the command is replaced by the quoted path.
+fn run_command(command: &str, path: &str, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command.replace("%f", &quoted))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Write from another thread so that a large output can't block it
+    let mut input = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || input.write_all(&data));
+    let output = child.wait_with_output()?;
+    let written = writer.join().unwrap();
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "external filter '{}' failed",
+            command
+        )));
+    }
+    // The filter may ignore its input
+    if let Err(e) = written {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            return Err(e);
+        }
+    }
+    Ok(output.stdout)
+}
+
+/// The content filters of the configuration, selected by the `filter`
+/// attribute. Each filter has `clean` and `smudge` commands or a long-running
+/// `process`, a `required` filter must succeed.
+pub struct Filters {
+    drivers: Drivers,
+    /// Started processes by filter, None once a process failed
+    processes: Mutex<HashMap<String, Option<Process>>>,
+}
+
+impl Filters {
+    pub fn load() -> Filters {
+        let drivers = Config::load()
+            .map(|config| config.filter)
+            .unwrap_or_default();
+        Filters::new(drivers)
+    }
+
+    pub fn new(drivers: Drivers) -> Filters {
+        Filters {
+            drivers,
+            processes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Filter the content of a path (relative to the root) with the filter
+    /// `name`. Unknown filters leave the content as is.
+    pub fn apply(
+        &self,
+        name: &str,
+        direction: Direction,
+        path: &str,
+        data: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        let empty = BTreeMap::new();
+        let driver = self.drivers.get(name).unwrap_or(&empty);
+        let required = driver.get("required").map(String::as_str) == Some("true");
+        let failed = |message: String| {
+            if required {
+                Err(io::Error::other(format!(
+                    "fatal: {}: {} filter '{}' failed",
+                    path, direction, name
+                )))
+            } else {
+                eprintln!("error: {}", message);
+                Ok(data.clone())
+            }
+        };
+
+        if let Some(command) = driver.get("process") {
+            let mut processes = self.processes.lock().unwrap();
+            let process = processes
+                .entry(name.to_string())
+                .or_insert_with(|| Process::start(command).ok());
+            let res = match process {
+                Some(process) => process.filter(direction, path, &data),
+                None => return failed(format!("cannot start filter process '{}'", command)),
+            };
+            return match res {
+                Ok(Ok(content)) => Ok(content),
+                Ok(Err(status)) => {
+                    if status == "abort" {
+                        *process = None;
+                    }
+                    failed(format!("external filter '{}' failed", command))
+                }
+                Err(e) => {
+                    *process = None;
+                    failed(format!("external filter '{}' failed: {}", command, e))
+                }
+            };
+        }
+
+        let command = match direction {
+            Direction::Clean => driver.get("clean"),
+            Direction::Smudge => driver.get("smudge"),
+        };
+        match command {
+            Some(command) => match run_command(command, path, data.clone()) {
+                Ok(content) => Ok(content),
+                Err(e) => failed(e.to_string()),
+            },
+            None if required => failed(String::new()),
+            None => Ok(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        let mut out = vec![];
+        write_packet(&mut out, b"version=2\n").unwrap();
+        write_flush(&mut out).unwrap();
+        write_packet(&mut out, &[1, 2, 3]).unwrap();
+        write_flush(&mut out).unwrap();
+        assert_eq!(&out[..14], b"000eversion=2\n");
+        let mut input = &out[..];
+        assert_eq!(read_lines(&mut input).unwrap(), vec!["version=2"]);
+        assert_eq!(read_content(&mut input).unwrap(), vec![1, 2, 3]);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn commands() {
+        let mut drivers = Drivers::new();
+        let mut upper = BTreeMap::new();
+        upper.insert(String::from("clean"), String::from("tr a-z A-Z"));
+        upper.insert(String::from("smudge"), String::from("false"));
+        drivers.insert(String::from("upper"), upper);
+        let filters = Filters::new(drivers);
+        let clean = filters.apply("upper", Direction::Clean, "f", b"abc".to_vec());
+        assert_eq!(clean.unwrap(), b"ABC");
+        // A failing filter which isn't required leaves the content as is
+        let smudge = filters.apply("upper", Direction::Smudge, "f", b"ABC".to_vec());
+        assert_eq!(smudge.unwrap(), b"ABC");
+        let unknown = filters.apply("other", Direction::Clean, "f", b"abc".to_vec());
+        assert_eq!(unknown.unwrap(), b"abc");
+    }
+}
@@ -0,0 +1,301 @@
+use crate::cmd::config::Config;
+use crate::index::{EntryType, Index};
+use crate::objects::Hash;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Signature of the index extension storing the state of the monitor
+pub const SIGNATURE: &[u8; 4] = b"FSMN";
+
+/// Read an EWAH compressed bitmap as written by git, return its bits and the
+/// length read
+fn read_ewah(data: &[u8]) -> Option<(Vec<bool>, usize)> {
+    let be32 = |pos: usize| -> Option<usize> {
+        let bytes = data.get(pos..pos + 4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let size = be32(0)?;
+    let count = be32(4)?;
+    let mut words = vec![];
+    for i in 0..count {
+        let bytes = data.get(8 + 8 * i..16 + 8 * i)?;
+        let mut word = [0; 8];
+        word.copy_from_slice(bytes);
+        words.push(u64::from_be_bytes(word));
+    }
+
+    // Each marker word is a run of identical words followed by literal words
+    let mut bits = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        let marker = words[i];
+        let run = (marker >> 1) & 0xffff_ffff;
+        let literals = (marker >> 33) as usize;
+        let fill = if marker & 1 == 1 { u64::MAX } else { 0 };
+        for _ in 0..run {
+            bits.extend((0..64).map(|bit| fill >> bit & 1 == 1));
+        }
+        for word in words.get(i + 1..i + 1 + literals)? {
+            bits.extend((0..64).map(|bit| word >> bit & 1 == 1));
+        }
+        i += 1 + literals;
+    }
+    bits.resize(size, false);
+    Some((bits, 12 + 8 * count))
+}
+
+/// Write a bitmap with the EWAH format, without compressing it
+fn write_ewah(out: &mut Vec<u8>, bits: &[bool]) {
+    let words: Vec<u64> = bits
+        .chunks(64)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |word, (bit, set)| word | (*set as u64) << bit)
+        })
+        .collect();
+    out.extend(&(bits.len() as u32).to_be_bytes());
+    out.extend(&(words.len() as u32 + 1).to_be_bytes());
+    out.extend(&((words.len() as u64) << 33).to_be_bytes());
+    for word in words.iter() {
+        out.extend(&word.to_be_bytes());
+    }
+    // Position of the last marker word
+    out.extend(&0u32.to_be_bytes());
+}
+
+/// The state of the file system monitor stored with the index: the token of
+/// its last query and the entries whose file was unchanged then. An entry
+/// updated since isn't valid anymore.
+pub struct Fsmonitor {
+    pub token: String,
+    valid: HashMap<String, (EntryType, Hash)>,
+}
+
+impl Fsmonitor {
+    /// Whether the file of an entry is known to be unchanged
+    pub fn is_valid(&self, path: &str, entry: &(EntryType, Hash)) -> bool {
+        self.valid.get(path) == Some(entry)
+    }
+
+    pub fn validate(&mut self, path: &str, entry: &(EntryType, Hash)) {
+        self.valid.insert(path.to_string(), entry.clone());
+    }
+
+    pub fn invalidate(&mut self, path: &str) {
+        self.valid.remove(path);
+    }
+
+    /// Parse the content of the index extension (version 2 of git's format),
+    /// its bitmap tells which entries aren't valid. `paths` are the paths of
+    /// the entries of the index file, None for the ignored ones.
+    pub fn parse(
+        data: &[u8],
+        paths: &[Option<String>],
+        entries: &BTreeMap<String, (EntryType, Hash)>,
+    ) -> Option<Fsmonitor> {
+        if data.get(0..4)? != 2u32.to_be_bytes() {
+            return None;
+        }
+        let len = data[4..].iter().position(|c| *c == 0)?;
+        let token = String::from_utf8(data[4..4 + len].to_vec()).ok()?;
+        let (dirty, _) = read_ewah(data.get(4 + len + 5..)?)?;
+        let valid = paths
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !dirty.get(*i).cloned().unwrap_or(false))
+            .filter_map(|(_, path)| path.as_ref())
+            .filter_map(|path| Some((path.clone(), entries.get(path)?.clone())))
+            .collect();
+        Some(Fsmonitor { token, valid })
+    }
+
+    /// Serialize the state for the index extension. `paths` are the paths of
+    /// the entries written, None for the ignored ones.
+    pub fn dump(
+        &self,
+        paths: &[Option<&str>],
+        entries: &BTreeMap<String, (EntryType, Hash)>,
+    ) -> Vec<u8> {
+        let mut data = 2u32.to_be_bytes().to_vec();
+        data.extend_from_slice(self.token.as_bytes());
+        data.push(0);
+        let dirty: Vec<bool> = paths
+            .iter()
+            .map(
+                |path| match path.and_then(|path| Some((path, entries.get(path)?))) {
+                    Some((path, entry)) => !self.is_valid(path, entry),
+                    None => true,
+                },
+            )
+            .collect();
+        let mut bitmap = vec![];
+        write_ewah(&mut bitmap, &dirty);
+        data.extend(&(bitmap.len() as u32).to_be_bytes());
+        data.extend(bitmap);
+        data
+    }
+}
+
+/// Paths reported changed by the monitor, relative to the root. Everything
+/// under a reported directory may have changed.
+struct Changes {
+    files: BTreeSet<String>,
+    dirs: Vec<String>,
+    /// Directories whose list of entries may have changed
+    parents: BTreeSet<String>,
+}
+
+impl Changes {
+    fn new(paths: Vec<String>) -> Changes {
+        let mut changes = Changes {
+            files: BTreeSet::new(),
+            dirs: vec![],
+            parents: BTreeSet::new(),
+        };
+        for path in paths {
+            let name = path.trim_end_matches('/');
+            let parent = name.rfind('/').map_or("", |i| &name[..i]);
+            changes.parents.insert(parent.to_string());
+            if path.ends_with('/') {
+                changes.dirs.push(path.clone());
+            } else {
+                changes.files.insert(path);
+            }
+        }
+        changes
+    }
+
+    /// Whether a file may have changed
+    fn file_changed(&self, path: &str) -> bool {
+        self.files.contains(path) || self.dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    /// Whether the entries of a directory may have changed
+    fn dir_changed(&self, dir: &str) -> bool {
+        self.parents.contains(dir) || (!dir.is_empty() && self.file_changed(&format!("{}/", dir)))
+    }
+}
+
+/// Ask the hook which paths changed since a token with version 2 of git's
+/// protocol: it prints a new token and the changed paths, separated by NUL
+/// bytes. None of the paths are returned when everything may have changed.
+fn query(root: &PathBuf, command: &str, token: &str) -> Option<(String, Option<Vec<String>>)> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg("2")
+        .arg(token)
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let mut fields = output.stdout.split(|c| *c == 0);
+    let token = String::from_utf8(fields.next()?.to_vec()).ok()?;
+    let paths: Vec<String> = fields
+        .filter(|path| !path.is_empty())
+        .map(|path| String::from_utf8_lossy(path).to_string())
+        .collect();
+    if token.is_empty() || paths.iter().any(|path| path == "/") {
+        return Some((token, None));
+    }
+    Some((token, Some(paths)))
+}
+
+/// Query the hook set by `core.fsmonitor` and forget the state of the index
+/// which may have changed since the last query: the valid entries and the
+/// untracked cache. Without a previous token, or when the hook fails,
+/// everything may have changed. Return whether the index was modified.
+pub fn refresh(root: &PathBuf, index: &mut Index) -> bool {
+    let command = Config::load().ok().and_then(|config| config.core.fsmonitor);
+    let command = match command.filter(|command| !command.is_empty()) {
+        Some(command) => command,
+        None => return index.fsmonitor.take().is_some(),
+    };
+    let previous = index.fsmonitor.take();
+    let token = previous.as_ref().map_or("", |state| state.token.as_str());
+    let (token, paths) = match query(root, &command, token) {
+        Some(res) => res,
+        None => {
+            eprintln!("warning: fsmonitor hook '{}' failed", command);
+            if let Some(cache) = &mut index.untracked {
+                cache.invalidate(|_| true);
+            }
+            return previous.is_some();
+        }
+    };
+
+    let mut state = Fsmonitor {
+        token,
+        valid: HashMap::new(),
+    };
+    match (previous, paths) {
+        (Some(previous), Some(paths)) => {
+            let changes = Changes::new(paths);
+            state.valid = previous.valid;
+            state.valid.retain(|path, _| !changes.file_changed(path));
+            if let Some(cache) = &mut index.untracked {
+                cache.invalidate(|dir| changes.dir_changed(dir));
+            }
+        }
+        _ => {
+            if let Some(cache) = &mut index.untracked {
+                cache.invalidate(|_| true);
+            }
+        }
+    }
+    index.fsmonitor = Some(state);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ewah() {
+        let bits: Vec<bool> = (0..150).map(|i| i % 3 == 0 || i == 149).collect();
+        let mut data = vec![];
+        write_ewah(&mut data, &bits);
+        let (read, len) = read_ewah(&data).unwrap();
+        assert_eq!(read, bits);
+        assert_eq!(len, data.len());
+
+        // A run of 64 set bits followed by a literal word
+        let mut data = vec![];
+        data.extend(&100u32.to_be_bytes());
+        data.extend(&2u32.to_be_bytes());
+        data.extend(&(1u64 << 33 | 1 << 1 | 1).to_be_bytes());
+        data.extend(&0b10u64.to_be_bytes());
+        data.extend(&0u32.to_be_bytes());
+        let (read, _) = read_ewah(&data).unwrap();
+        assert!(read[..64].iter().all(|bit| *bit));
+        assert_eq!(read[64..].iter().filter(|bit| **bit).count(), 1);
+        assert!(read[65]);
+    }
+
+    #[test]
+    fn changes() {
+        let changes = Changes::new(vec![String::from("a/b"), String::from("c/")]);
+        assert!(changes.file_changed("a/b") && !changes.file_changed("a/c"));
+        assert!(changes.file_changed("c/d/e"));
+        assert!(changes.dir_changed("a") && changes.dir_changed(""));
+        assert!(changes.dir_changed("c/d") && !changes.dir_changed("d"));
+    }
+
+    #[test]
+    fn hook() {
+        let root = PathBuf::from("/");
+        let hook = "printf 'next\\0a/b\\0c/\\0' #";
+        let (token, paths) = query(&root, hook, "previous").unwrap();
+        assert_eq!(token, "next");
+        assert_eq!(paths.unwrap(), vec!["a/b", "c/"]);
+        let (_, paths) = query(&root, "printf 'next\\0/\\0' #", "").unwrap();
+        assert!(paths.is_none());
+        assert!(query(&root, "false", "").is_none());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::cmd::config::Config;
+use crate::index::Index;
+use crate::utils;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Sample hooks created by `init`. They are disabled until renamed without
+/// the `.sample` suffix.
+pub const SAMPLES: [(&str, &str); 4] = [
+    (
+        "pre-commit",
+        "#!/bin/sh\n\
+         #\n\
+         # Called by \"my_git commit\" with no arguments. The hook should exit\n\
+         # with non-zero status after issuing an appropriate message if it wants\n\
+         # to stop the commit.\n\
+         #\n\
+         # To enable this hook, rename this file to \"pre-commit\".\n\
+         \n\
+         # Refuse files containing conflict markers\n\
+         if grep -rlE '^(<<<<<<<|>>>>>>>)( |$)' --exclude-dir=.my_git . ; then\n\
+         \techo \"error: conflict markers found in the files above\" >&2\n\
+         \texit 1\n\
+         fi\n",
+    ),
+    (
+        "prepare-commit-msg",
+        "#!/bin/sh\n\
+         #\n\
+         # Called by \"my_git commit\" with the name of the file that has the\n\
+         # commit message, followed by the source of the message and the commit\n\
+         # object name (for --amend). The hook may edit the message file.\n\
+         #\n\
+         # To enable this hook, rename this file to \"prepare-commit-msg\".\n\
+         \n\
+         COMMIT_MSG_FILE=$1\n\
+         COMMIT_SOURCE=$2\n\
+         SHA1=$3\n",
+    ),
+    (
+        "commit-msg",
+        "#!/bin/sh\n\
+         #\n\
+         # Called by \"my_git commit\" with one argument, the name of the file\n\
+         # that has the commit message. The hook should exit with non-zero\n\
+         # status after issuing an appropriate message if it wants to stop the\n\
+         # commit. The hook is allowed to edit the commit message file.\n\
+         #\n\
+         # To enable this hook, rename this file to \"commit-msg\".\n\
+         \n\
+         # Refuse empty subject lines\n\
+         if ! head -n 1 \"$1\" | grep -q '[^[:space:]]'; then\n\
+         \techo \"error: the commit subject is empty\" >&2\n\
+         \texit 1\n\
+         fi\n",
+    ),
+    (
+        "post-checkout",
+        "#!/bin/sh\n\
+         #\n\
+         # Called by \"my_git switch\" after the working tree has been updated\n\
+         # with the previous HEAD, the new HEAD and a flag set to 1 for a branch\n\
+         # checkout. The exit status does not affect the outcome of the command.\n\
+         #\n\
+         # To enable this hook, rename this file to \"post-checkout\".\n\
+         \n\
+         echo \"Moved HEAD from $1 to $2\"\n",
+    ),
+];
+
+/// Return the directory containing the hooks: `core.hooksPath` (relative to
+/// the root of the working tree) or `.my_git/hooks`.
+pub fn hooks_dir(repo_path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let config = Config::load()?;
+    match config.core.hooks_path {
+        Some(path) => Ok(utils::find_root()?.join(path)),
+        None => Ok(repo_path.join("hooks")),
+    }
+}
+
+/// Create the hooks directory with the sample hooks
+pub fn install_samples(repo_path: &Path) -> Result<(), Box<dyn Error>> {
+    let dir = repo_path.join("hooks");
+    fs::create_dir_all(&dir)?;
+    for (name, content) in SAMPLES.iter() {
+        let path = dir.join(format!("{}.sample", name));
+        if !path.exists() {
+            fs::write(&path, content)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the hook `name` with the given arguments and standard input if it
+/// exists and is executable. The hook runs from the root of the working tree
+/// with the same environment variables as git sets.
+///
+/// Return an error if the hook exits with a non-zero status.
+pub fn run(
+    repo_path: &PathBuf,
+    name: &str,
+    args: &[&str],
+    stdin: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let hook = hooks_dir(repo_path)?.join(name);
+    execute(&hook, &utils::find_root()?, repo_path, args, stdin)
+}
+
+/// Run a hook file from `root` if it exists and is executable
+fn execute(
+    hook: &PathBuf,
+    root: &PathBuf,
+    repo_path: &PathBuf,
+    args: &[&str],
+    stdin: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match fs::metadata(hook) {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => (),
+        _ => return Ok(()),
+    }
+
+    let repo_path = fs::canonicalize(repo_path)?;
+    let mut child = Command::new(hook)
+        .args(args)
+        .current_dir(root)
+        .env("GIT_DIR", &repo_path)
+        .env("GIT_INDEX_FILE", Index::file(&repo_path))
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .spawn()?;
+    if let Some(input) = stdin {
+        // The hook may not read its input
+        child.stdin.take().unwrap().write_all(input.as_bytes()).ok();
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        let name = hook.file_name().unwrap().to_string_lossy();
+        return Err(Box::new(HookFailed(name.to_string())));
+    }
+    Ok(())
+}
+
+/// Run a hook whose exit status doesn't affect the outcome of the command
+pub fn run_post(repo_path: &PathBuf, name: &str, args: &[&str], stdin: Option<&str>) {
+    run(repo_path, name, args, stdin).ok();
+}
+
+#[derive(Debug)]
+pub struct HookFailed(String);
+
+impl fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error: the '{}' hook exited with a non-zero status",
+            self.0
+        )
+    }
+}
+
+impl Error for HookFailed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn exit_status() {
+        let dir = env::temp_dir().join(format!("my_git_hooks_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hook = |name: &str, content: &str| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+
+        // The hook gets its arguments and input and runs from the root
+        let passing = hook("commit-msg", "#!/bin/sh\necho \"$1 $(cat)\" > out\n");
+        execute(&passing, &dir, &dir, &["MSG"], Some("input")).unwrap();
+        let out = fs::read_to_string(dir.join("out")).unwrap();
+        assert_eq!(out, "MSG input\n");
+
+        let failing = hook("pre-commit", "#!/bin/sh\nexit 1\n");
+        let err = execute(&failing, &dir, &dir, &[], None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: the 'pre-commit' hook exited with a non-zero status"
+        );
+
+        // Hooks which aren't executable are skipped
+        fs::set_permissions(&failing, fs::Permissions::from_mode(0o644)).unwrap();
+        execute(&failing, &dir, &dir, &[], None).unwrap();
+        assert!(execute(&dir.join("missing"), &dir, &dir, &[], None).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
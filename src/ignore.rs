@@ -0,0 +1,374 @@
+use crate::cmd::config::Config;
+use crate::objects::Hash;
+use dirs::home_dir;
+use regex::Regex;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A pattern of an ignore file
+#[derive(Clone)]
+pub struct Rule {
+    /// The file the rule comes from, relative to the root when inside the
+    /// repository
+    pub source: String,
+    /// Line number of the rule in its file
+    pub line: usize,
+    /// The pattern as written in the file
+    pub pattern: String,
+    /// Whether the rule re-includes the paths it matches (`!` prefix)
+    pub negated: bool,
+    dir_only: bool,
+    /// Whether the pattern is matched against the name of the path only
+    basename: bool,
+    /// Directory containing the ignore file, relative to the root
+    base: PathBuf,
+    regex: Regex,
+}
+
+/// Translate a wildcard pattern to a regex. With `pathname`, `*`, `?` and
+/// bracket expressions never match a `/` while `**` between slashes matches
+/// any number of directories. Otherwise wildcards match any character.
+pub(crate) fn translate(pattern: &str, pathname: bool) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut res = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if !pathname => res.push_str(".*"),
+            '?' if !pathname => res.push('.'),
+            '*' => {
+                let start = i;
+                while i < chars.len() && chars[i] == '*' {
+                    i += 1;
+                }
+                let alone = (start == 0 || chars[start - 1] == '/')
+                    && (i == chars.len() || chars[i] == '/');
+                if i - start < 2 || !alone {
+                    res.push_str("[^/]*");
+                } else if i == chars.len() {
+                    res.push_str(".*");
+                } else {
+                    res.push_str("(?:.*/)?");
+                    i += 1;
+                }
+                continue;
+            }
+            '?' => res.push_str("[^/]"),
+            '[' => match bracket(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    res.push_str(&class);
+                    i += len;
+                }
+                None => res.push_str(r"\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                res.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            c => res.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    res
+}
+
+/// Translate the bracket expression following a `[`, return the regex class
+/// and the number of characters used or None if it isn't closed
+fn bracket(chars: &[char]) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut i = 0;
+    if i < chars.len() && (chars[i] == '!' || chars[i] == '^') {
+        class.push_str("^/");
+        i += 1;
+    }
+    let start = i;
+    while i < chars.len() {
+        match chars[i] {
+            ']' if i > start => return Some((class + "]", i + 1)),
+            '[' if chars.get(i + 1) == Some(&':') => {
+                // Character classes like [:alpha:] are kept as is
+                let end = (i + 2..chars.len().saturating_sub(1))
+                    .find(|j| chars[*j] == ':' && chars[*j + 1] == ']')?;
+                class.extend(&chars[i..end + 2]);
+                i = end + 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                class.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            '-' => class.push('-'),
+            c => class.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    None
+}
+
+impl Rule {
+    /// Parse a line of an ignore file located in the `base` directory.
+    /// Return None for blank lines and comments.
+    pub fn parse(line: &str, base: &Path, source: &str, number: usize) -> Option<Rule> {
+        let mut pattern = line.trim_end_matches('\r').to_string();
+        // Trailing spaces are ignored unless escaped
+        while pattern.ends_with(' ') && !pattern.ends_with("\\ ") {
+            pattern.pop();
+        }
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return None;
+        }
+        let written = pattern.clone();
+
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern.remove(0);
+        }
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern.pop();
+        }
+        let basename = !pattern.contains('/');
+        if pattern.starts_with('/') {
+            pattern.remove(0);
+        }
+        if pattern.is_empty() {
+            return None;
+        }
+        let regex = Regex::new(&format!("^{}$", translate(&pattern, true))).ok()?;
+        Some(Rule {
+            source: source.to_string(),
+            line: number,
+            pattern: written,
+            negated,
+            dir_only,
+            basename,
+            base: base.to_path_buf(),
+            regex,
+        })
+    }
+
+    /// Whether the rule matches a path relative to the root
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = match path.strip_prefix(&self.base) {
+            Ok(path) if path.iter().next().is_some() => path,
+            _ => return false,
+        };
+        if self.basename {
+            let name = path.file_name().unwrap().to_str().unwrap_or("");
+            return self.regex.is_match(name);
+        }
+        let path: Vec<&str> = path.iter().map(|c| c.to_str().unwrap_or("")).collect();
+        self.regex.is_match(&path.join("/"))
+    }
+}
+
+/// Read the rules of an ignore file, a missing file has no rules
+fn read_rules(file: &Path, base: &Path, source: &str) -> Vec<Rule> {
+    match fs::read_to_string(file) {
+        Ok(content) => content
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| Rule::parse(line, base, source, i + 1))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// The global excludes file: `core.excludesFile` or `my_git/ignore` in the
+/// XDG config directory
+fn global_excludes() -> Option<PathBuf> {
+    let configured = Config::load()
+        .ok()
+        .and_then(|config| config.core.excludes_file);
+    match configured {
+        Some(path) => match path.strip_prefix("~/") {
+            Some(path) => Some(home_dir()?.join(path)),
+            None => Some(PathBuf::from(path)),
+        },
+        None => {
+            let config = match env::var_os("XDG_CONFIG_HOME") {
+                Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                _ => home_dir()?.join(".config"),
+            };
+            Some(config.join("my_git").join("ignore"))
+        }
+    }
+}
+
+/// The ignore rules of a working tree. By order of precedence they come
+/// from the `.my_gitignore` file of the directory of a path and of its
+/// parents, `.my_git/info/exclude` and the global excludes file.
+pub struct Ignore {
+    root: PathBuf,
+    /// Whether the `.my_gitignore` files are read
+    per_directory: bool,
+    /// Rules of the `.my_gitignore` file of each directory, read when needed
+    dirs: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+    /// Rules of the exclude files by order of precedence
+    excludes: Vec<Vec<Rule>>,
+    /// Hash of the content of the exclude files
+    excludes_hash: Hash,
+    /// Hash of the rules of each directory, computed when needed
+    hashes: Mutex<HashMap<PathBuf, Hash>>,
+}
+
+impl Ignore {
+    pub fn load(root: &Path) -> Ignore {
+        let info = root.join(".my_git/info/exclude");
+        let mut excludes = vec![read_rules(&info, Path::new(""), ".my_git/info/exclude")];
+        let mut content = fs::read(&info).unwrap_or_default();
+        if let Some(file) = global_excludes() {
+            let source = file.to_str().unwrap_or("").to_string();
+            excludes.push(read_rules(&file, Path::new(""), &source));
+            content.push(0);
+            content.extend(fs::read(&file).unwrap_or_default());
+        }
+        Ignore {
+            root: root.to_path_buf(),
+            per_directory: true,
+            dirs: Mutex::new(HashMap::new()),
+            excludes,
+            excludes_hash: Sha1::from(content).digest(),
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rules ignoring nothing but the repository directory
+    pub fn empty(root: &Path) -> Ignore {
+        Ignore {
+            root: root.to_path_buf(),
+            per_directory: false,
+            dirs: Mutex::new(HashMap::new()),
+            excludes: vec![],
+            excludes_hash: Sha1::new().digest(),
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Hash of the rules deciding whether the entries of a directory
+    /// (relative to the root) are ignored. It changes with the exclude files
+    /// and the `.my_gitignore` files of the directory and of its parents.
+    pub fn hash(&self, dir: &Path) -> Hash {
+        if let Some(hash) = self.hashes.lock().unwrap().get(dir) {
+            return *hash;
+        }
+        let parent = match dir.parent() {
+            Some(parent) => self.hash(parent),
+            None => self.excludes_hash,
+        };
+        let mut content = parent.bytes().to_vec();
+        if self.per_directory {
+            let file = self.root.join(dir).join(".my_gitignore");
+            content.extend(fs::read(file).unwrap_or_default());
+        }
+        let hash = Sha1::from(content).digest();
+        self.hashes.lock().unwrap().insert(dir.to_path_buf(), hash);
+        hash
+    }
+
+    fn dir_rules(&self, dir: &Path) -> Arc<Vec<Rule>> {
+        if let Some(rules) = self.dirs.lock().unwrap().get(dir) {
+            return rules.clone();
+        }
+        let file = dir.join(".my_gitignore");
+        let rules = read_rules(&self.root.join(&file), dir, file.to_str().unwrap_or(""));
+        let rules = Arc::new(rules);
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), rules.clone());
+        rules
+    }
+
+    /// The last matching rule of the most specific ignore file
+    fn rule_for(&self, path: &Path, is_dir: bool) -> Option<Rule> {
+        let mut dir = path.parent().filter(|_| self.per_directory);
+        while let Some(parent) = dir {
+            let rules = self.dir_rules(parent);
+            if let Some(rule) = rules.iter().rev().find(|r| r.matches(path, is_dir)) {
+                return Some(rule.clone());
+            }
+            dir = parent.parent();
+        }
+        self.excludes
+            .iter()
+            .find_map(|rules| rules.iter().rev().find(|r| r.matches(path, is_dir)))
+            .cloned()
+    }
+
+    /// Find the rule deciding whether a path relative to the root is ignored.
+    /// A path inside an ignored directory is ignored by the rule of the
+    /// directory, whatever its own rules say.
+    pub fn matching(&self, path: &Path, is_dir: bool) -> Option<Rule> {
+        let mut parent = PathBuf::new();
+        let components: Vec<_> = path.iter().collect();
+        for component in components.iter().take(components.len().saturating_sub(1)) {
+            parent.push(component);
+            match self.rule_for(&parent, true) {
+                Some(rule) if !rule.negated => return Some(rule),
+                _ => (),
+            }
+        }
+        self.rule_for(path, is_dir)
+    }
+
+    /// Whether a path relative to the root is ignored. The repository
+    /// directory is always ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.iter().any(|component| component == ".my_git") {
+            return true;
+        }
+        self.matching(path, is_dir)
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str) -> Rule {
+        Rule::parse(pattern, Path::new(""), ".my_gitignore", 1).unwrap()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(rule("*.log").matches(Path::new("a/b/c.log"), false));
+        assert!(!rule("/*.log").matches(Path::new("a/c.log"), false));
+        assert!(rule("a/*.log").matches(Path::new("a/c.log"), false));
+        assert!(!rule("a/*.log").matches(Path::new("a/b/c.log"), false));
+        assert!(rule("**/b").matches(Path::new("a/x/b"), true));
+        assert!(rule("**/b").matches(Path::new("b"), false));
+        assert!(rule("a/**/b").matches(Path::new("a/b"), false));
+        assert!(rule("a/**/b").matches(Path::new("a/x/y/b"), false));
+        assert!(rule("a/**").matches(Path::new("a/x/y"), false));
+        assert!(!rule("a/**").matches(Path::new("a"), true));
+        assert!(rule("f[!a-c]o").matches(Path::new("fdo"), false));
+        assert!(!rule("f[!a-c]o").matches(Path::new("fbo"), false));
+        assert!(rule("f[[:digit:]]").matches(Path::new("f1"), false));
+        assert!(rule(r"\#a\!").matches(Path::new("#a!"), false));
+        assert!(rule("a\\ ").matches(Path::new("a "), false));
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(Rule::parse("# comment", Path::new(""), "", 1).is_none());
+        assert!(Rule::parse("   ", Path::new(""), "", 1).is_none());
+        let dir = rule("build/");
+        assert!(dir.matches(Path::new("x/build"), true));
+        assert!(!dir.matches(Path::new("x/build"), false));
+        let negated = rule("!keep.log ");
+        assert!(negated.negated);
+        assert_eq!(negated.pattern, "!keep.log");
+        let nested = Rule::parse("/out", Path::new("sub"), "sub/.my_gitignore", 1).unwrap();
+        assert!(nested.matches(Path::new("sub/out"), false));
+        assert!(!nested.matches(Path::new("out"), false));
+        assert!(!nested.matches(Path::new("sub/x/out"), false));
+    }
+}
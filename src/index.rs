@@ -1,20 +1,27 @@
+use crate::attributes::Attributes;
+use crate::cmd::config::Config;
+use crate::fsmonitor::{self, Fsmonitor};
+use crate::ignore::Ignore;
 use crate::objects::Hash;
 use crate::objects::{Blob, Object};
+use crate::parallel;
+use crate::pathspec::Pathspec;
+use crate::sparse::Sparse;
+use crate::untracked::{self, UntrackedCache};
 use crate::utils;
-use glob::Pattern;
 use path_abs::PathAbs;
-use std::collections::HashMap;
+use sha1::Sha1;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::io::{Error, ErrorKind};
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum EntryType {
     File,
     Executable,
@@ -42,48 +49,555 @@ impl From<&str> for EntryType {
     }
 }
 
+/// The stat data of a file when its entry was last updated, truncated to 32
+/// bits like git does
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stat {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+}
+
+impl Stat {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Stat {
+        Stat {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+        }
+    }
+
+    /// Whether two stat data describe the same file content. The device is
+    /// ignored like git does since it isn't stable on every file system.
+    pub fn same(&self, other: &Stat) -> bool {
+        self.ctime == other.ctime
+            && self.mtime == other.mtime
+            && self.ino == other.ino
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.size == other.size
+    }
+}
+
+/// Flags of an entry changing how it is compared with its file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Flags {
+    /// The file is assumed to match the entry (`update-index
+    /// --assume-unchanged`)
+    pub assume_unchanged: bool,
+    /// The file is left out of the working tree (`update-index
+    /// --skip-worktree`)
+    pub skip_worktree: bool,
+    /// Only the intent to add the file is recorded (`add -N`), the entry is
+    /// left out of commits
+    pub intent_to_add: bool,
+}
+
+impl Flags {
+    /// Whether the flags need the extended flags of version 3
+    fn extended(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+
+    /// Whether the file is not compared with the entry
+    pub fn ignores_worktree(&self) -> bool {
+        self.assume_unchanged || self.skip_worktree
+    }
+}
+
+/// An entry as stored in the index file. Gitlinks and the stages of
+/// unmerged paths are only kept in this form to be written back.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawEntry {
+    pub path: String,
+    pub stage: u16,
+    mode: u32,
+    hash: Hash,
+    stat: Stat,
+    flags: Flags,
+}
+
+/// Mode of an entry in the index file
+pub fn mode(entry_type: &EntryType) -> u32 {
+    match entry_type {
+        EntryType::File => 0o100644,
+        EntryType::Executable => 0o100755,
+        EntryType::Symlink => 0o120000,
+    }
+}
+
+/// Read the variable length integer of the path compression of version 4
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut c = *data.get(*pos)?;
+    *pos += 1;
+    let mut value = usize::from(c & 127);
+    while c & 128 != 0 {
+        c = *data.get(*pos)?;
+        *pos += 1;
+        value = ((value + 1) << 7) + usize::from(c & 127);
+    }
+    Some(value)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    let mut bytes = vec![(value & 127) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(128 | (value & 127) as u8);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Path of the entry of an absolute path in the repository
+fn entry_path(path: &Path, root: &Path) -> String {
+    let path: PathBuf = path.iter().skip(root.iter().count()).collect();
+    String::from(path.to_str().unwrap())
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("error: {}\nfatal: index file corrupt", reason),
+    )
+}
+
+/// The staging area, stored in the `index` file with git's binary format
 pub struct Index {
-    pub entries: HashMap<String, (EntryType, Hash)>,
+    pub entries: BTreeMap<String, (EntryType, Hash)>,
+    /// Stat data of the files when their entries were updated
+    pub stats: HashMap<String, Stat>,
+    /// Flags of the entries which have some
+    pub flags: HashMap<String, Flags>,
+    /// Version of the file format (2, 3 or 4)
+    pub version: u32,
+    /// Modification time of the index file when it was loaded
+    timestamp: Option<(u32, u32)>,
+    /// State of the file system monitor, when `core.fsmonitor` is set
+    pub fsmonitor: Option<Fsmonitor>,
+    /// Untracked files by directory, when `core.untrackedCache` is set
+    pub untracked: Option<UntrackedCache>,
+    /// Directories checked out, when `core.sparseCheckout` is set. The other
+    /// files skip the working tree.
+    pub sparse: Option<Sparse>,
+    /// Gitlinks and unmerged stages, sorted like in the file. The stages of
+    /// a path are dropped once it has an entry.
+    pub kept: Vec<RawEntry>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+/// The files to add, the ignored files and the files outside the sparse
+/// checkout which were found by `add`
+type ToAdd = (Vec<PathBuf>, Vec<PathBuf>, Vec<PathBuf>);
+
 impl Index {
+    /// An empty index using the version configured by `index.version`
+    pub fn new() -> Index {
+        let version = Config::load()
+            .ok()
+            .and_then(|config| config.index.version)
+            .and_then(|version| version.parse().ok())
+            .filter(|version| (2..=4).contains(version))
+            .unwrap_or(2);
+        Index {
+            entries: BTreeMap::new(),
+            stats: HashMap::new(),
+            flags: HashMap::new(),
+            timestamp: None,
+            fsmonitor: None,
+            untracked: None,
+            sparse: None,
+            kept: vec![],
+            version,
+        }
+    }
+
+    /// The index file: `$GIT_INDEX_FILE` when it's set, like for the hooks
+    /// of `commit -a`, or `.my_git/index`
+    pub fn file(repo_path: &Path) -> PathBuf {
+        match env::var_os("GIT_INDEX_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => repo_path.join("index"),
+        }
+    }
+
     /// This function load the current index from the repository path.
-    pub fn load(repo_path: &PathBuf) -> Index {
-        let index = File::open(repo_path.join("index")).expect("No index found in the repository");
-        let mut index = BufReader::new(index);
-        let mut entries = HashMap::new();
-        loop {
-            let mut line = String::new();
-            match index.read_line(&mut line) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let hash = &line.split(' ').last().expect("No hash found in the entry")[..40];
-                    let hash = Hash::from_str(hash).unwrap();
-                    let size = line.split(' ').count();
-                    let entry_type = line.split(' ').skip(size - 2).next().unwrap();
-                    let entry_type = EntryType::from(entry_type);
-                    let path: Vec<&str> = line.split(' ').take(size - 2).collect();
-                    let path = path.join(" ");
-                    entries.insert(path, (entry_type, hash));
+    pub fn load(repo_path: &Path) -> Result<Index, Error> {
+        let path = Index::file(repo_path);
+        let data = fs::read(&path)?;
+        if !data.starts_with(b"DIRC") {
+            return Ok(Index::load_text(&data));
+        }
+        let mut index = Index::parse(&data)?;
+        index.timestamp = fs::metadata(&path)
+            .ok()
+            .map(|metadata| Stat::from_metadata(&metadata).mtime);
+        index.sparse = Sparse::load(repo_path);
+        Ok(index)
+    }
+
+    /// Read an index written with the former text format (one
+    /// `<path> <type> <hash>` line per entry)
+    pub(crate) fn load_text(data: &[u8]) -> Index {
+        let mut index = Index {
+            entries: BTreeMap::new(),
+            stats: HashMap::new(),
+            flags: HashMap::new(),
+            timestamp: None,
+            fsmonitor: None,
+            untracked: None,
+            sparse: None,
+            kept: vec![],
+            version: 2,
+        };
+        for line in data.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            let line = String::from_utf8_lossy(line);
+            let mut fields = line.rsplitn(3, ' ');
+            let hash = Hash::from_str(fields.next().unwrap()).expect("No hash found in the entry");
+            let entry_type = EntryType::from(fields.next().unwrap());
+            let path = fields.next().unwrap().to_string();
+            index.entries.insert(path, (entry_type, hash));
+        }
+        index
+    }
+
+    /// Parse an index file: a header, the entries sorted by path and the
+    /// extensions followed by the checksum of the file
+    fn parse(data: &[u8]) -> Result<Index, Error> {
+        if data.len() < 32 {
+            return Err(corrupt("index file smaller than expected"));
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if checksum.iter().any(|c| *c != 0) && Sha1::from(content).digest().bytes() != checksum {
+            return Err(corrupt("bad index file sha1 signature"));
+        }
+        let bytes = |from: usize, to: usize| -> Result<&[u8], Error> {
+            content
+                .get(from..to)
+                .ok_or_else(|| corrupt("index file smaller than expected"))
+        };
+        let be32 = |pos: usize| -> Result<u32, Error> {
+            bytes(pos, pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let be16 = |pos: usize| -> Result<u16, Error> {
+            bytes(pos, pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+        // The NUL terminated path starting at a position
+        let path_at = |pos: usize| -> Result<&[u8], Error> {
+            let rest = bytes(pos, content.len())?;
+            let len = rest
+                .iter()
+                .position(|c| *c == 0)
+                .ok_or_else(|| corrupt("unterminated path"))?;
+            Ok(&rest[..len])
+        };
+        let version = be32(4)?;
+        if !(2..=4).contains(&version) {
+            return Err(corrupt(&format!("bad index version {}", version)));
+        }
+        let count = be32(8)?;
+
+        let mut index = Index {
+            entries: BTreeMap::new(),
+            stats: HashMap::new(),
+            flags: HashMap::new(),
+            timestamp: None,
+            fsmonitor: None,
+            untracked: None,
+            sparse: None,
+            kept: vec![],
+            version,
+        };
+        let mut pos = 12;
+        let mut previous: Vec<u8> = vec![];
+        // Paths of the entries in the file, the file system monitor refers
+        // to them by position
+        let mut paths = vec![];
+        for _ in 0..count {
+            let start = pos;
+            let field = |i: usize| be32(start + 4 * i);
+            let stat = Stat {
+                ctime: (field(0)?, field(1)?),
+                mtime: (field(2)?, field(3)?),
+                dev: field(4)?,
+                ino: field(5)?,
+                uid: field(7)?,
+                gid: field(8)?,
+                size: field(9)?,
+            };
+            let mode = field(6)?;
+            let hash = bytes(start + 40, start + 60)?;
+            let hash: String = hash.iter().map(|c| format!("{:02x}", c)).collect();
+            let flags = be16(start + 60)?;
+            pos = start + 62;
+            let mut entry_flags = Flags {
+                assume_unchanged: flags & 0x8000 != 0,
+                ..Default::default()
+            };
+            if flags & 0x4000 != 0 {
+                if version < 3 {
+                    return Err(corrupt("extended flags in a version 2 index"));
                 }
-                Err(e) => panic!(e),
+                let extended = be16(start + 62)?;
+                entry_flags.skip_worktree = extended & 0x4000 != 0;
+                entry_flags.intent_to_add = extended & 0x2000 != 0;
+                pos += 2;
             }
+
+            let path = if version == 4 {
+                let strip = read_varint(content, &mut pos)
+                    .filter(|strip| *strip <= previous.len())
+                    .ok_or_else(|| corrupt("bad path compression"))?;
+                let name = path_at(pos)?;
+                let mut path = previous[..previous.len() - strip].to_vec();
+                path.extend_from_slice(name);
+                pos += name.len() + 1;
+                path
+            } else {
+                let path = path_at(pos)?.to_vec();
+                // Entries are padded with NUL bytes to a multiple of 8
+                pos += path.len() + 8 - (pos - start + path.len()) % 8;
+                path
+            };
+            previous = path.clone();
+
+            let path = String::from_utf8(path).map_err(|_| corrupt("invalid path"))?;
+            let hash = Hash::from_str(&hash).unwrap();
+            // Only merged entries of files are used, the others are kept
+            // to be written back
+            let stage = (flags & 0x3000) >> 12;
+            let entry_type = match mode {
+                0o100644 if stage == 0 => EntryType::File,
+                0o100755 if stage == 0 => EntryType::Executable,
+                0o120000 if stage == 0 => EntryType::Symlink,
+                _ => {
+                    paths.push(None);
+                    index.kept.push(RawEntry {
+                        path,
+                        stage,
+                        mode,
+                        hash,
+                        stat,
+                        flags: entry_flags,
+                    });
+                    continue;
+                }
+            };
+            paths.push(Some(path.clone()));
+            index.entries.insert(path.clone(), (entry_type, hash));
+            if entry_flags != Flags::default() {
+                index.flags.insert(path.clone(), entry_flags);
+            }
+            index.stats.insert(path, stat);
         }
-        Index { entries }
+
+        if pos > content.len() {
+            return Err(corrupt("index file smaller than expected"));
+        }
+
+        // Optional extensions start with an uppercase letter and can be
+        // dropped, the others must be understood
+        while pos + 8 <= content.len() {
+            let signature = &content[pos..pos + 4];
+            let size = be32(pos + 4)? as usize;
+            if !signature[0].is_ascii_uppercase() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "error: index uses {} extension, which we do not understand\n\
+                         fatal: index file corrupt",
+                        String::from_utf8_lossy(signature)
+                    ),
+                ));
+            }
+            let data = bytes(pos + 8, pos + 8 + size)?;
+            if signature == fsmonitor::SIGNATURE {
+                index.fsmonitor = Fsmonitor::parse(data, &paths, &index.entries);
+            } else if signature == untracked::SIGNATURE {
+                index.untracked = UntrackedCache::parse(data);
+            }
+            pos += 8 + size;
+        }
+        Ok(index)
     }
 
     /// Save the current index to the repository
-    pub fn save(&self, repo_path: &PathBuf) {
-        let mut dump = String::new();
-        for (path, (entry_type, hash)) in self.entries.iter() {
-            dump.push_str(format!("{} {} {}\n", path, entry_type, hash).as_str());
+    pub fn save(&self, repo_path: &Path) {
+        // Write a lock file then move it over the index
+        let path = Index::file(repo_path);
+        let lock = PathBuf::from(format!("{}.lock", path.display()));
+        fs::write(&lock, self.dump()).expect("Index writing failed");
+        fs::rename(&lock, path).expect("Index writing failed");
+    }
+
+    /// Serialize the index in the format of its version
+    fn dump(&self) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as u32)
+            .unwrap_or(0);
+
+        // Extended flags need version 3
+        let extended = self.entries.keys().any(|path| self.flags(path).extended());
+        let version = match self.version {
+            2 if extended => 3,
+            version => version,
+        };
+        // The entries and the kept ones sorted by path then by stage
+        let mut rows: Vec<RawEntry> = self
+            .entries
+            .iter()
+            .map(|(path, (entry_type, hash))| RawEntry {
+                path: path.clone(),
+                stage: 0,
+                mode: mode(entry_type),
+                hash: *hash,
+                stat: self.stats.get(path).cloned().unwrap_or_default(),
+                flags: self.flags(path),
+            })
+            .chain(
+                self.kept
+                    .iter()
+                    .filter(|kept| !self.entries.contains_key(&kept.path))
+                    .cloned(),
+            )
+            .collect();
+        rows.sort_by(|a, b| (&a.path, a.stage).cmp(&(&b.path, b.stage)));
+
+        let mut data = b"DIRC".to_vec();
+        data.extend(&version.to_be_bytes());
+        data.extend(&(rows.len() as u32).to_be_bytes());
+        let mut previous = "";
+        for row in rows.iter() {
+            let path = &row.path;
+            let mut stat = row.stat;
+            // A file modified in the same second as the index could change
+            // again without its stat data changing: the size of its entry is
+            // cleared so that its content is checked next time
+            if stat.mtime.0 >= now {
+                stat.size = 0;
+            }
+            let start = data.len();
+            for field in [
+                stat.ctime.0,
+                stat.ctime.1,
+                stat.mtime.0,
+                stat.mtime.1,
+                stat.dev,
+                stat.ino,
+                row.mode,
+                stat.uid,
+                stat.gid,
+                stat.size,
+            ]
+            .iter()
+            {
+                data.extend(&field.to_be_bytes());
+            }
+            data.extend_from_slice(&row.hash.bytes());
+            let entry_flags = row.flags;
+            let mut flags = path.len().min(0xfff) as u16 | row.stage << 12;
+            if entry_flags.assume_unchanged {
+                flags |= 0x8000;
+            }
+            if entry_flags.extended() {
+                flags |= 0x4000;
+            }
+            data.extend(&flags.to_be_bytes());
+            if entry_flags.extended() {
+                let mut extended: u16 = 0;
+                if entry_flags.skip_worktree {
+                    extended |= 0x4000;
+                }
+                if entry_flags.intent_to_add {
+                    extended |= 0x2000;
+                }
+                data.extend(&extended.to_be_bytes());
+            }
+
+            if version == 4 {
+                let common = previous
+                    .bytes()
+                    .zip(path.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_varint(&mut data, previous.len() - common);
+                data.extend_from_slice(&path.as_bytes()[common..]);
+                data.push(0);
+            } else {
+                data.extend_from_slice(path.as_bytes());
+                let len = data.len() - start;
+                data.extend(vec![0; 8 - len % 8]);
+            }
+            previous = path;
+        }
+        let mut extension = |signature: &[u8], content: Vec<u8>| {
+            data.extend_from_slice(signature);
+            data.extend(&(content.len() as u32).to_be_bytes());
+            data.extend(content);
+        };
+        if let Some(fsmonitor) = &self.fsmonitor {
+            let paths: Vec<_> = rows
+                .iter()
+                .map(|row| Some(row.path.as_str()).filter(|_| row.stage == 0))
+                .collect();
+            extension(fsmonitor::SIGNATURE, fsmonitor.dump(&paths, &self.entries));
+        }
+        if let Some(untracked) = &self.untracked {
+            extension(untracked::SIGNATURE, untracked.dump(now));
+        }
+        let checksum = Sha1::from(&data).digest().bytes();
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    /// Flags of an entry
+    pub fn flags(&self, path: &str) -> Flags {
+        self.flags.get(path).copied().unwrap_or_default()
+    }
+
+    /// Whether a file is known to match its entry from its stat data alone,
+    /// without reading its content. Entries modified after the index was
+    /// written (racily clean) and entries whose size was cleared never
+    /// match.
+    pub fn stat_matches(&self, path: &str, metadata: &fs::Metadata) -> bool {
+        let stat = match self.stats.get(path) {
+            Some(stat) => stat,
+            None => return false,
+        };
+        let racy = self
+            .timestamp
+            .is_none_or(|timestamp| stat.mtime >= timestamp);
+        stat.size != 0 && !racy && stat.same(&Stat::from_metadata(metadata))
+    }
+
+    /// Update the stat data of an entry whose file content was found
+    /// unchanged, return whether it changed
+    pub fn refresh(&mut self, path: &str, metadata: &fs::Metadata) -> bool {
+        let stat = Stat::from_metadata(metadata);
+        match self.stats.insert(path.to_string(), stat) {
+            Some(previous) => !previous.same(&stat),
+            None => true,
         }
-        fs::write(repo_path.join("index"), dump).expect("Index writing failed");
     }
 
     /// Return the type of an existing file
     pub fn get_file_type(path: &PathBuf) -> EntryType {
-        let metadata = fs::symlink_metadata(&path).unwrap();
+        let metadata = fs::symlink_metadata(path).unwrap();
         if metadata.file_type().is_symlink() {
             EntryType::Symlink
         } else if metadata.permissions().mode() & 1 == 1 {
@@ -93,64 +607,228 @@ impl Index {
         }
     }
 
-    /// Add an existing file/directory to the index
+    /// Find the files matching a pathspec which are to be added, without
+    /// ignore rules when forced. Return them, the ignored files which were
+    /// given explicitly and the files outside the sparse checkout: the
+    /// untracked ones and the ones given explicitly.
+    fn files_to_add(
+        &self,
+        pathspec: &Pathspec,
+        repo_path: &PathBuf,
+        root: &Path,
+        rules: Option<&Ignore>,
+    ) -> Result<ToAdd, Box<Error>> {
+        let threads = parallel::threads();
+        let mut files = vec![];
+        let mut fails = vec![];
+        let mut outside = vec![];
+        let mut dirs = vec![];
+        for base in pathspec.bases() {
+            let file = root.join(&base);
+            // Deleted files are handled by remove
+            let metadata = match fs::symlink_metadata(&file) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let name = base.to_str().unwrap();
+            if file.starts_with(repo_path) || !pathspec.matches(name, metadata.is_dir()) {
+                if metadata.is_dir() {
+                    dirs.push(file);
+                }
+                continue;
+            }
+
+            // Check ignored if not a force add
+            let is_ignored = match rules {
+                Some(ignored) => utils::is_ignored(&file, ignored)?,
+                None => false,
+            };
+            if !name.is_empty() && is_ignored && !self.contains_entry(name) {
+                fails.push(utils::find_relative_path(&file));
+            } else if metadata.is_dir() {
+                dirs.push(file);
+            } else if self.in_sparse(name, false) {
+                files.push(file);
+            } else {
+                outside.push(file);
+            }
+        }
+        for dir in dirs {
+            let (found, excluded) = self.walk(&dir, repo_path, root, rules, pathspec, threads)?;
+            files.extend(found);
+            outside.extend(excluded);
+        }
+        for paths in [&mut files, &mut outside].iter_mut() {
+            paths.sort();
+            paths.dedup();
+        }
+        Ok((files, fails, outside))
+    }
+
+    /// Add the files matching a pathspec to the index, without ignore rules
+    /// when forced. The files outside the sparse checkout are only added when
+    /// `sparse` is set, they don't skip the working tree anymore. Return the
+    /// ignored files which were given explicitly and the files outside the
+    /// sparse checkout which weren't added.
     pub fn add(
         &mut self,
-        path: &PathBuf,
+        pathspec: &Pathspec,
         repo_path: &PathBuf,
-        root: &PathBuf,
-        force: bool,
-        ignored: &Vec<Pattern>,
-    ) -> Result<(Vec<PathBuf>), Box<Error>> {
-        let file: PathBuf = PathAbs::new(path).unwrap().into();
-
-        // Check if the file is in the repository root
-        if !file.starts_with(root) {
-            return Err(Box::new(Error::new(
-                ErrorKind::InvalidInput,
-                format!(
-                    "fatal: {}: '{}' is outside repository",
-                    file.to_str().unwrap(),
-                    file.to_str().unwrap()
-                ),
-            )));
-        }
-
-        // Check if the file is in repository
-        if file.starts_with(&repo_path) {
-            return Ok(vec![]);
-        }
-
-        // Check ignored if not a force add
-        if !force && utils::is_ignored(&file, ignored)? && !self.contains(&file)? {
-            return Ok(vec![path.clone()]);
-        }
-
-        // Call recursively in case of dir
-        let metadata = fs::symlink_metadata(&file).unwrap();
-        if metadata.is_dir() {
-            let mut failed = vec![];
-            for file in fs::read_dir(file)? {
-                failed.append(&mut self.add(&file?.path(), repo_path, root, force, ignored)?);
-            }
-            return Ok(failed);
-        }
-
-        // Compute and save blob
-        let content = if metadata.file_type().is_symlink() {
-            fs::read_link(&file)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .as_bytes()
-                .to_vec()
-        } else {
-            fs::read(&file)?
-        };
-        let blob = Blob::new(content);
-        blob.save(&repo_path);
-        self.update_entry(&file, &blob)?;
-        Ok(vec![])
+        root: &Path,
+        rules: Option<&Ignore>,
+        attributes: &Attributes,
+        sparse: bool,
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Box<Error>> {
+        let threads = parallel::threads();
+        let (mut files, fails, mut outside) =
+            self.files_to_add(pathspec, repo_path, root, rules)?;
+        if sparse {
+            files.append(&mut outside);
+        }
+
+        // Hash and save the blobs on several threads, unchanged files aren't
+        // hashed again
+        let entries = parallel::map(threads, &files, |file| -> Result<_, Error> {
+            let path = entry_path(file, root);
+            if self.flags(&path).ignores_worktree() && self.in_sparse(&path, false) {
+                return Ok(None);
+            }
+            let metadata = fs::symlink_metadata(file)?;
+            let file_type = Self::get_file_type(file);
+            if self.entries.get(&path).map(|(entry_type, _)| entry_type) == Some(&file_type)
+                && self.stat_matches(&path, &metadata)
+            {
+                return Ok(None);
+            }
+            let blob = Blob::from_worktree(file, attributes)?;
+            blob.save(repo_path);
+            Ok(Some((
+                path,
+                file_type,
+                blob.hash(),
+                Stat::from_metadata(&metadata),
+            )))
+        });
+        for entry in entries {
+            if let Some((path, file_type, hash, stat)) = entry? {
+                self.entries.insert(path.clone(), (file_type, hash));
+                if let Some(flags) = self.flags.get_mut(&path) {
+                    flags.skip_worktree = false;
+                }
+                self.clear_intent_to_add(&path);
+                self.stats.insert(path, stat);
+            }
+        }
+        Ok((fails, outside))
+    }
+
+    /// Record the intent to add the untracked files matching a pathspec with
+    /// empty entries, without reading them. The arguments and the result are
+    /// the ones of `add`.
+    pub fn add_intent_to_add(
+        &mut self,
+        pathspec: &Pathspec,
+        repo_path: &PathBuf,
+        root: &Path,
+        rules: Option<&Ignore>,
+        sparse: bool,
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Box<Error>> {
+        let (mut files, fails, mut outside) =
+            self.files_to_add(pathspec, repo_path, root, rules)?;
+        if sparse {
+            files.append(&mut outside);
+        }
+        let empty = Blob::new(vec![]);
+        empty.save(repo_path);
+        for file in files {
+            let path = entry_path(&file, root);
+            if self.entries.contains_key(&path) {
+                continue;
+            }
+            let entry_type = Self::get_file_type(&file);
+            self.entries
+                .insert(path.clone(), (entry_type, empty.hash()));
+            let flags = Flags {
+                intent_to_add: true,
+                ..Default::default()
+            };
+            self.flags.insert(path, flags);
+        }
+        Ok((fails, outside))
+    }
+
+    /// Whether a path is in the areas of the sparse checkout, if any
+    fn in_sparse(&self, path: &str, is_dir: bool) -> bool {
+        match &self.sparse {
+            Some(sparse) if is_dir => sparse.may_contain(path),
+            Some(sparse) => sparse.includes(path),
+            None => true,
+        }
+    }
+
+    /// Return the files of a directory which match the pathspec and aren't
+    /// ignored (without rules when forced), sorted, and the untracked ones
+    /// outside the sparse checkout. The directories of each level are read on
+    /// several threads.
+    fn walk(
+        &self,
+        dir: &Path,
+        repo_path: &PathBuf,
+        root: &Path,
+        ignored: Option<&Ignore>,
+        pathspec: &Pathspec,
+        threads: usize,
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Error> {
+        let mut files = vec![];
+        let mut outside = vec![];
+        let mut dirs = vec![dir.to_path_buf()];
+        while !dirs.is_empty() {
+            let entries = parallel::map(threads, &dirs, |dir| -> Result<_, Error> {
+                let mut res = vec![];
+                for entry in fs::read_dir(dir)? {
+                    // Ignored files are only reported when given explicitly
+                    let entry = entry?;
+                    let path = entry.path();
+                    let name = entry_path(&path, root);
+                    let is_dir = entry.file_type()?.is_dir();
+                    let selected = match is_dir {
+                        true => pathspec.may_contain(&name) || pathspec.matches(&name, true),
+                        false => pathspec.matches(&name, false),
+                    };
+                    let is_ignored = match ignored {
+                        Some(ignored) => {
+                            utils::is_ignored(&path, ignored)? && !self.contains_entry(&name)
+                        }
+                        None => false,
+                    };
+                    if !selected || path.starts_with(repo_path) || is_ignored {
+                        continue;
+                    }
+                    // The tracked files outside the sparse checkout skip the
+                    // working tree
+                    let included = is_dir || self.in_sparse(&name, false);
+                    if included || !self.contains_entry(&name) {
+                        res.push((path, is_dir, included));
+                    }
+                }
+                Ok(res)
+            });
+            dirs = vec![];
+            for entries in entries {
+                for (path, is_dir, included) in entries? {
+                    if is_dir {
+                        dirs.push(path);
+                    } else if included {
+                        files.push(path);
+                    } else {
+                        outside.push(path);
+                    }
+                }
+            }
+        }
+        files.sort();
+        outside.sort();
+        Ok((files, outside))
     }
 
     /// This function add/update an entry to the index given the file path and its blob
@@ -158,16 +836,26 @@ impl Index {
         // Compute type
         let file_type = Self::get_file_type(path);
 
-        // Add the entry to the index
+        // Add the entry to the index with the stat data of the file
         let root = utils::find_root()?;
-        let file: PathBuf = path.iter().skip(root.iter().count()).collect();
-        self.entries.insert(
-            String::from(file.to_str().unwrap()),
-            (file_type, blob.hash()),
-        );
+        let file = entry_path(path, &root);
+        let stat = Stat::from_metadata(&fs::symlink_metadata(path)?);
+        self.entries.insert(file.clone(), (file_type, blob.hash()));
+        self.clear_intent_to_add(&file);
+        self.stats.insert(file, stat);
         Ok(())
     }
 
+    /// Record that the entry of a path now has some content
+    pub fn clear_intent_to_add(&mut self, path: &str) {
+        if let Some(flags) = self.flags.get_mut(path) {
+            flags.intent_to_add = false;
+            if *flags == Flags::default() {
+                self.flags.remove(path);
+            }
+        }
+    }
+
     /// This function remove an entry to the index given a file/directory path
     pub fn remove_entry(&mut self, path: &PathBuf) -> Result<(), Error> {
         let root = utils::find_root()?;
@@ -177,55 +865,29 @@ impl Index {
             let path = PathBuf::from(path);
             !path.starts_with(&file)
         });
+        let entries = &self.entries;
+        self.flags.retain(|path, _| entries.contains_key(path));
         Ok(())
     }
 
-    /// Remove file/directory from the index
-    /// NOTE: This function checks that the path doesn't exist before deletion
-    /// TODO: Handle globing
-    pub fn remove(&mut self, file: &PathBuf, root: &PathBuf) -> Result<(), Error> {
-        // Get absolute path of file
-        let full_path = if file.is_absolute() {
-            file.clone()
-        } else {
-            let mut full_path = env::current_dir().unwrap();
-            for filename in file.iter() {
-                if filename == "." {
-                    continue;
-                }
-                if filename == ".." {
-                    full_path.pop();
-                } else {
-                    full_path.push(filename);
-                }
-            }
-            full_path
-        };
-
-        // Check file is inside the repository
-        if !full_path.starts_with(root) {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!(
-                    "fatal: {}: '{}' is outside repository",
-                    file.to_str().unwrap(),
-                    file.to_str().unwrap()
-                ),
-            ));
-        }
-
-        let index_path: PathBuf = full_path.iter().skip(root.iter().count()).collect();
-        for (entry_path, _) in self.entries.clone().iter() {
-            let path = PathBuf::from(entry_path);
-            if path.starts_with(&index_path) {
-                let path = root.join(path);
-                if !path.exists() {
-                    self.entries.remove(entry_path);
-                }
-            }
-        }
-
-        Ok(())
+    /// Remove the entries matching a pathspec whose file doesn't exist
+    /// anymore. The files of entries which skip the working tree are
+    /// expected to be missing.
+    pub fn remove(&mut self, pathspec: &Pathspec, root: &Path) {
+        let flags = &self.flags;
+        self.entries.retain(|path, _| {
+            !pathspec.matches(path, false)
+                || flags.get(path).is_some_and(|flags| flags.skip_worktree)
+                || fs::symlink_metadata(root.join(path)).is_ok()
+        });
+        let entries = &self.entries;
+        self.flags.retain(|path, _| entries.contains_key(path));
+        // Unmerged paths whose file is deleted are resolved
+        self.kept.retain(|kept| {
+            kept.stage == 0
+                || !pathspec.matches(&kept.path, false)
+                || fs::symlink_metadata(root.join(&kept.path)).is_ok()
+        });
     }
 
     /// Return whether the index contains or not the given path to file/directory
@@ -239,11 +901,329 @@ impl Index {
             return Ok(true);
         }
 
-        let index_path: PathBuf = real_path.iter().skip(root.iter().count()).collect();
+        let index_path = entry_path(&real_path, &root);
+        Ok(self.contains_entry(&index_path))
+    }
+
+    /// Whether an entry is the given path (relative to the root) or is in
+    /// this directory
+    pub fn contains_entry(&self, path: &str) -> bool {
+        let dir = format!("{}/", path);
+        self.entries.contains_key(path)
+            || self
+                .entries
+                .range(dir.clone()..)
+                .next()
+                .is_some_and(|(entry_path, _)| entry_path.starts_with(&dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 20].iter() {
+            let mut data = vec![];
+            write_varint(&mut data, *value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&data, &mut pos), Some(*value));
+            assert_eq!(pos, data.len());
+        }
+        let mut data = vec![];
+        write_varint(&mut data, 128);
+        assert_eq!(data, vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn dump_parse() {
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        for version in 2..=4 {
+            let mut index = Index {
+                entries: BTreeMap::new(),
+                stats: HashMap::new(),
+                flags: HashMap::new(),
+                timestamp: None,
+                fsmonitor: None,
+                untracked: None,
+                sparse: None,
+                kept: vec![],
+                version,
+            };
+            for path in ["a b", "dir/file", "dir/file2", "new\nline"].iter() {
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, hash));
+            }
+            let stat = Stat {
+                mtime: (1, 2),
+                size: 6,
+                ..Default::default()
+            };
+            index.stats.insert(String::from("dir/file"), stat);
+            let data = index.dump();
+            let parsed = Index::parse(&data).unwrap();
+            assert_eq!(parsed.version, version);
+            assert_eq!(parsed.entries, index.entries);
+            assert_eq!(parsed.stats["dir/file"], stat);
+        }
+    }
+
+    #[test]
+    fn kept_entries() {
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let raw = |path: &str, stage: u16, mode: u32| RawEntry {
+            path: path.to_string(),
+            stage,
+            mode,
+            hash,
+            stat: Stat::default(),
+            flags: Flags::default(),
+        };
+        for version in 2..=4 {
+            let mut index = Index::load_text(b"");
+            index.version = version;
+            for path in ["a", "c"].iter() {
+                index
+                    .entries
+                    .insert(path.to_string(), (EntryType::File, hash));
+            }
+            index.kept = vec![
+                raw("b", 0, 0o160000),
+                raw("d", 1, 0o100644),
+                raw("d", 2, 0o100644),
+                raw("d", 3, 0o100755),
+            ];
+            let data = index.dump();
+            let parsed = Index::parse(&data).unwrap();
+            assert_eq!(parsed.entries, index.entries);
+            assert_eq!(parsed.kept, index.kept);
+            assert_eq!(parsed.dump(), data);
+
+            // An entry resolves the unmerged path
+            index
+                .entries
+                .insert(String::from("d"), (EntryType::File, hash));
+            let parsed = Index::parse(&index.dump()).unwrap();
+            assert_eq!(parsed.entries, index.entries);
+            assert_eq!(parsed.kept, vec![raw("b", 0, 0o160000)]);
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let mut index = Index::load_text(b"");
+        for path in ["assumed", "plain", "skipped"].iter() {
+            index
+                .entries
+                .insert(path.to_string(), (EntryType::File, hash));
+        }
+        let assumed = Flags {
+            assume_unchanged: true,
+            ..Default::default()
+        };
+        index.flags.insert(String::from("assumed"), assumed);
+        let parsed = Index::parse(&index.dump()).unwrap();
+        assert_eq!(parsed.version, 2);
+        assert_eq!(parsed.flags, index.flags);
+
+        // Extended flags need version 3
+        let skipped = Flags {
+            skip_worktree: true,
+            intent_to_add: true,
+            ..Default::default()
+        };
+        index.flags.insert(String::from("skipped"), skipped);
+        let parsed = Index::parse(&index.dump()).unwrap();
+        assert_eq!(parsed.version, 3);
+        assert_eq!(parsed.flags, index.flags);
+        assert_eq!(parsed.entries, index.entries);
+        assert_eq!(parsed.flags("plain"), Flags::default());
+    }
+
+    #[test]
+    fn corrupt_checksum() {
+        let index = Index {
+            entries: BTreeMap::new(),
+            stats: HashMap::new(),
+            flags: HashMap::new(),
+            timestamp: None,
+            fsmonitor: None,
+            untracked: None,
+            sparse: None,
+            kept: vec![],
+            version: 2,
+        };
+        let mut data = index.dump();
+        let len = data.len();
+        data[len - 1] ^= 1;
+        assert!(Index::parse(&data).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let mut index = Index::load_text(b"");
+        for path in ["a", "dir/long_file_name"].iter() {
+            let entry = (EntryType::File, hash);
+            index.entries.insert(path.to_string(), entry);
+        }
+        let data = index.dump();
+        let content = &data[..data.len() - 20];
+        // Cut the entries anywhere, the checksum is zeroed so that only the
+        // content is checked
+        for len in 12..content.len() {
+            let mut data = content[..len].to_vec();
+            data.extend_from_slice(&[0; 20]);
+            assert!(Index::parse(&data).is_err(), "truncated at {}", len);
+        }
+        let mut data = content.to_vec();
+        data.extend_from_slice(&[0; 20]);
+        assert_eq!(Index::parse(&data).unwrap().entries, index.entries);
+    }
+
+    #[test]
+    fn racily_clean() {
+        let path = env::temp_dir().join(format!("my_git_racy_{}", std::process::id()));
+        fs::write(&path, "content").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
 
-        Ok(self
+        let hash = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let mut index = Index::load_text(b"");
+        index
             .entries
-            .iter()
-            .any(|(entry_path, _)| PathBuf::from(entry_path).starts_with(&index_path)))
+            .insert(String::from("f"), (EntryType::File, hash));
+        assert!(!index.stat_matches("f", &metadata));
+        assert!(index.refresh("f", &metadata));
+        assert!(!index.refresh("f", &metadata));
+
+        // Only an index written after the file was modified can be trusted
+        index.timestamp = Some(Stat::from_metadata(&metadata).mtime);
+        assert!(!index.stat_matches("f", &metadata));
+        index.timestamp = Some((u32::MAX, 0));
+        assert!(index.stat_matches("f", &metadata));
+        assert!(index.contains_entry("f") && !index.contains_entry("g"));
+
+        // The file was just modified so its size isn't written
+        let parsed = Index::parse(&index.dump()).unwrap();
+        assert_eq!(parsed.stats["f"].size, 0);
+        index.stats.insert(String::from("f"), parsed.stats["f"]);
+        assert!(!index.stat_matches("f", &metadata));
+    }
+
+    #[test]
+    fn text_format() {
+        let index = Index::load_text(b"a b f ce013625030ba8dba906f756967f9e9ca394464a\n");
+        assert_eq!(index.entries["a b"].0, EntryType::File);
+    }
+
+    #[test]
+    fn intent_to_add() {
+        crate::utils::tests::in_repo("intent", |root| {
+            let repo_path = root.join(".my_git");
+            let mut index = Index::load_text(b"");
+            let tracked = Blob::new(b"tracked\n".to_vec()).hash();
+            index
+                .entries
+                .insert(String::from("tracked"), (EntryType::File, tracked));
+            fs::create_dir(root.join("dir")).unwrap();
+            for path in ["tracked", "new", "dir/new", "x.log", ".my_gitignore"].iter() {
+                fs::write(root.join(path), "content\n").unwrap();
+            }
+            fs::write(root.join(".my_gitignore"), "*.log\n").unwrap();
+
+            let pathspec = Pathspec::new(vec!["."]).unwrap();
+            let ignored = Ignore::load(root);
+            let (fails, outside) = index
+                .add_intent_to_add(&pathspec, &repo_path, root, Some(&ignored), false)
+                .unwrap();
+            assert!(fails.is_empty() && outside.is_empty());
+            let empty = Blob::new(vec![]).hash();
+            let intent = Flags {
+                intent_to_add: true,
+                ..Default::default()
+            };
+            for path in [".my_gitignore", "dir/new", "new"].iter() {
+                assert_eq!(index.entries[*path], (EntryType::File, empty));
+                assert_eq!(index.flags(path), intent);
+            }
+            // Tracked files are kept as they are, ignored ones left out
+            assert_eq!(index.entries["tracked"], (EntryType::File, tracked));
+            assert_eq!(index.flags("tracked"), Flags::default());
+            assert_eq!(index.entries.len(), 4);
+            // The files aren't hashed
+            let content = Blob::new(b"content\n".to_vec()).hash().to_string();
+            let object = repo_path
+                .join("objects")
+                .join(&content[..2])
+                .join(&content[2..]);
+            assert!(!object.exists());
+            assert!(index.stats.is_empty());
+
+            // Ignored files given explicitly are reported
+            let pathspec = Pathspec::new(vec!["x.log"]).unwrap();
+            let (fails, _) = index
+                .add_intent_to_add(&pathspec, &repo_path, root, Some(&ignored), false)
+                .unwrap();
+            assert_eq!(fails, vec![PathBuf::from("x.log")]);
+            index
+                .add_intent_to_add(&pathspec, &repo_path, root, None, false)
+                .unwrap();
+            assert_eq!(index.flags("x.log"), intent);
+        });
+    }
+
+    #[test]
+    fn sparse_add() {
+        crate::utils::tests::in_repo("sparse_add", |root| {
+            let repo_path = root.join(".my_git");
+            let attributes = Attributes::new(
+                root,
+                Some(HashMap::new()),
+                crate::filter::Filters::new(crate::cmd::config::Drivers::new()),
+            );
+            let mut index = Index::load_text(b"");
+            let old = Blob::new(b"old\n".to_vec()).hash();
+            index
+                .entries
+                .insert(String::from("out/b"), (EntryType::File, old));
+            let skipped = Flags {
+                skip_worktree: true,
+                ..Default::default()
+            };
+            index.flags.insert(String::from("out/b"), skipped);
+            index.sparse = Some(Sparse::new(vec!["in"]));
+            fs::create_dir(root.join("in")).unwrap();
+            fs::create_dir(root.join("out")).unwrap();
+            for path in ["in/a", "out/b", "out/new"].iter() {
+                fs::write(root.join(path), "new\n").unwrap();
+            }
+            let ignored = Ignore::empty(root);
+            let mut add = |spec: &str, sparse: bool| {
+                let pathspec = Pathspec::new(vec![spec]).unwrap();
+                let rules = Some(&ignored);
+                let (_, outside) = index
+                    .add(&pathspec, &repo_path, root, rules, &attributes, sparse)
+                    .unwrap();
+                outside
+            };
+
+            // The untracked files outside the cone and the ones given
+            // explicitly are left out
+            assert_eq!(add(".", false), vec![root.join("out/new")]);
+            assert_eq!(add("out/b", false), vec![root.join("out/b")]);
+            assert!(add("in", false).is_empty());
+            assert!(add("out/b", true).is_empty());
+            assert!(add("out/new", true).is_empty());
+            let new = Blob::new(b"new\n".to_vec()).hash();
+            for path in ["in/a", "out/b", "out/new"].iter() {
+                assert_eq!(index.entries[*path], (EntryType::File, new));
+            }
+            // Files added outside the cone don't skip the working tree
+            assert!(index.flags.is_empty());
+        });
     }
 }
@@ -3,14 +3,42 @@
 //! `my_git` is a simple implementation of the versionning tool git.
 use clap::App;
 use std::error::Error;
+use std::fmt;
 
 pub mod cmd;
 pub mod objects;
 
+pub mod archive;
+pub mod attributes;
+pub mod diff;
+pub mod filter;
+pub mod fsmonitor;
+pub mod hooks;
+pub mod ignore;
 pub mod index;
+pub mod merge;
+pub mod pager;
+pub mod parallel;
+pub mod patch;
+pub mod pathspec;
 pub mod refs;
+pub mod sparse;
+pub mod untracked;
 pub mod utils;
 
+/// Error ending the program with the given status and no message, like
+/// `grep` when nothing matches
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "exit status {}", self.0)
+    }
+}
+
+impl Error for Exit {}
+
 /// This dispatch the main subcommand and return an error if something went
 /// wrong
 ///
@@ -24,15 +52,38 @@ pub mod utils;
 pub fn run(app: &mut App) -> Result<(), Box<dyn Error>> {
     let matches = app.clone().get_matches();
 
+    // Die quietly when the reader of the output goes away like git does
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+    if !atty::is(atty::Stream::Stdout) && std::env::var_os("CLICOLOR_FORCE").is_none() {
+        colored::control::set_override(false);
+    }
+    if matches.is_present("no-pager") {
+        pager::disable();
+    }
+
     match matches.subcommand() {
         ("add", Some(matches)) => cmd::add::run(matches),
+        ("archive", Some(matches)) => cmd::archive::run(matches),
+        ("bisect", Some(matches)) => cmd::bisect::run(matches),
+        ("blame", Some(matches)) => cmd::blame::run(matches),
         ("branch", Some(matches)) => cmd::branch::run(matches),
+        ("check-ignore", Some(matches)) => cmd::check_ignore::run(matches),
+        ("clean", Some(matches)) => cmd::clean::run(matches),
         ("commit", Some(matches)) => cmd::commit::run(matches),
         ("config", Some(matches)) => cmd::config::run(matches),
+        ("grep", Some(matches)) => cmd::grep::run(matches),
         ("init", Some(matches)) => cmd::init::run(matches),
         ("log", Some(matches)) => cmd::log::run(matches),
+        ("ls-files", Some(matches)) => cmd::ls_files::run(matches),
+        ("reset", Some(matches)) => cmd::reset::run(matches),
+        ("restore", Some(matches)) => cmd::restore::run(matches),
+        ("show", Some(matches)) => cmd::show::run(matches),
+        ("sparse-checkout", Some(matches)) => cmd::sparse_checkout::run(matches),
         ("status", Some(matches)) => cmd::status::run(matches),
         ("switch", Some(matches)) => cmd::switch::run(matches),
+        ("update-index", Some(matches)) => cmd::update_index::run(matches),
         (_, None) => {
             app.print_help()?;
             println!();
@@ -7,6 +7,9 @@ fn main() {
     let yaml = load_yaml!("cli.yml");
     let mut app = App::from_yaml(yaml);
     if let Err(e) = my_git::run(&mut app) {
+        if let Some(my_git::Exit(status)) = e.downcast_ref() {
+            exit(*status);
+        }
         eprintln!("{}", e);
         exit(2);
     }
@@ -0,0 +1,236 @@
+use crate::attributes::State;
+use crate::cmd::config::Config;
+use crate::diff::{self, Edit};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// The result of a three-way merge of file contents
+pub struct MergeResult {
+    pub data: Vec<u8>,
+    pub conflicts: usize,
+}
+
+/// Map each line of the base to the matching line of the other version
+fn matches(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut res = vec![None; base.len()];
+    for edit in diff::diff(base, other) {
+        if let Edit::Equal(i, j) = edit {
+            res[i] = Some(j);
+        }
+    }
+    res
+}
+
+fn push_lines(data: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        data.extend_from_slice(line);
+    }
+}
+
+fn push_marker(data: &mut Vec<u8>, marker: &str, label: &str) {
+    if !data.is_empty() && !data.ends_with(b"\n") {
+        data.push(b'\n');
+    }
+    if label.is_empty() {
+        data.extend_from_slice(format!("{}\n", marker).as_bytes());
+    } else {
+        data.extend_from_slice(format!("{} {}\n", marker, label).as_bytes());
+    }
+}
+
+/// Merge `ours` and `theirs` given their common ancestor `base` (diff3
+/// algorithm). Conflicting chunks are surrounded with conflict markers using
+/// the given labels.
+pub fn merge(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    ours_label: &str,
+    theirs_label: &str,
+) -> MergeResult {
+    three_way(base, ours, theirs, Some((ours_label, theirs_label)))
+}
+
+/// Merge like `merge` but keep the lines of both sides of the conflicting
+/// chunks, ours first, without markers
+pub fn union(base: &[u8], ours: &[u8], theirs: &[u8]) -> MergeResult {
+    three_way(base, ours, theirs, None)
+}
+
+/// Run the diff3 algorithm, conflicts are marked with the given labels or
+/// resolved as a union
+fn three_way(base: &[u8], ours: &[u8], theirs: &[u8], labels: Option<(&str, &str)>) -> MergeResult {
+    let (base, ours, theirs) = (diff::lines(base), diff::lines(ours), diff::lines(theirs));
+    let (match_ours, match_theirs) = (matches(&base, &ours), matches(&base, &theirs));
+
+    let mut data = vec![];
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // Stable chunk: lines unchanged on both sides
+        let mut len = 0;
+        while i + len < base.len()
+            && match_ours[i + len] == Some(j + len)
+            && match_theirs[i + len] == Some(k + len)
+        {
+            len += 1;
+        }
+        if len > 0 {
+            push_lines(&mut data, &base[i..i + len]);
+            i += len;
+            j += len;
+            k += len;
+            continue;
+        }
+
+        // Unstable chunk: find the next base line matched on both sides
+        let next = (i..base.len()).find(|&o| {
+            matches!(match_ours[o], Some(a) if a >= j)
+                && matches!(match_theirs[o], Some(b) if b >= k)
+        });
+        let (o, a, b) = match next {
+            Some(o) => (o, match_ours[o].unwrap(), match_theirs[o].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        if (o, a, b) == (i, j, k) {
+            break;
+        }
+
+        let (base_chunk, ours_chunk, theirs_chunk) = (&base[i..o], &ours[j..a], &theirs[k..b]);
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_lines(&mut data, theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            push_lines(&mut data, ours_chunk);
+        } else if let Some((ours_label, theirs_label)) = labels {
+            conflicts += 1;
+            push_marker(&mut data, "<<<<<<<", ours_label);
+            push_lines(&mut data, ours_chunk);
+            push_marker(&mut data, "=======", "");
+            push_lines(&mut data, theirs_chunk);
+            push_marker(&mut data, ">>>>>>>", theirs_label);
+        } else {
+            push_lines(&mut data, ours_chunk);
+            push_lines(&mut data, theirs_chunk);
+        }
+        i = o;
+        j = a;
+        k = b;
+    }
+    MergeResult { data, conflicts }
+}
+
+/// Merge with the command of a `merge.<driver>.driver` setting. `%O`, `%A`
+/// and `%B` are replaced by temporary files holding the base, ours and
+/// theirs, `%L` by the conflict marker size and `%P` by the path. The result
+/// is read back from `%A` and the command fails on conflicts.
+fn external(
+    repo_path: &Path,
+    command: &str,
+    path: &str,
+    versions: [&[u8]; 3],
+) -> Result<MergeResult, Box<dyn Error>> {
+    let mut files = vec![];
+    for (i, data) in versions.iter().enumerate() {
+        let file = repo_path.join(format!(".merge_file_{}_{}", std::process::id(), i));
+        fs::write(&file, data)?;
+        files.push(file);
+    }
+    let command = command
+        .replace("%O", files[0].to_str().unwrap())
+        .replace("%A", files[1].to_str().unwrap())
+        .replace("%B", files[2].to_str().unwrap())
+        .replace("%L", "7")
+        .replace("%P", path);
+    let status = Command::new("sh").arg("-c").arg(&command).status();
+    let data = fs::read(&files[1]);
+    for file in files.iter() {
+        fs::remove_file(file).ok();
+    }
+    Ok(MergeResult {
+        data: data?,
+        conflicts: if status?.success() { 0 } else { 1 },
+    })
+}
+
+/// Merge the contents of a file with the driver given by its `merge`
+/// attribute: the text merge by default, `binary` (or unset) keeps ours,
+/// `union` and the drivers of the configuration. Unknown drivers fall back
+/// to the text merge.
+pub fn merge_file(
+    repo_path: &Path,
+    path: &str,
+    driver: Option<&State>,
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    (ours_label, theirs_label): (&str, &str),
+) -> Result<MergeResult, Box<dyn Error>> {
+    let name = match driver {
+        Some(State::Unset) => "binary",
+        Some(State::Value(name)) => name.as_str(),
+        _ => "text",
+    };
+    let configured = Config::load()?
+        .merge
+        .get(name)
+        .and_then(|driver| driver.get("driver"))
+        .cloned();
+    if let Some(command) = configured {
+        return external(repo_path, &command, path, [base, ours, theirs]);
+    }
+    match name {
+        "binary" => {
+            println!(
+                "warning: Cannot merge binary files: {} ({} vs. {})",
+                path, ours_label, theirs_label
+            );
+            Ok(MergeResult {
+                data: ours.to_vec(),
+                conflicts: 1,
+            })
+        }
+        "union" => Ok(union(base, ours, theirs)),
+        _ => Ok(merge(base, ours, theirs, ours_label, theirs_label)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_clean() {
+        let base = b"a\nb\nc\nd\ne\n";
+        let ours = b"a\nB\nc\nd\ne\n";
+        let theirs = b"a\nb\nc\nd\nE\n";
+        let res = merge(base, ours, theirs, "ours", "theirs");
+        assert_eq!(res.conflicts, 0);
+        assert_eq!(res.data, b"a\nB\nc\nd\nE\n".to_vec());
+    }
+
+    #[test]
+    fn merge_same_change() {
+        let res = merge(b"a\nb\n", b"a\nc\n", b"a\nc\n", "ours", "theirs");
+        assert_eq!(res.conflicts, 0);
+        assert_eq!(res.data, b"a\nc\n".to_vec());
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let res = merge(b"a\nb\nc\n", b"a\nx\nc\n", b"a\ny\nc\n", "ours", "theirs");
+        assert_eq!(res.conflicts, 1);
+        assert_eq!(
+            String::from_utf8(res.data).unwrap(),
+            "a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn merge_union() {
+        let res = union(b"a\nb\nc\n", b"a\nB\nc\n", b"a\nX\nc\n");
+        assert_eq!(res.conflicts, 0);
+        assert_eq!(res.data, b"a\nB\nX\nc\n".to_vec());
+    }
+}
@@ -1,5 +1,12 @@
+use crate::attributes::Attributes;
+use crate::index::EntryType;
 use crate::objects::Object;
-use std::io::BufRead;
+use std::fs;
+use std::fs::Permissions;
+use std::io::{BufRead, Error};
+use std::os::unix;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
 
 /// This object carry the content of a file.
 pub struct Blob {
@@ -10,13 +17,61 @@ impl Blob {
     pub fn new(data: Vec<u8>) -> Blob {
         Blob { data }
     }
+
+    /// Create the blob of an existing file of the working tree. The content
+    /// of a symlink is its target.
+    pub fn from_file(path: &PathBuf) -> Result<Blob, Error> {
+        let metadata = fs::symlink_metadata(path)?;
+        let content = if metadata.file_type().is_symlink() {
+            fs::read_link(path)?.to_str().unwrap().as_bytes().to_vec()
+        } else {
+            fs::read(path)?
+        };
+        Ok(Blob::new(content))
+    }
+
+    /// Create the blob of a file of the working tree converted according to
+    /// its attributes
+    pub fn from_worktree(path: &PathBuf, attributes: &Attributes) -> Result<Blob, Error> {
+        let blob = Blob::from_file(path)?;
+        if fs::symlink_metadata(path)?.file_type().is_symlink() {
+            return Ok(blob);
+        }
+        Ok(Blob::new(attributes.to_git(path, blob.data)?))
+    }
+
+    /// Write the blob to the working tree converted according to the
+    /// attributes of the path
+    pub fn to_worktree(
+        &self,
+        path: &PathBuf,
+        entry_type: &EntryType,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        match entry_type {
+            EntryType::Symlink => self.to_file(path, entry_type),
+            _ => Blob::new(attributes.to_worktree(path, self.data.clone())?)
+                .to_file(path, entry_type),
+        }
+    }
+
+    /// Write the blob to the working tree as a file of the given type
+    pub fn to_file(&self, path: &PathBuf, entry_type: &EntryType) -> Result<(), Error> {
+        match entry_type {
+            EntryType::File => fs::write(path, &self.data),
+            EntryType::Executable => {
+                fs::write(path, &self.data)?;
+                fs::set_permissions(path, Permissions::from_mode(0o755))
+            }
+            EntryType::Symlink => unix::fs::symlink(std::str::from_utf8(&self.data).unwrap(), path),
+        }
+    }
 }
 
 impl Object for Blob {
     fn dump(&self) -> Vec<u8> {
         let header = format!("blob {}\0", self.data.len());
-        let mut res = vec![];
-        res.reserve(self.data.len() + header.len());
+        let mut res = Vec::with_capacity(self.data.len() + header.len());
         res.append(&mut header.into_bytes());
         res.append(&mut self.data.clone());
         res
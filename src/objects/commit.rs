@@ -1,15 +1,18 @@
 use crate::objects::Hash;
 use crate::objects::{Object, Tree};
+use crate::utils;
 use chrono::offset::{FixedOffset, Local, TimeZone};
 use chrono::DateTime;
 use colored::Colorize;
+use std::collections::HashSet;
 use std::fmt;
 use std::io::BufRead;
-use std::path::PathBuf;
+use std::path::Path;
 use std::str::FromStr;
 
 /// This object represents a version. It contains the root of the tree and
 /// metadata about the author and committer.
+#[derive(Clone)]
 pub struct Commit {
     pub tree: Hash,
     pub parents: Vec<Hash>,
@@ -58,13 +61,11 @@ impl Commit {
         )
     }
 
-    fn parse_user_date(data: &str) -> (User, DateTime<FixedOffset>) {
+    pub(crate) fn parse_user_date(data: &str) -> (User, DateTime<FixedOffset>) {
         let mut splitted = data.split_whitespace();
         let email = splitted.find(|&e| e.starts_with("<")).unwrap();
         let date = splitted.collect::<Vec<&str>>().join(" ");
-        let date = FixedOffset::east(0)
-            .datetime_from_str(date.as_str(), "%s %z")
-            .unwrap();
+        let date = DateTime::parse_from_str(date.as_str(), "%s %z").unwrap();
         let name = data
             .split_whitespace()
             .take_while(|&e| !e.starts_with("<"))
@@ -77,8 +78,171 @@ impl Commit {
         )
     }
 
+    /// Return the subject of the message (its first paragraph on one line)
+    pub fn subject(&self) -> String {
+        self.message
+            .trim_start()
+            .lines()
+            .take_while(|line| !line.trim().is_empty())
+            .map(|line| line.trim())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    /// Return the body of the message (everything after the subject)
+    pub fn body(&self) -> String {
+        let mut lines = self.message.trim_start().lines();
+        for line in lines.by_ref() {
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        let body: Vec<&str> = lines.skip_while(|line| line.trim().is_empty()).collect();
+        let body = body.join("\n");
+        let body = body.trim_end();
+        if body.is_empty() {
+            String::new()
+        } else {
+            format!("{}\n", body)
+        }
+    }
+
+    /// Format the commit given a pretty format string. Supported placeholders
+    /// are the ones of git: `%H`, `%h`, `%T`, `%t`, `%P`, `%p`, `%an`, `%ae`,
+    /// `%ad`, `%at`, `%ar`, `%ai`, `%aI` (and their `%c` committer
+    /// counterparts), `%s`, `%b`, `%B`, `%n`, `%%` and colors (`%Cred`,
+    /// `%Cgreen`, `%Cblue`, `%Creset`, `%C(<color>)`).
+    pub fn format(&self, format: &str) -> String {
+        self.format_decorated(format, &[])
+    }
+
+    /// Format the commit like `format` does, the ref names pointing to the
+    /// commit are available through `%d` (decoration) and `%D` (bare names).
+    pub fn format_decorated(&self, format: &str, refs: &[String]) -> String {
+        let mut res = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                res.push(c);
+                continue;
+            }
+            let rest: String = chars.clone().collect();
+            let (value, len) = self.placeholder(&rest, refs);
+            match value {
+                Some(value) => {
+                    res.push_str(&value);
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                }
+                None => res.push('%'),
+            }
+        }
+        res
+    }
+
+    /// Expand the placeholder at the beginning of `spec` (after the `%`).
+    /// Return the expansion and the number of chars consumed.
+    fn placeholder(&self, spec: &str, refs: &[String]) -> (Option<String>, usize) {
+        let abbrev = |hash: &Hash| hash.to_string()[..7].to_string();
+        let join = |hashes: Vec<String>| hashes.join(" ");
+        let color = |name: &str| {
+            if colored::control::SHOULD_COLORIZE.should_colorize() {
+                ansi_color(name)
+            } else {
+                Some(String::new())
+            }
+        };
+
+        // Colors
+        for name in ["red", "green", "blue", "reset"].iter() {
+            if spec.starts_with(&format!("C{}", name)) {
+                return (color(name), name.len() + 1);
+            }
+        }
+        if spec.starts_with("C(") {
+            if let Some(end) = spec.find(')') {
+                return (color(&spec[2..end]), end + 1);
+            }
+        }
+
+        // User and date
+        let user_date = match spec.chars().next() {
+            Some('a') => Some(&self.author),
+            Some('c') => Some(&self.committer),
+            _ => None,
+        };
+        if let (Some((user, date)), Some(field)) = (user_date, spec.chars().nth(1)) {
+            let value = match field {
+                'n' => Some(user.name.clone()),
+                'e' => Some(user.email.clone()),
+                'd' => Some(date.format("%a %b %e %T %Y %z").to_string()),
+                't' => Some(date.timestamp().to_string()),
+                'r' => Some(utils::relative_date(date)),
+                'i' => Some(date.format("%Y-%m-%d %H:%M:%S %z").to_string()),
+                'I' => Some(date.to_rfc3339()),
+                _ => None,
+            };
+            if value.is_some() {
+                return (value, 2);
+            }
+        }
+
+        let value = match spec.chars().next() {
+            Some('H') => self.hash().to_string(),
+            Some('h') => abbrev(&self.hash()),
+            Some('T') => self.tree.to_string(),
+            Some('t') => abbrev(&self.tree),
+            Some('P') => join(self.parents.iter().map(|p| p.to_string()).collect()),
+            Some('p') => join(self.parents.iter().map(abbrev).collect()),
+            Some('s') => self.subject(),
+            Some('b') => self.body(),
+            Some('B') => self.message.clone(),
+            Some('d') => decoration(refs),
+            Some('D') => refs.join(", "),
+            Some('n') => String::from("\n"),
+            Some('%') => String::from("%"),
+            _ => return (None, 0),
+        };
+        (Some(value), 1)
+    }
+
+    /// Format the commit like `git log` does by default. The ref names
+    /// pointing to the commit are displayed after its hash.
+    pub fn medium(&self, refs: &[String]) -> String {
+        let mut commit = format!("commit {}", self.hash()).yellow().to_string();
+        commit.push_str(&decoration(refs));
+        if self.parents.len() > 1 {
+            commit.push_str(&self.format("\nMerge: %p"));
+        }
+        let (user, date) = &self.author;
+        let date = date.format("%a %b %e %T %Y %z");
+        let message: String = self
+            .message
+            .trim_end()
+            .lines()
+            .map(|line| format!("    {}\n", line))
+            .collect();
+        format!(
+            "{}\nAuthor: {} <{}>\nDate:   {}\n\n{}",
+            commit, user.name, user.email, date, message
+        )
+    }
+
+    /// Return the given commits and all their ancestors
+    pub fn ancestors(repo_path: &Path, hashes: &[Hash]) -> HashSet<Hash> {
+        let mut res = HashSet::new();
+        let mut stack = hashes.to_vec();
+        while let Some(hash) = stack.pop() {
+            if res.insert(hash) {
+                stack.extend(Commit::load(repo_path, hash).parents);
+            }
+        }
+        res
+    }
+
     /// This method check if the current commit is an ancestor of other
-    pub fn is_ancestor(&self, repo_path: &PathBuf, other: &Self) -> bool {
+    pub fn is_ancestor(&self, repo_path: &Path, other: &Self) -> bool {
         let mut stack = vec![other.hash()];
         let hash = self.hash();
         while let Some(commit) = stack.pop() {
@@ -92,16 +256,69 @@ impl Commit {
     }
 }
 
+/// Format ref names like git does next to a commit hash: ` (HEAD -> master,
+/// tag: v1.0, feature)`. Return an empty string if there is no ref.
+pub fn decoration(refs: &[String]) -> String {
+    if refs.is_empty() {
+        return String::new();
+    }
+    let names: Vec<String> = refs
+        .iter()
+        .map(|name| {
+            if let Some(branch) = name.strip_prefix("HEAD -> ") {
+                format!("{} {}", "HEAD ->".cyan().bold(), branch.green().bold())
+            } else if name == "HEAD" {
+                name.cyan().bold().to_string()
+            } else if name.starts_with("tag: ") {
+                name.yellow().bold().to_string()
+            } else {
+                name.green().bold().to_string()
+            }
+        })
+        .collect();
+    let separator = ", ".yellow().to_string();
+    format!(
+        " {}{}{}",
+        "(".yellow(),
+        names.join(&separator),
+        ")".yellow()
+    )
+}
+
+/// Return the ANSI escape sequence of a color name
+fn ansi_color(name: &str) -> Option<String> {
+    let mut codes = vec![];
+    for word in name.split_whitespace() {
+        let code = match word {
+            "reset" => "0",
+            "bold" => "1",
+            "dim" => "2",
+            "ul" => "4",
+            "black" => "30",
+            "red" => "31",
+            "green" => "32",
+            "yellow" => "33",
+            "blue" => "34",
+            "magenta" => "35",
+            "cyan" => "36",
+            "white" => "37",
+            _ => return None,
+        };
+        codes.push(code);
+    }
+    Some(format!("\x1b[{}m", codes.join(";")))
+}
+
 impl Object for Commit {
     fn dump(&self) -> Vec<u8> {
         let mut data = vec![];
 
         // Tree
-        data.append(&mut format!("tree {}\n", self.tree.to_string()).into_bytes());
+        data.append(&mut format!("tree {}\n", self.tree).into_bytes());
 
         // Parents
         for parent in self.parents.iter() {
-            data.append(&mut format!("parent {}\n", parent.to_string()).into_bytes());
+            data.append(&mut format!("parent {}\n", parent).into_bytes());
         }
 
         // Author
@@ -137,8 +354,7 @@ impl Object for Commit {
 
         // Add header
         let header = format!("commit {}\0", data.len());
-        let mut res = vec![];
-        res.reserve(data.len() + header.len());
+        let mut res = Vec::with_capacity(data.len() + header.len());
         res.append(&mut header.into_bytes());
         res.append(&mut data);
         res
@@ -151,7 +367,7 @@ impl Object for Commit {
 
         let mut res = Commit::default();
         let mut buff = String::new();
-        while let Ok(_) = reader.read_line(&mut buff) {
+        while reader.read_line(&mut buff).is_ok() {
             if buff == "\n" {
                 break;
             }
@@ -161,10 +377,10 @@ impl Object for Commit {
             } else if buff.starts_with("parent ") {
                 let buff: Vec<&str> = buff.split(' ').collect();
                 res.parents.push(Hash::from_str(&buff[1][..40]).unwrap());
-            } else if buff.starts_with("author ") {
-                (res.author) = Commit::parse_user_date(&buff[7..]);
-            } else if buff.starts_with("committer ") {
-                (res.committer) = Commit::parse_user_date(&buff[10..]);
+            } else if let Some(author) = buff.strip_prefix("author ") {
+                res.author = Commit::parse_user_date(author);
+            } else if let Some(committer) = buff.strip_prefix("committer ") {
+                res.committer = Commit::parse_user_date(committer);
             } else {
                 panic!("Unexpected content in commit object");
             }
@@ -180,19 +396,7 @@ impl Object for Commit {
 
 impl fmt::Display for Commit {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO: Handle refs/branches
-        let commit = format!("commit {}", self.hash());
-        let (user, date) = &self.author;
-        let date = date.format("%a %b %e %T %Y");
-        write!(
-            f,
-            "{}\nAuthor: {} <{}>\nDate:   {}\n\n    {}\n",
-            commit.yellow(),
-            user.name,
-            user.email,
-            date,
-            self.message
-        )
+        write!(f, "{}", self.medium(&[]))
     }
 }
 
@@ -205,6 +409,19 @@ impl User {
     pub fn new(name: String, email: String) -> User {
         User { name, email }
     }
+
+    /// Parse a user identity of the form `Name <email>`
+    pub fn parse(ident: &str) -> Option<User> {
+        let start = ident.find('<')?;
+        let end = ident.rfind('>')?;
+        if end < start {
+            return None;
+        }
+        Some(User::new(
+            ident[..start].trim().to_string(),
+            ident[start + 1..end].trim().to_string(),
+        ))
+    }
 }
 
 impl Clone for User {
@@ -236,4 +453,36 @@ mod tests {
         let dump = commit.dump();
         assert_eq!(dump.len(), 262);
     }
+
+    #[test]
+    fn commit_format() {
+        let commit = Commit::new(
+            Hash::from_str("e3095e3fb2e3cbc0dea81d961650feda7f6448f7").unwrap(),
+            vec![Hash::from_str("f8ebe55b90a19ab7e5dea5ec51390948109623e5").unwrap()],
+            User::new(
+                String::from("Florian Amsallem"),
+                String::from("florian.amsallem@epita.fr"),
+            ),
+            FixedOffset::east(7200).timestamp(1561665499, 0),
+            String::from("second:\ncommit\n\nSome details\n"),
+        );
+        assert_eq!(
+            commit.format("%t %p %an <%ae> %at%n%s%n%b%%"),
+            "e3095e3 f8ebe55 Florian Amsallem <florian.amsallem@epita.fr> 1561665499\n\
+             second: commit\nSome details\n%"
+        );
+        assert_eq!(
+            commit.format("%ad %ai"),
+            "Thu Jun 27 21:58:19 2019 +0200 2019-06-27 21:58:19 +0200"
+        );
+        assert_eq!(commit.format("%x %"), "%x %");
+    }
+
+    #[test]
+    fn user_parse() {
+        let user = User::parse("Florian Amsallem <florian.amsallem@epita.fr>").unwrap();
+        assert_eq!(user.name, "Florian Amsallem");
+        assert_eq!(user.email, "florian.amsallem@epita.fr");
+        assert!(User::parse("Florian Amsallem").is_none());
+    }
 }
@@ -1,5 +1,7 @@
 pub use blob::Blob;
+pub use commit::decoration;
 pub use commit::Commit;
+pub use commit::User;
 use flate2::bufread::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
@@ -9,16 +11,35 @@ use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+pub use tag::Tag;
 pub use tree::Tree;
 pub use tree::TreeEntry;
 
 mod blob;
 mod commit;
+mod tag;
 mod tree;
 
 pub type Hash = Digest;
 
+/// This function return the type of an object (`blob`, `tree`, `commit` or
+/// `tag`) or None if it doesn't exist.
+pub fn object_type(repo: &Path, hash: Hash) -> Option<String> {
+    let hash = hash.to_string();
+    let path = repo.join("objects").join(&hash[..2]).join(&hash[2..]);
+    let decoder = ZlibDecoder::new(BufReader::new(fs::File::open(path).ok()?));
+    let mut kind = vec![];
+    BufReader::new(decoder).read_until(b' ', &mut kind).ok()?;
+    kind.pop();
+    String::from_utf8(kind).ok()
+}
+
+/// Number of temporary object files created by this process
+static TEMP_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
 /// This represents Git object as blob, tree and commit
 pub trait Object {
     /// This function dump an object to his raw data
@@ -32,7 +53,7 @@ pub trait Object {
     fn from<R: BufRead>(reader: R) -> Box<Self>;
 
     /// This function load an object from a given hash dans repository path.
-    fn load(repo: &PathBuf, hash: Hash) -> Box<Self> {
+    fn load(repo: &Path, hash: Hash) -> Box<Self> {
         // Compute the path to the object file
         let mut objects_path = repo.join("objects");
         objects_path.push(&hash.to_string()[..2]);
@@ -51,19 +72,27 @@ pub trait Object {
     }
 
     /// Save the object
-    fn save(&self, repo_path: &PathBuf) {
-        let hash = self.hash().to_string();
-        let repo_path = &repo_path.join("objects").join(&hash[..2]);
-        if !repo_path.is_dir() {
-            fs::create_dir(repo_path).expect("Fail creating object directory");
-        }
-        let repo_path = repo_path.join(&hash[2..]);
-        if !repo_path.is_file() {
-            // Compress and write the object
-            let file = File::create(repo_path).expect("Fail opening the object file");
-            let mut data = ZlibEncoder::new(file, Compression::default());
-            data.write_all(&self.dump())
+    fn save(&self, repo_path: &Path) {
+        let data = self.dump();
+        let hash = Sha1::from(&data).digest().to_string();
+        let dir = &repo_path.join("objects").join(&hash[..2]);
+        fs::create_dir_all(dir).expect("Fail creating object directory");
+        let path = dir.join(&hash[2..]);
+        if !path.is_file() {
+            // Compress the object in a temporary file moved once complete,
+            // another thread may be writing the same object
+            let temp = dir.join(format!(
+                "tmp_obj_{}_{}",
+                process::id(),
+                TEMP_OBJECTS.fetch_add(1, Ordering::Relaxed)
+            ));
+            let file = File::create(&temp).expect("Fail opening the object file");
+            let mut encoder = ZlibEncoder::new(file, Compression::default());
+            encoder
+                .write_all(&data)
+                .and_then(|_| encoder.finish())
                 .expect("Error writing data to the object file");
+            fs::rename(&temp, &path).expect("Error writing data to the object file");
         }
     }
 }
@@ -75,7 +104,6 @@ mod tests {
     use super::*;
     use chrono::offset::TimeZone;
     use chrono::FixedOffset;
-    use commit::User;
     use std::str::FromStr;
 
     #[test]
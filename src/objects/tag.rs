@@ -0,0 +1,99 @@
+use crate::objects::commit::User;
+use crate::objects::{Commit, Hash, Object};
+use chrono::{DateTime, FixedOffset};
+use std::io::BufRead;
+use std::str::FromStr;
+
+/// This object represents an annotated tag. It names another object and
+/// carries a message and the identity of its creator.
+pub struct Tag {
+    pub object: Hash,
+    pub kind: String,
+    pub name: String,
+    pub tagger: Option<(User, DateTime<FixedOffset>)>,
+    pub message: String,
+}
+
+impl Object for Tag {
+    fn dump(&self) -> Vec<u8> {
+        let mut data = format!(
+            "object {}\ntype {}\ntag {}\n",
+            self.object, self.kind, self.name
+        );
+        if let Some((user, date)) = &self.tagger {
+            data.push_str(&format!(
+                "tagger {} <{}> {} {}\n",
+                user.name,
+                user.email,
+                date.timestamp(),
+                date.format("%z")
+            ));
+        }
+        data.push_str(&format!("\n{}", self.message));
+
+        // Add header
+        format!("tag {}\0{}", data.len(), data).into_bytes()
+    }
+
+    fn from<R: BufRead>(mut reader: R) -> Box<Tag> {
+        let mut buff = vec![];
+        reader.read_until(0, &mut buff).unwrap();
+        assert!(std::str::from_utf8(&buff).unwrap().starts_with("tag "));
+
+        let mut res = Tag {
+            object: Hash::from_str("0000000000000000000000000000000000000000").unwrap(),
+            kind: String::new(),
+            name: String::new(),
+            tagger: None,
+            message: String::new(),
+        };
+        let mut buff = String::new();
+        while reader.read_line(&mut buff).is_ok() {
+            if buff == "\n" || buff.is_empty() {
+                break;
+            }
+            let line = buff.trim_end();
+            if let Some(object) = line.strip_prefix("object ") {
+                res.object = Hash::from_str(object).unwrap();
+            } else if let Some(kind) = line.strip_prefix("type ") {
+                res.kind = kind.to_string();
+            } else if let Some(name) = line.strip_prefix("tag ") {
+                res.name = name.to_string();
+            } else if let Some(tagger) = line.strip_prefix("tagger ") {
+                res.tagger = Some(Commit::parse_user_date(tagger));
+            } else {
+                panic!("Unexpected content in tag object");
+            }
+            buff.clear();
+        }
+        let mut buff = vec![];
+        reader.read_to_end(&mut buff).unwrap();
+        res.message = String::from_utf8_lossy(&buff).to_string();
+        Box::new(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn tag_dump() {
+        let tag = Tag {
+            object: Hash::from_str("e3095e3fb2e3cbc0dea81d961650feda7f6448f7").unwrap(),
+            kind: String::from("commit"),
+            name: String::from("v1.0"),
+            tagger: Some((
+                User::new(String::from("Florian"), String::from("f@x.fr")),
+                FixedOffset::east(7200).timestamp(1561665499, 0),
+            )),
+            message: String::from("First release\n"),
+        };
+        let dump = tag.dump();
+        let parsed = <Tag as Object>::from(&dump[..]);
+        assert_eq!(parsed.dump(), dump);
+        assert_eq!(parsed.name, "v1.0");
+        assert_eq!(parsed.tagger.unwrap().1.format("%z").to_string(), "+0200");
+    }
+}
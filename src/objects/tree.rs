@@ -1,3 +1,4 @@
+use crate::attributes::Attributes;
 use crate::index::{EntryType, Index};
 use crate::objects::{Blob, Hash, Object};
 use crate::utils;
@@ -7,12 +8,9 @@ use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::fs::Permissions;
 use std::io;
 use std::io::{BufRead, Write};
-use std::os::unix;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 /// This enum contains all the entries in a Tree.
@@ -31,6 +29,12 @@ pub struct Tree {
     pub entries: BTreeMap<String, TreeEntry>,
 }
 
+impl Default for Tree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tree {
     pub fn new() -> Tree {
         Tree {
@@ -95,20 +99,18 @@ impl Tree {
                 }
             } else {
                 self.add_directory(root, Tree::new());
-                self.create_tree(&path);
+                self.create_tree(path);
             }
         }
     }
 
     /// Given a path return the mutable corresponding directory tree
-    fn get_mut_tree(&mut self, path: &PathBuf) -> &mut Self {
+    fn get_mut_tree(&mut self, path: &Path) -> &mut Self {
         if let Some(root) = path.iter().next() {
             let root = root.to_str().unwrap().to_string();
             let path: PathBuf = path.iter().skip(1).collect();
-            if let Some(entry) = self.entries.get_mut(&root) {
-                if let TreeEntry::Directory(tree) = entry {
-                    return tree.get_mut_tree(&path);
-                }
+            if let Some(TreeEntry::Directory(tree)) = self.entries.get_mut(&root) {
+                return tree.get_mut_tree(&path);
             }
             panic!("Path invalid for the given index");
         } else {
@@ -116,34 +118,38 @@ impl Tree {
         }
     }
 
-    /// This function apply a new tree to file system and update the given index
-    /// Eg: To apply a commit `head.apply(repo_path, index, root, commit)`
+    /// This function apply a new tree to file system and update the given index,
+    /// files are converted according to the attributes. With a sparse
+    /// checkout only the included files are written, the others skip the
+    /// working tree.
+    /// Eg: To apply a commit `head.apply(repo_path, index, root, commit, attributes)`
     pub fn apply(
         &self,
         repo_path: &PathBuf,
         index: &mut Index,
-        path: &PathBuf,
+        path: &Path,
         new: &Self,
+        attributes: &Attributes,
     ) -> Result<(), Box<dyn Error>> {
+        let root = utils::find_root()?;
         for (filename, new_entry) in new.entries.iter() {
             let path = path.join(filename);
+            let name = path.strip_prefix(&root)?.to_str().unwrap();
             // Update and create files of the new tree
             match (self.entries.get(filename), new_entry) {
                 (Some(TreeEntry::Directory(cur_tree)), TreeEntry::Directory(new_tree)) => {
                     if cur_tree != new_tree {
-                        cur_tree.apply(repo_path, index, &path, new_tree)?;
-                        continue;
+                        cur_tree.apply(repo_path, index, &path, new_tree, attributes)?;
                     }
+                    continue;
                 }
                 (Some(TreeEntry::Directory(_)), _) => {
-                    fs::remove_dir_all(&path)?;
                     index.remove_entry(&path)?;
                 }
                 (Some(cur_entry), _) => {
                     if cur_entry == new_entry {
                         continue;
                     } else if let TreeEntry::Directory(_) = new_entry {
-                        fs::remove_file(&path)?;
                         index.remove_entry(&path)?;
                     }
                 }
@@ -151,56 +157,108 @@ impl Tree {
             }
 
             // Apply new files / directories
-            let blob = match new_entry {
+            let (entry_type, hash) = match new_entry {
                 TreeEntry::Directory(new_tree) => {
-                    fs::create_dir(&path)?;
-                    Tree::new().apply(repo_path, index, &path, new_tree)?;
+                    let excluded = index
+                        .sparse
+                        .as_ref()
+                        .is_some_and(|sparse| !sparse.may_contain(name));
+                    if !excluded
+                        && !fs::symlink_metadata(&path)
+                            .map(|m| m.is_dir())
+                            .unwrap_or(false)
+                    {
+                        remove_path(&path)?;
+                        fs::create_dir(&path)?;
+                    }
+                    Tree::new().apply(repo_path, index, &path, new_tree, attributes)?;
                     continue;
                 }
-                TreeEntry::File(hash) => {
-                    let blob = Blob::load(repo_path, *hash);
-                    fs::write(&path, &blob.data)?;
-                    blob
-                }
-                TreeEntry::Executable(hash) => {
-                    let blob = Blob::load(repo_path, *hash);
-                    fs::write(&path, &blob.data)?;
-                    fs::set_permissions(&path, Permissions::from_mode(0o755))?;
-                    blob
-                }
-                TreeEntry::Symlink(hash) => {
-                    let blob = Blob::load(repo_path, *hash);
-                    unix::fs::symlink(std::str::from_utf8(&blob.data[..]).unwrap(), &path)?;
-                    blob
-                }
+                TreeEntry::File(hash) => (EntryType::File, hash),
+                TreeEntry::Executable(hash) => (EntryType::Executable, hash),
+                TreeEntry::Symlink(hash) => (EntryType::Symlink, hash),
             };
+            // Files left out of the working tree only have their entry updated
+            let mut flags = index.flags(name);
+            let excluded = index
+                .sparse
+                .as_ref()
+                .is_some_and(|sparse| !sparse.includes(name));
+            if flags.skip_worktree || excluded {
+                index.entries.insert(name.to_string(), (entry_type, *hash));
+                index.stats.remove(name);
+                flags.skip_worktree = true;
+                index.flags.insert(name.to_string(), flags);
+                continue;
+            }
+
+            // Replace whatever is in the way (checked by the caller)
+            remove_path(&path)?;
+            let blob = Blob::load(repo_path, *hash);
+            blob.to_worktree(&path, &entry_type, attributes)?;
 
             // Update the file to the index
             index.update_entry(&path, &blob)?;
         }
 
         // Remove files / directories from old tree
-        for (filename, _) in self.entries.iter() {
+        for (filename, entry) in self.entries.iter() {
             if !new.entries.contains_key(filename) {
                 let path = path.join(filename);
-                index.remove_entry(&path)?;
-                if path.is_file() {
-                    fs::remove_file(&path)?;
-                } else if path.is_dir() {
-                    fs::remove_dir_all(&path)?;
+                if let TreeEntry::Directory(tree) = entry {
+                    // Untracked files are kept with their directory
+                    tree.apply(repo_path, index, &path, &Tree::new(), attributes)?;
+                    fs::remove_dir(&path).ok();
+                } else {
+                    let name = path.strip_prefix(&root)?.to_str().unwrap();
+                    let skip_worktree = index.flags(name).skip_worktree;
+                    index.remove_entry(&path)?;
+                    if !skip_worktree && fs::symlink_metadata(&path).is_ok() {
+                        fs::remove_file(&path)?;
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Return every file of the tree (recursively) with its type and hash,
+    /// indexed by its path from the root of the tree
+    pub fn files(&self) -> BTreeMap<String, (EntryType, Hash)> {
+        let mut res = BTreeMap::new();
+        for (filename, entry) in self.entries.iter() {
+            match entry {
+                TreeEntry::File(hash) => {
+                    res.insert(filename.clone(), (EntryType::File, *hash));
+                }
+                TreeEntry::Executable(hash) => {
+                    res.insert(filename.clone(), (EntryType::Executable, *hash));
+                }
+                TreeEntry::Symlink(hash) => {
+                    res.insert(filename.clone(), (EntryType::Symlink, *hash));
+                }
+                TreeEntry::Directory(tree) => {
+                    for (path, entry) in tree.files() {
+                        res.insert(format!("{}/{}", filename, path), entry);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Build the tree of the index, the entries of files intended to be added
+    /// are left out
     pub fn from(index: &Index) -> Self {
         let mut root = Tree::new();
         for (path, (entry_type, hash)) in index.entries.iter() {
+            if index.flags(path).intent_to_add {
+                continue;
+            }
             // Compute the tree
             let path = PathBuf::from(path);
             root.create_tree(&path.parent().unwrap().to_path_buf());
-            let tree = root.get_mut_tree(&path.parent().unwrap().to_path_buf());
+            let tree = root.get_mut_tree(path.parent().unwrap());
 
             // Add the file
             match entry_type {
@@ -228,7 +286,7 @@ impl Tree {
     }
 
     /// Return whether the tree contains or not the given path
-    pub fn contains(&self, path: &PathBuf) -> Result<bool, Box<Error>> {
+    pub fn contains(&self, path: &Path) -> Result<bool, Box<dyn Error>> {
         if let Some(root) = path.iter().next() {
             let root = root.to_str().unwrap().to_string();
             let mut path: PathBuf = path.iter().skip(1).collect();
@@ -248,7 +306,7 @@ impl Tree {
     }
 
     /// Given a path return the corresponding entry
-    pub fn get_entry(&self, path: &PathBuf) -> Result<&TreeEntry, Box<Error>> {
+    pub fn get_entry(&self, path: &Path) -> Result<&TreeEntry, Box<dyn Error>> {
         if let Some(root) = path.iter().next() {
             let root = root.to_str().unwrap().to_string();
             let path: PathBuf = path.iter().skip(1).collect();
@@ -269,6 +327,15 @@ impl Tree {
     }
 }
 
+/// Remove a file or a directory if it exists
+fn remove_path(path: &PathBuf) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
 impl Object for Tree {
     fn dump(&self) -> Vec<u8> {
         // Compute data for each entry
@@ -287,8 +354,7 @@ impl Object for Tree {
 
         // Add header
         let header = format!("tree {}\0", data.len());
-        let mut res = vec![];
-        res.reserve(data.len() + header.len());
+        let mut res = Vec::with_capacity(data.len() + header.len());
         res.append(&mut header.into_bytes());
         res.append(&mut data);
         res
@@ -305,7 +371,7 @@ impl Object for Tree {
                 break;
             }
             buff.pop();
-            let desc: Vec<&str> = std::str::from_utf8(&buff).unwrap().split(' ').collect();
+            let desc: Vec<&str> = std::str::from_utf8(&buff).unwrap().splitn(2, ' ').collect();
             let mut hash = [0; 20];
             reader.read_exact(&mut hash).unwrap();
             let hash = hash
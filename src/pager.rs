@@ -0,0 +1,129 @@
+use crate::cmd::config::Config;
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Commands whose output is paged unless `pager.<cmd>` says otherwise
+const PAGED: [&str; 5] = ["log", "show", "blame", "branch", "grep"];
+
+/// Set by the `--no-pager` option
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// A pager process reading the standard output, with a copy of the original
+/// standard output. The output is flushed, restored and the pager is waited
+/// for when this is dropped.
+pub struct Pager(Child, i32);
+
+/// Never start a pager for this process
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Return the pager to use for `command` or None if its output must not be
+/// paged, because `paging` is false (`--no-pager` or the output isn't a
+/// terminal) or by configuration. The pager is `pager.<cmd>` (when it isn't a
+/// boolean), then the first of `pagers` which is set (`$MY_GIT_PAGER`,
+/// `core.pager` and `$PAGER`) or `less -FRX`.
+fn choose(
+    command: &str,
+    paging: bool,
+    specific: Option<&str>,
+    pagers: Vec<Option<String>>,
+) -> Option<String> {
+    if !paging {
+        return None;
+    }
+    let specific = match specific {
+        Some("false") | Some("no") | Some("off") | Some("0") => return None,
+        Some("true") | Some("yes") | Some("on") | Some("1") => None,
+        Some(pager) => Some(pager.to_string()),
+        None if PAGED.contains(&command) => None,
+        None => return None,
+    };
+    let pager = specific
+        .or_else(|| pagers.into_iter().flatten().next())
+        .unwrap_or_else(|| String::from("less -FRX"));
+    match pager.trim() {
+        "" | "cat" => None,
+        _ => Some(pager),
+    }
+}
+
+/// Send the standard output of `command` to a pager if it is a terminal
+pub fn start(command: &str) -> Option<Pager> {
+    let paging = !DISABLED.load(Ordering::Relaxed) && atty::is(atty::Stream::Stdout);
+    if !paging {
+        return None;
+    }
+    let config = Config::load().ok()?;
+    let specific = config.pager.get(command).map(String::as_str);
+    let pagers = vec![
+        env::var("MY_GIT_PAGER").ok(),
+        config.core.pager.clone(),
+        env::var("PAGER").ok(),
+    ];
+    let pager = choose(command, paging, specific, pagers)?;
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(&pager).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        cmd.env("LESS", "FRX");
+    }
+    if env::var_os("LV").is_none() {
+        cmd.env("LV", "-c");
+    }
+    let child = cmd.spawn().ok()?;
+
+    io::stdout().flush().ok();
+    let pipe = child.stdin.as_ref().unwrap().as_raw_fd();
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    unsafe {
+        libc::dup2(pipe, libc::STDOUT_FILENO);
+    }
+    Some(Pager(child, stdout))
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        // The pager stops once every end of the pipe is closed, the standard
+        // output is the original one again
+        drop(self.0.stdin.take());
+        unsafe {
+            libc::dup2(self.1, libc::STDOUT_FILENO);
+            libc::close(self.1);
+        }
+        self.0.wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choice() {
+        let core = |pager: &str| vec![None, Some(pager.to_string()), None];
+        assert_eq!(choose("log", true, None, vec![]).unwrap(), "less -FRX");
+        assert_eq!(choose("log", true, None, core("more")).unwrap(), "more");
+        // $MY_GIT_PAGER comes first, `pager.<cmd>` overrides everything
+        let pagers = vec![Some(String::from("most")), Some(String::from("more")), None];
+        assert_eq!(choose("log", true, None, pagers).unwrap(), "most");
+        assert_eq!(choose("log", true, Some("lv"), core("more")).unwrap(), "lv");
+
+        // --no-pager or an output which isn't a terminal
+        assert_eq!(choose("log", false, None, core("more")), None);
+        assert_eq!(choose("log", false, Some("true"), vec![]), None);
+        // Disabled by configuration
+        assert_eq!(choose("log", true, None, core("cat")), None);
+        assert_eq!(choose("log", true, None, core("")), None);
+        assert_eq!(choose("log", true, Some("false"), vec![]), None);
+        // Commands not paged by default
+        assert_eq!(choose("status", true, None, vec![]), None);
+        assert_eq!(
+            choose("status", true, Some("yes"), vec![]).unwrap(),
+            "less -FRX"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::cmd::config::Config;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Number of threads walking and hashing the working tree: `core.threads`,
+/// one per CPU when unset or 0
+pub fn threads() -> usize {
+    Config::load()
+        .ok()
+        .and_then(|config| config.core.threads)
+        .and_then(|threads| threads.parse().ok())
+        .filter(|threads| *threads > 0)
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Apply `f` to the items on several threads. The results are in the order
+/// of the items whichever thread computed them.
+pub fn map<T, R, F>(threads: usize, items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = threads.min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Each thread takes the next item until there is none left
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match items.get(i) {
+                            Some(item) => done.push((i, f(item))),
+                            None => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, result) in worker.join().unwrap() {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_results() {
+        let items: Vec<usize> = (0..1000).collect();
+        for threads in [1, 4, 2000].iter() {
+            let squares = map(*threads, &items, |i| i * i);
+            assert_eq!(squares, items.iter().map(|i| i * i).collect::<Vec<_>>());
+        }
+    }
+}
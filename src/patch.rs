@@ -0,0 +1,514 @@
+use crate::cmd::commit;
+use crate::diff::{self, Edit};
+use colored::Colorize;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// What is done with the hunks accepted by the user. Staging applies them
+/// to the old side (the index), the other modes revert them from the new
+/// side (the index or the working tree).
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Stage,
+    Unstage,
+    Discard,
+}
+
+impl Mode {
+    fn reverse(self) -> bool {
+        self != Mode::Stage
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            Mode::Stage => "stage",
+            Mode::Unstage => "unstage",
+            Mode::Discard => "discard",
+        }
+    }
+
+    fn prompt(self, what: &str) -> String {
+        match self {
+            Mode::Stage => format!("Stage {}", what),
+            Mode::Unstage => format!("Unstage {}", what),
+            Mode::Discard => format!("Discard {} from worktree", what),
+        }
+    }
+}
+
+/// A line of a hunk with its prefix: ` `, `-` or `+`
+type Line = (char, Vec<u8>);
+
+/// A hunk offered to the user
+#[derive(Clone, Debug, PartialEq)]
+struct Hunk {
+    old_start: usize,
+    new_start: usize,
+    lines: Vec<Line>,
+}
+
+/// Compute the hunks between two contents with 3 lines of context
+fn hunks(old: &[u8], new: &[u8]) -> Vec<Hunk> {
+    let (old, new) = (diff::lines(old), diff::lines(new));
+    diff::hunks(&diff::diff(&old, &new), 3)
+        .into_iter()
+        .map(|hunk| Hunk {
+            old_start: hunk.old_start,
+            new_start: hunk.new_start,
+            lines: hunk
+                .edits
+                .iter()
+                .map(|edit| match edit {
+                    Edit::Equal(i, _) => (' ', old[*i].to_vec()),
+                    Edit::Delete(i) => ('-', old[*i].to_vec()),
+                    Edit::Insert(j) => ('+', new[*j].to_vec()),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+impl Hunk {
+    /// Number of lines of the hunk on the side of `prefix` (`-` or `+`)
+    fn len(&self, prefix: char) -> usize {
+        let lines = self.lines.iter();
+        lines.filter(|(c, _)| *c == ' ' || *c == prefix).count()
+    }
+
+    fn header(&self) -> String {
+        format!(
+            "@@ -{} +{} @@",
+            diff::range(self.old_start, self.len('-')),
+            diff::range(self.new_start, self.len('+'))
+        )
+    }
+
+    /// Format the hunk like a patch, lines without a final newline are
+    /// followed by a marker
+    fn format(&self, color: bool) -> String {
+        let mut res = String::new();
+        let header = self.header();
+        let header = if color {
+            header.cyan().to_string()
+        } else {
+            header
+        };
+        res.push_str(&header);
+        res.push('\n');
+        for (prefix, line) in self.lines.iter() {
+            let text = format!("{}{}", prefix, String::from_utf8_lossy(line));
+            let text = text.trim_end_matches('\n');
+            let text = match prefix {
+                '-' if color => text.red().to_string(),
+                '+' if color => text.green().to_string(),
+                _ => text.to_string(),
+            };
+            res.push_str(&text);
+            res.push('\n');
+            if !line.ends_with(b"\n") {
+                res.push_str("\\ No newline at end of file\n");
+            }
+        }
+        res
+    }
+
+    /// Split the hunk at the context lines between its changes. The
+    /// context lines between two changes are shared by both hunks.
+    fn split(&self) -> Vec<Hunk> {
+        let mut blocks = vec![];
+        let mut i = 0;
+        while i < self.lines.len() {
+            if self.lines[i].0 == ' ' {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < self.lines.len() && self.lines[i].0 != ' ' {
+                i += 1;
+            }
+            blocks.push((start, i));
+        }
+        if blocks.len() < 2 {
+            return vec![self.clone()];
+        }
+        (0..blocks.len())
+            .map(|k| {
+                let start = if k == 0 { 0 } else { blocks[k - 1].1 };
+                let end = blocks.get(k + 1).map_or(self.lines.len(), |b| b.0);
+                let before = Hunk {
+                    old_start: 0,
+                    new_start: 0,
+                    lines: self.lines[..start].to_vec(),
+                };
+                Hunk {
+                    old_start: self.old_start + before.len('-'),
+                    new_start: self.new_start + before.len('+'),
+                    lines: self.lines[start..end].to_vec(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parse the lines of a hunk edited by the user, None when every line was
+/// removed
+fn parse_edited(text: &str) -> Result<Option<Vec<Line>>, String> {
+    let mut lines: Vec<Line> = vec![];
+    for line in text.lines() {
+        if line.starts_with('#') || line.starts_with("@@") {
+            continue;
+        }
+        if line.starts_with('\\') {
+            if let Some((_, last)) = lines.last_mut() {
+                last.pop();
+            }
+            continue;
+        }
+        let mut chars = line.chars();
+        let prefix = match chars.next() {
+            Some(c @ (' ' | '-' | '+')) => c,
+            // Empty lines are empty context lines
+            None => ' ',
+            Some(_) => return Err(format!("unexpected line: {}", line)),
+        };
+        lines.push((prefix, format!("{}\n", chars.as_str()).into_bytes()));
+    }
+    Ok(if lines.is_empty() { None } else { Some(lines) })
+}
+
+/// Build the content resulting from the decisions on the hunks. The other
+/// side of accepted hunks replaces the base side: the old side when staging,
+/// the new side when reverting.
+fn apply(base: &[u8], hunks: &[Hunk], accepted: &[bool], reverse: bool) -> Vec<u8> {
+    let base = diff::lines(base);
+    let (base_side, other_side) = if reverse { ('+', '-') } else { ('-', '+') };
+    let mut res = vec![];
+    let mut pos = 0;
+    for (hunk, accepted) in hunks.iter().zip(accepted) {
+        let start = if reverse {
+            hunk.new_start
+        } else {
+            hunk.old_start
+        };
+        for line in base.iter().take(start).skip(pos) {
+            res.extend_from_slice(line);
+        }
+        // The context shared with the previous hunk was already written
+        let mut skip = pos.saturating_sub(start);
+        let side = if *accepted { other_side } else { base_side };
+        for (prefix, line) in hunk.lines.iter() {
+            if skip > 0 && *prefix == ' ' {
+                skip -= 1;
+                continue;
+            }
+            if *prefix == ' ' || *prefix == side {
+                res.extend_from_slice(line);
+            }
+        }
+        pos = pos.max(start + hunk.len(base_side));
+    }
+    for line in base.iter().skip(pos) {
+        res.extend_from_slice(line);
+    }
+    res
+}
+
+/// What becomes of the base side of a file
+pub enum Outcome {
+    Unchanged,
+    Content(Vec<u8>),
+    Deleted,
+}
+
+/// Read an answer, None at the end of the input
+fn ask(input: &mut dyn BufRead, prompt: &str) -> io::Result<Option<String>> {
+    print!("{} ", prompt.blue().bold());
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+fn help(mode: Mode, options: &str) {
+    let verb = mode.verb();
+    let lines = [
+        ('y', format!("{} this hunk", verb)),
+        ('n', format!("do not {} this hunk", verb)),
+        (
+            'q',
+            format!(
+                "quit; do not {} this hunk or any of the remaining ones",
+                verb
+            ),
+        ),
+        (
+            'a',
+            format!("{} this hunk and all later hunks in the file", verb),
+        ),
+        (
+            'd',
+            format!(
+                "do not {} this hunk or any of the later hunks in the file",
+                verb
+            ),
+        ),
+        (
+            's',
+            String::from("split the current hunk into smaller hunks"),
+        ),
+        ('e', String::from("manually edit the current hunk")),
+        ('?', String::from("print help")),
+    ];
+    for (key, text) in lines.iter() {
+        if options.contains(*key) {
+            println!("{}", format!("{} - {}", key, text).red().bold());
+        }
+    }
+}
+
+/// Let the user edit a hunk in their editor, None when the edit is aborted
+fn edit(repo_path: &Path, hunk: &Hunk, mode: Mode) -> Result<Option<Hunk>, Box<dyn Error>> {
+    let (remove, keep) = if mode.reverse() {
+        ('+', '-')
+    } else {
+        ('-', '+')
+    };
+    let marked = match mode {
+        Mode::Stage => "staging",
+        Mode::Unstage => "unstaging",
+        Mode::Discard => "discarding",
+    };
+    let text = format!(
+        "# Manual hunk edit mode -- see bottom for a quick guide.\n{}# ---\n\
+         # To remove '{}' lines, make them ' ' lines (context).\n\
+         # To remove '{}' lines, delete them.\n\
+         # Lines starting with # will be removed.\n#\n\
+         # If the patch applies cleanly, the edited hunk will immediately be\n\
+         # marked for {}. If it does not apply cleanly, you will be given an\n\
+         # opportunity to edit again. If all lines of the hunk are removed,\n\
+         # then the edit is aborted and the hunk is left unchanged.\n",
+        hunk.format(false),
+        remove,
+        keep,
+        marked
+    );
+    let file = repo_path.join("addp-hunk-edit.diff");
+    fs::write(&file, text)?;
+    let edited = commit::edit(&file);
+    fs::remove_file(&file).ok();
+    let lines = match parse_edited(&edited?) {
+        Ok(Some(lines)) => lines,
+        Ok(None) => return Ok(None),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return Ok(None);
+        }
+    };
+    let edited = Hunk {
+        lines,
+        ..hunk.clone()
+    };
+
+    // The edited hunk must still apply to the base side
+    let base = if mode.reverse() { '+' } else { '-' };
+    let side = |hunk: &Hunk| -> Vec<Vec<u8>> {
+        let lines = hunk.lines.iter();
+        let lines = lines.filter(|(c, _)| *c == ' ' || *c == base);
+        lines.map(|(_, line)| line.clone()).collect()
+    };
+    if side(&edited) != side(hunk) {
+        eprintln!("error: the edited hunk does not apply");
+        return Ok(None);
+    }
+    Ok(Some(edited))
+}
+
+/// Ask the user which changes of a file to use, from the `old` content to
+/// the `new` one (None when the file doesn't exist on a side). Return what
+/// becomes of the base side and whether the user quit.
+pub fn select(
+    input: &mut dyn BufRead,
+    repo_path: &Path,
+    path: &str,
+    (old, new): (Option<&[u8]>, Option<&[u8]>),
+    mode: Mode,
+) -> Result<(Outcome, bool), Box<dyn Error>> {
+    // Binary files can't be split into hunks
+    if old.into_iter().chain(new).any(diff::is_binary) {
+        return Ok((Outcome::Unchanged, false));
+    }
+    let mut hunks = hunks(old.unwrap_or(b""), new.unwrap_or(b""));
+    let whole_file = old.is_none() || new.is_none();
+    if hunks.is_empty() && !whole_file {
+        return Ok((Outcome::Unchanged, false));
+    }
+    let old_name = old.map_or(String::from("/dev/null"), |_| format!("a/{}", path));
+    let new_name = new.map_or(String::from("/dev/null"), |_| format!("b/{}", path));
+    println!("{}", format!("diff --git a/{} b/{}", path, path).bold());
+    println!("{}", format!("--- {}\n+++ {}", old_name, new_name).bold());
+
+    // Additions and deletions are accepted as a whole
+    if whole_file {
+        for hunk in hunks.iter() {
+            print!("{}", hunk.format(true));
+        }
+        let what = if old.is_none() {
+            "addition"
+        } else {
+            "deletion"
+        };
+        let options = "y,n,q,a,d,?";
+        loop {
+            let prompt = format!("(1/1) {} [{}]?", mode.prompt(what), options);
+            let (accepted, quit) = match ask(input, &prompt)?.as_deref() {
+                Some("y") | Some("a") => (true, false),
+                Some("n") | Some("d") => (false, false),
+                Some("q") | None => (false, true),
+                _ => {
+                    help(mode, options);
+                    continue;
+                }
+            };
+            let result = if mode.reverse() { old } else { new };
+            return Ok(match (accepted, result) {
+                (false, _) => (Outcome::Unchanged, quit),
+                (true, Some(data)) => (Outcome::Content(data.to_vec()), quit),
+                (true, None) => (Outcome::Deleted, quit),
+            });
+        }
+    }
+
+    let mut decisions: Vec<Option<bool>> = vec![None; hunks.len()];
+    let mut quit = false;
+    let mut i = 0;
+    while i < hunks.len() {
+        if decisions[i].is_some() {
+            i += 1;
+            continue;
+        }
+        print!("{}", hunks[i].format(true));
+        let splittable = hunks[i].split().len() > 1;
+        let options = if splittable {
+            "y,n,q,a,d,s,e,?"
+        } else {
+            "y,n,q,a,d,e,?"
+        };
+        let prompt = format!(
+            "({}/{}) {} [{}]?",
+            i + 1,
+            hunks.len(),
+            mode.prompt("this hunk"),
+            options
+        );
+        match ask(input, &prompt)?.as_deref() {
+            Some("y") => decisions[i] = Some(true),
+            Some("n") => decisions[i] = Some(false),
+            answer @ (Some("a" | "d" | "q") | None) => {
+                // The hunks already decided upon keep their decision, the
+                // others are skipped when quitting or at the end of the input
+                for decision in decisions[i..].iter_mut().filter(|d| d.is_none()) {
+                    *decision = Some(answer == Some("a"));
+                }
+                quit = matches!(answer, Some("q") | None);
+                if quit {
+                    break;
+                }
+            }
+            Some("s") if splittable => {
+                let split = hunks[i].split();
+                println!(
+                    "{}",
+                    format!("Split into {} hunks.", split.len()).blue().bold()
+                );
+                decisions.splice(i..=i, vec![None; split.len()]);
+                hunks.splice(i..=i, split);
+            }
+            Some("e") => {
+                if let Some(edited) = edit(repo_path, &hunks[i], mode)? {
+                    hunks[i] = edited;
+                    decisions[i] = Some(true);
+                }
+            }
+            _ => help(mode, options),
+        }
+    }
+
+    let accepted: Vec<bool> = decisions.iter().map(|d| d == &Some(true)).collect();
+    if !accepted.contains(&true) {
+        return Ok((Outcome::Unchanged, quit));
+    }
+    let base = if mode.reverse() { new } else { old };
+    let content = apply(base.unwrap_or(b""), &hunks, &accepted, mode.reverse());
+    Ok((Outcome::Content(content), quit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &[u8] = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+    const NEW: &[u8] = b"1\ntwo\n3\n4\n5\n6\nseven\n8\n9\n10\n";
+
+    #[test]
+    fn split_shares_context() {
+        let hunks = hunks(OLD, NEW);
+        assert_eq!(hunks.len(), 1);
+        let split = hunks[0].split();
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].header(), "@@ -1,6 +1,6 @@");
+        assert_eq!(split[1].header(), "@@ -3,8 +3,8 @@");
+    }
+
+    #[test]
+    fn apply_accepted_hunks() {
+        let split = hunks(OLD, NEW)[0].split();
+        let staged = apply(OLD, &split, &[false, true], false);
+        assert_eq!(staged, b"1\n2\n3\n4\n5\n6\nseven\n8\n9\n10\n");
+        let discarded = apply(NEW, &split, &[true, false], true);
+        assert_eq!(discarded, b"1\n2\n3\n4\n5\n6\nseven\n8\n9\n10\n");
+        assert_eq!(apply(OLD, &split, &[true, true], false), NEW);
+        assert_eq!(apply(OLD, &split, &[false, false], false), OLD);
+    }
+
+    #[test]
+    fn apply_without_final_newline() {
+        let hunks = hunks(b"a\nb", b"a\nb\nc\n");
+        assert_eq!(apply(b"a\nb", &hunks, &[true], false), b"a\nb\nc\n");
+        assert_eq!(apply(b"a\nb\nc\n", &hunks, &[true], true), b"a\nb");
+    }
+
+    #[test]
+    fn select_at_end_of_input() {
+        let mut input: &[u8] = b"";
+        let repo_path = Path::new("");
+        let files = (Some(OLD), Some(NEW));
+        let (outcome, quit) = select(&mut input, repo_path, "f", files, Mode::Stage).unwrap();
+        assert!(matches!(outcome, Outcome::Unchanged));
+        assert!(quit);
+        let mut input: &[u8] = b"s\ny\n";
+        let (outcome, quit) = select(&mut input, repo_path, "f", files, Mode::Stage).unwrap();
+        assert!(
+            matches!(outcome, Outcome::Content(content) if content == b"1\ntwo\n3\n4\n5\n6\n7\n8\n9\n10\n")
+        );
+        assert!(quit);
+    }
+
+    #[test]
+    fn parse_edited_hunk() {
+        let text = "@@ -1,2 +1,2 @@\n a\n-b\n+c\n\\ No newline at end of file\n# comment\n";
+        let lines = parse_edited(text).unwrap().unwrap();
+        let expected = vec![
+            (' ', b"a\n".to_vec()),
+            ('-', b"b\n".to_vec()),
+            ('+', b"c".to_vec()),
+        ];
+        assert_eq!(lines, expected);
+        assert_eq!(parse_edited("# only comments\n").unwrap(), None);
+        assert!(parse_edited("x\n").is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::attributes::{Attributes, State};
+use crate::ignore::translate;
+use crate::utils;
+use regex::Regex;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A pathspec of the command line with its magic, see gitglossary(7)
+struct Item {
+    /// The pathspec as given
+    original: String,
+    /// The pattern relative to the root
+    pattern: String,
+    /// Length of the pattern before its first wildcard
+    nowildcard: usize,
+    /// The pattern as a regex, None when it has no wildcard
+    regex: Option<Regex>,
+    /// Whether the pattern ends with a `/`, then it only matches directories
+    dir_only: bool,
+    icase: bool,
+    exclude: bool,
+    /// Attributes required by `:(attr:...)`, None for `!attr`
+    attributes: Vec<(String, Option<State>)>,
+}
+
+/// Parse the requirements of `:(attr:...)`, written like in attributes files
+fn parse_attributes(requirements: &str) -> Vec<(String, Option<State>)> {
+    requirements
+        .split_whitespace()
+        .map(|word| {
+            if let Some(name) = word.strip_prefix('-') {
+                (name.to_string(), Some(State::Unset))
+            } else if let Some(name) = word.strip_prefix('!') {
+                (name.to_string(), None)
+            } else if let Some((name, value)) = word.split_once('=') {
+                (name.to_string(), Some(State::Value(value.to_string())))
+            } else {
+                (word.to_string(), Some(State::Set))
+            }
+        })
+        .collect()
+}
+
+impl Item {
+    /// Parse a pathspec given relatively to `prefix`, the current directory
+    /// relative to the root
+    fn parse(spec: &str, prefix: &Path, root: &Path) -> Result<Item, ErrorPathspec> {
+        let mut item = Item {
+            original: spec.to_string(),
+            pattern: String::new(),
+            nowildcard: 0,
+            regex: None,
+            dir_only: false,
+            icase: false,
+            exclude: false,
+            attributes: vec![],
+        };
+        let (mut top, mut literal, mut glob) = (false, false, false);
+        let rest = if let Some(magic) = spec.strip_prefix(":(") {
+            let end = magic
+                .find(')')
+                .ok_or_else(|| ErrorPathspec::MissingParen(spec.to_string()))?;
+            for word in magic[..end].split(',') {
+                match word {
+                    "top" => top = true,
+                    "exclude" => item.exclude = true,
+                    "icase" => item.icase = true,
+                    "literal" => literal = true,
+                    "glob" => glob = true,
+                    "" => (),
+                    _ => match word.strip_prefix("attr:") {
+                        Some(requirements) => item.attributes = parse_attributes(requirements),
+                        None => {
+                            return Err(ErrorPathspec::InvalidMagic(
+                                word.to_string(),
+                                spec.to_string(),
+                            ))
+                        }
+                    },
+                }
+            }
+            &magic[end + 1..]
+        } else if let Some(short) = spec.strip_prefix(':') {
+            // Short magic: `:/` for top and `:!` or `:^` for exclude
+            let len = short
+                .find(|c| !matches!(c, '/' | '!' | '^'))
+                .unwrap_or(short.len());
+            top = short[..len].contains('/');
+            item.exclude = short[..len].contains(['!', '^']);
+            let rest = &short[len..];
+            rest.strip_prefix(':').unwrap_or(rest)
+        } else {
+            spec
+        };
+
+        // The pattern is relative to the current directory unless `top`
+        let base = if top { Path::new("") } else { prefix };
+        let mut components: Vec<&str> = base.iter().map(|c| c.to_str().unwrap()).collect();
+        for component in rest.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(ErrorPathspec::Outside(spec.to_string(), root.to_path_buf()));
+                    }
+                }
+                component => components.push(component),
+            }
+        }
+        item.pattern = components.join("/");
+        item.dir_only = rest.ends_with('/') && !item.pattern.is_empty();
+        if item.icase {
+            item.pattern = item.pattern.to_lowercase();
+        }
+        item.nowildcard = match literal {
+            true => item.pattern.len(),
+            false => item
+                .pattern
+                .find(['*', '?', '[', '\\'])
+                .unwrap_or(item.pattern.len()),
+        };
+        if item.nowildcard < item.pattern.len() {
+            let regex = format!("^{}$", translate(&item.pattern, glob));
+            item.regex = Regex::new(&regex).ok();
+        }
+        Ok(item)
+    }
+
+    /// Whether the pattern matches a path relative to the root, leaving the
+    /// required attributes aside. A directory matches all its content.
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let lower;
+        let path = match self.icase {
+            true => {
+                lower = path.to_lowercase();
+                &lower
+            }
+            false => path,
+        };
+        let pattern = &self.pattern;
+        let inside = pattern.is_empty()
+            || (path.starts_with(pattern.as_str()) && path[pattern.len()..].starts_with('/'));
+        if inside || (path == pattern && (is_dir || !self.dir_only)) {
+            return true;
+        }
+        self.regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(path))
+    }
+
+    /// Whether paths inside a directory may match the pattern
+    fn may_contain(&self, dir: &str) -> bool {
+        let literal = &self.pattern[..self.nowildcard];
+        let dir = match self.icase {
+            true => dir.to_lowercase(),
+            false => dir.to_string(),
+        };
+        dir.is_empty()
+            || format!("{}/", dir).starts_with(literal)
+            || literal.starts_with(&format!("{}/", dir))
+    }
+}
+
+/// The pathspecs given to a command, matched against the paths of the index
+/// and of trees rather than the file system
+pub struct Pathspec {
+    items: Vec<Item>,
+    /// Attributes of the working tree, only loaded for `:(attr:...)`
+    attributes: Option<Attributes>,
+}
+
+impl Pathspec {
+    /// Parse pathspecs given relatively to the current directory. Without
+    /// any pathspec every path matches, with only excluding ones the paths
+    /// of the current directory match.
+    pub fn new<'a>(specs: impl IntoIterator<Item = &'a str>) -> Result<Pathspec, Box<dyn Error>> {
+        let root = utils::find_root()?;
+        let cwd = env::current_dir()?;
+        let prefix = cwd.strip_prefix(&root)?;
+        let mut items = vec![];
+        for spec in specs {
+            items.push(Item::parse(spec, prefix, &root)?);
+        }
+        if !items.is_empty() && items.iter().all(|item| item.exclude) {
+            items.push(Item::parse(".", prefix, &root)?);
+        }
+        let attributes = match items.iter().any(|item| !item.attributes.is_empty()) {
+            true => Some(Attributes::load(&root)),
+            false => None,
+        };
+        Ok(Pathspec { items, attributes })
+    }
+
+    fn item_matches(&self, item: &Item, path: &str, is_dir: bool) -> bool {
+        if !item.matches(path, is_dir) {
+            return false;
+        }
+        let attributes = match &self.attributes {
+            Some(attributes) if !item.attributes.is_empty() => attributes.get(Path::new(path)),
+            _ => return true,
+        };
+        item.attributes
+            .iter()
+            .all(|(name, state)| attributes.get(name) == state.as_ref())
+    }
+
+    /// Whether a path relative to the root matches the pathspecs
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let mut included = self.items.iter().all(|item| item.exclude);
+        for item in self.items.iter() {
+            if self.item_matches(item, path, is_dir) {
+                if item.exclude {
+                    return false;
+                }
+                included = true;
+            }
+        }
+        included
+    }
+
+    /// Whether paths inside a directory relative to the root may match the
+    /// pathspecs, the other directories don't need to be looked at
+    pub fn may_contain(&self, dir: &str) -> bool {
+        let excluded = self
+            .items
+            .iter()
+            .any(|item| item.exclude && item.regex.is_none() && self.item_matches(item, dir, true));
+        let mut included = self.items.iter().filter(|item| !item.exclude).peekable();
+        !excluded && (included.peek().is_none() || included.any(|item| item.may_contain(dir)))
+    }
+
+    /// The paths relative to the root where the matching files can be
+    /// found: the part of the patterns before their first wildcard
+    pub fn bases(&self) -> Vec<PathBuf> {
+        let mut res: Vec<PathBuf> = vec![];
+        let mut bases: Vec<PathBuf> = self
+            .items
+            .iter()
+            .filter(|item| !item.exclude)
+            .map(|item| match item.regex {
+                _ if item.icase => PathBuf::new(),
+                None => PathBuf::from(&item.pattern),
+                Some(_) => {
+                    let literal = &item.pattern[..item.nowildcard];
+                    PathBuf::from(&literal[..literal.rfind('/').unwrap_or(0)])
+                }
+            })
+            .collect();
+        if bases.is_empty() {
+            bases.push(PathBuf::new());
+        }
+        bases.sort();
+        for base in bases {
+            if !res.iter().any(|other| base.starts_with(other)) {
+                res.push(base);
+            }
+        }
+        res
+    }
+
+    /// Check that each pathspec matches one of the paths, relative to the
+    /// root, like git does before changing anything
+    pub fn check_matched<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ErrorPathspec> {
+        let mut unmatched: Vec<&Item> = self.items.iter().filter(|item| !item.exclude).collect();
+        for path in paths {
+            unmatched.retain(|item| !self.item_matches(item, path, false));
+            if unmatched.is_empty() {
+                break;
+            }
+        }
+        match unmatched.first() {
+            Some(item) => Err(ErrorPathspec::Unmatched(item.original.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorPathspec {
+    InvalidMagic(String, String),
+    MissingParen(String),
+    Outside(String, PathBuf),
+    Unmatched(String),
+}
+
+impl fmt::Display for ErrorPathspec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorPathspec::InvalidMagic(magic, spec) => {
+                write!(f, "fatal: Invalid pathspec magic '{}' in '{}'", magic, spec)
+            }
+            ErrorPathspec::MissingParen(spec) => write!(
+                f,
+                "fatal: Missing ')' at the end of pathspec magic in '{}'",
+                spec
+            ),
+            ErrorPathspec::Outside(spec, root) => write!(
+                f,
+                "fatal: {}: '{}' is outside repository at '{}'",
+                spec,
+                spec,
+                root.display()
+            ),
+            ErrorPathspec::Unmatched(spec) => {
+                write!(f, "fatal: pathspec '{}' did not match any files", spec)
+            }
+        }
+    }
+}
+
+impl Error for ErrorPathspec {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathspec(specs: &[&str], prefix: &str) -> Pathspec {
+        let items = specs
+            .iter()
+            .map(|spec| Item::parse(spec, Path::new(prefix), Path::new("/repo")).unwrap())
+            .collect();
+        Pathspec {
+            items,
+            attributes: None,
+        }
+    }
+
+    #[test]
+    fn relative() {
+        let spec = pathspec(&["b.rs", "../doc/", ":/top", ":(top)x"], "src");
+        assert!(spec.matches("src/b.rs", false) && spec.matches("doc/a.md", false));
+        assert!(!spec.matches("doc", false) && spec.matches("doc", true));
+        assert!(spec.matches("top", false) && spec.matches("x/y", false));
+        assert!(!spec.matches("b.rs", false) && !spec.matches("src/b.rs.orig", false));
+        assert!(Item::parse("../..", Path::new("src"), Path::new("/repo")).is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        let spec = pathspec(&["*.rs"], "");
+        assert!(spec.matches("main.rs", false) && spec.matches("src/a/b.rs", false));
+        let spec = pathspec(&[":(glob)src/*.rs", ":(glob)**/test/**"], "");
+        assert!(spec.matches("src/a.rs", false) && !spec.matches("src/a/b.rs", false));
+        assert!(spec.matches("a/b/test/c", false) && spec.matches("test/c", false));
+        let spec = pathspec(&[":(literal)*.rs", ":(icase)README"], "");
+        assert!(spec.matches("*.rs", false) && !spec.matches("a.rs", false));
+        assert!(spec.matches("ReadMe", false));
+    }
+
+    #[test]
+    fn exclude() {
+        let spec = pathspec(&["src", ":!src/gen", ":(exclude)*.bak"], "");
+        assert!(spec.matches("src/main.rs", false));
+        assert!(!spec.matches("src/gen/a.rs", false) && !spec.matches("src/a.bak", false));
+        assert!(!spec.may_contain("src/gen") && spec.may_contain("src"));
+        assert!(!spec.may_contain("doc"));
+        let spec = pathspec(&[":^a"], "");
+        assert!(spec.matches("b", false) && !spec.matches("a/b", false));
+    }
+
+    #[test]
+    fn bases() {
+        let spec = pathspec(&["src/a*/b", "src/b.rs", "doc", ":!doc/x"], "");
+        assert_eq!(
+            spec.bases(),
+            vec![PathBuf::from("doc"), PathBuf::from("src")]
+        );
+        let spec = pathspec(&["src/b.rs", "*.md"], "");
+        assert_eq!(spec.bases(), vec![PathBuf::new()]);
+    }
+
+    #[test]
+    fn magic() {
+        let spec = pathspec(&[":(attr:-text a=b !c)"], "");
+        assert_eq!(
+            spec.items[0].attributes,
+            vec![
+                (String::from("text"), Some(State::Unset)),
+                (String::from("a"), Some(State::Value(String::from("b")))),
+                (String::from("c"), None),
+            ]
+        );
+        assert!(Item::parse(":(foo)x", Path::new(""), Path::new("/")).is_err());
+        assert!(Item::parse(":(top", Path::new(""), Path::new("/")).is_err());
+        let spec = pathspec(&["a", "b"], "");
+        assert!(spec.check_matched(vec!["a/x", "b"]).is_ok());
+        assert!(spec.check_matched(vec!["a/x"]).is_err());
+    }
+}
@@ -1,8 +1,10 @@
-use crate::objects::{Commit, Hash, Object};
+use crate::cmd::config::Config;
+use crate::index::Index;
+use crate::objects::{self, Commit, Hash, Object, Tag, Tree, TreeEntry};
 use std::collections::HashMap;
 use std::fs;
-use std::io::Error;
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 /// Dereference a ref (not guaranteed value existence)
@@ -17,8 +19,8 @@ fn deref(repo_path: &PathBuf, ref_: &String) -> Result<String, Error> {
     let mut content = fs::read_to_string(path)?;
     // Remove trailing newline
     content.pop();
-    if content.starts_with("ref: ") {
-        deref(repo_path, &content[5..].to_string())
+    if let Some(content) = content.strip_prefix("ref: ") {
+        deref(repo_path, &content.to_string())
     } else {
         Ok(ref_.clone())
     }
@@ -42,26 +44,24 @@ pub fn get_head(repo_path: &PathBuf) -> Option<Commit> {
 }
 
 /// This function get all references given a path and adding a prefix
-fn branches_(
-    branches: &mut HashMap<String, Hash>,
+fn refs_(
+    refs: &mut HashMap<String, Hash>,
     repo_path: &PathBuf,
     path: &PathBuf,
     prefix: &String,
+    base: &str,
 ) {
-    for file in fs::read_dir(path).expect("Can't read in heads directory") {
+    for file in fs::read_dir(path).expect("Can't read in refs directory") {
         let file = file.unwrap();
         let file_name = file.file_name().into_string().unwrap();
         if file.path().is_dir() {
             let prefix = format!("{}{}/", prefix, file_name);
-            branches_(branches, repo_path, &path.join(file_name), &prefix)
+            refs_(refs, repo_path, &path.join(file_name), &prefix, base)
         } else {
-            let branch_name = format!("{}{}", prefix, file_name);
-            let hash = resolve(
-                repo_path,
-                &format!("refs/heads/{}", branch_name).to_string(),
-            );
+            let ref_name = format!("{}{}", prefix, file_name);
+            let hash = resolve(repo_path, &format!("{}{}", base, ref_name));
             if let Ok(hash) = hash {
-                branches.insert(branch_name, hash);
+                refs.insert(ref_name, hash);
             }
         }
     }
@@ -70,15 +70,64 @@ fn branches_(
 /// This function return the list of branches and their associated commits hash
 pub fn branches(repo_path: &PathBuf) -> HashMap<String, Hash> {
     let mut res = HashMap::new();
-    branches_(
+    refs_(
         &mut res,
-        &repo_path,
+        repo_path,
         &repo_path.join("refs/heads"),
         &String::new(),
+        "refs/heads/",
     );
     res
 }
 
+/// This function return the list of tags and their associated objects hash
+pub fn tags(repo_path: &PathBuf) -> HashMap<String, Hash> {
+    let mut res = HashMap::new();
+    let path = repo_path.join("refs/tags");
+    if path.is_dir() {
+        refs_(&mut res, repo_path, &path, &String::new(), "refs/tags/");
+    }
+    res
+}
+
+/// Return the object an annotated tag points to, following tags of tags, or
+/// the hash itself for other objects
+fn peel(repo_path: &Path, mut hash: Hash) -> Hash {
+    while objects::object_type(repo_path, hash).as_deref() == Some("tag") {
+        hash = Tag::load(repo_path, hash).object;
+    }
+    hash
+}
+
+/// This function return the names of the refs pointing to each commit: HEAD
+/// first, then branches and tags.
+pub fn decorations(repo_path: &PathBuf) -> HashMap<Hash, Vec<String>> {
+    let mut res: HashMap<Hash, Vec<String>> = HashMap::new();
+    let current = current_branch(repo_path).map(|(branch, _)| branch);
+    if let Ok(head_hash) = resolve(repo_path, &String::from("HEAD")) {
+        let head = match &current {
+            Some(branch) => format!("HEAD -> {}", branch),
+            None => String::from("HEAD"),
+        };
+        res.entry(head_hash).or_default().push(head);
+    }
+
+    let mut branches: Vec<(String, Hash)> = branches(repo_path).into_iter().collect();
+    branches.sort();
+    for (branch, hash) in branches {
+        if Some(&branch) != current.as_ref() {
+            res.entry(hash).or_default().push(branch);
+        }
+    }
+    let mut tags: Vec<(String, Hash)> = tags(repo_path).into_iter().collect();
+    tags.sort();
+    for (tag, hash) in tags {
+        let hash = peel(repo_path, hash);
+        res.entry(hash).or_default().push(format!("tag: {}", tag));
+    }
+    res
+}
+
 /// This function return the current branch name and his associated commits hash
 /// Return None if no commit or detached HEAD
 pub fn current_branch(repo_path: &PathBuf) -> Option<(String, Hash)> {
@@ -101,15 +150,43 @@ pub fn current_branch(repo_path: &PathBuf) -> Option<(String, Hash)> {
     }
 }
 
+/// Return the branch HEAD points to, even if it has no commit yet, or None if
+/// HEAD is detached
+pub fn head_branch(repo_path: &Path) -> Option<String> {
+    let head = fs::read_to_string(repo_path.join("HEAD")).ok()?;
+    head.trim_end()
+        .strip_prefix("ref: refs/heads/")
+        .map(String::from)
+}
+
+/// Return the upstream of a branch, set by `branch.<name>.remote` and
+/// `branch.<name>.merge`, with its commit if the ref exists. The remote `.`
+/// is the local repository.
+pub fn upstream(repo_path: &PathBuf, branch: &str) -> Option<(String, Option<Hash>)> {
+    let config = Config::load().ok()?;
+    let settings = config.branch.get(branch)?;
+    let (remote, merge) = (settings.get("remote")?, settings.get("merge")?);
+    let merge = merge.strip_prefix("refs/heads/").unwrap_or(merge);
+    let (name, ref_) = match remote.as_str() {
+        "." => (merge.to_string(), format!("refs/heads/{}", merge)),
+        remote => (
+            format!("{}/{}", remote, merge),
+            format!("refs/remotes/{}/{}", remote, merge),
+        ),
+    };
+    let hash = resolve(repo_path, &ref_).ok();
+    Some((name, hash))
+}
+
 /// This function removes a ref given its path
 pub fn remove_ref(path: &PathBuf) -> Result<(), Box<Error>> {
-    fs::remove_file(&path)?;
+    fs::remove_file(path)?;
     let mut path = path.clone();
 
     // Remove parent directories if empty
     loop {
         path.pop();
-        if let Err(_) = fs::remove_dir(&path) {
+        if fs::remove_dir(&path).is_err() {
             return Ok(());
         }
     }
@@ -139,3 +216,212 @@ pub fn update(
     fs::write(path, format!("{}\n", value))?;
     Ok(())
 }
+
+/// Find the object whose hash starts with the given (abbreviated) hash
+fn expand_hash(repo_path: &Path, prefix: &str) -> Option<Hash> {
+    if prefix.len() < 4 || prefix.len() > 40 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let dir = repo_path.join("objects").join(&prefix[..2]);
+    let mut found = vec![];
+    for entry in fs::read_dir(dir).ok()? {
+        let name = entry.ok()?.file_name().into_string().ok()?;
+        if name.starts_with(&prefix[2..]) {
+            found.push(format!("{}{}", &prefix[..2], name));
+        }
+    }
+    match found.len() {
+        1 => Hash::from_str(&found[0]).ok(),
+        _ => None,
+    }
+}
+
+/// Resolve a name (ref, branch, tag or hash) without any suffix
+fn resolve_name(repo_path: &PathBuf, name: &str) -> Option<Hash> {
+    let name = if name == "@" { "HEAD" } else { name };
+    let mut candidates = vec![];
+    if name.starts_with("refs/") || name.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        candidates.push(name.to_string());
+    }
+    candidates.push(format!("refs/{}", name));
+    candidates.push(format!("refs/tags/{}", name));
+    candidates.push(format!("refs/heads/{}", name));
+    for candidate in candidates {
+        if repo_path.join(&candidate).is_file() {
+            return resolve(repo_path, &candidate).ok();
+        }
+    }
+    expand_hash(repo_path, name)
+}
+
+/// This function resolves a revision to the hash of a commit. Supported
+/// revisions are refs, branch and tag names, (abbreviated) hashes followed by
+/// any number of `^<n>` (n-th parent) and `~<n>` (n-th first-parent ancestor)
+/// suffixes. Revisions naming another type of object are an error.
+pub fn rev_parse(repo_path: &PathBuf, rev: &str) -> Result<Hash, Error> {
+    let unknown = || {
+        Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "fatal: ambiguous argument '{}': unknown revision or path not in the working tree.",
+                rev
+            ),
+        )
+    };
+    let split = rev.find(['^', '~']).unwrap_or(rev.len());
+    let hash = resolve_name(repo_path, &rev[..split]).ok_or_else(unknown)?;
+    let mut hash = peel(repo_path, hash);
+    match objects::object_type(repo_path, hash).as_deref() {
+        Some("commit") => (),
+        Some(kind) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("fatal: object {} is a {}, not a commit", hash, kind),
+            ))
+        }
+        None => return Err(unknown()),
+    }
+
+    let mut suffix = rev[split..].chars().peekable();
+    while let Some(op) = suffix.next() {
+        let mut count = String::new();
+        while let Some(c) = suffix.peek().filter(|c| c.is_ascii_digit()) {
+            count.push(*c);
+            suffix.next();
+        }
+        let count: usize = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(|_| unknown())?
+        };
+        match op {
+            '^' if count == 0 => (),
+            '^' => {
+                let commit = Commit::load(repo_path, hash);
+                hash = *commit.parents.get(count - 1).ok_or_else(unknown)?;
+            }
+            '~' => {
+                for _ in 0..count {
+                    let commit = Commit::load(repo_path, hash);
+                    hash = *commit.parents.first().ok_or_else(unknown)?;
+                }
+            }
+            _ => return Err(unknown()),
+        }
+    }
+    Ok(hash)
+}
+
+/// This function resolves a revision to the hash of any object. On top of
+/// the revisions of `rev_parse`, tags are not dereferenced and
+/// `<rev>:<path>` (`:<path>` for the index) designate a blob or a tree.
+pub fn resolve_object(repo_path: &PathBuf, rev: &str) -> Result<Hash, Error> {
+    let (rev, path) = match rev.find(':') {
+        Some(pos) => (&rev[..pos], &rev[pos + 1..]),
+        None if rev.contains(['^', '~']) => return rev_parse(repo_path, rev),
+        None => {
+            return resolve_name(repo_path, rev).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "fatal: ambiguous argument '{}': unknown revision or path not in the working tree.",
+                        rev
+                    ),
+                )
+            })
+        }
+    };
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    let missing = |place: &str| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("fatal: path '{}' does not exist in '{}'", path, place),
+        )
+    };
+
+    if rev.is_empty() {
+        let index = Index::load(repo_path)?;
+        return match index.entries.get(path) {
+            Some((_, hash)) => Ok(*hash),
+            None => Err(missing("the index")),
+        };
+    }
+    let commit = Commit::load(repo_path, rev_parse(repo_path, rev)?);
+    if path.is_empty() {
+        return Ok(commit.tree);
+    }
+    let tree = Tree::load(repo_path, commit.tree);
+    match tree.get_entry(&PathBuf::from(path)) {
+        Ok(TreeEntry::Directory(tree)) => Ok(tree.hash()),
+        Ok(TreeEntry::File(hash))
+        | Ok(TreeEntry::Executable(hash))
+        | Ok(TreeEntry::Symlink(hash)) => Ok(*hash),
+        Err(_) => Err(missing(rev)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{Blob, User};
+    use chrono::{FixedOffset, TimeZone};
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn revisions() {
+        let repo_path = env::temp_dir().join(format!("my_git_refs_{}", process::id()));
+        fs::create_dir_all(repo_path.join("refs/heads")).unwrap();
+        let commit = |parents: Vec<Hash>, message: &str| {
+            let commit = Commit::new(
+                Hash::from_str(&"0".repeat(40)).unwrap(),
+                parents,
+                User::new(String::from("John Doe"), String::from("john@doe.com")),
+                FixedOffset::east(0).timestamp(1_000_000_000, 0),
+                message.to_string(),
+            );
+            commit.save(&repo_path);
+            commit.hash()
+        };
+
+        // root <- first <- merge, root <- side <- merge
+        let root = commit(vec![], "root\n");
+        let first = commit(vec![root], "first\n");
+        let side = commit(vec![root], "side\n");
+        let merge = commit(vec![first, side], "merge\n");
+        fs::write(repo_path.join("refs/heads/master"), format!("{}\n", merge)).unwrap();
+        fs::write(repo_path.join("refs/heads/side"), format!("{}\n", side)).unwrap();
+        fs::write(repo_path.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+
+        let rev = |rev: &str| rev_parse(&repo_path, rev).ok();
+        assert_eq!(rev("HEAD"), Some(merge));
+        assert_eq!(rev("@"), Some(merge));
+        assert_eq!(rev("master^0"), Some(merge));
+        assert_eq!(rev("HEAD~"), Some(first));
+        assert_eq!(rev("HEAD^"), Some(first));
+        assert_eq!(rev("HEAD^2"), Some(side));
+        assert_eq!(rev("HEAD~2"), Some(root));
+        assert_eq!(rev("HEAD^2~1"), Some(root));
+        assert_eq!(rev("side^^0"), Some(root));
+        assert_eq!(rev("refs/heads/side"), Some(side));
+        assert_eq!(rev(&first.to_string()[..7]), Some(first));
+        assert_eq!(rev("HEAD~3"), None);
+        assert_eq!(rev("HEAD^3"), None);
+        assert_eq!(rev("unknown"), None);
+
+        // Other objects aren't commits
+        let blob = Blob::new(b"content\n".to_vec());
+        blob.save(&repo_path);
+        let err = rev_parse(&repo_path, &blob.hash().to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("fatal: object {} is a blob, not a commit", blob.hash())
+        );
+        let mut tree = Tree::new();
+        tree.add_file(String::from("file"), blob.hash());
+        tree.save(&repo_path);
+        assert_eq!(rev(&format!("{}^", tree.hash())), None);
+        fs::remove_dir_all(&repo_path).unwrap();
+    }
+}
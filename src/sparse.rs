@@ -0,0 +1,203 @@
+use crate::cmd::config::Config;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The directories of a sparse checkout in cone mode. Their files are
+/// checked out with the files at the root and directly in their parents.
+/// They are stored in `info/sparse-checkout` as git's cone patterns.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sparse {
+    /// Directories checked out recursively, none is inside another one
+    dirs: BTreeSet<String>,
+    /// Parents of the directories, only their files are checked out
+    parents: BTreeSet<String>,
+}
+
+/// Escape the characters with a special meaning in patterns
+fn escape(dir: &str) -> String {
+    let mut res = String::new();
+    for c in dir.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut res = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.extend(chars.next()),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+impl Sparse {
+    /// A sparse checkout of the given directories (relative to the root)
+    pub fn new<'a>(dirs: impl IntoIterator<Item = &'a str>) -> Sparse {
+        let dirs: BTreeSet<&str> = dirs
+            .into_iter()
+            .map(|dir| dir.trim_matches('/'))
+            .filter(|dir| !dir.is_empty())
+            .collect();
+        let mut sparse = Sparse::default();
+        for dir in dirs.iter() {
+            // Directories inside another one add nothing
+            let mut ancestors = dir.match_indices('/').map(|(i, _)| &dir[..i]);
+            if ancestors.any(|ancestor| dirs.contains(ancestor)) {
+                continue;
+            }
+            for (i, _) in dir.match_indices('/') {
+                sparse.parents.insert(dir[..i].to_string());
+            }
+            sparse.dirs.insert(dir.to_string());
+        }
+        sparse
+    }
+
+    /// The sparse checkout of the repository, when `core.sparseCheckout` is
+    /// set
+    pub fn load(repo_path: &Path) -> Option<Sparse> {
+        let enabled = Config::load().ok()?.core.sparse_checkout;
+        if enabled.as_deref() != Some("true") {
+            return None;
+        }
+        Sparse::read(repo_path).ok()
+    }
+
+    /// Read the patterns of `info/sparse-checkout`, which only checks out
+    /// the root when it doesn't exist
+    pub fn read(repo_path: &Path) -> Result<Sparse, Box<dyn Error>> {
+        match fs::read_to_string(repo_path.join("info/sparse-checkout")) {
+            Ok(content) => Ok(Sparse::parse(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Sparse::default()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Parse cone patterns: the files at the root (`/*` and `!/*/`), the
+    /// parents (`/<dir>/` followed by `!/<dir>/*/`) and the directories
+    /// (`/<dir>/`)
+    fn parse(content: &str) -> Result<Sparse, ErrorSparse> {
+        let mut dirs: Vec<String> = vec![];
+        let mut parents = BTreeSet::new();
+        for line in content.lines().map(str::trim_end) {
+            if line.is_empty() || line.starts_with('#') || line == "/*" || line == "!/*/" {
+                continue;
+            }
+            let dir = if let Some(parent) = line.strip_prefix("!/") {
+                parent.strip_suffix("/*/").map(|parent| (parent, true))
+            } else if let Some(dir) = line.strip_prefix('/') {
+                dir.strip_suffix('/').map(|dir| (dir, false))
+            } else {
+                None
+            };
+            match dir {
+                Some((parent, true)) => {
+                    parents.insert(unescape(parent));
+                }
+                Some((dir, false)) => dirs.push(unescape(dir)),
+                None => return Err(ErrorSparse::NotCone(line.to_string())),
+            }
+        }
+        let dirs = dirs.iter().filter(|dir| !parents.contains(*dir));
+        Ok(Sparse::new(dirs.map(String::as_str)))
+    }
+
+    /// Write the cone patterns of the directories
+    pub fn dump(&self) -> String {
+        let mut res = String::from("/*\n!/*/\n");
+        for dir in self.parents.union(&self.dirs) {
+            res.push_str(&format!("/{}/\n", escape(dir)));
+            if self.parents.contains(dir) {
+                res.push_str(&format!("!/{}/*/\n", escape(dir)));
+            }
+        }
+        res
+    }
+
+    pub fn save(&self, repo_path: &Path) -> io::Result<()> {
+        fs::create_dir_all(repo_path.join("info"))?;
+        fs::write(repo_path.join("info/sparse-checkout"), self.dump())
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &String> {
+        self.dirs.iter()
+    }
+
+    /// Whether a file (relative to the root) is checked out
+    pub fn includes(&self, path: &str) -> bool {
+        let parent = path.rfind('/').map_or("", |i| &path[..i]);
+        parent.is_empty() || self.parents.contains(parent) || self.in_dirs(parent)
+    }
+
+    /// Whether a directory may contain files which are checked out
+    pub fn may_contain(&self, dir: &str) -> bool {
+        dir.is_empty() || self.parents.contains(dir) || self.in_dirs(dir)
+    }
+
+    /// Whether a directory is one of the directories or inside one
+    fn in_dirs(&self, dir: &str) -> bool {
+        self.dirs.contains(dir)
+            || dir
+                .match_indices('/')
+                .any(|(i, _)| self.dirs.contains(&dir[..i]))
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorSparse {
+    NotCone(String),
+}
+
+impl fmt::Display for ErrorSparse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorSparse::NotCone(pattern) => write!(
+                f,
+                "fatal: unrecognized pattern: '{}', only cone patterns are supported",
+                pattern
+            ),
+        }
+    }
+}
+
+impl Error for ErrorSparse {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cone() {
+        let sparse = Sparse::new(vec!["a/b/", "c", "a/b/d"]);
+        assert_eq!(sparse.dirs().collect::<Vec<_>>(), vec!["a/b", "c"]);
+        assert!(sparse.includes("README"));
+        assert!(sparse.includes("a/file"));
+        assert!(!sparse.includes("a/other/file"));
+        assert!(sparse.includes("a/b/d/e/file"));
+        assert!(sparse.includes("c/file"));
+        assert!(!sparse.includes("cd/file"));
+        assert!(sparse.may_contain("a") && sparse.may_contain("a/b/d"));
+        assert!(!sparse.may_contain("a/other") && !sparse.may_contain("d"));
+    }
+
+    #[test]
+    fn dump_parse() {
+        let sparse = Sparse::new(vec!["a/b", "c*"]);
+        let dump = sparse.dump();
+        assert_eq!(dump, "/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n/c\\*/\n");
+        assert_eq!(Sparse::parse(&dump).unwrap(), sparse);
+        assert_eq!(Sparse::parse("").unwrap(), Sparse::default());
+        assert!(Sparse::parse("*.rs\n").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::cmd::config::Config;
+use crate::index::Stat;
+use crate::objects::Hash;
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+
+/// Signature of the index extension storing the cache
+pub const SIGNATURE: &[u8; 4] = b"MYUC";
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let bytes = data.get(*pos..*pos + 4)?;
+    *pos += 4;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read a NUL terminated string
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+    let len = data.get(*pos..)?.iter().position(|c| *c == 0)?;
+    let res = String::from_utf8(data[*pos..*pos + len].to_vec()).ok()?;
+    *pos += len + 1;
+    Some(res)
+}
+
+/// What a directory contained when it was last searched for untracked files
+struct Dir {
+    stat: Stat,
+    /// Hash of the ignore rules of its entries
+    rules: Hash,
+    /// Names of the entries which aren't ignored, `true` for directories
+    entries: Vec<(String, bool)>,
+}
+
+/// The untracked cache, stored with the index when `core.untrackedCache` is
+/// set. The entries of a directory are read again only when its stat data or
+/// its ignore rules changed.
+#[derive(Default)]
+pub struct UntrackedCache {
+    /// Directories by path relative to the root
+    dirs: BTreeMap<String, Dir>,
+    /// Whether directories were updated since the cache was read
+    modified: bool,
+}
+
+impl UntrackedCache {
+    pub fn new() -> UntrackedCache {
+        UntrackedCache {
+            dirs: BTreeMap::new(),
+            modified: false,
+        }
+    }
+
+    /// Whether the untracked cache is enabled by `core.untrackedCache`
+    pub fn enabled() -> bool {
+        Config::load()
+            .ok()
+            .and_then(|config| config.core.untracked_cache)
+            .is_some_and(|value| value == "true")
+    }
+
+    /// The entries of a directory if they are still valid. Without stat data
+    /// the directory is known to be unchanged (by the file system monitor).
+    pub fn get(
+        &self,
+        dir: &str,
+        rules: &Hash,
+        metadata: Option<&fs::Metadata>,
+    ) -> Option<&Vec<(String, bool)>> {
+        let cached = self.dirs.get(dir)?;
+        let unchanged = metadata.is_none_or(|m| cached.stat.same(&Stat::from_metadata(m)));
+        if cached.rules == *rules && unchanged {
+            Some(&cached.entries)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        dir: String,
+        metadata: &fs::Metadata,
+        rules: Hash,
+        entries: Vec<(String, bool)>,
+    ) {
+        let stat = Stat::from_metadata(metadata);
+        self.modified = true;
+        self.dirs.insert(
+            dir,
+            Dir {
+                stat,
+                rules,
+                entries,
+            },
+        );
+    }
+
+    /// Forget the directories for which `changed` is true
+    pub fn invalidate(&mut self, changed: impl Fn(&str) -> bool) {
+        let len = self.dirs.len();
+        self.dirs.retain(|dir, _| !changed(dir));
+        self.modified |= self.dirs.len() != len;
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Parse the content of the index extension
+    pub fn parse(data: &[u8]) -> Option<UntrackedCache> {
+        let mut pos = 0;
+        let mut cache = UntrackedCache::new();
+        for _ in 0..read_u32(data, &mut pos)? {
+            let dir = read_string(data, &mut pos)?;
+            let mut fields = vec![];
+            for _ in 0..10 {
+                fields.push(read_u32(data, &mut pos)?);
+            }
+            let stat = Stat {
+                ctime: (fields[0], fields[1]),
+                mtime: (fields[2], fields[3]),
+                dev: fields[4],
+                ino: fields[5],
+                uid: fields[6],
+                gid: fields[7],
+                size: fields[8],
+            };
+            let rules = data.get(pos..pos + 20)?;
+            let rules: String = rules.iter().map(|c| format!("{:02x}", c)).collect();
+            pos += 20;
+            let mut entries = vec![];
+            for _ in 0..fields[9] {
+                let is_dir = *data.get(pos)? == 1;
+                pos += 1;
+                entries.push((read_string(data, &mut pos)?, is_dir));
+            }
+            let rules = Hash::from_str(&rules).ok()?;
+            cache.dirs.insert(
+                dir,
+                Dir {
+                    stat,
+                    rules,
+                    entries,
+                },
+            );
+        }
+        Some(cache)
+    }
+
+    /// Serialize the cache for the index extension. Directories modified in
+    /// the same second as the index could change again without their stat
+    /// data changing, they are left out.
+    pub fn dump(&self, now: u32) -> Vec<u8> {
+        let dirs: Vec<_> = self
+            .dirs
+            .iter()
+            .filter(|(_, dir)| dir.stat.mtime.0 < now)
+            .collect();
+        let mut data = (dirs.len() as u32).to_be_bytes().to_vec();
+        for (path, dir) in dirs {
+            data.extend_from_slice(path.as_bytes());
+            data.push(0);
+            let stat = &dir.stat;
+            for field in [
+                stat.ctime.0,
+                stat.ctime.1,
+                stat.mtime.0,
+                stat.mtime.1,
+                stat.dev,
+                stat.ino,
+                stat.uid,
+                stat.gid,
+                stat.size,
+                dir.entries.len() as u32,
+            ]
+            .iter()
+            {
+                data.extend(&field.to_be_bytes());
+            }
+            data.extend_from_slice(&dir.rules.bytes());
+            for (name, is_dir) in dir.entries.iter() {
+                data.push(*is_dir as u8);
+                data.extend_from_slice(name.as_bytes());
+                data.push(0);
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::Sha1;
+
+    #[test]
+    fn dump_parse() {
+        let metadata = fs::metadata(".").unwrap();
+        let rules = Hash::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let entries = vec![(String::from("a"), false), (String::from("b c"), true)];
+        let mut cache = UntrackedCache::new();
+        cache.insert(String::from("dir"), &metadata, rules, entries.clone());
+        let parsed = UntrackedCache::parse(&cache.dump(u32::MAX)).unwrap();
+        assert_eq!(parsed.get("dir", &rules, Some(&metadata)), Some(&entries));
+        assert_eq!(parsed.get("dir", &Sha1::new().digest(), None), None);
+        assert_eq!(parsed.get("other", &rules, None), None);
+
+        // Recently modified directories aren't stored
+        let parsed = UntrackedCache::parse(&cache.dump(0)).unwrap();
+        assert_eq!(parsed.get("dir", &rules, None), None);
+    }
+}
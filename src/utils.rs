@@ -1,10 +1,10 @@
-use glob::{Pattern, PatternError};
+use crate::ignore::Ignore;
+use chrono::offset::{Local, TimeZone};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 /// This function return the path to the repository. If not in a my-git repository then return an
 /// error.
@@ -34,6 +34,36 @@ pub fn find_root() -> Result<PathBuf, Error> {
     Ok(path)
 }
 
+/// This function return the path relative to the root of the repository of
+/// a path given relatively to the current directory. Return an error if the
+/// path is outside the repository.
+pub fn path_in_repo(path: &str) -> Result<PathBuf, Error> {
+    let root = find_root()?;
+    let abs = normalize(&env::current_dir()?.join(path));
+    if !abs.starts_with(&root) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("fatal: {}: '{}' is outside repository", path, abs.display()),
+        ));
+    }
+    Ok(abs.iter().skip(root.iter().count()).collect())
+}
+
+/// Resolve the `.` and `..` components of a path without following symlinks
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                res.pop();
+            }
+            component => res.push(component),
+        }
+    }
+    res
+}
+
 /// This function return relative the path to `dest`.
 pub fn find_relative_path(dest: &PathBuf) -> PathBuf {
     let mut path = env::current_dir().unwrap();
@@ -60,8 +90,7 @@ pub fn find_relative_path(dest: &PathBuf) -> PathBuf {
     while dest != path {
         let filename = dest
             .iter()
-            .skip(path.iter().count())
-            .next()
+            .nth(path.iter().count())
             .unwrap()
             .to_str()
             .unwrap()
@@ -73,40 +102,192 @@ pub fn find_relative_path(dest: &PathBuf) -> PathBuf {
     res
 }
 
-/// Return the list of ignored pattern. Contains at least .my_git folder
-pub fn ignored(root: &PathBuf) -> Result<Vec<Pattern>, PatternError> {
-    let mut ignored = vec![Pattern::new(".my_git")?];
-    let ignore_file = root.join(".my_gitignore");
-    if ignore_file.is_file() {
-        let mut reader = BufReader::new(File::open(ignore_file).unwrap());
-        let mut buffer = String::new();
-        while reader.read_line(&mut buffer).unwrap() != 0 {
-            // Remove trailing \n
-            buffer.pop();
-            // Remove trailing / (for directories)
-            if buffer.ends_with("/") {
-                buffer.pop();
-            }
-            if !buffer.is_empty() {
-                ignored.push(Pattern::new(&buffer)?);
-                buffer.clear()
-            }
+/// Return the ignore rules of the working tree
+pub fn ignored(root: &Path) -> Result<Ignore, Error> {
+    Ok(Ignore::load(root))
+}
+
+/// Check if an existing path is ignored or not
+pub fn is_ignored(path: &PathBuf, ignored: &Ignore) -> Result<bool, Error> {
+    // Symlinks are not followed, only the parent directories are resolved
+    let path = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            fs::canonicalize(parent)?.join(name)
+        }
+        (_, Some(name)) => env::current_dir()?.join(name),
+        _ => fs::canonicalize(path)?,
+    };
+    let is_dir = fs::symlink_metadata(&path)?.is_dir();
+    let path: PathBuf = path.iter().skip(find_root()?.iter().count()).collect();
+    Ok(ignored.is_ignored(&path, is_dir))
+}
+
+/// Parse a date given by the user. Accepted formats are the git internal
+/// format (`<timestamp> <timezone>`), RFC 2822, ISO 8601 (with or without
+/// timezone) and relative dates like `now`, `yesterday` or `2 weeks ago`.
+pub fn parse_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    let date = date.trim();
+    if let Some(date) = parse_relative_date(date) {
+        return Some(date);
+    }
+    let raw = date.strip_prefix('@').unwrap_or(date);
+    let raw = if raw.contains(' ') {
+        raw.to_string()
+    } else {
+        format!("{} +0000", raw)
+    };
+    if let Ok(date) = DateTime::parse_from_str(&raw, "%s %z") {
+        return Some(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(date) {
+        return Some(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date);
+    }
+    for format in ["%Y-%m-%d %H:%M:%S %z", "%Y-%m-%dT%H:%M:%S%z"].iter() {
+        if let Ok(date) = DateTime::parse_from_str(date, format) {
+            return Some(date);
+        }
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter() {
+        if let Ok(date) = Local.datetime_from_str(date, format) {
+            return Some(DateTime::from(date));
         }
     }
-    Ok(ignored)
+    if let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        let date = Local.from_local_datetime(&date.and_hms(0, 0, 0)).single()?;
+        return Some(DateTime::from(date));
+    }
+    None
 }
 
-/// Check if an existing path is ignored or not
-pub fn is_ignored(path: &PathBuf, ignored: &Vec<Pattern>) -> Result<bool, Error> {
-    let path = fs::canonicalize(path)?;
-    let mut path: PathBuf = path.iter().skip(find_root()?.iter().count()).collect();
-    loop {
-        if ignored.iter().any(|pattern| pattern.matches_path(&path)) {
-            return Ok(true);
+/// Parse a relative date (eg: `3.days.ago`, `2 weeks ago`, `yesterday`)
+fn parse_relative_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    let now = DateTime::<FixedOffset>::from(Local::now());
+    let date = date.to_ascii_lowercase().replace('.', " ");
+    match date.as_str() {
+        "now" => return Some(now),
+        "yesterday" => return Some(now - Duration::days(1)),
+        _ => (),
+    }
+    let words: Vec<&str> = date.split_whitespace().collect();
+    if words.len() != 3 || words[2] != "ago" {
+        return None;
+    }
+    let count: i64 = words[0].parse().ok()?;
+    let unit = words[1].trim_end_matches('s');
+    let duration = match unit {
+        "second" => Duration::seconds(count),
+        "minute" => Duration::minutes(count),
+        "hour" => Duration::hours(count),
+        "day" => Duration::days(count),
+        "week" => Duration::weeks(count),
+        "month" => Duration::days(30 * count),
+        "year" => Duration::days(365 * count),
+        _ => return None,
+    };
+    Some(now - duration)
+}
+
+/// Format a date relatively to now (eg: `3 days ago`)
+pub fn relative_date(date: &DateTime<FixedOffset>) -> String {
+    let seconds = (Local::now().timestamp() - date.timestamp()).max(0);
+    let plural = |count: i64, unit: &str| {
+        if count == 1 {
+            format!("1 {} ago", unit)
+        } else {
+            format!("{} {}s ago", count, unit)
         }
-        if !path.pop() {
-            break;
+    };
+    match seconds {
+        s if s < 90 => plural(s, "second"),
+        s if s < 90 * 60 => plural((s + 30) / 60, "minute"),
+        s if s < 36 * 3600 => plural((s + 1800) / 3600, "hour"),
+        s if s < 14 * 86400 => plural((s + 43200) / 86400, "day"),
+        s if s < 70 * 86400 => plural((s + 302_400) / 604_800, "week"),
+        s if s < 365 * 86400 => plural((s + 1_296_000) / 2_592_000, "month"),
+        s => plural((s + 15_768_000) / 31_536_000, "year"),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use clap::{App, ArgMatches};
+    use std::process;
+    use std::sync::Mutex;
+
+    /// The current directory and `$HOME` are shared by the tests
+    static CWD: Mutex<()> = Mutex::new(());
+
+    /// Parse the arguments of a subcommand, eg: `["clean", "-n"]`
+    pub(crate) fn subcommand(args: &[&str]) -> ArgMatches<'static> {
+        let yaml = Box::leak(Box::new(clap::load_yaml!("cli.yml").clone()));
+        let matches = App::from_yaml(yaml).get_matches_from([&["my_git"], args].concat());
+        matches.subcommand_matches(args[0]).unwrap().clone()
+    }
+
+    /// Run `test` from the root of a new repository, `$HOME` being its parent
+    /// directory. Both are removed afterwards.
+    pub(crate) fn in_repo<T>(name: &str, test: impl FnOnce(&Path) -> T) -> T {
+        let _lock = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let home = env::temp_dir().join(format!("my_git_{}_{}", name, process::id()));
+        let root = home.join("repo");
+        let repo_path = root.join(".my_git");
+        fs::create_dir_all(repo_path.join("objects")).unwrap();
+        fs::create_dir_all(repo_path.join("refs/heads")).unwrap();
+        fs::write(repo_path.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        crate::index::Index::load_text(b"").save(&repo_path);
+
+        let (cwd, old_home) = (env::current_dir().unwrap(), env::var_os("HOME"));
+        env::set_current_dir(&root).unwrap();
+        env::set_var("HOME", &home);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| test(&root)));
+        env::set_current_dir(cwd).unwrap();
+        match old_home {
+            Some(home) => env::set_var("HOME", home),
+            None => env::remove_var("HOME"),
         }
+        fs::remove_dir_all(&home).unwrap();
+        res.unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    #[test]
+    fn dates() {
+        let date = |date: &str| {
+            parse_date(date).map(|date| (date.timestamp(), date.offset().local_minus_utc()))
+        };
+        assert_eq!(date("1000000000 +0200"), Some((1_000_000_000, 7200)));
+        assert_eq!(date("@1000000000"), Some((1_000_000_000, 0)));
+        assert_eq!(
+            date("Sun, 9 Sep 2001 03:46:40 +0200"),
+            Some((1_000_000_000, 7200))
+        );
+        assert_eq!(date("2001-09-09T01:46:40Z"), Some((1_000_000_000, 0)));
+        assert_eq!(
+            date("2001-09-09 00:46:40 -0100"),
+            Some((1_000_000_000, -3600))
+        );
+        assert_eq!(
+            date("2001-09-09T03:46:40+0200"),
+            Some((1_000_000_000, 7200))
+        );
+        // Dates without timezone are local
+        let local = Local.ymd(2001, 9, 9).and_hms(1, 46, 40).timestamp();
+        assert_eq!(date("2001-09-09 01:46:40").unwrap().0, local);
+        assert_eq!(date("2001-09-09").unwrap().0, local - 6400);
+        assert_eq!(date("not a date"), None);
+        assert_eq!(date("2001-13-09"), None);
+
+        let now = Local::now().timestamp();
+        let ago = |date: &str| now - parse_date(date).unwrap().timestamp();
+        assert!(ago("now") <= 1);
+        assert!((86400..=86401).contains(&ago("yesterday")));
+        assert!((1_209_600..=1_209_601).contains(&ago("2 weeks ago")));
+        assert!((259_200..=259_201).contains(&ago("3.days.ago")));
+        assert!((3600..=3601).contains(&ago("1 HOUR AGO")));
+        assert_eq!(parse_date("2 fortnights ago"), None);
+        assert_eq!(parse_date("weeks ago"), None);
     }
-    Ok(false)
 }